@@ -0,0 +1,66 @@
+//! Parsing and rendering baselines for representative provider IDs, run with
+//! `cargo bench`.
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use node_provider_labeler::{
+    provider_id::ProviderID,
+    template::{AnnotationTemplate, LabelTemplate, RenderContext, Template},
+};
+
+const PROVIDER_IDS: [(&str, &str); 4] = [
+    ("aws", "aws:///us-east-2a/i-0abcdef1234567890"),
+    ("gce", "gce://my-project/us-central1-a/gke-cluster-1-default-pool-12345678-abc1"),
+    (
+        "azure",
+        "azure:///subscriptions/00000000-0000-0000-0000-000000000000/resourceGroups/my-rg/providers/Microsoft.Compute/virtualMachineScaleSets/aks-nodepool1-12345678-vmss/virtualMachines/0",
+    ),
+    ("kind", "kind://podman/node-prov/node-prov-worker"),
+];
+
+const LABEL_TEMPLATES: [(&str, &str); 3] = [
+    ("last", "{:last}"),
+    ("indexed", "{0}-{1}"),
+    ("mixed", "{:provider}-{:first}-{:last}"),
+];
+
+fn provider_id(c: &mut Criterion) {
+    let mut group = c.benchmark_group("provider_id");
+    for (name, id) in PROVIDER_IDS {
+        group.bench_function(name, |b| {
+            b.iter(|| ProviderID::new(black_box("node"), black_box(id)))
+        });
+    }
+    group.finish();
+}
+
+fn parse(c: &mut Criterion) {
+    let mut group = c.benchmark_group("parse");
+    for (name, template) in LABEL_TEMPLATES {
+        group.bench_function(name, |b| {
+            b.iter(|| black_box(template).parse::<LabelTemplate>())
+        });
+    }
+    group.bench_function("annotation", |b| {
+        b.iter(|| {
+            black_box("{:node} runs {:provider} instance {:last}").parse::<AnnotationTemplate>()
+        })
+    });
+    group.finish();
+}
+
+fn render(c: &mut Criterion) {
+    let mut group = c.benchmark_group("render");
+    for (provider, id) in PROVIDER_IDS {
+        let provider_id = ProviderID::new("node", id).unwrap();
+        let ctx = RenderContext::new(&provider_id);
+        for (name, template) in LABEL_TEMPLATES {
+            let template = template.parse::<LabelTemplate>().unwrap();
+            group.bench_function(format!("{provider}/{name}"), |b| {
+                b.iter(|| template.render(black_box(&ctx)))
+            });
+        }
+    }
+    group.finish();
+}
+
+criterion_group!(benches, provider_id, parse, render);
+criterion_main!(benches);
@@ -0,0 +1,246 @@
+//! The parsed structure of templates, as typed tokens with their spans in the
+//! template, for tooling that analyzes templates, e.g. linters and editor
+//! plugins, without re-implementing the grammar.
+//!
+//! ```
+//! use node_provider_labeler::{
+//!     ast::{Filter, TokenKind},
+//!     template::AnnotationTemplate,
+//! };
+//!
+//! let template: AnnotationTemplate = "{:first|kebab}-{label:pool}".parse().unwrap();
+//! let tokens = template.tokens();
+//! assert_eq!(tokens[0].kind, TokenKind::First);
+//! assert_eq!(tokens[0].filters, [Filter::Kebab]);
+//! assert_eq!(tokens[1].kind, TokenKind::Text("-".into()));
+//! assert_eq!(tokens[2].span, 15..27);
+//! ```
+use crate::template::Rule;
+use pest::iterators::Pair;
+use std::ops::Range;
+
+/// A byte range of the template.
+pub type Span = Range<usize>;
+
+/// A token of a template: literal text, or a `{...}` token rendering a value.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Token {
+    pub kind: TokenKind,
+    /// The topology label the value falls back to for nodes without a
+    /// provider ID, e.g. `zone` in `{:first|zone}`
+    pub fallback: Option<Topology>,
+    /// The `|<filter>` suffixes, applied in order
+    pub filters: Vec<Filter>,
+    pub span: Span,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum TokenKind {
+    /// Literal text between tokens
+    Text(String),
+    /// `{:last}`
+    Last,
+    /// `{:first}`
+    First,
+    /// `{:all}`
+    All,
+    /// `{:provider}`
+    Provider,
+    /// `{:url}`
+    Url,
+    /// `{:node}`
+    Node,
+    /// `{:lifecycle}`
+    Lifecycle,
+    /// `{:bucket(<n>)}`, with the number of buckets
+    Bucket(u64),
+    /// `{<n>}`, the nth part of the provider ID
+    Nth(usize),
+    /// `{<namespace>:<key>}`, e.g. `{label:pool}`
+    Field { namespace: String, key: String },
+    /// `{plugin:<name>(<input>)}`, with the input token without braces, e.g.
+    /// `:last` or `label:pool`
+    Plugin { name: String, input: String },
+}
+
+/// The topology labels values fall back to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Topology {
+    Zone,
+    Region,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Filter {
+    Kebab,
+    Snake,
+    Camel,
+    /// `split('<delimiter>', <index>)`
+    Split {
+        delimiter: String,
+        index: usize,
+    },
+    /// `map(<name>)`, with the name of the lookup table
+    Map(String),
+}
+
+/// The tokens of a parsed label or annotation template, merging adjacent
+/// characters into text.
+pub(crate) fn tokens(template: Pair<Rule>) -> Vec<Token> {
+    let mut tokens: Vec<Token> = vec![];
+    for pair in template.into_inner() {
+        let span = pair.as_span().start()..pair.as_span().end();
+        let kind = match pair.as_rule() {
+            Rule::char | Rule::label_char => {
+                if let Some(Token {
+                    kind: TokenKind::Text(text),
+                    span: last,
+                    ..
+                }) = tokens.last_mut()
+                {
+                    text.push_str(pair.as_str());
+                    last.end = span.end;
+                    continue;
+                }
+                TokenKind::Text(pair.as_str().to_string())
+            }
+            Rule::last => TokenKind::Last,
+            Rule::first => TokenKind::First,
+            Rule::all => TokenKind::All,
+            Rule::provider => TokenKind::Provider,
+            Rule::url => TokenKind::Url,
+            Rule::node => TokenKind::Node,
+            Rule::lifecycle => TokenKind::Lifecycle,
+            Rule::bucket => {
+                let buckets = pair.clone().into_inner().next().unwrap().as_str();
+                // the grammar only allows digits
+                TokenKind::Bucket(buckets.parse().unwrap_or(u64::MAX))
+            }
+            Rule::nth => {
+                let idx = pair.clone().into_inner().next().unwrap().as_str();
+                TokenKind::Nth(idx.parse().unwrap_or(usize::MAX))
+            }
+            Rule::field => {
+                let mut inner = pair.clone().into_inner();
+                TokenKind::Field {
+                    namespace: inner.next().unwrap().as_str().to_string(),
+                    key: inner.next().unwrap().as_str().to_string(),
+                }
+            }
+            Rule::plugin => {
+                let mut inner = pair.clone().into_inner();
+                TokenKind::Plugin {
+                    name: inner.next().unwrap().as_str().to_string(),
+                    input: inner.next().unwrap().as_str().to_string(),
+                }
+            }
+            _ => continue,
+        };
+        let mut token = Token {
+            kind,
+            fallback: None,
+            filters: vec![],
+            span,
+        };
+        for inner in pair.into_inner() {
+            match inner.as_rule() {
+                Rule::topology if inner.as_str() == "zone" => token.fallback = Some(Topology::Zone),
+                Rule::topology => token.fallback = Some(Topology::Region),
+                Rule::filter => token.filters.extend(filter(inner)),
+                _ => (),
+            }
+        }
+        tokens.push(token);
+    }
+    tokens
+}
+
+fn filter(pair: Pair<Rule>) -> Option<Filter> {
+    let filter = pair.into_inner().next()?;
+    Some(match filter.as_rule() {
+        Rule::kebab => Filter::Kebab,
+        Rule::snake => Filter::Snake,
+        Rule::camel => Filter::Camel,
+        Rule::split => {
+            let mut inner = filter.into_inner();
+            Filter::Split {
+                delimiter: inner.next()?.as_str().to_string(),
+                index: inner.next()?.as_str().parse().unwrap_or(usize::MAX),
+            }
+        }
+        Rule::map => Filter::Map(filter.into_inner().next()?.as_str().to_string()),
+        _ => return None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::template::{AnnotationTemplate, LabelTemplate, ProviderIDTemplate};
+
+    fn token(kind: TokenKind, span: Span) -> Token {
+        Token {
+            kind,
+            fallback: None,
+            filters: vec![],
+            span,
+        }
+    }
+
+    #[test]
+    fn test_tokens() {
+        let label: LabelTemplate = "id-{:last|zone|split('-', 1)|snake}.{2};max-length=20"
+            .parse()
+            .unwrap();
+        assert_eq!(
+            label.tokens(),
+            [
+                token(TokenKind::Text("id-".into()), 0..3),
+                Token {
+                    fallback: Some(Topology::Zone),
+                    filters: vec![
+                        Filter::Split {
+                            delimiter: "-".into(),
+                            index: 1
+                        },
+                        Filter::Snake
+                    ],
+                    ..token(TokenKind::Last, 3..35)
+                },
+                token(TokenKind::Text(".".into()), 35..36),
+                token(TokenKind::Nth(2), 36..39),
+            ]
+        );
+
+        let annotation: AnnotationTemplate =
+            "{plugin:site(label:pool)} {:bucket(8)}".parse().unwrap();
+        let tokens = annotation.tokens();
+        assert_eq!(
+            tokens[0].kind,
+            TokenKind::Plugin {
+                name: "site".into(),
+                input: "label:pool".into()
+            }
+        );
+        assert_eq!(tokens[1], token(TokenKind::Text(" ".into()), 25..26));
+        assert_eq!(tokens[2], token(TokenKind::Bucket(8), 26..38));
+
+        let provider_id: ProviderIDTemplate = "metal://{label:rack}/{:node}".parse().unwrap();
+        assert_eq!(
+            provider_id
+                .tokens()
+                .into_iter()
+                .map(|t| t.kind)
+                .collect::<Vec<_>>(),
+            [
+                TokenKind::Text("metal://".into()),
+                TokenKind::Field {
+                    namespace: "label".into(),
+                    key: "rack".into()
+                },
+                TokenKind::Text("/".into()),
+                TokenKind::Node,
+            ]
+        );
+    }
+}
@@ -0,0 +1,399 @@
+use crate::{enrich::Enricher, lifecycle, provider_id::ProviderID, template::Fields, Error};
+use async_trait::async_trait;
+use k8s_openapi::api::core::v1::Node;
+use serde::Deserialize;
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::PathBuf,
+    time::Duration,
+};
+use tokio::{sync::RwLock, time::Instant};
+use tracing::debug;
+
+const PROVIDER: &str = "azure";
+const ARM_ENDPOINT: &str = "https://management.azure.com";
+const ARM_SCOPE: &str = "https://management.azure.com/.default";
+const DEFAULT_AUTHORITY_HOST: &str = "https://login.microsoftonline.com/";
+const VM_API_VERSION: &str = "2023-03-01";
+const CLIENT_ASSERTION_TYPE: &str = "urn:ietf:params:oauth:client-assertion-type:jwt-bearer";
+// refresh access tokens this long before they expire
+const TOKEN_REFRESH_MARGIN: Duration = Duration::from_secs(300);
+
+/// Enriches Azure nodes with the ARM tags and SKU of the VM or VMSS instance
+/// behind their provider ID.
+///
+/// Exposes `{azure:sku}` and `{azure:tag:<name>}` for each selected tag.
+pub struct AzureEnricher {
+    client: reqwest::Client,
+    credential: WorkloadIdentityCredential,
+    tags: Vec<String>,
+    cache_ttl: Duration,
+    cache: RwLock<HashMap<String, (Instant, Fields)>>,
+}
+
+impl std::fmt::Debug for AzureEnricher {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("AzureEnricher")
+            .field("tags", &self.tags)
+            .field("cache_ttl", &self.cache_ttl)
+            .finish()
+    }
+}
+
+impl AzureEnricher {
+    /// Creates an enricher authenticating with the workload identity
+    /// environment injected by the Azure Workload Identity webhook.
+    pub fn from_env(tags: Vec<String>, cache_ttl: Duration) -> Result<Self, Error> {
+        Ok(Self {
+            client: reqwest::Client::new(),
+            credential: WorkloadIdentityCredential::from_env()?,
+            tags,
+            cache_ttl,
+            cache: RwLock::new(HashMap::new()),
+        })
+    }
+
+    /// Returns the enrichment fields for the node. Non-Azure nodes get no
+    /// fields.
+    pub(crate) async fn fields(&self, provider_id: &ProviderID) -> Result<Fields, Error> {
+        if provider_id.provider() != PROVIDER {
+            return Ok(Fields::new());
+        }
+
+        let path = resource_path(provider_id)?;
+        if let Some((fetched, fields)) = self.cache.read().await.get(&path) {
+            if fetched.elapsed() < self.cache_ttl {
+                return Ok(fields.clone());
+            }
+        }
+
+        debug!({ resource = path }, "fetching azure resource");
+        let token = self.credential.token(&self.client).await?;
+        let resource = self
+            .client
+            .get(format!("{ARM_ENDPOINT}{path}"))
+            .query(&[("api-version", VM_API_VERSION)])
+            .bearer_auth(token)
+            .send()
+            .await
+            .and_then(reqwest::Response::error_for_status)
+            .map_err(|e| Error::Azure(e.to_string()))?
+            .json::<Resource>()
+            .await
+            .map_err(|e| Error::Azure(e.to_string()))?;
+
+        let fields = resource.fields(&self.tags);
+        self.cache
+            .write()
+            .await
+            .insert(path, (Instant::now(), fields.clone()));
+
+        Ok(fields)
+    }
+}
+
+#[async_trait]
+impl Enricher for AzureEnricher {
+    fn name(&self) -> &str {
+        PROVIDER
+    }
+
+    async fn fields(&self, _node: &Node, provider_id: &ProviderID) -> Result<Fields, Error> {
+        AzureEnricher::fields(self, provider_id).await
+    }
+}
+
+/// Extracts the ARM resource path of a VM or VMSS instance from an Azure
+/// provider ID, e.g.
+/// `azure:///subscriptions/<sub>/resourceGroups/<rg>/providers/Microsoft.Compute/virtualMachines/<vm>`.
+fn resource_path(provider_id: &ProviderID) -> Result<String, Error> {
+    let node_id = provider_id.node_id();
+    let path = format!("/{}", node_id.trim_start_matches('/'));
+    let parts = path.split('/').skip(1).collect::<Vec<_>>();
+
+    let valid = match parts.as_slice() {
+        ["subscriptions", _, "resourceGroups", _, "providers", p, "virtualMachines", _] => {
+            p.eq_ignore_ascii_case("Microsoft.Compute")
+        }
+        ["subscriptions", _, "resourceGroups", _, "providers", p, "virtualMachineScaleSets", _, "virtualMachines", _] => {
+            p.eq_ignore_ascii_case("Microsoft.Compute")
+        }
+        _ => false,
+    };
+
+    if !valid {
+        return Err(Error::Azure(format!(
+            "provider id is not a VM or VMSS instance: {provider_id}"
+        )));
+    }
+
+    Ok(path)
+}
+
+#[derive(Deserialize, Debug, Default)]
+struct Resource {
+    #[serde(default)]
+    tags: BTreeMap<String, String>,
+    sku: Option<Sku>,
+    properties: Option<Properties>,
+}
+
+#[derive(Deserialize, Debug)]
+struct Sku {
+    name: String,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct Properties {
+    hardware_profile: Option<HardwareProfile>,
+    priority: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct HardwareProfile {
+    vm_size: String,
+}
+
+impl Resource {
+    fn fields(&self, tags: &[String]) -> Fields {
+        let mut fields = Fields::new();
+
+        // VMSS instances carry their SKU at the top level, standalone VMs in
+        // the hardware profile
+        let sku = self.sku.as_ref().map(|s| s.name.clone()).or_else(|| {
+            self.properties
+                .as_ref()
+                .and_then(|p| p.hardware_profile.as_ref())
+                .map(|h| h.vm_size.clone())
+        });
+        if let Some(sku) = sku {
+            fields.insert(format!("{PROVIDER}:sku"), sku);
+        }
+
+        // only standalone VMs carry their priority, VMSS instances inherit it
+        // from the scale set
+        if let Some(priority) = self.properties.as_ref().and_then(|p| p.priority.as_ref()) {
+            let lifecycle = match priority.as_str() {
+                "Spot" | "Low" => lifecycle::SPOT,
+                _ => lifecycle::ON_DEMAND,
+            };
+            fields.insert(format!("{PROVIDER}:lifecycle"), lifecycle.into());
+        }
+
+        for tag in tags {
+            if let Some(value) = self.tags.get(tag) {
+                fields.insert(format!("{PROVIDER}:tag:{tag}"), value.clone());
+            }
+        }
+
+        fields
+    }
+}
+
+/// Exchanges the projected service account token for an ARM access token.
+///
+/// The token file is re-read on every exchange so rotated service account
+/// tokens are picked up without a restart.
+struct WorkloadIdentityCredential {
+    client_id: String,
+    tenant_id: String,
+    token_file: PathBuf,
+    authority_host: String,
+    token: RwLock<Option<AccessToken>>,
+}
+
+struct AccessToken {
+    secret: String,
+    expires_at: Instant,
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    access_token: String,
+    expires_in: u64,
+}
+
+impl WorkloadIdentityCredential {
+    fn from_env() -> Result<Self, Error> {
+        let var = |name: &str| {
+            std::env::var(name).map_err(|_| Error::Azure(format!("{name} is not set")))
+        };
+
+        let mut authority_host =
+            std::env::var("AZURE_AUTHORITY_HOST").unwrap_or(DEFAULT_AUTHORITY_HOST.to_string());
+        if !authority_host.ends_with('/') {
+            authority_host.push('/');
+        }
+
+        Ok(Self {
+            client_id: var("AZURE_CLIENT_ID")?,
+            tenant_id: var("AZURE_TENANT_ID")?,
+            token_file: var("AZURE_FEDERATED_TOKEN_FILE")?.into(),
+            authority_host,
+            token: RwLock::new(None),
+        })
+    }
+
+    async fn token(&self, client: &reqwest::Client) -> Result<String, Error> {
+        if let Some(secret) = fresh(&*self.token.read().await) {
+            return Ok(secret);
+        }
+        self.refresh(client).await
+    }
+
+    /// Fetches a new token, unless another caller did while this one waited
+    /// for the lock.
+    async fn refresh(&self, client: &reqwest::Client) -> Result<String, Error> {
+        let mut token = self.token.write().await;
+        if let Some(secret) = fresh(&token) {
+            return Ok(secret);
+        }
+        debug!("refreshing azure access token");
+        let assertion = tokio::fs::read_to_string(&self.token_file)
+            .await
+            .map_err(|e| Error::Azure(format!("reading federated token: {e}")))?;
+        let response = client
+            .post(format!(
+                "{}{}/oauth2/v2.0/token",
+                self.authority_host, self.tenant_id
+            ))
+            .form(&[
+                ("client_id", self.client_id.as_str()),
+                ("scope", ARM_SCOPE),
+                ("grant_type", "client_credentials"),
+                ("client_assertion_type", CLIENT_ASSERTION_TYPE),
+                ("client_assertion", assertion.trim()),
+            ])
+            .send()
+            .await
+            .and_then(reqwest::Response::error_for_status)
+            .map_err(|e| Error::Azure(e.to_string()))?
+            .json::<TokenResponse>()
+            .await
+            .map_err(|e| Error::Azure(e.to_string()))?;
+
+        let secret = response.access_token;
+        *token = Some(AccessToken {
+            secret: secret.clone(),
+            expires_at: Instant::now() + Duration::from_secs(response.expires_in),
+        });
+
+        Ok(secret)
+    }
+}
+
+/// The cached token's secret, unless it expires within the refresh margin.
+fn fresh(token: &Option<AccessToken>) -> Option<String> {
+    token
+        .as_ref()
+        .filter(|t| t.expires_at > Instant::now() + TOKEN_REFRESH_MARGIN)
+        .map(|t| t.secret.clone())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_resource_path() {
+        let p = |id: &str| resource_path(&ProviderID::new("my-node-name", id).unwrap());
+
+        assert_eq!(
+            p("azure:///subscriptions/sub/resourceGroups/rg/providers/Microsoft.Compute/virtualMachines/vm").unwrap(),
+            "/subscriptions/sub/resourceGroups/rg/providers/Microsoft.Compute/virtualMachines/vm"
+        );
+        assert_eq!(
+            p("azure:///subscriptions/sub/resourceGroups/mc_rg/providers/Microsoft.Compute/virtualMachineScaleSets/aks-pool-vmss/virtualMachines/3").unwrap(),
+            "/subscriptions/sub/resourceGroups/mc_rg/providers/Microsoft.Compute/virtualMachineScaleSets/aks-pool-vmss/virtualMachines/3"
+        );
+        assert!(p("azure:///subscriptions/sub/resourceGroups/rg").is_err());
+        assert!(p("azure:///subscriptions/sub/resourceGroups/rg/providers/Microsoft.Network/virtualMachines/vm").is_err());
+    }
+
+    #[test]
+    fn test_resource_fields() {
+        let tags = vec!["team".to_string(), "missing".to_string()];
+
+        // vmss instance
+        let resource: Resource = serde_json::from_str(
+            r#"{"sku":{"name":"Standard_D2s_v3","tier":"Standard"},"tags":{"team":"platform","env":"prod"}}"#,
+        )
+        .unwrap();
+        let fields = resource.fields(&tags);
+        assert_eq!(fields.get("azure:sku").unwrap(), "Standard_D2s_v3");
+        assert_eq!(fields.get("azure:tag:team").unwrap(), "platform");
+        assert!(!fields.contains_key("azure:tag:env"));
+        assert!(!fields.contains_key("azure:tag:missing"));
+
+        // standalone vm
+        let resource: Resource =
+            serde_json::from_str(r#"{"properties":{"hardwareProfile":{"vmSize":"Standard_B2s"}}}"#)
+                .unwrap();
+        let fields = resource.fields(&tags);
+        assert_eq!(fields.get("azure:sku").unwrap(), "Standard_B2s");
+        assert_eq!(fields.len(), 1);
+
+        let resource: Resource =
+            serde_json::from_str(r#"{"properties":{"priority":"Spot"}}"#).unwrap();
+        assert_eq!(
+            resource.fields(&tags).get("azure:lifecycle").unwrap(),
+            "spot"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_token_refresh() {
+        use std::sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        };
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        // a token endpoint counting the requests it answers
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let requests = Arc::new(AtomicUsize::new(0));
+        tokio::spawn({
+            let requests = requests.clone();
+            async move {
+                loop {
+                    let (mut stream, _) = listener.accept().await.unwrap();
+                    let n = requests.fetch_add(1, Ordering::SeqCst);
+                    let mut buf = vec![0; 4096];
+                    let _ = stream.read(&mut buf).await.unwrap();
+                    let body = format!(r#"{{"access_token":"token-{n}","expires_in":3600}}"#);
+                    let response = format!(
+                        "HTTP/1.1 200 OK\r\ncontent-type: application/json\r\ncontent-length: {}\r\nconnection: close\r\n\r\n{body}",
+                        body.len()
+                    );
+                    stream.write_all(response.as_bytes()).await.unwrap();
+                }
+            }
+        });
+
+        let dir = std::env::temp_dir().join(format!("azure-token-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let token_file = dir.join("token");
+        std::fs::write(&token_file, "federated").unwrap();
+        let credential = WorkloadIdentityCredential {
+            client_id: "client".into(),
+            tenant_id: "tenant".into(),
+            token_file,
+            authority_host: format!("http://{addr}/"),
+            token: RwLock::new(Some(AccessToken {
+                secret: "expired".into(),
+                expires_at: Instant::now(),
+            })),
+        };
+
+        let client = reqwest::Client::new();
+        assert_eq!(credential.token(&client).await.unwrap(), "token-0");
+        // a caller that saw the expired token before the refresh
+        assert_eq!(credential.refresh(&client).await.unwrap(), "token-0");
+        assert_eq!(credential.token(&client).await.unwrap(), "token-0");
+        assert_eq!(requests.load(Ordering::SeqCst), 1);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,253 @@
+//! Backups of the values the controller overwrote, for rolling back a bad
+//! template rollout with [`undo`].
+use crate::{
+    controller::remove_taints,
+    export::managed_keys,
+    sink::{taint_keys, MetadataPairs, Sink, TaintRenderer, Target, TargetPatch},
+    template::RenderContext,
+    Error,
+};
+use k8s_openapi::api::core::v1::Node;
+use kube::{
+    api::{ListParams, ObjectMeta, Patch, PatchParams},
+    Api, Client, ResourceExt,
+};
+use serde::{Deserialize, Serialize};
+use tracing::info;
+
+/// Holds the [`Originals`] of a node as JSON.
+pub const BACKUP_ANNOTATION: &str = "node-provider-labeler/originals";
+
+const MANAGER: &str = "node-provider-labeler";
+const RESTORE_MANAGER: &str = "node-provider-labeler-undo";
+
+/// The values of pre-existing keys, from before the controller first took
+/// them over.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct Originals {
+    #[serde(default, skip_serializing_if = "MetadataPairs::is_empty")]
+    pub labels: MetadataPairs,
+    #[serde(default, skip_serializing_if = "MetadataPairs::is_empty")]
+    pub annotations: MetadataPairs,
+}
+
+impl Originals {
+    /// The originals recorded on the object, if any.
+    pub fn from_metadata(metadata: &ObjectMeta) -> Result<Self, Error> {
+        let Some(backup) = metadata
+            .annotations
+            .as_ref()
+            .and_then(|a| a.get(BACKUP_ANNOTATION))
+        else {
+            return Ok(Self::default());
+        };
+        serde_json::from_str(backup).map_err(|e| {
+            Error::Config(format!(
+                "invalid {BACKUP_ANNOTATION} annotation on {}: {e}",
+                metadata.name.as_deref().unwrap_or_default()
+            ))
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.labels.is_empty() && self.annotations.is_empty()
+    }
+}
+
+/// Records the current value of each label and annotation the earlier sinks
+/// set that exists but isn't the controller's yet in the
+/// [`BACKUP_ANNOTATION`], before the patch takes it over. Runs after the
+/// other sinks.
+#[derive(Debug, Default)]
+pub struct BackupSink;
+
+impl Sink for BackupSink {
+    fn render(
+        &self,
+        target: Target<'_>,
+        _ctx: &RenderContext,
+        patch: &mut TargetPatch,
+    ) -> Result<(), Error> {
+        let metadata = target.metadata();
+        let mut originals = Originals::from_metadata(metadata)?;
+
+        backup(
+            &mut originals.labels,
+            metadata.labels.as_ref(),
+            &patch.labels,
+            managed_keys(metadata, "f:labels"),
+        );
+        backup(
+            &mut originals.annotations,
+            metadata.annotations.as_ref(),
+            &patch.annotations,
+            managed_keys(metadata, "f:annotations"),
+        );
+
+        if !originals.is_empty() {
+            patch
+                .annotations
+                .insert(BACKUP_ANNOTATION.into(), serde_json::to_string(&originals)?);
+        }
+        Ok(())
+    }
+}
+
+/// Adds the current values of the keys the patch sets that the controller
+/// doesn't own and that aren't backed up already.
+fn backup(
+    originals: &mut MetadataPairs,
+    current: Option<&MetadataPairs>,
+    patch: &MetadataPairs,
+    managed: Vec<String>,
+) {
+    for key in patch.keys() {
+        if key == BACKUP_ANNOTATION || managed.contains(key) || originals.contains_key(key) {
+            continue;
+        }
+        if let Some(value) = current.and_then(|c| c.get(key)) {
+            originals.insert(key.clone(), value.clone());
+        }
+    }
+}
+
+/// Removes the labels and annotations the controller manages, and the
+/// `taints` it was configured with, from every node it manages, like
+/// [`crate::controller::cleanup`], then restores the originals they replaced.
+/// Returns the names of the nodes. With `dry_run`, the API server only
+/// validates the changes.
+pub async fn undo(
+    client: Client,
+    taints: &[TaintRenderer],
+    dry_run: bool,
+) -> Result<Vec<String>, Error> {
+    let node_api: Api<Node> = Api::all(client);
+    let taints = taint_keys(taints);
+    let mut release = PatchParams::apply(MANAGER).force();
+    release.dry_run = dry_run;
+    let restore = PatchParams {
+        field_manager: Some(RESTORE_MANAGER.into()),
+        dry_run,
+        ..Default::default()
+    };
+
+    let mut undone = vec![];
+    for node in node_api.list(&ListParams::default()).await? {
+        let managed = node
+            .managed_fields()
+            .iter()
+            .any(|f| f.manager.as_deref() == Some(MANAGER));
+        if !managed {
+            continue;
+        }
+        let name = node.name_any();
+        let originals = Originals::from_metadata(&node.metadata)?;
+
+        // applying nothing releases every field the manager owns
+        let payload = Node {
+            metadata: ObjectMeta {
+                name: Some(name.clone()),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        info!({ node = name, dry_run, restored = originals.labels.len() + originals.annotations.len() }, "undoing");
+        remove_taints(&node_api, &node, &taints, dry_run).await?;
+        node_api
+            .patch(&name, &release, &Patch::Apply(&payload))
+            .await?;
+
+        if !originals.is_empty() {
+            let payload = serde_json::json!({
+                "metadata": {
+                    "labels": originals.labels,
+                    "annotations": originals.annotations,
+                },
+            });
+            node_api
+                .patch(&name, &restore, &Patch::Merge(&payload))
+                .await?;
+        }
+        undone.push(name);
+    }
+
+    Ok(undone)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{provider_id::ProviderID, sink::LabelSink, testing};
+    use k8s_openapi::apimachinery::pkg::apis::meta::v1::{FieldsV1, ManagedFieldsEntry};
+
+    #[test]
+    fn test_backup_sink() {
+        let provider_id = ProviderID::new("my-node", "fake://region/instance").unwrap();
+        let render_ctx = RenderContext::new(&provider_id);
+        let sinks: Vec<Box<dyn Sink>> = vec![
+            Box::new(LabelSink(vec![
+                "zone={:first}".parse().unwrap(),
+                "id={:last}".parse().unwrap(),
+                "new={:last}".parse().unwrap(),
+            ])),
+            Box::new(BackupSink),
+        ];
+        let render = |node: &Node| {
+            let mut patch = TargetPatch::default();
+            for sink in &sinks {
+                sink.render(Target::Node(node), &render_ctx, &mut patch)
+                    .unwrap();
+            }
+            patch
+        };
+
+        // pre-existing keys are backed up, even with the rendered value
+        let node = testing::node("my-node")
+            .label("zone", "user-zone")
+            .label("id", "instance")
+            .build();
+        let patch = render(&node);
+        let backup = patch.annotations[BACKUP_ANNOTATION].clone();
+        assert_eq!(
+            serde_json::from_str::<Originals>(&backup).unwrap(),
+            Originals {
+                labels: [
+                    ("id".into(), "instance".into()),
+                    ("zone".into(), "user-zone".into())
+                ]
+                .into(),
+                ..Default::default()
+            }
+        );
+
+        // once the controller owns the keys, the backup is kept as it is
+        let mut node = testing::node("my-node")
+            .label("zone", "region")
+            .label("id", "instance")
+            .label("new", "instance")
+            .annotation(BACKUP_ANNOTATION, &backup)
+            .build();
+        node.metadata.managed_fields = Some(vec![ManagedFieldsEntry {
+            manager: Some(MANAGER.into()),
+            fields_v1: Some(FieldsV1(serde_json::json!({
+                "f:metadata": {
+                    "f:labels": { "f:zone": {}, "f:id": {}, "f:new": {} },
+                },
+            }))),
+            ..Default::default()
+        }]);
+        assert_eq!(render(&node).annotations[BACKUP_ANNOTATION], backup);
+
+        // nothing to back up
+        let patch = render(&testing::node("my-node").build());
+        assert!(!patch.annotations.contains_key(BACKUP_ANNOTATION));
+
+        let node = testing::node("my-node")
+            .annotation(BACKUP_ANNOTATION, "{")
+            .build();
+        assert!(matches!(
+            Originals::from_metadata(&node.metadata),
+            Err(Error::Config(_))
+        ));
+    }
+}
@@ -0,0 +1,138 @@
+//! A per-node circuit breaker: a node whose reconciliations keep failing is
+//! quarantined, reconciled again only once the quarantine passed, so one
+//! pathological node can't dominate the error budget and log volume.
+use crate::Error;
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+/// When to quarantine a node, and for how long.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct QuarantineOptions {
+    /// Consecutive failed reconciliations after which a node is quarantined
+    pub after_failures: u32,
+    pub duration: Duration,
+}
+
+impl QuarantineOptions {
+    pub fn new(after_failures: u32) -> Self {
+        Self {
+            after_failures,
+            duration: Duration::from_secs(3600),
+        }
+    }
+
+    pub(crate) fn validate(&self) -> Result<(), Error> {
+        if self.after_failures == 0 {
+            return Err(Error::Config(
+                "quarantine failures must be at least 1".into(),
+            ));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Default)]
+struct NodeState {
+    failures: u32,
+    until: Option<Instant>,
+}
+
+/// Counts consecutive failed reconciliations per node.
+#[derive(Debug)]
+pub struct CircuitBreaker {
+    options: QuarantineOptions,
+    nodes: Mutex<HashMap<String, NodeState>>,
+}
+
+impl CircuitBreaker {
+    pub fn new(options: QuarantineOptions) -> Self {
+        Self {
+            options,
+            nodes: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn options(&self) -> &QuarantineOptions {
+        &self.options
+    }
+
+    /// How long the node stays quarantined, if it is.
+    pub fn quarantined(&self, node: &str) -> Option<Duration> {
+        let nodes = self.nodes.lock().unwrap();
+        let until = nodes.get(node)?.until?;
+        Some(until.saturating_duration_since(Instant::now())).filter(|d| !d.is_zero())
+    }
+
+    /// Counts the outcome of the node's reconciliation, returning whether it
+    /// failed often enough to quarantine the node. A node reconciled after
+    /// its quarantine is quarantined again by its next failure, and released
+    /// by a success.
+    pub fn observe(&self, node: &str, ok: bool) -> bool {
+        let mut nodes = self.nodes.lock().unwrap();
+        if ok {
+            nodes.remove(node);
+            return false;
+        }
+        let state = nodes.entry(node.to_string()).or_default();
+        state.failures += 1;
+        if state.failures < self.options.after_failures {
+            return false;
+        }
+        state.until = Some(Instant::now() + self.options.duration);
+        true
+    }
+
+    /// Forgets a deleted node.
+    pub fn remove(&self, node: &str) {
+        self.nodes.lock().unwrap().remove(node);
+    }
+
+    /// The number of nodes that failed often enough to be quarantined.
+    pub fn len(&self) -> usize {
+        let nodes = self.nodes.lock().unwrap();
+        nodes
+            .values()
+            .filter(|n| n.failures >= self.options.after_failures)
+            .count()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_circuit_breaker() {
+        let breaker = CircuitBreaker::new(QuarantineOptions {
+            after_failures: 2,
+            duration: Duration::from_secs(60),
+        });
+
+        assert!(!breaker.observe("a", false));
+        assert_eq!(breaker.quarantined("a"), None);
+        // a success resets the count
+        assert!(!breaker.observe("a", true));
+        assert!(!breaker.observe("a", false));
+        assert!(breaker.observe("a", false));
+        assert!(breaker.quarantined("a").unwrap() > Duration::from_secs(59));
+        assert_eq!(breaker.quarantined("b"), None);
+        assert_eq!(breaker.len(), 1);
+
+        // quarantined again by the next failure once the quarantine passed
+        breaker.nodes.lock().unwrap().get_mut("a").unwrap().until = Some(Instant::now());
+        assert_eq!(breaker.quarantined("a"), None);
+        assert!(breaker.observe("a", false));
+        assert!(!breaker.observe("a", true));
+        assert!(breaker.is_empty());
+
+        assert!(QuarantineOptions::new(0).validate().is_err());
+        assert!(QuarantineOptions::new(1).validate().is_ok());
+    }
+}
@@ -0,0 +1,297 @@
+//! Canary rollouts of configuration changes: a new or changed configuration
+//! is first applied to some nodes only, and to the rest once the canaries
+//! reconciled without too many errors for a verification window.
+use crate::{
+    sink::{changed_keys, MetadataPairs, Sink, Target, TargetPatch},
+    template::{stable_hash, RenderContext},
+    Error,
+};
+use k8s_openapi::api::core::v1::Node;
+use kube::ResourceExt;
+use std::{
+    collections::HashSet,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+use tracing::{error, info};
+
+/// Holds a hash of the configuration the values of a node were rendered with.
+pub const CONFIG_ANNOTATION: &str = "node-provider-labeler/config";
+
+/// Which nodes are canaries.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CanarySize {
+    /// This percentage of nodes, picked by a stable hash of their names.
+    Percent(u8),
+    /// The first this many nodes reconciled.
+    Nodes(usize),
+}
+
+/// How to roll out a configuration change.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct CanaryOptions {
+    pub size: CanarySize,
+    /// How long the canaries reconcile before the change is verified
+    pub window: Duration,
+    /// The share of failed canary reconciliations, from 0 to 1, above which
+    /// the rollout stops
+    pub max_error_rate: f64,
+}
+
+impl CanaryOptions {
+    pub fn new(size: CanarySize) -> Self {
+        Self {
+            size,
+            window: Duration::from_secs(300),
+            max_error_rate: 0.0,
+        }
+    }
+
+    pub(crate) fn validate(&self) -> Result<(), Error> {
+        if matches!(self.size, CanarySize::Percent(p) if p == 0 || p > 100) {
+            return Err(Error::Config("canary percentage must be 1-100".into()));
+        }
+        if self.size == CanarySize::Nodes(0) {
+            return Err(Error::Config("canary nodes must be at least 1".into()));
+        }
+        if !(0.0..=1.0).contains(&self.max_error_rate) {
+            return Err(Error::Config("canary error rate must be 0-1".into()));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Stage {
+    Canary,
+    Promoted,
+    Failed,
+}
+
+#[derive(Debug)]
+struct State {
+    stage: Stage,
+    nodes: HashSet<String>,
+    reconciled: u64,
+    failed: u64,
+}
+
+/// Tracks the rollout of one configuration, identified by its hash.
+#[derive(Debug)]
+pub struct Canary {
+    options: CanaryOptions,
+    config: String,
+    started: Instant,
+    state: Mutex<State>,
+}
+
+impl Canary {
+    /// Starts the rollout of the configuration, e.g. the label, annotation,
+    /// and taint specs.
+    pub fn new(options: CanaryOptions, config: &str) -> Self {
+        Self {
+            options,
+            config: format!("{:016x}", stable_hash(config)),
+            started: Instant::now(),
+            state: Mutex::new(State {
+                stage: Stage::Canary,
+                nodes: HashSet::new(),
+                reconciled: 0,
+                failed: 0,
+            }),
+        }
+    }
+
+    /// The hash recorded in the [`CONFIG_ANNOTATION`].
+    pub fn config(&self) -> &str {
+        &self.config
+    }
+
+    /// Whether the node may be patched with the configuration, or else how
+    /// long to wait: until the verification window passed, or indefinitely
+    /// if the rollout failed. Nodes already rendered with it, and canaries,
+    /// always may.
+    pub fn admits(&self, node: &Node) -> Result<(), Duration> {
+        if node.annotations().get(CONFIG_ANNOTATION) == Some(&self.config) {
+            return Ok(());
+        }
+        let mut state = self.state.lock().unwrap();
+        match self.stage(&mut state) {
+            Stage::Promoted => Ok(()),
+            _ if self.is_canary(&mut state, node) => Ok(()),
+            Stage::Failed => Err(Duration::MAX),
+            Stage::Canary => Err(self
+                .options
+                .window
+                .saturating_sub(self.started.elapsed())
+                .max(Duration::from_secs(1))),
+        }
+    }
+
+    /// Counts the outcome of a canary's reconciliation during verification.
+    pub fn observe(&self, node: &Node, ok: bool) {
+        let mut state = self.state.lock().unwrap();
+        if state.stage != Stage::Canary || !state.nodes.contains(&node.name_any()) {
+            return;
+        }
+        state.reconciled += 1;
+        if !ok {
+            state.failed += 1;
+        }
+    }
+
+    fn is_canary(&self, state: &mut State, node: &Node) -> bool {
+        let name = node.name_any();
+        if state.nodes.contains(&name) {
+            return true;
+        }
+        let canary = match self.options.size {
+            CanarySize::Percent(percent) => stable_hash(&name) % 100 < u64::from(percent),
+            CanarySize::Nodes(n) => state.nodes.len() < n,
+        };
+        if canary {
+            state.nodes.insert(name);
+        }
+        canary
+    }
+
+    /// The stage of the rollout, verifying it once the window passed.
+    fn stage(&self, state: &mut State) -> Stage {
+        if state.stage != Stage::Canary || self.started.elapsed() < self.options.window {
+            return state.stage;
+        }
+        let rate = match state.reconciled {
+            0 => 0.0,
+            n => state.failed as f64 / n as f64,
+        };
+        if rate > self.options.max_error_rate {
+            error!(
+                { config = self.config, failed = state.failed, reconciled = state.reconciled },
+                "canary rollout failed, holding the configuration back from the remaining nodes"
+            );
+            state.stage = Stage::Failed;
+        } else {
+            info!(
+                { config = self.config, failed = state.failed, reconciled = state.reconciled },
+                "canary rollout verified, rolling out to all nodes"
+            );
+            state.stage = Stage::Promoted;
+        }
+        state.stage
+    }
+}
+
+/// Records the configuration's hash in the [`CONFIG_ANNOTATION`] of nodes.
+/// Machines are left alone.
+#[derive(Debug)]
+pub struct ConfigSink(pub String);
+
+impl Sink for ConfigSink {
+    fn render(
+        &self,
+        target: Target<'_>,
+        _ctx: &RenderContext,
+        patch: &mut TargetPatch,
+    ) -> Result<(), Error> {
+        if !matches!(target, Target::Node(_)) {
+            return Ok(());
+        }
+        let new = MetadataPairs::from([(CONFIG_ANNOTATION.to_string(), self.0.clone())]);
+        let old = target.metadata().annotations.clone().unwrap_or_default();
+        patch.changed += changed_keys(&new, &old);
+        patch.annotations.extend(new);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+
+    fn options(size: CanarySize, window: Duration, max_error_rate: f64) -> CanaryOptions {
+        CanaryOptions {
+            size,
+            window,
+            max_error_rate,
+        }
+    }
+
+    #[test]
+    fn test_canary_nodes() {
+        let canary = Canary::new(
+            options(CanarySize::Nodes(1), Duration::from_secs(300), 0.0),
+            "zone={:first}",
+        );
+        let first = testing::node("first").build();
+        let second = testing::node("second").build();
+        let rolled_out = testing::node("third")
+            .annotation(CONFIG_ANNOTATION, canary.config())
+            .build();
+
+        assert_eq!(canary.admits(&first), Ok(()));
+        assert!(canary.admits(&second).is_err());
+        assert_eq!(canary.admits(&first), Ok(()));
+        assert_eq!(canary.admits(&rolled_out), Ok(()));
+    }
+
+    #[test]
+    fn test_canary_verification() {
+        let node = testing::node("first").build();
+        let other = testing::node("second").build();
+
+        let canary = Canary::new(options(CanarySize::Nodes(1), Duration::ZERO, 0.5), "a");
+        assert_eq!(canary.admits(&node), Ok(()));
+        // verified once the window passed: no canary failed
+        assert_eq!(canary.admits(&other), Ok(()));
+
+        let canary = Canary::new(
+            options(CanarySize::Nodes(1), Duration::from_secs(300), 0.5),
+            "a",
+        );
+        assert_eq!(canary.admits(&node), Ok(()));
+        canary.observe(&node, false);
+        canary.observe(&node, true);
+        canary.observe(&other, false);
+        let mut state = canary.state.lock().unwrap();
+        assert_eq!((state.reconciled, state.failed), (2, 1));
+        state.failed = 2;
+        drop(state);
+
+        let canary = Canary {
+            started: Instant::now() - Duration::from_secs(301),
+            ..canary
+        };
+        assert!(canary.admits(&other).is_err());
+        assert_eq!(canary.admits(&node), Ok(()));
+    }
+
+    #[test]
+    fn test_canary_percent() {
+        let canary = Canary::new(
+            options(CanarySize::Percent(50), Duration::from_secs(300), 0.0),
+            "a",
+        );
+        let admitted = (0..100)
+            .filter(|i| {
+                canary
+                    .admits(&testing::node(&format!("node-{i}")).build())
+                    .is_ok()
+            })
+            .count();
+        assert!((30..70).contains(&admitted), "{admitted}");
+
+        assert!(options(CanarySize::Percent(0), Duration::ZERO, 0.0)
+            .validate()
+            .is_err());
+        assert!(options(CanarySize::Nodes(0), Duration::ZERO, 0.0)
+            .validate()
+            .is_err());
+        assert!(options(CanarySize::Nodes(1), Duration::ZERO, 1.5)
+            .validate()
+            .is_err());
+        assert!(options(CanarySize::Percent(100), Duration::ZERO, 1.0)
+            .validate()
+            .is_ok());
+    }
+}
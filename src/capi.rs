@@ -0,0 +1,107 @@
+use crate::Error;
+use k8s_openapi::api::core::v1::Node;
+use kube::{
+    api::{
+        ApiResource, DynamicObject, GroupVersionKind, ListParams, ObjectMeta, PartialObjectMeta,
+    },
+    core::TypeMeta,
+    Api, Client, ResourceExt,
+};
+use std::marker::PhantomData;
+use tracing::debug;
+
+const GROUP: &str = "cluster.x-k8s.io";
+const VERSION: &str = "v1beta1";
+const KIND: &str = "Machine";
+// annotations the Cluster API machine controller sets on the Nodes it owns
+const MACHINE_ANNOTATION: &str = "cluster.x-k8s.io/machine";
+const NAMESPACE_ANNOTATION: &str = "cluster.x-k8s.io/cluster-namespace";
+
+fn machine_resource() -> ApiResource {
+    ApiResource::from_gvk(&GroupVersionKind::gvk(GROUP, VERSION, KIND))
+}
+
+/// Returns a namespaced api for the Machine.
+pub(crate) fn machine_api(client: Client, machine: &DynamicObject) -> Api<DynamicObject> {
+    let ns = machine.namespace().unwrap_or_default();
+    Api::namespaced_with(client, &ns, &machine_resource())
+}
+
+/// Builds an apply patch for Machine metadata.
+pub(crate) fn machine_patch(metadata: ObjectMeta) -> PartialObjectMeta<DynamicObject> {
+    PartialObjectMeta {
+        types: Some(TypeMeta {
+            api_version: format!("{GROUP}/{VERSION}"),
+            kind: KIND.into(),
+        }),
+        metadata,
+        _phantom: PhantomData,
+    }
+}
+
+/// Finds the Cluster API Machine whose `status.nodeRef` points at the node.
+///
+/// The machine annotations on the node are tried first; if they are missing
+/// (or stale), all Machines are listed and matched by nodeRef.
+pub(crate) async fn find_machine(
+    client: &Client,
+    node: &Node,
+) -> Result<Option<DynamicObject>, Error> {
+    let node_name = node.name_any();
+    let ar = machine_resource();
+    let annotations = node.annotations();
+
+    if let (Some(name), Some(ns)) = (
+        annotations.get(MACHINE_ANNOTATION),
+        annotations.get(NAMESPACE_ANNOTATION),
+    ) {
+        let api: Api<DynamicObject> = Api::namespaced_with(client.clone(), ns, &ar);
+        if let Some(machine) = api.get_opt(name).await? {
+            if node_ref(&machine) == Some(node_name.as_str()) {
+                return Ok(Some(machine));
+            }
+        }
+        debug!({ node = node_name, machine = name }, "machine annotation is stale");
+    }
+
+    let api: Api<DynamicObject> = Api::all_with(client.clone(), &ar);
+    let machines = api.list(&ListParams::default()).await?;
+
+    Ok(machines
+        .items
+        .into_iter()
+        .find(|m| node_ref(m) == Some(node_name.as_str())))
+}
+
+fn node_ref(machine: &DynamicObject) -> Option<&str> {
+    machine
+        .data
+        .pointer("/status/nodeRef/name")
+        .and_then(|v| v.as_str())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_node_ref() {
+        let machine: DynamicObject = serde_json::from_value(serde_json::json!({
+            "apiVersion": "cluster.x-k8s.io/v1beta1",
+            "kind": "Machine",
+            "metadata": { "name": "md-0-abcde", "namespace": "default" },
+            "status": { "nodeRef": { "kind": "Node", "name": "my-node-name" } }
+        }))
+        .unwrap();
+        assert_eq!(node_ref(&machine), Some("my-node-name"));
+
+        let machine: DynamicObject = serde_json::from_value(serde_json::json!({
+            "apiVersion": "cluster.x-k8s.io/v1beta1",
+            "kind": "Machine",
+            "metadata": { "name": "md-0-abcde", "namespace": "default" },
+            "status": {}
+        }))
+        .unwrap();
+        assert_eq!(node_ref(&machine), None);
+    }
+}
@@ -0,0 +1,398 @@
+use crate::{
+    middleware::{LogLayer, RetryLayer},
+    ratelimit::RateLimitLayer,
+};
+use kube::{
+    client::ClientBuilder,
+    config::{KubeConfigOptions, Kubeconfig},
+};
+use node_provider_labeler::Error;
+use std::{fs::File, io::BufReader, path::PathBuf, time::Duration};
+use tower::{
+    buffer::BufferLayer, limit::ConcurrencyLimitLayer, util::option_layer, ServiceBuilder,
+};
+use tracing::warn;
+
+// kube-runtime's default server-side watch timeout
+const DEFAULT_WATCH_TIMEOUT: u64 = 290;
+// leave room for the server to end the watch before the read times out
+const WATCH_TIMEOUT_MARGIN: u64 = 5;
+// requests waiting for the retry layer
+const RETRY_BUFFER: usize = 1024;
+
+#[derive(clap::Args, Debug)]
+pub(crate) struct ClientArgs {
+    /// Path to a kubeconfig file to use instead of the in-cluster or default
+    /// kubeconfig
+    #[arg(long)]
+    kubeconfig: Option<PathBuf>,
+    /// The kubeconfig context to use instead of the current context
+    #[arg(long)]
+    context: Option<String>,
+    /// A user to impersonate for all API requests
+    #[arg(long = "as", value_name = "USER")]
+    as_user: Option<String>,
+    /// A group to impersonate for all API requests. Requires --as.
+    /// Repeat to impersonate multiple groups.
+    #[arg(long, value_name = "GROUP", requires = "as_user")]
+    as_group: Option<Vec<String>>,
+    /// Maximum sustained rate of API requests per second. Unlimited if not
+    /// set.
+    #[arg(long, value_name = "QPS")]
+    client_qps: Option<f64>,
+    /// Maximum burst of API requests above --client-qps
+    #[arg(
+        long,
+        value_name = "BURST",
+        default_value_t = 10,
+        requires = "client_qps"
+    )]
+    client_burst: u32,
+    /// Maximum number of API requests awaiting a response at once.
+    /// Unlimited if not set.
+    #[arg(long, value_name = "REQUESTS")]
+    client_max_concurrency: Option<usize>,
+    /// Retry API requests that fail transiently (throttled, or reads that
+    /// hit a gateway error) up to this many times
+    #[arg(long, value_name = "RETRIES", default_value_t = 0)]
+    client_retries: u32,
+    /// The percentage of recent API requests that may be retries, limiting
+    /// retry storms when the API server is struggling
+    #[arg(long, value_name = "PERCENT", default_value_t = 20.0)]
+    client_retry_budget: f32,
+    /// Log every API request with its response status and latency
+    #[arg(long)]
+    client_log_requests: bool,
+    /// Timeout in seconds for connecting to the API server
+    #[arg(long, value_name = "SECONDS", default_value_t = 30)]
+    client_connect_timeout: u64,
+    /// Timeout in seconds for reading an API response. Node watches are
+    /// shortened to fit within it.
+    #[arg(long, value_name = "SECONDS", default_value_t = 295)]
+    client_read_timeout: u64,
+    /// Timeout in seconds for writing an API request
+    #[arg(long, value_name = "SECONDS", default_value_t = 295)]
+    client_write_timeout: u64,
+    /// A PEM file of CA certificates to trust for the API server, in addition
+    /// to the cluster's CA
+    #[arg(long, value_name = "PATH")]
+    ca_bundle: Option<PathBuf>,
+    /// A PEM client certificate to authenticate to the API server with.
+    /// Requires --client-key.
+    #[arg(long, value_name = "PATH", requires = "client_key")]
+    client_cert: Option<PathBuf>,
+    /// The PEM private key of --client-cert
+    #[arg(long, value_name = "PATH", requires = "client_cert")]
+    client_key: Option<PathBuf>,
+    /// Skip verifying the API server's certificate. Insecure; for lab
+    /// environments only.
+    #[arg(long)]
+    insecure_skip_tls_verify: bool,
+    /// Restrict the TLS cipher suites of the API connection to these, e.g.
+    /// TLS13_AES_256_GCM_SHA384. Separate multiple suites with commas.
+    #[cfg(feature = "rustls-tls")]
+    #[arg(long, value_name = "SUITES", value_delimiter = ',')]
+    tls_cipher_suites: Option<Vec<String>>,
+}
+
+impl ClientArgs {
+    pub(crate) async fn config(&self) -> Result<kube::Config, Error> {
+        let options = KubeConfigOptions {
+            context: self.context.clone(),
+            ..Default::default()
+        };
+
+        let mut config = match (&self.kubeconfig, &self.context) {
+            (Some(path), _) => {
+                kube::Config::from_custom_kubeconfig(Kubeconfig::read_from(path)?, &options).await?
+            }
+            (None, Some(_)) => kube::Config::from_kubeconfig(&options).await?,
+            (None, None) => kube::Config::infer()
+                .await
+                .map_err(kube::Error::InferConfig)?,
+        };
+        self.apply_overrides(&mut config);
+        self.apply_tls(&mut config)?;
+
+        Ok(config)
+    }
+
+    /// Returns a watch timeout that fits within the read timeout, if the
+    /// default watch timeout doesn't.
+    pub(crate) fn watch_timeout(&self) -> Option<u32> {
+        if self.client_read_timeout >= DEFAULT_WATCH_TIMEOUT + WATCH_TIMEOUT_MARGIN {
+            return None;
+        }
+
+        let timeout = self
+            .client_read_timeout
+            .saturating_sub(WATCH_TIMEOUT_MARGIN)
+            .max(1);
+        Some(timeout as u32)
+    }
+
+    fn apply_overrides(&self, config: &mut kube::Config) {
+        config.connect_timeout = Some(Duration::from_secs(self.client_connect_timeout));
+        config.read_timeout = Some(Duration::from_secs(self.client_read_timeout));
+        config.write_timeout = Some(Duration::from_secs(self.client_write_timeout));
+
+        if let Some(user) = &self.as_user {
+            config.auth_info.impersonate = Some(user.clone());
+            config.auth_info.impersonate_groups = self.as_group.clone();
+        }
+    }
+
+    /// Layers the TLS flags over the loaded config.
+    fn apply_tls(&self, config: &mut kube::Config) -> Result<(), Error> {
+        if let Some(path) = &self.ca_bundle {
+            let read = |e: std::io::Error| {
+                Error::Config(format!("unable to read CA bundle {}: {e}", path.display()))
+            };
+            let mut reader = BufReader::new(File::open(path).map_err(read)?);
+            let certs = rustls_pemfile::certs(&mut reader)
+                .map(|cert| cert.map(|c| c.to_vec()))
+                .collect::<Result<Vec<_>, _>>()
+                .map_err(read)?;
+            if certs.is_empty() {
+                return Err(Error::Config(format!(
+                    "no certificates found in CA bundle {}",
+                    path.display()
+                )));
+            }
+            config.root_cert.get_or_insert_with(Vec::new).extend(certs);
+        }
+
+        if let (Some(cert), Some(key)) = (&self.client_cert, &self.client_key) {
+            let auth = &mut config.auth_info;
+            auth.client_certificate = Some(cert.to_string_lossy().into());
+            auth.client_certificate_data = None;
+            auth.client_key = Some(key.to_string_lossy().into());
+            auth.client_key_data = None;
+        }
+
+        if self.insecure_skip_tls_verify {
+            warn!("API server certificate verification is disabled");
+            config.accept_invalid_certs = true;
+        }
+        Ok(())
+    }
+
+    pub(crate) async fn client(&self) -> Result<kube::Client, Error> {
+        let config = self.config().await?;
+        #[cfg(feature = "rustls-tls")]
+        if let Some(suites) = &self.tls_cipher_suites {
+            // the client's TLS config is built with the process default
+            crypto_provider(suites)?
+                .install_default()
+                .map_err(|_| Error::Config("a TLS crypto provider is already installed".into()))?;
+        }
+        let builder = ClientBuilder::try_from(config)?;
+
+        // from the innermost: retries count against the limits
+        let rate_limit = match self.client_qps {
+            Some(qps) if qps <= 0.0 => {
+                return Err(Error::Config(format!(
+                    "--client-qps must be greater than 0, got {qps}"
+                )))
+            }
+            qps => qps.map(|qps| RateLimitLayer::new(qps, self.client_burst)),
+        };
+        let concurrency_limit = match self.client_max_concurrency {
+            Some(0) => {
+                return Err(Error::Config(
+                    "--client-max-concurrency must be greater than 0".into(),
+                ))
+            }
+            max => max.map(ConcurrencyLimitLayer::new),
+        };
+        if !(0.0..=100.0).contains(&self.client_retry_budget) {
+            return Err(Error::Config(format!(
+                "--client-retry-budget must be between 0 and 100, got {}",
+                self.client_retry_budget
+            )));
+        }
+        let retry = (self.client_retries > 0).then(|| {
+            ServiceBuilder::new()
+                .layer(RetryLayer::new(
+                    self.client_retries,
+                    self.client_retry_budget,
+                ))
+                .layer(BufferLayer::new(RETRY_BUFFER))
+        });
+        let client = builder
+            .with_layer(&option_layer(rate_limit))
+            .with_layer(&option_layer(concurrency_limit))
+            .with_layer(&option_layer(retry))
+            .with_layer(&option_layer(self.client_log_requests.then_some(LogLayer)))
+            .build();
+
+        Ok(client)
+    }
+}
+
+/// The rustls crypto provider, limited to the named cipher suites.
+#[cfg(feature = "rustls-tls")]
+fn crypto_provider(suites: &[String]) -> Result<rustls::crypto::CryptoProvider, Error> {
+    let mut provider = rustls::crypto::ring::default_provider();
+    let name = |suite: &rustls::SupportedCipherSuite| suite.suite().as_str().unwrap_or_default();
+    if let Some(unknown) = suites
+        .iter()
+        .find(|s| !provider.cipher_suites.iter().any(|c| name(c) == s.as_str()))
+    {
+        let supported = provider.cipher_suites.iter().map(name).collect::<Vec<_>>();
+        return Err(Error::Config(format!(
+            "unsupported TLS cipher suite '{unknown}', expected one of {}",
+            supported.join(", ")
+        )));
+    }
+    provider
+        .cipher_suites
+        .retain(|c| suites.iter().any(|s| s == name(c)));
+    Ok(provider)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+
+    #[derive(Parser)]
+    struct TestArgs {
+        #[command(flatten)]
+        client: ClientArgs,
+    }
+
+    fn config(args: &[&str]) -> kube::Config {
+        let args =
+            TestArgs::try_parse_from(std::iter::once("test").chain(args.iter().copied())).unwrap();
+        let mut config = kube::Config::new("https://localhost:6443".parse().unwrap());
+        args.client.apply_overrides(&mut config);
+        config
+    }
+
+    #[test]
+    fn test_impersonation() {
+        let c = config(&[]);
+        assert_eq!(c.auth_info.impersonate, None);
+        assert_eq!(c.auth_info.impersonate_groups, None);
+
+        let c = config(&["--as=system:serviceaccount:npl:labeler"]);
+        assert_eq!(
+            c.auth_info.impersonate.as_deref(),
+            Some("system:serviceaccount:npl:labeler")
+        );
+        assert_eq!(c.auth_info.impersonate_groups, None);
+
+        let c = config(&["--as=labeler", "--as-group=auditors", "--as-group=nodes"]);
+        assert_eq!(c.auth_info.impersonate.as_deref(), Some("labeler"));
+        assert_eq!(
+            c.auth_info.impersonate_groups,
+            Some(vec!["auditors".to_string(), "nodes".to_string()])
+        );
+
+        assert!(TestArgs::try_parse_from(["test", "--as-group=auditors"]).is_err());
+    }
+
+    #[test]
+    fn test_timeouts() {
+        let c = config(&[]);
+        assert_eq!(c.connect_timeout, Some(Duration::from_secs(30)));
+        assert_eq!(c.read_timeout, Some(Duration::from_secs(295)));
+        assert_eq!(c.write_timeout, Some(Duration::from_secs(295)));
+
+        let c = config(&[
+            "--client-connect-timeout=5",
+            "--client-read-timeout=60",
+            "--client-write-timeout=10",
+        ]);
+        assert_eq!(c.connect_timeout, Some(Duration::from_secs(5)));
+        assert_eq!(c.read_timeout, Some(Duration::from_secs(60)));
+        assert_eq!(c.write_timeout, Some(Duration::from_secs(10)));
+    }
+
+    #[test]
+    fn test_watch_timeout() {
+        let w = |args: &[&str]| {
+            TestArgs::try_parse_from(std::iter::once("test").chain(args.iter().copied()))
+                .unwrap()
+                .client
+                .watch_timeout()
+        };
+
+        assert_eq!(w(&[]), None);
+        assert_eq!(w(&["--client-read-timeout=600"]), None);
+        assert_eq!(w(&["--client-read-timeout=60"]), Some(55));
+        assert_eq!(w(&["--client-read-timeout=2"]), Some(1));
+    }
+
+    #[cfg(feature = "rustls-tls")]
+    #[test]
+    fn test_crypto_provider() {
+        let args = TestArgs::try_parse_from([
+            "test",
+            "--tls-cipher-suites=TLS13_AES_256_GCM_SHA384,TLS_ECDHE_RSA_WITH_AES_256_GCM_SHA384",
+        ])
+        .unwrap();
+        let provider = crypto_provider(&args.client.tls_cipher_suites.unwrap()).unwrap();
+        let suites = provider
+            .cipher_suites
+            .iter()
+            .map(|c| c.suite())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            suites,
+            [
+                rustls::CipherSuite::TLS13_AES_256_GCM_SHA384,
+                rustls::CipherSuite::TLS_ECDHE_RSA_WITH_AES_256_GCM_SHA384
+            ]
+        );
+
+        assert!(matches!(
+            crypto_provider(&["TLS_RSA_WITH_RC4_128_SHA".into()]),
+            Err(Error::Config(_))
+        ));
+    }
+
+    #[test]
+    fn test_tls() {
+        let path = std::env::temp_dir().join(format!("npl-ca-{}", std::process::id()));
+        std::fs::write(
+            &path,
+            "-----BEGIN CERTIFICATE-----\nAAEC\n-----END CERTIFICATE-----\n",
+        )
+        .unwrap();
+        let tls = |args: &[&str]| {
+            let args =
+                TestArgs::try_parse_from(std::iter::once("test").chain(args.iter().copied()))
+                    .unwrap();
+            let mut config = kube::Config::new("https://localhost:6443".parse().unwrap());
+            config.root_cert = Some(vec![vec![9]]);
+            args.client.apply_tls(&mut config).map(|_| config)
+        };
+
+        let c = tls(&[]).unwrap();
+        assert_eq!(c.root_cert, Some(vec![vec![9]]));
+        assert!(!c.accept_invalid_certs);
+
+        let c = tls(&[
+            &format!("--ca-bundle={}", path.display()),
+            "--client-cert=/etc/npl/tls.crt",
+            "--client-key=/etc/npl/tls.key",
+            "--insecure-skip-tls-verify",
+        ])
+        .unwrap();
+        assert_eq!(c.root_cert, Some(vec![vec![9], vec![0, 1, 2]]));
+        assert_eq!(
+            c.auth_info.client_certificate.as_deref(),
+            Some("/etc/npl/tls.crt")
+        );
+        assert_eq!(c.auth_info.client_key.as_deref(), Some("/etc/npl/tls.key"));
+        assert!(c.accept_invalid_certs);
+
+        std::fs::write(&path, "not a certificate").unwrap();
+        assert!(tls(&[&format!("--ca-bundle={}", path.display())]).is_err());
+        std::fs::remove_file(&path).unwrap();
+        assert!(tls(&["--ca-bundle=/nonexistent/ca.pem"]).is_err());
+        assert!(TestArgs::try_parse_from(["test", "--client-cert=/etc/npl/tls.crt"]).is_err());
+    }
+}
@@ -0,0 +1,292 @@
+use crate::{client::ClientArgs, Cli, TemplateArgs};
+use clap::CommandFactory;
+use k8s_openapi::api::core::v1::{Node, NodeSpec};
+use kube::api::ObjectMeta;
+use node_provider_labeler::{
+    backup, config, controller,
+    sink::{TaintRenderer, TargetPatch},
+    Error,
+};
+use std::process::ExitCode;
+
+#[derive(clap::Args, Debug)]
+pub(crate) struct RenderArgs {
+    /// The provider ID to render for, e.g. "aws://us-east-2/i-0abcdef1234567890"
+    #[arg(long, value_name = "PROVIDER_ID")]
+    provider_id: String,
+    /// The node name, available to templates as {:node}
+    #[arg(long, value_name = "NAME", default_value = "node")]
+    node_name: String,
+    /// A label of the node, available to templates as {label:<key>}.
+    /// Repeat to add multiple labels.
+    #[arg(long, value_name = "KEY=VALUE")]
+    node_label: Option<Vec<String>>,
+    #[command(flatten)]
+    templates: TemplateArgs,
+}
+
+#[derive(clap::Args, Debug)]
+pub(crate) struct CleanupArgs {
+    /// Only report the nodes that would be cleaned up
+    #[arg(long)]
+    dry_run: bool,
+    /// A taint the controller was run with, as for the controller's --taint.
+    /// Only taints with the same key and effect are removed, others are left
+    /// alone. Repeat to remove multiple taints.
+    #[arg(long)]
+    taint: Option<Vec<String>>,
+    #[command(flatten)]
+    client: ClientArgs,
+}
+
+impl CleanupArgs {
+    fn taints(&self) -> Result<Vec<TaintRenderer>, Error> {
+        self.taint.iter().flatten().map(|t| t.parse()).collect()
+    }
+}
+
+pub(crate) fn validate(args: &TemplateArgs) -> ExitCode {
+    match args.parse() {
+        Ok(_) => {
+            println!("templates are valid");
+            ExitCode::SUCCESS
+        }
+        Err(e) => failure("invalid templates", e),
+    }
+}
+
+pub(crate) fn render(args: &RenderArgs) -> ExitCode {
+    let rendered = node(args).and_then(|node| args.templates.parse()?.render(&node));
+    match rendered {
+        Ok(patch) => {
+            print!("{}", format_patch(&patch));
+            ExitCode::SUCCESS
+        }
+        Err(e) => failure("unable to render templates", e),
+    }
+}
+
+pub(crate) async fn cleanup(args: &CleanupArgs) -> ExitCode {
+    let client = match args.client.client().await {
+        Ok(client) => client,
+        Err(e) => return failure("unable to create kube client", e),
+    };
+    let taints = match args.taints() {
+        Ok(taints) => taints,
+        Err(e) => return failure("invalid taints", e),
+    };
+    match controller::cleanup(client, &taints, args.dry_run).await {
+        Ok(nodes) => report("cleaned up", &nodes, args.dry_run),
+        Err(e) => failure("unable to clean up nodes", e),
+    }
+}
+
+pub(crate) async fn undo(args: &CleanupArgs) -> ExitCode {
+    let client = match args.client.client().await {
+        Ok(client) => client,
+        Err(e) => return failure("unable to create kube client", e),
+    };
+    let taints = match args.taints() {
+        Ok(taints) => taints,
+        Err(e) => return failure("invalid taints", e),
+    };
+    match backup::undo(client, &taints, args.dry_run).await {
+        Ok(nodes) => report("restored", &nodes, args.dry_run),
+        Err(e) => failure("unable to undo changes", e),
+    }
+}
+
+fn report(action: &str, nodes: &[String], dry_run: bool) -> ExitCode {
+    let suffix = if dry_run { " (dry run)" } else { "" };
+    for node in nodes {
+        println!("{action} {node}{suffix}");
+    }
+    if nodes.is_empty() {
+        println!("no managed nodes found");
+    }
+    ExitCode::SUCCESS
+}
+
+pub(crate) fn version() -> ExitCode {
+    println!("{} {}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"));
+    ExitCode::SUCCESS
+}
+
+pub(crate) fn completions(shell: clap_complete::Shell) -> ExitCode {
+    let mut command = Cli::command();
+    let name = command.get_name().to_string();
+    clap_complete::generate(shell, &mut command, name, &mut std::io::stdout());
+    ExitCode::SUCCESS
+}
+
+pub(crate) fn init_config() -> ExitCode {
+    print!("{}", config::starter(Cli::command()));
+    ExitCode::SUCCESS
+}
+
+pub(crate) fn schema() -> ExitCode {
+    match serde_json::to_string_pretty(&config::schema(Cli::command())) {
+        Ok(schema) => {
+            println!("{schema}");
+            ExitCode::SUCCESS
+        }
+        Err(e) => failure("unable to print schema", Error::Config(e.to_string())),
+    }
+}
+
+fn failure(context: &str, e: Error) -> ExitCode {
+    eprintln!("{context}: {e}");
+    ExitCode::FAILURE
+}
+
+/// The node fixture to render for.
+fn node(args: &RenderArgs) -> Result<Node, Error> {
+    let labels = args
+        .node_label
+        .iter()
+        .flatten()
+        .map(|l| match l.split_once('=') {
+            Some((k, v)) => Ok((k.to_string(), v.to_string())),
+            None => Err(Error::Config(format!(
+                "invalid node label '{l}', expected key=value"
+            ))),
+        })
+        .collect::<Result<_, _>>()?;
+
+    Ok(Node {
+        metadata: ObjectMeta {
+            name: Some(args.node_name.clone()),
+            labels: Some(labels),
+            ..Default::default()
+        },
+        spec: Some(NodeSpec {
+            provider_id: Some(args.provider_id.clone()),
+            ..Default::default()
+        }),
+        ..Default::default()
+    })
+}
+
+/// Formats the patch as the flags that would configure it, one per line.
+fn format_patch(patch: &TargetPatch) -> String {
+    let mut output = String::new();
+    for (k, v) in &patch.labels {
+        output.push_str(&format!("label {k}={v}\n"));
+    }
+    for (k, v) in &patch.annotations {
+        output.push_str(&format!("annotation {k}={v}\n"));
+    }
+    for k in &patch.unset_labels {
+        output.push_str(&format!("remove label {k}\n"));
+    }
+    for k in &patch.unset_annotations {
+        output.push_str(&format!("remove annotation {k}\n"));
+    }
+    for taint in patch.taints.iter().flatten() {
+        let value = taint.value.as_deref().unwrap_or_default();
+        output.push_str(&format!("taint {}={value}:{}\n", taint.key, taint.effect));
+    }
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Command;
+    use clap::Parser;
+
+    #[test]
+    fn test_commands() {
+        // bare flags still run the controller
+        let cli = Cli::try_parse_from(["npl", "--label=zone={:first}"]).unwrap();
+        assert!(cli.command.is_none());
+        assert_eq!(
+            cli.run.templates.label,
+            Some(vec!["zone={:first}".to_string()])
+        );
+        assert!(matches!(
+            Cli::try_parse_from(["npl", "run", "--label=zone"])
+                .unwrap()
+                .command,
+            Some(Command::Run(_))
+        ));
+        assert!(Cli::try_parse_from(["npl", "--label=zone", "version"]).is_err());
+
+        let cli = Cli::try_parse_from([
+            "npl",
+            "render",
+            "--provider-id=aws:///us-east-2a/i-0abc",
+            "--node-name=my-node",
+            "--node-label=team=core",
+            "--label=id={:last}",
+            "--label=team=",
+            "--annotation=team={label:team}",
+            "--taint=dedicated={:node}:NoSchedule",
+        ])
+        .unwrap();
+        let Some(Command::Render(args)) = cli.command else {
+            panic!("expected render");
+        };
+        let patch = args
+            .templates
+            .parse()
+            .unwrap()
+            .render(&node(&args).unwrap())
+            .unwrap();
+        assert_eq!(
+            format_patch(&patch),
+            "label id=i-0abc\nannotation team=core\nremove label team\n\
+             taint dedicated=my-node:NoSchedule\n"
+        );
+
+        let cli = Cli::try_parse_from(["npl", "validate", "--label=zone={:first"]).unwrap();
+        let Some(Command::Validate(args)) = cli.command else {
+            panic!("expected validate");
+        };
+        assert!(args.parse().is_err());
+
+        // maps are checked when the templates are, not when a node renders
+        let cli = Cli::try_parse_from([
+            "npl",
+            "validate",
+            "--label=site={:first|map(regions)}",
+            "--annotation=rack={:last|map(racks)|kebab}",
+        ])
+        .unwrap();
+        let Some(Command::Validate(args)) = cli.command else {
+            panic!("expected validate");
+        };
+        assert!(matches!(
+            args.parse(),
+            Err(Error::Config(e)) if e == "unknown maps: 'racks', 'regions'"
+        ));
+    }
+
+    #[test]
+    fn test_config() {
+        // the starter config is valid, and sets nothing until uncommented
+        let starter = config::starter(Cli::command());
+        assert!(starter.contains("\n#requeue-duration: 3600\n"));
+        assert!(config::flags(&starter, Cli::command()).unwrap().is_empty());
+        let uncommented = starter
+            .replace("#requeue-duration", "requeue-duration")
+            .replace("#label-machines", "label-machines")
+            .replace("#stale-policy", "stale-policy");
+        let flags = config::flags(&uncommented, Cli::command()).unwrap();
+        let cli = Cli::try_parse_from(["npl".to_string()].into_iter().chain(flags)).unwrap();
+        assert_eq!(cli.run.requeue_duration, 3600);
+        assert!(cli.run.label_machines);
+
+        // every flag of the run command is in the schema
+        let schema = config::schema(Cli::command());
+        let properties = schema["properties"].as_object().unwrap();
+        let mut run = Cli::command();
+        run.build();
+        let run = run.find_subcommand("run").unwrap();
+        for arg in run.get_arguments().filter_map(|a| a.get_long()) {
+            assert!(arg == "help" || properties.contains_key(arg), "{arg}");
+        }
+        assert_eq!(properties["label"]["type"], "array");
+        assert_eq!(properties["requeue-duration"]["default"], 3600);
+    }
+}
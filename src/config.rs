@@ -0,0 +1,447 @@
+//! Config files: YAML mappings of a command's long flags, without the leading
+//! dashes, to their values, e.g. `requeue-duration: 60` or `label:
+//! ["zone={:first}"]`. They're expanded into flags before parsing, so clap
+//! validates them like the command line, and described by a JSON Schema for
+//! editors and CI checks of config ConfigMaps.
+//!
+//! ```
+//! use clap::{CommandFactory, Parser};
+//! use node_provider_labeler::config;
+//!
+//! #[derive(Parser)]
+//! struct Cli {
+//!     /// Add a label
+//!     #[arg(long)]
+//!     label: Vec<String>,
+//! }
+//!
+//! let schema = config::schema(Cli::command());
+//! assert_eq!(schema["properties"]["label"]["type"], "array");
+//! let flags = config::flags("label: ['zone={:first}']", Cli::command()).unwrap();
+//! assert_eq!(flags, ["--label=zone={:first}"]);
+//! ```
+use crate::Error;
+use clap::{Arg, ArgAction, Command};
+use serde_json::{json, Map, Value};
+use serde_yaml::Value as Yaml;
+use std::{any::TypeId, ffi::OsString, fmt::Write};
+
+const CONFIG_FLAG: &str = "--config";
+
+/// Replaces `--config=<file>` (or `--config <file>`) with the flags set in the
+/// file, so flags after it add to or override the file's.
+pub fn expand(
+    args: impl IntoIterator<Item = OsString>,
+    command: Command,
+) -> Result<Vec<OsString>, Error> {
+    let mut expanded = vec![];
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        let path = match arg.to_str() {
+            Some(CONFIG_FLAG) => args
+                .next()
+                .ok_or_else(|| Error::Config(format!("{CONFIG_FLAG} requires a file")))?,
+            Some(a) if a.starts_with("--config=") => a[CONFIG_FLAG.len() + 1..].into(),
+            _ => {
+                expanded.push(arg);
+                continue;
+            }
+        };
+        let path = path.to_string_lossy();
+        let contents = std::fs::read_to_string(path.as_ref())
+            .map_err(|e| Error::Config(format!("unable to read config {path}: {e}")))?;
+        let flags = flags(&contents, command.clone()).map_err(|e| match e {
+            Error::Config(e) => Error::Config(format!("invalid config {path}: {e}")),
+            e => e,
+        })?;
+        expanded.extend(flags.into_iter().map(OsString::from));
+    }
+    Ok(expanded)
+}
+
+/// The flags a config sets: `--<flag>` for flags set to true, and
+/// `--<flag>=<value>` for each value of the others.
+pub fn flags(config: &str, mut command: Command) -> Result<Vec<String>, Error> {
+    command.build();
+    let config = match serde_yaml::from_str(config).map_err(|e| Error::Config(e.to_string()))? {
+        Yaml::Null => return Ok(vec![]),
+        Yaml::Mapping(config) => config,
+        _ => return Err(Error::Config("expected a mapping of flags".into())),
+    };
+
+    let mut flags = vec![];
+    for (key, value) in config {
+        let key = match key {
+            Yaml::String(key) => key,
+            key => return Err(Error::Config(format!("invalid flag {key:?}"))),
+        };
+        let arg = options(&command)
+            .find(|arg| arg.get_long() == Some(key.as_str()))
+            .ok_or_else(|| Error::Config(format!("unknown flag '{key}'")))?;
+        let values = match value {
+            Yaml::Null => continue,
+            Yaml::Sequence(values) if multiple(arg) => values,
+            Yaml::Sequence(_) => {
+                return Err(Error::Config(format!("'{key}' takes a single value")))
+            }
+            value => vec![value],
+        };
+        for value in values {
+            let value = match value {
+                Yaml::String(s) => s,
+                Yaml::Number(n) => n.to_string(),
+                Yaml::Bool(b) if !takes_value(arg) => {
+                    if b {
+                        flags.push(format!("--{key}"));
+                    }
+                    continue;
+                }
+                Yaml::Bool(b) => b.to_string(),
+                _ => return Err(Error::Config(format!("invalid value for '{key}'"))),
+            };
+            if !takes_value(arg) {
+                return Err(Error::Config(format!("'{key}' must be true or false")));
+            }
+            flags.push(format!("--{key}={value}"));
+        }
+    }
+    Ok(flags)
+}
+
+/// A starter config with every flag, commented out, under its help text.
+pub fn starter(mut command: Command) -> String {
+    command.build();
+    let mut config = format!(
+        "# {} configuration, loaded with {CONFIG_FLAG}=<file>: a mapping of flags,\n\
+         # without the leading dashes, to their values. Uncomment to set.\n",
+        command.get_name()
+    );
+
+    for arg in options(&command) {
+        let long = arg.get_long().unwrap_or_default();
+        config.push('\n');
+        for line in help(arg).lines() {
+            let _ = writeln!(config, "{}", format!("# {line}").trim_end());
+        }
+
+        let defaults = defaults(arg);
+        let value = if !takes_value(arg) {
+            "true".to_string()
+        } else if multiple(arg) && defaults.is_empty() {
+            format!("[<{}>]", value_name(arg))
+        } else if multiple(arg) {
+            Value::Array(defaults).to_string()
+        } else if let Some(default) = defaults.first() {
+            default.to_string()
+        } else {
+            format!("<{}>", value_name(arg))
+        };
+        let _ = writeln!(config, "#{long}: {value}");
+    }
+
+    config
+}
+
+/// The JSON Schema of the command's config files.
+pub fn schema(mut command: Command) -> Value {
+    command.build();
+    let mut properties = Map::new();
+    for arg in options(&command) {
+        let mut property = if multiple(arg) {
+            json!({ "type": "array", "items": value_schema(arg) })
+        } else {
+            value_schema(arg)
+        };
+        let help = help(arg);
+        if !help.is_empty() {
+            property["description"] = help.into();
+        }
+        let defaults = defaults(arg);
+        if multiple(arg) && !defaults.is_empty() {
+            property["default"] = defaults.into();
+        } else if let Some(default) = defaults.into_iter().next() {
+            property["default"] = default;
+        }
+        properties.insert(arg.get_long().unwrap_or_default().into(), property);
+    }
+
+    json!({
+        "$schema": "https://json-schema.org/draft/2020-12/schema",
+        "title": format!("{} configuration", command.get_name()),
+        "type": "object",
+        "properties": properties,
+        "additionalProperties": false,
+    })
+}
+
+/// The command's long flags, except help and version.
+fn options(command: &Command) -> impl Iterator<Item = &Arg> {
+    command.get_arguments().filter(|arg| {
+        arg.get_long().is_some()
+            && !matches!(arg.get_action(), ArgAction::Help | ArgAction::Version)
+    })
+}
+
+fn takes_value(arg: &Arg) -> bool {
+    arg.get_num_args().is_some_and(|n| n.takes_values())
+}
+
+fn multiple(arg: &Arg) -> bool {
+    matches!(arg.get_action(), ArgAction::Append) || arg.get_value_delimiter().is_some()
+}
+
+fn value_name(arg: &Arg) -> String {
+    arg.get_value_names()
+        .and_then(|names| names.first())
+        .map(|n| n.to_string())
+        .unwrap_or_else(|| arg.get_id().to_string().to_uppercase())
+}
+
+fn help(arg: &Arg) -> String {
+    let help = arg.get_long_help().or(arg.get_help());
+    help.map(|h| h.to_string()).unwrap_or_default()
+}
+
+/// The default values, typed as in the schema.
+fn defaults(arg: &Arg) -> Vec<Value> {
+    let schema = value_schema(arg);
+    arg.get_default_values()
+        .iter()
+        .map(|v| {
+            let v = v.to_string_lossy();
+            match schema["type"].as_str() {
+                Some("integer" | "number") => v.parse().unwrap_or_else(|_| v.into()),
+                Some("boolean") => v.parse().map(Value::Bool).unwrap_or_else(|_| v.into()),
+                _ => v.into(),
+            }
+        })
+        .collect()
+}
+
+/// The schema of a single value of the flag.
+fn value_schema(arg: &Arg) -> Value {
+    if !takes_value(arg) {
+        return json!({ "type": "boolean" });
+    }
+    let type_id = arg.get_value_parser().type_id();
+    let is = |id: TypeId| type_id == id;
+    if [TypeId::of::<u8>(), TypeId::of::<u16>(), TypeId::of::<u32>()]
+        .into_iter()
+        .chain([TypeId::of::<u64>(), TypeId::of::<usize>()])
+        .any(is)
+    {
+        return json!({ "type": "integer", "minimum": 0 });
+    }
+    if [TypeId::of::<i32>(), TypeId::of::<i64>()]
+        .into_iter()
+        .any(is)
+    {
+        return json!({ "type": "integer" });
+    }
+    if [TypeId::of::<f32>(), TypeId::of::<f64>()]
+        .into_iter()
+        .any(is)
+    {
+        return json!({ "type": "number" });
+    }
+    if is(TypeId::of::<bool>()) {
+        return json!({ "type": "boolean" });
+    }
+    let possible = arg.get_possible_values();
+    if possible.is_empty() {
+        return json!({ "type": "string" });
+    }
+    json!({
+        "type": "string",
+        "enum": possible.iter().map(|v| v.get_name()).collect::<Vec<_>>(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::{CommandFactory, Parser, ValueEnum};
+
+    #[derive(Clone, Debug, PartialEq, ValueEnum)]
+    enum Policy {
+        Keep,
+        Remove,
+    }
+
+    #[derive(Parser, Debug)]
+    #[command(name = "npl", args_override_self = true)]
+    struct Cli {
+        /// Add a label
+        #[arg(long)]
+        label: Option<Vec<String>>,
+        /// Requeue after this many seconds
+        #[arg(long, default_value_t = 3600)]
+        requeue_duration: u64,
+        #[arg(long, value_delimiter = ',', value_name = "BUCKETS")]
+        buckets: Option<Vec<f64>>,
+        #[arg(long, value_enum, default_value_t = Policy::Keep)]
+        stale_policy: Policy,
+        /// Also label machines
+        #[arg(long)]
+        label_machines: bool,
+    }
+
+    fn parse(args: Vec<String>) -> Cli {
+        Cli::try_parse_from(["npl".to_string()].into_iter().chain(args)).unwrap()
+    }
+
+    #[test]
+    fn test_flags() {
+        let flags = flags(
+            "# labels\n\
+             label:\n  - zone={:first}\n  - id\n\
+             requeue-duration: 60\n\
+             buckets: [0.5, 1]\n\
+             label-machines: true\n\
+             stale-policy: remove\n",
+            Cli::command(),
+        )
+        .unwrap();
+        assert_eq!(
+            flags,
+            [
+                "--label=zone={:first}",
+                "--label=id",
+                "--requeue-duration=60",
+                "--buckets=0.5",
+                "--buckets=1",
+                "--label-machines",
+                "--stale-policy=remove",
+            ]
+        );
+        let cli = parse(flags);
+        assert_eq!(cli.requeue_duration, 60);
+        assert_eq!(cli.buckets, Some(vec![0.5, 1.0]));
+        assert!(cli.label_machines);
+
+        assert!(super::flags("", Cli::command()).unwrap().is_empty());
+        assert_eq!(
+            super::flags("label-machines: false\nlabel: id", Cli::command()).unwrap(),
+            ["--label=id"]
+        );
+        let err = |config: &str| {
+            super::flags(config, Cli::command())
+                .unwrap_err()
+                .to_string()
+        };
+        assert!(err("unknown: 1").contains("unknown flag 'unknown'"));
+        assert!(err("requeue-duration: [1, 2]").contains("takes a single value"));
+        assert!(err("label-machines: yes").contains("must be true or false"));
+        assert!(err("- --label=id").contains("expected a mapping"));
+        assert!(err("label: {zone: id}").contains("invalid value"));
+    }
+
+    #[test]
+    fn test_expand() {
+        let path = std::env::temp_dir().join(format!("npl-config-{}.yaml", std::process::id()));
+        std::fs::write(&path, "label: ['zone={:first}']\nlabel-machines: true\n").unwrap();
+
+        let args = |args: &[&str]| args.iter().map(OsString::from).collect::<Vec<_>>();
+        let config = format!("--config={}", path.display());
+        assert_eq!(
+            expand(args(&["npl", &config, "--label=id"]), Cli::command()).unwrap(),
+            args(&[
+                "npl",
+                "--label=zone={:first}",
+                "--label-machines",
+                "--label=id"
+            ])
+        );
+        assert_eq!(
+            expand(
+                args(&["npl", "--config", path.to_str().unwrap()]),
+                Cli::command()
+            )
+            .unwrap(),
+            args(&["npl", "--label=zone={:first}", "--label-machines"])
+        );
+        std::fs::write(&path, "label-machine: true\n").unwrap();
+        assert!(expand(args(&["npl", &config]), Cli::command()).is_err());
+        std::fs::remove_file(&path).unwrap();
+
+        assert!(expand(args(&["npl", "--config"]), Cli::command()).is_err());
+        assert!(expand(
+            args(&["npl", "--config=/nonexistent/config"]),
+            Cli::command()
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_starter() {
+        let config = starter(Cli::command());
+        assert!(config.contains("\n# Requeue after this many seconds\n#requeue-duration: 3600\n"));
+        assert!(config.contains("\n#label-machines: true\n"));
+        assert!(config.contains("\n#label: [<LABEL>]\n"));
+        assert!(config.contains("\n#buckets: [<BUCKETS>]\n"));
+        assert!(config.contains("\n#stale-policy: \"keep\"\n"));
+        assert!(!config.contains("help"));
+
+        // the starter config is valid, and sets nothing until uncommented
+        assert!(flags(&config, Cli::command()).unwrap().is_empty());
+        let uncommented = config
+            .replace("#requeue-duration", "requeue-duration")
+            .replace("#label-machines", "label-machines")
+            .replace("#stale-policy", "stale-policy");
+        let cli = parse(flags(&uncommented, Cli::command()).unwrap());
+        assert_eq!(cli.requeue_duration, 3600);
+        assert!(cli.label_machines);
+        assert_eq!(cli.stale_policy, Policy::Keep);
+    }
+
+    #[test]
+    fn test_schema() {
+        let schema = schema(Cli::command());
+        assert_eq!(schema["title"], "npl configuration");
+        assert_eq!(schema["additionalProperties"], false);
+        let properties = schema["properties"].as_object().unwrap();
+        assert_eq!(
+            properties.keys().collect::<Vec<_>>(),
+            [
+                "buckets",
+                "label",
+                "label-machines",
+                "requeue-duration",
+                "stale-policy"
+            ]
+        );
+        assert_eq!(
+            properties["label"],
+            json!({
+                "type": "array",
+                "items": { "type": "string" },
+                "description": "Add a label",
+            })
+        );
+        assert_eq!(
+            properties["requeue-duration"],
+            json!({
+                "type": "integer",
+                "minimum": 0,
+                "description": "Requeue after this many seconds",
+                "default": 3600,
+            })
+        );
+        assert_eq!(
+            properties["buckets"],
+            json!({ "type": "array", "items": { "type": "number" } })
+        );
+        assert_eq!(
+            properties["stale-policy"],
+            json!({ "type": "string", "enum": ["keep", "remove"], "default": "keep" })
+        );
+        assert_eq!(
+            properties["label-machines"],
+            json!({
+                "type": "boolean",
+                "description": "Also label machines",
+                "default": false,
+            })
+        );
+    }
+}
@@ -1,93 +1,280 @@
-use crate::{diagnostics::Diagnostics, meta::MetadataKey, metrics::Metrics, State};
+#[cfg(feature = "azure")]
+use crate::azure::AzureEnricher;
+use crate::{
+    ast::{Filter, Token},
+    backup::{BackupSink, BACKUP_ANNOTATION},
+    breaker::{CircuitBreaker, QuarantineOptions},
+    canary::{Canary, CanaryOptions, ConfigSink, CONFIG_ANNOTATION},
+    capi,
+    diagnostics::{self, Diagnostics},
+    enrich::{self, Enricher},
+    export::{managed_keys, Exporter, NodeValues},
+    hook::{self, PatchDiff, PatchHook},
+    metrics::Metrics,
+    pacing::AdaptiveRate,
+    renderer::{node_provider_id, Renderer},
+    shutdown::Shutdown,
+    sink::{
+        checksum, taint_keys, taint_patch, without_taints, AnnotationSink, ChecksumSink,
+        HistorySink, LabelSink, MetadataPairs, ProviderIDSink, Sink, TaintRenderer, TaintSink,
+        Target, TargetPatch, CHECKSUM_ANNOTATION, PROVIDER_ID_ANNOTATION,
+    },
+    source::{self, env_names, EnvVars, ValueSource},
+    startup::StartupThrottle,
+    transform::Transform,
+    window::{self, ChangeWindow, Schedule},
+};
+use crate::{
+    provider_id::ProviderID,
+    template::{
+        stable_hash, AnnotationTemplate, Fields, LabelTemplate, Maps, ProviderIDTemplate,
+        RenderContext, Template,
+    },
+    Error,
+};
+use backoff::ExponentialBackoff;
 use futures::StreamExt;
 use k8s_openapi::api::core::v1::Node;
 use kube::{
-    api::{ObjectMeta, PartialObjectMetaExt, Patch, PatchParams},
+    api::{ListParams, ObjectMeta, Patch, PatchParams},
+    runtime,
     runtime::{
         controller::{
             Action,
             Error::{ObjectNotFound, QueueError, ReconcilerFailed, RunnerError},
         },
-        watcher, Config, Controller,
+        events::{Event, EventType, Recorder},
+        reflector::{self, Store},
+        utils::ResetTimerBackoff,
+        watcher, Config, WatchStreamExt,
     },
-    Api, Client,
+    Api, Client, Resource, ResourceExt,
 };
-use node_provider_labeler::Error;
-use node_provider_labeler::{
-    provider_id::ProviderID,
-    template::{AnnotationTemplate, LabelTemplate, Template},
+use std::{
+    collections::{BTreeMap, BTreeSet, HashSet},
+    sync::{Arc, Mutex, OnceLock},
+    time::Duration,
 };
-use std::{str::FromStr, sync::Arc, time::Duration};
 use time::OffsetDateTime;
 use tokio::sync::RwLock;
-use tracing::{debug, error, info, warn};
+use tracing::{debug, error, field, info, info_span, warn, Instrument, Span};
+use ttl_queue::TtlQueue;
 
 const MANAGER: &str = "node-provider-labeler";
-const DEFAULT_KEY_NAME: &str = "provider-id";
-const DEFAULT_TEMPLATE: &str = "{:last}";
+const CONFLICT_BACKOFF: Duration = Duration::from_millis(100);
+const MAX_CONFLICT_BACKOFF: Duration = Duration::from_secs(2);
+const EXPORT_INTERVAL: Duration = Duration::from_secs(10);
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(10);
+// the page size of node lists in polling mode, as the watcher's
+const POLL_PAGE_SIZE: u32 = 500;
+// how far ahead to look for the next resync: a schedule for February 29
+// fires every four years
+const RESYNC_HORIZON: Duration = Duration::from_secs(4 * 366 * 24 * 3600);
+const TRANSIENT_ERROR_BACKOFF: Duration = Duration::from_secs(5);
+// the API server rejects longer Event notes
+const EVENT_NOTE_LIMIT: usize = 1024;
+const NODE_OBJECT: &str = "node";
+// what nodes without a provider ID render from; the fallback tokens don't read
+// it
+const FALLBACK_PROVIDER_ID: &str = "none://none";
+const MACHINE_OBJECT: &str = "machine";
 
-type MetadataPairs = std::collections::BTreeMap<String, String>;
+/// Marks the values of a node as stale with [`StalePolicy::Mark`], holding
+/// why: "missing-provider-id" or "invalid-provider-id".
+pub const STALE_ANNOTATION: &str = "node-provider-labeler/stale";
 
-#[derive(Debug)]
-struct Renderer<T>
-where
-    T: std::fmt::Debug + std::default::Default + Template + std::str::FromStr,
-    Error: std::convert::From<<T as std::str::FromStr>::Err>,
-{
-    key: MetadataKey,
-    template: T,
+/// What to do with the values the controller set on a node that lost its
+/// provider ID, or whose provider ID no longer parses, since they can't be
+/// verified anymore. A provider ID template or topology fallback applies
+/// first.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum StalePolicy {
+    /// Leave the values as they are.
+    #[default]
+    Keep,
+    /// Remove the values, like [`cleanup`].
+    Remove,
+    /// Keep the values, marking them with the [`STALE_ANNOTATION`]. The mark
+    /// goes away once the templates render again.
+    Mark,
 }
 
-impl<T> Default for Renderer<T>
-where
-    T: std::fmt::Debug + std::default::Default + Template + std::str::FromStr,
-    Error: std::convert::From<<T as std::str::FromStr>::Err>,
-{
-    fn default() -> Self {
-        Self {
-            key: DEFAULT_KEY_NAME.parse::<MetadataKey>().unwrap(),
-            template: T::from_str(DEFAULT_TEMPLATE).unwrap_or_default(),
+/// The context reconciliations run with, built from a [`Controller`]'s
+/// configuration.
+pub struct Ctx {
+    client: Client,
+    sinks: Vec<Arc<dyn Sink>>,
+    requeue_duration: u64,
+    diagnostics: Arc<RwLock<Diagnostics>>,
+    metrics: Metrics,
+    enrichers: Vec<Arc<dyn Enricher>>,
+    enrichment_timeout: Duration,
+    label_machines: bool,
+    conflict_retries: u32,
+    exporter: Option<Arc<Exporter>>,
+    sources: Vec<Arc<dyn ValueSource>>,
+    transforms: Vec<Arc<dyn Transform>>,
+    maps: Maps,
+    hooks: Vec<Arc<dyn PatchHook>>,
+    provider_id_template: Option<ProviderIDTemplate>,
+    topology_fallback: bool,
+    track_provider_id: bool,
+    stale_policy: StalePolicy,
+    require_node_ready: bool,
+    min_node_age: Option<Duration>,
+    skip_unschedulable: bool,
+    report_only: bool,
+    // the hash of the configuration, with checksums
+    checksum: Option<String>,
+    // the nodes of the last resync yet to be reconciled past their checksums
+    resyncing: Mutex<HashSet<String>>,
+    // the keys and effects of the taints, removed from stale nodes
+    taint_keys: Vec<(String, String)>,
+    canary: Option<Canary>,
+    change_windows: Vec<ChangeWindow>,
+    // the time change windows are checked at, fixed in tests
+    now: fn() -> OffsetDateTime,
+    startup: Option<StartupThrottle>,
+    patch_rate: Option<AdaptiveRate>,
+    breaker: Option<CircuitBreaker>,
+}
+
+impl Ctx {
+    /// Marks the nodes of a resync for reconciliation in full, past their
+    /// checksums.
+    fn resync(&self, nodes: &[Node]) {
+        if self.checksum.is_some() {
+            *self.resyncing.lock().unwrap() = nodes.iter().map(ResourceExt::name_any).collect();
+        }
+    }
+
+    /// Whether a change window is open, or none are configured.
+    fn writes_allowed(&self) -> bool {
+        window::allows_writes(&self.change_windows, (self.now)(), Duration::ZERO).is_ok()
+    }
+
+    /// Waits until the next patch may go out under the startup and adaptive
+    /// patch rates.
+    async fn pace_patch(&self) {
+        if let Some(startup) = &self.startup {
+            startup.wait().await;
+        }
+        if let Some(patch_rate) = &self.patch_rate {
+            patch_rate.wait().await;
+        }
+    }
+
+    /// Sends a patch request, observing its latency by outcome.
+    async fn timed_patch<T>(
+        &self,
+        object: &str,
+        request: impl std::future::Future<Output = Result<T, kube::Error>>,
+    ) -> Result<T, kube::Error> {
+        let start = std::time::Instant::now();
+        let res = request.await;
+        self.metrics
+            .observe_patch_latency(object, start.elapsed(), &res);
+        res
+    }
+
+    /// Records the result of a patch, adapting the patch rate to it.
+    fn observe_patch<T>(&self, object: &str, result: &Result<T, Error>) {
+        self.metrics.observe_patch(object, result);
+        if let Some(patch_rate) = &self.patch_rate {
+            self.metrics.observe_patch_rate(patch_rate.observe(result));
+        }
+    }
+
+    /// Reconciles again after the requeue duration, or when a change window
+    /// opens if that's sooner.
+    fn requeue(&self) -> Action {
+        let requeue = Duration::from_secs(self.requeue_duration);
+        match window::allows_writes(&self.change_windows, (self.now)(), requeue) {
+            Err(Some(opens)) => Action::requeue(opens),
+            _ => Action::requeue(requeue),
         }
     }
 }
 
-impl<T> FromStr for Renderer<T>
-where
-    T: std::fmt::Debug + std::default::Default + Template + std::str::FromStr,
-    Error: std::convert::From<<T as std::str::FromStr>::Err>,
-{
-    type Err = Error;
-
-    fn from_str(s: &str) -> Result<Self, Self::Err> {
-        if s.is_empty() {
-            return Ok(Self::default());
-        }
-        let parts = s.splitn(2, '=').collect::<Vec<&str>>();
-        let key = parts[0]
-            .parse::<MetadataKey>()
-            .map_err(|e| Error::MetadataKey(e.to_string()))?;
-        let template = if parts.len() > 1 {
-            parts[1]
-        } else {
-            DEFAULT_TEMPLATE
-        };
-        let template = T::from_str(template)?;
+/// Reconciles a single node as the controller would, e.g. against a mocked
+/// client in tests. The context comes from [`Controller::context`].
+#[cfg(any(test, feature = "reconcile"))]
+pub async fn reconcile(node: Arc<Node>, ctx: Arc<Ctx>) -> Result<Action, Error> {
+    reconcile_instrumented(node, ctx).await
+}
 
-        Ok(Self { key, template })
+/// Reconciles the node within a span recording the node, its provider, the
+/// number of changed metadata keys, the outcome, and the duration.
+async fn reconcile_instrumented(node: Arc<Node>, ctx: Arc<Ctx>) -> Result<Action, Error> {
+    let span = info_span!(
+        "reconcile",
+        node = node.name_any(),
+        provider = field::Empty,
+        changed_keys = field::Empty,
+        outcome = field::Empty,
+        duration_ms = field::Empty,
+    );
+    if let Some(remaining) = ctx
+        .breaker
+        .as_ref()
+        .and_then(|b| b.quarantined(&node.name_any()))
+    {
+        span.in_scope(|| debug!({ remaining = ?remaining }, "node quarantined, skipping"));
+        return Ok(Action::requeue(remaining));
+    }
+    let res = reconcile_node(node.clone(), ctx.clone())
+        .instrument(span.clone())
+        .await;
+    if let Some(breaker) = &ctx.breaker {
+        let tripped = breaker.observe(&node.name_any(), res.is_ok());
+        ctx.metrics.observe_quarantined_nodes(breaker.len());
+        if tripped {
+            quarantine(&node, &ctx, breaker)
+                .instrument(span.clone())
+                .await;
+        }
+    }
+    if let Some(canary) = &ctx.canary {
+        canary.observe(&node, res.is_ok());
     }
+    if let Some(startup) = &ctx.startup {
+        startup.reconciled(&node);
+    }
+    span.record(
+        "outcome",
+        match &res {
+            Ok(_) => "success",
+            Err(e) => e.kind(),
+        },
+    );
+    span.in_scope(|| debug!("reconcile finished"));
+    res
 }
 
-struct Ctx {
-    client: Client,
-    labels: Option<Vec<Renderer<LabelTemplate>>>,
-    annotations: Option<Vec<Renderer<AnnotationTemplate>>>,
-    requeue_duration: u64,
-    diagnostics: Arc<RwLock<Diagnostics>>,
-    metrics: Metrics,
+/// Announces that the node's reconciliations failed too often in a row, so
+/// it's left alone for the quarantine duration.
+async fn quarantine(node: &Node, ctx: &Ctx, breaker: &CircuitBreaker) {
+    let failures = breaker.options().after_failures;
+    let duration = breaker.options().duration;
+    warn!({ node = node.name_any(), failures, duration = ?duration }, "reconciliation keeps failing, quarantining node");
+    publish_event(
+        ctx,
+        node,
+        Event {
+            type_: EventType::Warning,
+            reason: "Quarantined".into(),
+            note: Some(format!(
+                "Reconciliation failed {failures} times in a row; retrying in {}s",
+                duration.as_secs()
+            )),
+            action: "Reconciling".into(),
+            secondary: None,
+        },
+    )
+    .await;
 }
 
-async fn reconcile(node: Arc<Node>, ctx: Arc<Ctx>) -> Result<Action, Error> {
-    let _timer = ctx.metrics.observe_reconciliation();
+async fn reconcile_node(node: Arc<Node>, ctx: Arc<Ctx>) -> Result<Action, Error> {
     ctx.diagnostics.write().await.last_event = OffsetDateTime::now_utc();
 
     let node_name = node
@@ -95,8 +282,36 @@ async fn reconcile(node: Arc<Node>, ctx: Arc<Ctx>) -> Result<Action, Error> {
         .name
         .as_ref()
         .ok_or_else(|| Error::MissingObjectKey(".metadata.name"))?;
+    let _timer = ctx.metrics.observe_reconciliation(node_name);
 
     debug!({ node = node_name }, "reconciling");
+    let (provider, outcome) = provider_outcome(&node);
+    ctx.metrics
+        .observe_node_provider(node_name, Some((&provider, outcome)));
+
+    // the node's Ready condition changing triggers another reconciliation
+    if ctx.require_node_ready && !node_ready(&node) {
+        debug!({ node = node_name }, "node not ready, deferring");
+        return Ok(Action::requeue(Duration::from_secs(ctx.requeue_duration)));
+    }
+    if let Some(remaining) = ctx.min_node_age.and_then(|age| younger_by(&node, age)) {
+        debug!({ node = node_name, remaining = ?remaining }, "node too young, deferring");
+        return Ok(Action::requeue(remaining));
+    }
+    // uncordoning triggers another reconciliation
+    if ctx.skip_unschedulable && node.spec.as_ref().and_then(|s| s.unschedulable) == Some(true) {
+        debug!({ node = node_name }, "node unschedulable, skipping");
+        return Ok(Action::requeue(Duration::from_secs(ctx.requeue_duration)));
+    }
+    if let Some(Err(wait)) = ctx.canary.as_ref().map(|canary| canary.admits(&node)) {
+        debug!(
+            { node = node_name },
+            "holding the configuration back until the canaries are verified"
+        );
+        return Ok(Action::requeue(
+            wait.min(Duration::from_secs(ctx.requeue_duration)),
+        ));
+    }
 
     let provider_id = node
         .spec
@@ -106,250 +321,3627 @@ async fn reconcile(node: Arc<Node>, ctx: Arc<Ctx>) -> Result<Action, Error> {
         .as_ref();
 
     if let Some(provider_id) = provider_id {
-        let provider_id = ProviderID::new(node_name, provider_id)?;
+        ctx.metrics.observe_missing_provider_id(node_name, false);
+        let provider_id = match ProviderID::new(node_name, provider_id) {
+            Ok(provider_id) => provider_id,
+            Err(e) => {
+                handle_stale(&node, &ctx, "invalid-provider-id").await?;
+                return Err(e.into());
+            }
+        };
         debug!({ node = node_name, provider_id = provider_id.to_string(), provider = provider_id.provider() }, "found provider id");
+        Span::current().record("provider", provider_id.provider());
 
-        let (new_labels, old_labels) =
-            calculate_metadata_pairs(node.metadata.labels.clone(), &ctx.labels, &provider_id)?;
+        apply_templates(&node, &ctx, &provider_id, false).await?;
+    } else if let Some(template) = &ctx.provider_id_template {
+        assign_provider_id(&node, &ctx, template).await?;
+    } else if ctx.topology_fallback {
+        ctx.metrics.observe_missing_provider_id(node_name, true);
+        debug!(
+            { node = node_name },
+            "no provider id found, falling back to topology labels"
+        );
+        let placeholder = ProviderID::new(node_name, FALLBACK_PROVIDER_ID)?;
+        apply_templates(&node, &ctx, &placeholder, true).await?;
+    } else {
+        warn!({ node = node_name }, "no provider id found");
+        if ctx.metrics.observe_missing_provider_id(node_name, true) {
+            publish_event(
+                &ctx,
+                &node,
+                Event {
+                    type_: EventType::Warning,
+                    reason: "MissingProviderID".into(),
+                    note: Some(
+                        "Node has no spec.providerID; check the cloud-controller-manager".into(),
+                    ),
+                    action: "Reconciling".into(),
+                    secondary: None,
+                },
+            )
+            .await;
+        }
+        if let Some(exporter) = &ctx.exporter {
+            exporter.remove(node_name).await;
+        }
+        handle_stale(&node, &ctx, "missing-provider-id").await?;
+    }
 
-        let (new_annotations, old_annotations) = calculate_metadata_pairs(
-            node.metadata.annotations.clone(),
-            &ctx.annotations,
-            &provider_id,
-        )?;
+    Ok(ctx.requeue())
+}
+
+/// The provider of the node and the outcome of parsing its provider ID: "ok",
+/// "invalid", or "missing". The provider is empty unless the ID parses.
+fn provider_outcome(node: &Node) -> (String, &'static str) {
+    let provider_id = node.spec.as_ref().and_then(|s| s.provider_id.as_deref());
+    match provider_id.map(|id| ProviderID::new(&node.name_any(), id)) {
+        Some(Ok(id)) => (id.provider(), "ok"),
+        Some(Err(_)) => (String::new(), "invalid"),
+        None => (String::new(), "missing"),
+    }
+}
+
+/// Whether the node's Ready condition is True.
+fn node_ready(node: &Node) -> bool {
+    node.status
+        .as_ref()
+        .and_then(|status| status.conditions.as_ref())
+        .into_iter()
+        .flatten()
+        .any(|c| c.type_ == "Ready" && c.status == "True")
+}
+
+/// How much longer until the node is `age` old, or `None` if it is. Nodes
+/// without a creation timestamp count as old.
+fn younger_by(node: &Node, age: Duration) -> Option<Duration> {
+    let created = node.metadata.creation_timestamp.as_ref()?.0;
+    let elapsed = (k8s_openapi::chrono::Utc::now() - created)
+        .to_std()
+        .unwrap_or_default();
+    age.checked_sub(elapsed).filter(|d| !d.is_zero())
+}
+
+/// Renders the templates for the node and applies the values that changed.
+/// With `fallback`, the node has no provider ID, and `provider_id` is a
+/// placeholder the templates render from topology labels instead.
+async fn apply_templates(
+    node: &Node,
+    ctx: &Ctx,
+    provider_id: &ProviderID,
+    fallback: bool,
+) -> Result<(), Error> {
+    let node_name = &node.name_any();
+    // drift is only reported from rendered values
+    if let Some(hash) = ctx
+        .checksum
+        .as_ref()
+        .filter(|_| !fallback && !ctx.report_only)
+    {
+        // a resync renders every node in full
+        let resync = ctx.resyncing.lock().unwrap().remove(node_name);
+        if !resync
+            && node.annotations().get(CHECKSUM_ANNOTATION) == Some(&checksum(hash, provider_id))
+        {
+            debug!({ node = node_name }, "checksum matches, skipping");
+            return Ok(());
+        }
+    }
+    // enrichers look the node up by its provider ID
+    let fields = if fallback {
+        Fields::new()
+    } else {
+        enrich::enrich(&ctx.enrichers, node, provider_id, ctx.enrichment_timeout).await?
+    };
+
+    let mut render_ctx = RenderContext::new(provider_id)
+        .with_metadata(&node.metadata)
+        .with_fields(&fields)
+        .with_sources(&ctx.sources)
+        .with_transforms(&ctx.transforms)
+        .with_maps(&ctx.maps);
+    if fallback {
+        render_ctx = render_ctx.with_topology_fallback();
+    }
+
+    if ctx.track_provider_id && !fallback {
+        observe_provider_id_change(node, ctx, provider_id).await;
+    }
+
+    let patch = render_observed(ctx, NODE_OBJECT, Target::Node(node), &render_ctx)?;
+    ctx.metrics
+        .observe_key_conflicts(NODE_OBJECT, patch.conflicts);
+
+    let values = NodeValues {
+        labels: patch.labels.clone(),
+        annotations: patch.annotations.clone(),
+    };
+
+    Span::current().record("changed_keys", patch.changed);
+    if ctx.report_only {
+        report_drift(node, ctx, &patch);
+    } else if patch.changed == 0 {
+        debug!({ node = node_name }, "no changes to apply");
+    } else if !ctx.writes_allowed() {
+        info!({ node = node_name, changes = patch.changed }, "changes pending until a change window opens");
+    } else {
+        let diff = PatchDiff::new(node, &patch);
+        hook::before(&ctx.hooks, &diff).await?;
 
-        if new_labels == old_labels && new_annotations == old_annotations {
-            debug!({ node = node_name }, "no changes to apply");
-            return Ok(Action::requeue(Duration::from_secs(ctx.requeue_duration)));
+        // taints go first, the resourceVersion they're guarded by changes
+        // with the other patches
+        if let Some(taints) = patch
+            .taints
+            .as_deref()
+            .map(|taints| taint_patch(node, taints))
+            .transpose()?
+            .flatten()
+        {
+            patch_taints(ctx, node_name, taints).await?;
         }
 
-        let payload = ObjectMeta {
-            labels: Some(new_labels),
-            annotations: Some(new_annotations),
+        let unset = unset_payload(&patch);
+        let payload = Node {
+            metadata: ObjectMeta {
+                labels: Some(patch.labels),
+                annotations: Some(patch.annotations),
+                ..Default::default()
+            },
             ..Default::default()
         };
+        ctx.pace_patch().await;
         info!({ node = node_name }, "patching");
         debug!({ node = node_name }, "payload {:?}", payload);
-        let patch = payload.into_request_partial::<Node>();
+        let patch = Patch::Apply(&payload);
+        let params = PatchParams::apply(MANAGER).force();
         let node_api: Api<Node> = Api::all(ctx.client.clone());
-        node_api
-            .patch_metadata(
-                node_name,
-                &PatchParams::apply(MANAGER).force(),
-                &Patch::Apply(&patch),
-            )
-            .await?;
-    } else {
-        warn!({ node = node_name }, "no provider id found");
+        let res = retry_on_conflict(ctx.conflict_retries, CONFLICT_BACKOFF, || {
+            ctx.timed_patch(NODE_OBJECT, node_api.patch(node_name, &params, &patch))
+        })
+        .await;
+        ctx.observe_patch(NODE_OBJECT, &res);
+        res?;
+
+        if let Some(unset) = unset {
+            ctx.pace_patch().await;
+            info!({ node = node_name }, "removing unset keys");
+            let params = PatchParams {
+                field_manager: Some(MANAGER.into()),
+                ..Default::default()
+            };
+            let res = ctx
+                .timed_patch(
+                    NODE_OBJECT,
+                    node_api.patch(node_name, &params, &Patch::Merge(&unset)),
+                )
+                .await
+                .map_err(Error::from);
+            ctx.observe_patch(NODE_OBJECT, &res);
+            res?;
+        }
+
+        hook::after(&ctx.hooks, &diff).await;
+    }
+
+    if let Some(exporter) = &ctx.exporter {
+        exporter.update(node_name, values).await;
     }
 
-    Ok(Action::requeue(Duration::from_secs(ctx.requeue_duration)))
+    if ctx.label_machines && !fallback {
+        reconcile_machine(node, ctx, &render_ctx).await?;
+    }
+    Ok(())
 }
 
-fn error_policy(_object: Arc<Node>, _error: &Error, _ctx: Arc<Ctx>) -> Action {
-    Action::requeue(Duration::from_secs(60))
+/// Sends a [`taint_patch`] for the node.
+async fn patch_taints(ctx: &Ctx, node_name: &str, patch: json_patch::Patch) -> Result<(), Error> {
+    ctx.pace_patch().await;
+    info!({ node = node_name }, "patching taints");
+    let node_api: Api<Node> = Api::all(ctx.client.clone());
+    let params = PatchParams {
+        field_manager: Some(MANAGER.into()),
+        ..Default::default()
+    };
+    let res = ctx
+        .timed_patch(
+            NODE_OBJECT,
+            node_api.patch(node_name, &params, &Patch::<()>::Json(patch)),
+        )
+        .await
+        .map_err(Error::from);
+    ctx.observe_patch(NODE_OBJECT, &res);
+    res?;
+    Ok(())
 }
 
-pub(crate) async fn run(
-    client: kube::Client,
-    state: State,
-    label_templates: Option<Vec<String>>,
-    annotation_templates: Option<Vec<String>>,
-    requeue_duration: u64,
+/// The merge patch removing the patch's unset keys, if it has any. Applying
+/// a patch only removes keys the controller owns.
+fn unset_payload(patch: &TargetPatch) -> Option<serde_json::Value> {
+    if patch.unset_labels.is_empty() && patch.unset_annotations.is_empty() {
+        return None;
+    }
+    let nulls = |keys: &BTreeSet<String>| {
+        keys.iter()
+            .map(|k| (k.clone(), serde_json::Value::Null))
+            .collect::<serde_json::Map<_, _>>()
+    };
+    Some(serde_json::json!({
+        "metadata": {
+            "labels": nulls(&patch.unset_labels),
+            "annotations": nulls(&patch.unset_annotations),
+        }
+    }))
+}
+
+/// Logs the keys whose values on the node differ from the rendered ones, and
+/// counts them in the drift metric.
+fn report_drift(node: &Node, ctx: &Ctx, patch: &TargetPatch) {
+    let node_name = node.name_any();
+    let diff = PatchDiff::new(node, patch);
+    let mut keys = vec![];
+    for change in diff.labels.iter().chain(&diff.annotations) {
+        warn!({ node = node_name, key = change.key, current = change.old, expected = change.new }, "drift detected");
+        keys.push(change.key.clone());
+    }
+    let removed = (diff
+        .removed_labels
+        .iter()
+        .map(|k| (k, node.labels().get(k))))
+    .chain(
+        diff.removed_annotations
+            .iter()
+            .map(|k| (k, node.annotations().get(k))),
+    );
+    for (key, current) in removed {
+        warn!({ node = node_name, key, current }, "drift detected, key should be absent");
+        keys.push(key.clone());
+    }
+    if let Some(taints) = &diff.taints {
+        for taint in taints.new.iter().filter(|t| !taints.old.contains(t)) {
+            warn!({ node = node_name, key = taint.key, effect = taint.effect, value = taint.value }, "taint drift detected");
+            keys.push(taint.key.clone());
+        }
+    }
+    if keys.is_empty() {
+        debug!({ node = node_name }, "no drift detected");
+    }
+    ctx.metrics.observe_drift(&node_name, &keys);
+}
+
+/// Sets the rendered provider ID on a node that has none. The patch carries
+/// the node's resourceVersion, so it fails with a conflict rather than
+/// overwriting a provider ID set since the node was observed; the API server
+/// also rejects changes to a set provider ID. The node's next reconciliation
+/// applies the templates.
+async fn assign_provider_id(
+    node: &Node,
+    ctx: &Ctx,
+    template: &ProviderIDTemplate,
 ) -> Result<(), Error> {
-    const QUEUE_ERROR: &str = "queue";
-    const RUNNER_ERROR: &str = "runner";
+    let node_name = node.name_any();
+    let provider_id = template.render(&node_name, &node.metadata, &ctx.sources)?;
+    if ctx.report_only {
+        warn!({ node = node_name, provider_id = provider_id.to_string() }, "drift detected, node has no provider id");
+        return Ok(());
+    }
+    if !ctx.writes_allowed() {
+        info!({ node = node_name, provider_id = provider_id.to_string() }, "provider id pending until a change window opens");
+        return Ok(());
+    }
+    let resource_version = node
+        .metadata
+        .resource_version
+        .as_ref()
+        .ok_or_else(|| Error::MissingObjectKey(".metadata.resourceVersion"))?;
+
+    ctx.pace_patch().await;
+    info!({ node = node_name, provider_id = provider_id.to_string() }, "setting provider id");
+    let payload = serde_json::json!({
+        "metadata": { "resourceVersion": resource_version },
+        "spec": { "providerID": provider_id.to_string() },
+    });
+    let node_api: Api<Node> = Api::all(ctx.client.clone());
+    let params = PatchParams {
+        field_manager: Some(MANAGER.into()),
+        ..Default::default()
+    };
+    let res = ctx
+        .timed_patch(
+            NODE_OBJECT,
+            node_api.patch(&node_name, &params, &Patch::Merge(&payload)),
+        )
+        .await
+        .map_err(Error::from);
+    ctx.observe_patch(NODE_OBJECT, &res);
+    res?;
 
-    let diagnostics = state.diagnostics.clone();
-    let metrics = Metrics::default().register(&state.registry).unwrap();
-    let node: Api<Node> = Api::all(client.clone());
+    publish_event(
+        ctx,
+        node,
+        Event {
+            type_: EventType::Normal,
+            reason: "ProviderIDAssigned".into(),
+            note: Some(format!("Set spec.providerID to {provider_id}")),
+            action: "Reconciling".into(),
+            secondary: None,
+        },
+    )
+    .await;
+    Ok(())
+}
 
-    let mut labels = parse_renderers(label_templates)?;
-    let annotations = parse_renderers(annotation_templates)?;
+/// Publishes an Event for the node. Failures are logged rather than failing
+/// the reconciliation.
+async fn publish_event(ctx: &Ctx, node: &Node, event: Event) {
+    if ctx.report_only {
+        return;
+    }
+    let recorder = Recorder::new(ctx.client.clone(), MANAGER.into(), node.object_ref(&()));
+    if let Err(e) = recorder.publish(event).await {
+        warn!({ node = node.name_any(), error = e.to_string() }, "unable to publish event");
+    }
+}
 
-    // if neither labels or annotations are configured, use a default label and
-    // template
-    if annotations.is_none() && labels.is_none() {
-        labels = Some(vec![Renderer::default()]);
+/// Applies the [`StalePolicy`] to a node the controller manages whose values
+/// can't be rendered for `reason`.
+async fn handle_stale(node: &Node, ctx: &Ctx, reason: &str) -> Result<(), Error> {
+    let managed = node
+        .managed_fields()
+        .iter()
+        .any(|f| f.manager.as_deref() == Some(MANAGER));
+    if ctx.stale_policy == StalePolicy::Keep || !managed || ctx.report_only {
+        return Ok(());
+    }
+    if ctx.stale_policy == StalePolicy::Mark
+        && node.annotations().get(STALE_ANNOTATION).map(String::as_str) == Some(reason)
+    {
+        return Ok(());
+    }
+    if !ctx.writes_allowed() {
+        info!({ node = node.name_any(), reason }, "stale values pending until a change window opens");
+        return Ok(());
     }
 
-    let inc_error_count = || async {
-        diagnostics
-            .write()
-            .await
-            .error_count
-            .refresh_and_push_back(1);
+    // applying nothing releases every field the manager owns, applying the
+    // current values keeps them
+    let node_name = node.name_any();
+    let mut payload = Node {
+        metadata: ObjectMeta {
+            name: Some(node_name.clone()),
+            ..Default::default()
+        },
+        ..Default::default()
     };
+    if ctx.stale_policy == StalePolicy::Mark {
+        let current = |pairs: &Option<MetadataPairs>, kind: &str| {
+            managed_keys(&node.metadata, kind)
+                .into_iter()
+                .filter_map(|k| pairs.as_ref()?.get(&k).cloned().map(|v| (k, v)))
+                .collect::<MetadataPairs>()
+        };
+        let mut annotations = current(&node.metadata.annotations, "f:annotations");
+        annotations.insert(STALE_ANNOTATION.into(), reason.into());
+        payload.metadata.labels = Some(current(&node.metadata.labels, "f:labels"));
+        payload.metadata.annotations = Some(annotations);
+    } else if let Some(taints) = taint_patch(node, &without_taints(node, &ctx.taint_keys))? {
+        // patched before the release, while the resourceVersion holds
+        patch_taints(ctx, &node_name, taints).await?;
+    }
 
-    info!("starting controller");
-    debug!({ labels = ?labels, annotation = ?annotations }, "config");
-    Controller::new(node, watcher::Config::default())
-        .with_config(Config::default().concurrency(2))
-        .shutdown_on_signal()
-        .run(
-            reconcile,
-            error_policy,
-            Arc::new(Ctx {
-                client,
-                labels,
-                annotations,
-                requeue_duration,
-                metrics: metrics.clone(),
-                diagnostics: diagnostics.clone(),
-            }),
+    ctx.pace_patch().await;
+    info!({ node = node_name, policy = ?ctx.stale_policy, reason }, "handling stale values");
+    let node_api: Api<Node> = Api::all(ctx.client.clone());
+    let params = PatchParams::apply(MANAGER).force();
+    let patch = Patch::Apply(&payload);
+    let res = retry_on_conflict(ctx.conflict_retries, CONFLICT_BACKOFF, || {
+        ctx.timed_patch(NODE_OBJECT, node_api.patch(&node_name, &params, &patch))
+    })
+    .await;
+    ctx.observe_patch(NODE_OBJECT, &res);
+    res?;
+    Ok(())
+}
+
+/// Publishes a `ProviderIDChanged` event if the node's values were rendered
+/// from another provider ID. The patch replaces them all, including the
+/// recorded provider ID.
+async fn observe_provider_id_change(node: &Node, ctx: &Ctx, provider_id: &ProviderID) {
+    let Some(previous) = node.annotations().get(PROVIDER_ID_ANNOTATION) else {
+        return;
+    };
+    let current = provider_id.to_string();
+    if *previous == current {
+        return;
+    }
+    info!({ node = node.name_any(), previous, provider_id = current }, "provider id changed, replacing values");
+    publish_event(
+        ctx,
+        node,
+        Event {
+            type_: EventType::Normal,
+            reason: "ProviderIDChanged".into(),
+            note: Some(format!(
+                "Provider ID changed from {previous} to {current}; replacing managed values"
+            )),
+            action: "Reconciling".into(),
+            secondary: None,
+        },
+    )
+    .await;
+}
+
+/// Applies the rendered metadata to the Cluster API Machine owning the node.
+async fn reconcile_machine(
+    node: &Node,
+    ctx: &Ctx,
+    render_ctx: &RenderContext<'_>,
+) -> Result<(), Error> {
+    let node_name = node.name_any();
+    let Some(machine) = capi::find_machine(&ctx.client, node).await? else {
+        debug!({ node = node_name }, "no machine found");
+        return Ok(());
+    };
+    let machine_name = machine.name_any();
+
+    let patch = render_observed(
+        ctx,
+        MACHINE_OBJECT,
+        Target::Machine(&machine.metadata),
+        render_ctx,
+    )?;
+    ctx.metrics
+        .observe_key_conflicts(MACHINE_OBJECT, patch.conflicts);
+
+    if patch.changed == 0 {
+        debug!({ node = node_name, machine = machine_name }, "no machine changes to apply");
+        return Ok(());
+    }
+    if ctx.report_only {
+        warn!({ node = node_name, machine = machine_name, changes = patch.changed }, "machine drift detected");
+        return Ok(());
+    }
+    if !ctx.writes_allowed() {
+        info!({ node = node_name, machine = machine_name, changes = patch.changed }, "machine changes pending until a change window opens");
+        return Ok(());
+    }
+
+    let unset = unset_payload(&patch);
+    let payload = ObjectMeta {
+        labels: Some(patch.labels),
+        annotations: Some(patch.annotations),
+        ..Default::default()
+    };
+    ctx.pace_patch().await;
+    info!({ node = node_name, machine = machine_name }, "patching machine");
+    debug!({ machine = machine_name }, "payload {:?}", payload);
+    let patch = Patch::Apply(capi::machine_patch(payload));
+    let params = PatchParams::apply(MANAGER).force();
+    let machine_api = capi::machine_api(ctx.client.clone(), &machine);
+    let res = retry_on_conflict(ctx.conflict_retries, CONFLICT_BACKOFF, || {
+        ctx.timed_patch(
+            MACHINE_OBJECT,
+            machine_api.patch_metadata(&machine_name, &params, &patch),
         )
-        .for_each(|res| async {
-            match res {
-                Ok(o) => {
-                    let node_name = o.0.clone().name;
-                    debug!({ node = node_name }, "reconciled");
-                }
-                Err(e) => match e {
-                    QueueError(e) => {
-                        error!("queue error: {e}");
-                        inc_error_count().await;
-                        metrics.observe_controller_failure(QUEUE_ERROR);
-                    }
-                    RunnerError(e) => {
-                        error!("runner error: {e}");
-                        inc_error_count().await;
-                        metrics.observe_controller_failure(RUNNER_ERROR);
-                    }
-                    ReconcilerFailed(e, o) => {
-                        error!({ node = o.name }, "reconciliation failed: {e}");
-                        metrics.observe_reconciliation_failure();
-                    }
-                    ObjectNotFound(o) => {
-                        warn!({ node = o.name }, "object not found");
-                        metrics.observe_object_not_found_error();
-                    }
-                },
-            }
-        })
-        .await;
+    })
+    .await;
+    ctx.observe_patch(MACHINE_OBJECT, &res);
+    res?;
 
-    info!("stopping");
+    if let Some(unset) = unset {
+        ctx.pace_patch().await;
+        info!({ node = node_name, machine = machine_name }, "removing unset machine keys");
+        let params = PatchParams {
+            field_manager: Some(MANAGER.into()),
+            ..Default::default()
+        };
+        let res = ctx
+            .timed_patch(
+                MACHINE_OBJECT,
+                machine_api.patch_metadata(&machine_name, &params, &Patch::Merge(&unset)),
+            )
+            .await
+            .map_err(Error::from);
+        ctx.observe_patch(MACHINE_OBJECT, &res);
+        res?;
+    }
 
     Ok(())
 }
 
-fn calculate_metadata_pairs<T>(
-    current: Option<MetadataPairs>,
-    renderers: &Option<Vec<Renderer<T>>>,
-    provider_id: &ProviderID,
-) -> Result<(MetadataPairs, MetadataPairs), Error>
-where
-    T: std::fmt::Debug + std::default::Default + Template + std::str::FromStr,
-    Error: std::convert::From<<T as std::str::FromStr>::Err>,
-{
-    let current = current.unwrap_or_default();
-    let mut old = MetadataPairs::new();
-    let mut new = MetadataPairs::new();
-
-    if let Some(renderers) = renderers {
-        for r in renderers {
-            let key = r.key.to_string();
-            let value = r.template.render(provider_id)?;
-            if let Some(v) = current.get(&key).cloned() {
-                old.insert(key.clone(), v);
-            }
-            new.insert(key, value);
-        }
+/// Returns the configured labels, or the default "provider-id={:last}" label
+/// if nothing at all is configured.
+pub(crate) fn default_labels(
+    labels: Option<Vec<Renderer<LabelTemplate>>>,
+    annotations: &Option<Vec<Renderer<AnnotationTemplate>>>,
+    taints: &Option<Vec<TaintRenderer>>,
+    sinks: &[Arc<dyn Sink>],
+) -> Option<Vec<Renderer<LabelTemplate>>> {
+    if labels.is_none() && annotations.is_none() && taints.is_none() && sinks.is_empty() {
+        return Some(vec![Renderer::default()]);
     }
+    labels
+}
 
-    Ok((new, old))
+/// The label, annotation, and taint sinks for whatever is configured.
+pub(crate) fn builtin_sinks(
+    labels: Option<Vec<Renderer<LabelTemplate>>>,
+    annotations: Option<Vec<Renderer<AnnotationTemplate>>>,
+    taints: Option<Vec<TaintRenderer>>,
+) -> impl Iterator<Item = Arc<dyn Sink>> {
+    [
+        labels.map(|r| Arc::new(LabelSink(r)) as Arc<dyn Sink>),
+        annotations.map(|r| Arc::new(AnnotationSink(r)) as Arc<dyn Sink>),
+        taints.map(|r| Arc::new(TaintSink(r)) as Arc<dyn Sink>),
+    ]
+    .into_iter()
+    .flatten()
 }
 
-fn parse_renderers<T>(args: Option<Vec<String>>) -> Result<Option<Vec<Renderer<T>>>, Error>
-where
-    T: std::fmt::Debug + std::default::Default + Template + std::str::FromStr,
-    Error: std::convert::From<<T as std::str::FromStr>::Err>,
-{
-    args.map(|list| {
-        list.iter()
-            .map(|s| s.parse::<Renderer<T>>())
-            .collect::<Result<Vec<_>, _>>()
-    })
-    .transpose()
+/// Orders the sinks by [`Sink::priority`], keeping the registration order at
+/// equal priority.
+pub(crate) fn by_priority(
+    sinks: impl Iterator<Item = Arc<dyn Sink>>,
+) -> impl Iterator<Item = Arc<dyn Sink>> {
+    let mut sinks = sinks.collect::<Vec<_>>();
+    sinks.sort_by_key(|sink| sink.priority());
+    sinks.into_iter()
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use node_provider_labeler::provider_id::ProviderID;
+/// Runs every sink for the target, counting the values a later sink
+/// overwrote as conflicts. An overwritten value no longer counts as a change.
+pub(crate) fn render_sinks(
+    sinks: &[Arc<dyn Sink>],
+    target: Target<'_>,
+    render_ctx: &RenderContext,
+) -> Result<TargetPatch, Error> {
+    let mut patch = TargetPatch::default();
+    render_into(sinks, target, render_ctx, &mut patch)?;
+    Ok(patch)
+}
 
-    #[test]
-    fn test_calculate_metadata_pairs() {
-        let provider_id = ProviderID::new("my-node-name", "fake://region/instance").unwrap();
+/// Renders the target for reconciliation like [`render_sinks`], observing
+/// the render duration, and the key that failed to render.
+fn render_observed(
+    ctx: &Ctx,
+    object: &str,
+    target: Target<'_>,
+    render_ctx: &RenderContext,
+) -> Result<TargetPatch, Error> {
+    let start = std::time::Instant::now();
+    let mut patch = TargetPatch::default();
+    let res = render_into(&ctx.sinks, target, render_ctx, &mut patch);
+    ctx.metrics.observe_render(
+        object,
+        start.elapsed(),
+        res.as_ref()
+            .err()
+            .map(|_| patch.failed_key.as_deref().unwrap_or_default()),
+    );
+    res.map(|_| patch)
+}
 
-        {
-            // no renderers
-            let renderers: Option<Vec<Renderer<LabelTemplate>>> = None;
-            let current = Some(MetadataPairs::new());
-            let (old, new) = calculate_metadata_pairs(current, &renderers, &provider_id).unwrap();
-            assert_eq!(old, new);
-            assert!(new.is_empty());
-        }
+fn render_into(
+    sinks: &[Arc<dyn Sink>],
+    target: Target<'_>,
+    render_ctx: &RenderContext,
+    patch: &mut TargetPatch,
+) -> Result<(), Error> {
+    let metadata = target.metadata();
+    for sink in sinks {
+        let labels = patch.labels.clone();
+        let annotations = patch.annotations.clone();
+        sink.render(target, render_ctx, patch)?;
 
-        {
-            // new node with single default renderer
-            let renderer: Renderer<LabelTemplate> = Renderer::default();
-            let renderers = Some(vec![renderer]);
-            let current = Some(MetadataPairs::new());
-            let (new, old) = calculate_metadata_pairs(current, &renderers, &provider_id).unwrap();
-            assert_ne!(new, old);
-            assert!(!new.is_empty());
-            assert_eq!("instance", new.get("provider-id").unwrap());
+        for (before, after, current) in [
+            (labels, &patch.labels, metadata.labels.as_ref()),
+            (
+                annotations,
+                &patch.annotations,
+                metadata.annotations.as_ref(),
+            ),
+        ] {
+            for (key, value) in before {
+                if after.get(&key) == Some(&value) {
+                    continue;
+                }
+                patch.conflicts += 1;
+                if current.and_then(|c| c.get(&key)) != Some(&value) {
+                    patch.changed = patch.changed.saturating_sub(1);
+                }
+            }
         }
+    }
+    Ok(())
+}
 
-        {
-            // already reconciled node
-            let renderers: Option<Vec<Renderer<AnnotationTemplate>>> = Some(vec![
-                Renderer::from_str("some={:last}").unwrap(),
-                Renderer::from_str("other={:first}").unwrap(),
-            ]);
-            let mut current = MetadataPairs::new();
-            current.insert("some".to_string(), "instance".to_string());
-            current.insert("other".to_string(), "region".to_string());
-            let (new, old) =
-                calculate_metadata_pairs(Some(current), &renderers, &provider_id).unwrap();
-            assert_eq!(new, old);
-            assert!(!new.is_empty());
-            assert_eq!("instance", new.get("some").unwrap());
-            assert_eq!("region", new.get("other").unwrap());
-        }
+/// Label, annotation, and taint templates given as for [`Options`], parsed,
+/// for validating and previewing configuration without a cluster.
+#[derive(Debug)]
+pub struct Templates {
+    sinks: Vec<Arc<dyn Sink>>,
+    label_keys: Vec<String>,
+    annotation_keys: Vec<String>,
+    maps: Maps,
+    // the environment variables the templates reference
+    env: BTreeSet<String>,
+    // the lookup tables the templates reference
+    map_names: BTreeSet<String>,
+}
 
-        {
-            // node with one key missing
-            let renderers: Option<Vec<Renderer<AnnotationTemplate>>> = Some(vec![
-                Renderer::from_str("some={:last}").unwrap(),
-                Renderer::from_str("other={:first}").unwrap(),
-            ]);
-            let mut current = MetadataPairs::new();
-            current.insert("some".to_string(), "instance".to_string());
-            let (new, old) =
-                calculate_metadata_pairs(Some(current), &renderers, &provider_id).unwrap();
-            assert_ne!(new, old);
-            assert!(!new.is_empty());
-            assert_eq!("instance", new.get("some").unwrap());
-            assert_eq!("region", new.get("other").unwrap());
-        }
+impl Templates {
+    /// Parses the templates, failing on invalid keys, templates, or effects,
+    /// or on conflicting keys.
+    pub fn parse(
+        label_templates: Option<Vec<String>>,
+        annotation_templates: Option<Vec<String>>,
+        taint_templates: Option<Vec<String>>,
+    ) -> Result<Self, Error> {
+        let annotations = parse_renderers(annotation_templates)?;
+        let taints = parse_taints(taint_templates)?;
+        let labels = parse_renderers(label_templates)?;
+        check_duplicates(&labels, &annotations, &taints)?;
+        let labels = default_labels(labels, &annotations, &taints, &[]);
+        Ok(Self {
+            label_keys: renderer_keys(&labels),
+            annotation_keys: renderer_keys(&annotations),
+            env: referenced_env(&labels, &annotations, &taints),
+            map_names: referenced_maps(&labels, &annotations, &taints),
+            sinks: builtin_sinks(labels, annotations, taints).collect(),
+            maps: Maps::new(),
+        })
+    }
 
-        {
-            // node with one different value
-            let renderers: Option<Vec<Renderer<AnnotationTemplate>>> = Some(vec![
-                Renderer::from_str("some={:last}").unwrap(),
-                Renderer::from_str("other={:first}").unwrap(),
-            ]);
-            let mut current = MetadataPairs::new();
-            current.insert("some".to_string(), "instance".to_string());
-            current.insert("other".to_string(), "notregion".to_string());
-            let (new, old) =
-                calculate_metadata_pairs(Some(current), &renderers, &provider_id).unwrap();
-            assert_ne!(new, old);
-            assert!(!new.is_empty());
-            assert_eq!("instance", new.get("some").unwrap());
-            assert_eq!("region", new.get("other").unwrap());
-        }
+    /// Adds lookup tables for `|map(<name>)` filters, failing if the
+    /// templates use others.
+    pub fn with_maps(mut self, maps: Maps) -> Result<Self, Error> {
+        check_maps(&self.map_names, &maps)?;
+        self.maps = maps;
+        Ok(self)
+    }
+
+    /// The keys of the labels the templates manage.
+    pub fn label_keys(&self) -> &[String] {
+        &self.label_keys
+    }
+
+    /// The keys of the annotations the templates manage.
+    pub fn annotation_keys(&self) -> &[String] {
+        &self.annotation_keys
+    }
+
+    /// Renders the templates for the node the way the controller would,
+    /// without enrichment.
+    pub fn render(&self, node: &Node) -> Result<TargetPatch, Error> {
+        let provider_id = node_provider_id(node)?;
+        let env = Arc::new(EnvVars::capture(&self.env)?);
+        let sources = source::defaults()
+            .into_iter()
+            .chain([env as Arc<dyn ValueSource>])
+            .collect::<Vec<_>>();
+        let ctx = RenderContext::new(&provider_id)
+            .with_metadata(&node.metadata)
+            .with_sources(&sources)
+            .with_maps(&self.maps);
+        render_sinks(&self.sinks, Target::Node(node), &ctx)
+    }
+}
+
+/// Removes the labels and annotations the controller manages, and the
+/// `taints` it was configured with, from every node it manages, returning
+/// their names. Other taints are left alone. With `dry_run`, the API server
+/// only validates the changes.
+pub async fn cleanup(
+    client: Client,
+    taints: &[TaintRenderer],
+    dry_run: bool,
+) -> Result<Vec<String>, Error> {
+    let node_api: Api<Node> = Api::all(client);
+    let mut params = PatchParams::apply(MANAGER).force();
+    params.dry_run = dry_run;
+    let taints = taint_keys(taints);
+
+    let mut cleaned = vec![];
+    for node in node_api.list(&ListParams::default()).await? {
+        let managed = node
+            .managed_fields()
+            .iter()
+            .any(|f| f.manager.as_deref() == Some(MANAGER));
+        if !managed {
+            continue;
+        }
+
+        // applying nothing releases every field the manager owns
+        let name = node.name_any();
+        let payload = Node {
+            metadata: ObjectMeta {
+                name: Some(name.clone()),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        info!({ node = name, dry_run }, "cleaning up");
+        remove_taints(&node_api, &node, &taints, dry_run).await?;
+        node_api
+            .patch(&name, &params, &Patch::Apply(&payload))
+            .await?;
+        cleaned.push(name);
+    }
+
+    Ok(cleaned)
+}
+
+/// Removes the taints with the keys and effects from the node, before the
+/// fields the controller owns are released, while the resourceVersion the
+/// patch is guarded by holds.
+pub(crate) async fn remove_taints(
+    node_api: &Api<Node>,
+    node: &Node,
+    keys: &[(String, String)],
+    dry_run: bool,
+) -> Result<(), Error> {
+    let Some(patch) = taint_patch(node, &without_taints(node, keys))? else {
+        return Ok(());
+    };
+    let params = PatchParams {
+        field_manager: Some(MANAGER.into()),
+        dry_run,
+        ..Default::default()
+    };
+    node_api
+        .patch(&node.name_any(), &params, &Patch::<()>::Json(patch))
+        .await?;
+    Ok(())
+}
+
+/// Retries `f` when the API server responds with a conflict, backing off
+/// exponentially from `backoff`, up to `retries` times.
+async fn retry_on_conflict<F, Fut, T>(retries: u32, backoff: Duration, mut f: F) -> Result<T, Error>
+where
+    F: FnMut() -> Fut,
+    Fut: std::future::Future<Output = Result<T, kube::Error>>,
+{
+    let mut attempt = 0;
+    loop {
+        match f().await {
+            Err(kube::Error::Api(e)) if e.code == 409 && attempt < retries => {
+                let delay = (backoff * 2u32.pow(attempt)).min(MAX_CONFLICT_BACKOFF);
+                attempt += 1;
+                debug!({ attempt = attempt, delay = ?delay }, "conflict, retrying: {}", e.message);
+                tokio::time::sleep(delay).await;
+            }
+            res => return res.map_err(Error::from),
+        }
+    }
+}
+
+/// Retries transient failures soon, and configuration and template errors,
+/// which won't fix themselves, on the regular requeue schedule. Patches the
+/// API server rejected as invalid aren't retried until the node changes.
+fn error_policy(node: Arc<Node>, error: &Error, ctx: Arc<Ctx>) -> Action {
+    if let Some(remaining) = ctx
+        .breaker
+        .as_ref()
+        .and_then(|b| b.quarantined(&node.name_any()))
+    {
+        return Action::requeue(remaining);
+    }
+    match error {
+        Error::Kube(kube::Error::Api(e)) if e.code == 400 || e.code == 422 => {
+            warn!({ node = node.name_any(), error = error.to_string() }, "patch rejected, waiting for the node to change");
+            let note = e.message.chars().take(EVENT_NOTE_LIMIT).collect();
+            tokio::spawn(async move {
+                publish_event(
+                    &ctx,
+                    &node,
+                    Event {
+                        type_: EventType::Warning,
+                        reason: "PatchRejected".into(),
+                        note: Some(note),
+                        action: "Reconciling".into(),
+                        secondary: None,
+                    },
+                )
+                .await;
+            });
+            Action::await_change()
+        }
+        Error::Kube(kube::Error::Api(e)) if e.code == 401 || e.code == 403 => {
+            Action::requeue(Duration::from_secs(ctx.requeue_duration))
+        }
+        Error::Kube(_)
+        | Error::Enrichment(_)
+        | Error::Azure(_)
+        | Error::Hook(_)
+        | Error::JoinError(_)
+        | Error::ServerError(_) => Action::requeue(TRANSIENT_ERROR_BACKOFF),
+        _ => Action::requeue(Duration::from_secs(ctx.requeue_duration)),
+    }
+}
+
+/// State shared between the controller and whatever serves its health and
+/// metrics
+#[derive(Clone, Debug, Default)]
+pub struct State {
+    pub diagnostics: Arc<RwLock<Diagnostics>>,
+    /// The nodes the controller observes, once it has started
+    pub nodes: Arc<OnceLock<Store<Node>>>,
+    /// Metrics registry
+    #[cfg(feature = "metrics")]
+    pub registry: prometheus::Registry,
+}
+
+#[cfg(feature = "metrics")]
+impl State {
+    pub fn metrics(&self) -> Vec<prometheus::proto::MetricFamily> {
+        self.registry.gather()
+    }
+}
+
+/// Controller configuration
+pub struct Options {
+    pub client: Client,
+    /// Diagnostics and metrics registry, shared with the caller
+    pub state: State,
+    /// Stops the controller, draining in-flight reconciliations
+    pub shutdown: Shutdown,
+    pub label_templates: Option<Vec<String>>,
+    pub annotation_templates: Option<Vec<String>>,
+    /// Taints as key=template:Effect
+    pub taint_templates: Option<Vec<String>>,
+    /// Additional destinations for rendered values
+    pub sinks: Vec<Arc<dyn Sink>>,
+    /// Resolve `{<namespace>:<key>}` tokens, after enrichment fields
+    pub sources: Vec<Arc<dyn ValueSource>>,
+    /// Environment variables `{env:<name>}` tokens may render besides those
+    /// the templates reference, e.g. in @skip conditions
+    pub env_allow: Vec<String>,
+    /// Resolve `{plugin:<name>(<token>)}` tokens
+    pub transforms: Vec<Arc<dyn Transform>>,
+    /// Lookup tables for `|map(<name>)` filters
+    pub maps: Maps,
+    /// Run before and after each node patch
+    pub hooks: Vec<Arc<dyn PatchHook>>,
+    /// Requeue reconciliation of a node after this duration in seconds
+    pub requeue_duration: u64,
+    #[cfg(feature = "azure")]
+    pub azure: Option<AzureEnricher>,
+    /// Contribute template fields per node, after Azure
+    pub enrichers: Vec<Arc<dyn Enricher>>,
+    /// Fail a reconciliation if an enricher takes longer than this
+    pub enrichment_timeout: Duration,
+    /// Also apply metadata to the Cluster API Machine owning each node
+    pub label_machines: bool,
+    /// Back up the values of pre-existing keys before taking them over, for
+    /// [`crate::backup::undo`]
+    pub backup_originals: bool,
+    /// Record the previous value and change time of changed values in
+    /// `<key>.previous` and `<key>.changed-at` annotations
+    pub record_history: bool,
+    /// Record the provider ID the values were rendered from, and publish an
+    /// event when it changes
+    pub track_provider_id: bool,
+    /// What to do with the values of nodes that lost their provider ID
+    pub stale_policy: StalePolicy,
+    /// Defer patching nodes until their Ready condition is True
+    pub require_node_ready: bool,
+    /// Defer patching nodes until they are this old
+    pub min_node_age: Option<Duration>,
+    /// Leave cordoned nodes alone
+    pub skip_unschedulable: bool,
+    /// Report drift from the rendered values without writing anything
+    pub report_only: bool,
+    /// Record a checksum of the configuration and provider ID on nodes, and
+    /// skip rendering nodes whose checksum matches
+    pub checksum: bool,
+    /// Roll configuration changes out to canary nodes first
+    pub canary: Option<CanaryOptions>,
+    /// Only write to nodes while one of these windows is open
+    pub change_windows: Vec<ChangeWindow>,
+    /// Limit how many nodes are patched per minute during the initial sync
+    pub startup_patches_per_minute: Option<u32>,
+    /// Patch at most this many objects per second, slowing down while the
+    /// API server throttles requests
+    pub max_patch_rate: Option<f64>,
+    /// Quarantine nodes whose reconciliations keep failing
+    pub quarantine: Option<QuarantineOptions>,
+    /// Render a provider ID for nodes without one, e.g.
+    /// "metal://{label:rack}/{:node}"
+    pub provider_id_template: Option<String>,
+    /// Render nodes without a provider ID from their topology labels, with
+    /// the templates' fallback tokens
+    pub topology_fallback: bool,
+    /// Server-side timeout for node watches in seconds
+    pub watch_timeout: Option<u32>,
+    /// Fetch the initial node list with a streaming watch instead of a list
+    pub streaming_list: bool,
+    /// How the node watch backs off reconnecting after errors
+    pub watcher_backoff: WatcherBackoff,
+    /// List and reconcile all nodes at this interval instead of watching
+    /// them
+    pub poll_interval: Option<Duration>,
+    /// Also list and reconcile all nodes at the times of this schedule
+    pub resync_schedule: Option<Schedule>,
+    /// Drop other managers' managed fields and the image list from cached
+    /// nodes
+    pub strip_cached_nodes: bool,
+    /// Only reconcile nodes matching this label selector
+    pub node_selector: Option<String>,
+    /// Retry conflicting patches this many times within a reconciliation
+    pub conflict_retries: u32,
+    /// Maintain a ConfigMap with the node to rendered values mapping
+    pub exporter: Option<Exporter>,
+    /// How long to wait for in-flight reconciliations on shutdown
+    pub drain_timeout: Duration,
+    /// Label reconciliation metrics by node, for up to this many nodes
+    pub metrics_max_nodes: Option<usize>,
+    /// Custom reconcile_duration histogram buckets
+    pub reconcile_duration_buckets: Option<Vec<f64>>,
+    /// Check API server connectivity for readiness this often
+    pub readiness_interval: Duration,
+    /// Fail once more than this many queue and runner errors occurred within
+    /// the controller error window
+    pub max_controller_errors: Option<usize>,
+    pub controller_error_window: Duration,
+}
+
+impl Options {
+    /// Creates options with the binary's defaults: the "provider-id={:last}"
+    /// label, hourly requeues, and no shutdown signal.
+    pub fn new(client: Client) -> Self {
+        Self {
+            client,
+            state: State::default(),
+            shutdown: Shutdown::never(),
+            label_templates: None,
+            annotation_templates: None,
+            taint_templates: None,
+            sinks: vec![],
+            sources: source::defaults(),
+            env_allow: vec![],
+            transforms: vec![],
+            maps: Maps::new(),
+            hooks: vec![],
+            requeue_duration: 3600,
+            #[cfg(feature = "azure")]
+            azure: None,
+            enrichers: vec![],
+            enrichment_timeout: Duration::from_secs(10),
+            label_machines: false,
+            backup_originals: false,
+            record_history: false,
+            track_provider_id: false,
+            stale_policy: StalePolicy::Keep,
+            require_node_ready: false,
+            min_node_age: None,
+            skip_unschedulable: false,
+            report_only: false,
+            checksum: false,
+            canary: None,
+            change_windows: vec![],
+            startup_patches_per_minute: None,
+            max_patch_rate: None,
+            quarantine: None,
+            provider_id_template: None,
+            topology_fallback: false,
+            watch_timeout: None,
+            streaming_list: false,
+            watcher_backoff: WatcherBackoff::default(),
+            poll_interval: None,
+            resync_schedule: None,
+            strip_cached_nodes: true,
+            node_selector: None,
+            conflict_retries: 3,
+            exporter: None,
+            drain_timeout: Duration::from_secs(20),
+            metrics_max_nodes: None,
+            reconcile_duration_buckets: None,
+            readiness_interval: Duration::from_secs(10),
+            max_controller_errors: None,
+            controller_error_window: Duration::from_secs(60),
+        }
+    }
+}
+
+/// Runs the node controller until shutdown is requested.
+pub async fn run(options: Options) -> Result<(), Error> {
+    ControllerBuilder::from(options).build()?.run().await
+}
+
+impl From<Options> for ControllerBuilder {
+    /// A builder for the options, validated like any other by
+    /// [`ControllerBuilder::build`].
+    fn from(options: Options) -> Self {
+        #[cfg(feature = "azure")]
+        let enrichers = options
+            .azure
+            .map(|azure| Arc::new(azure) as Arc<dyn Enricher>)
+            .into_iter()
+            .chain(options.enrichers)
+            .collect();
+        #[cfg(not(feature = "azure"))]
+        let enrichers = options.enrichers;
+        Self {
+            client: Some(options.client),
+            state: Some(options.state),
+            shutdown: Some(options.shutdown),
+            labels: options.label_templates.unwrap_or_default(),
+            annotations: options.annotation_templates.unwrap_or_default(),
+            taints: options.taint_templates.unwrap_or_default(),
+            sinks: options.sinks,
+            default_sources: Some(options.sources),
+            sources: vec![],
+            env_allow: options.env_allow,
+            transforms: options.transforms,
+            maps: options.maps,
+            hooks: options.hooks,
+            requeue_duration: Some(Duration::from_secs(options.requeue_duration)),
+            enrichers,
+            enrichment_timeout: Some(options.enrichment_timeout),
+            label_machines: options.label_machines,
+            backup_originals: options.backup_originals,
+            record_history: options.record_history,
+            track_provider_id: options.track_provider_id,
+            stale_policy: options.stale_policy,
+            require_node_ready: options.require_node_ready,
+            min_node_age: options.min_node_age,
+            skip_unschedulable: options.skip_unschedulable,
+            report_only: options.report_only,
+            checksum: options.checksum,
+            canary: options.canary,
+            change_windows: options.change_windows,
+            startup_patches_per_minute: options.startup_patches_per_minute,
+            max_patch_rate: options.max_patch_rate,
+            quarantine: options.quarantine,
+            provider_id_template: options.provider_id_template,
+            topology_fallback: options.topology_fallback,
+            watch_timeout: options
+                .watch_timeout
+                .map(|secs| Duration::from_secs(secs.into())),
+            streaming_list: options.streaming_list,
+            watcher_backoff: options.watcher_backoff,
+            poll_interval: options.poll_interval,
+            resync_schedule: options.resync_schedule,
+            strip_cached_nodes: Some(options.strip_cached_nodes),
+            node_selector: options.node_selector,
+            conflict_retries: Some(options.conflict_retries),
+            exporter: options.exporter,
+            drain_timeout: Some(options.drain_timeout),
+            metrics_max_nodes: options.metrics_max_nodes,
+            reconcile_duration_buckets: options.reconcile_duration_buckets,
+            readiness_interval: Some(options.readiness_interval),
+            max_controller_errors: options.max_controller_errors,
+            controller_error_window: Some(options.controller_error_window),
+        }
+    }
+}
+
+/// The node controller, with validated configuration. Created with
+/// [`Controller::builder`].
+pub struct Controller {
+    client: Client,
+    state: State,
+    shutdown: Shutdown,
+    labels: Option<Vec<Renderer<LabelTemplate>>>,
+    annotations: Option<Vec<Renderer<AnnotationTemplate>>>,
+    taints: Option<Vec<TaintRenderer>>,
+    sinks: Vec<Arc<dyn Sink>>,
+    sources: Vec<Arc<dyn ValueSource>>,
+    transforms: Vec<Arc<dyn Transform>>,
+    maps: Maps,
+    hooks: Vec<Arc<dyn PatchHook>>,
+    requeue_duration: u64,
+    enrichers: Vec<Arc<dyn Enricher>>,
+    enrichment_timeout: Duration,
+    label_machines: bool,
+    backup_originals: bool,
+    record_history: bool,
+    track_provider_id: bool,
+    stale_policy: StalePolicy,
+    require_node_ready: bool,
+    min_node_age: Option<Duration>,
+    skip_unschedulable: bool,
+    report_only: bool,
+    checksum: bool,
+    canary: Option<CanaryOptions>,
+    change_windows: Vec<ChangeWindow>,
+    startup_patches_per_minute: Option<u32>,
+    max_patch_rate: Option<f64>,
+    quarantine: Option<QuarantineOptions>,
+    provider_id_template: Option<ProviderIDTemplate>,
+    topology_fallback: bool,
+    watch_timeout: Option<u32>,
+    streaming_list: bool,
+    watcher_backoff: WatcherBackoff,
+    poll_interval: Option<Duration>,
+    resync_schedule: Option<Schedule>,
+    strip_cached_nodes: bool,
+    node_selector: Option<String>,
+    conflict_retries: u32,
+    exporter: Option<Exporter>,
+    drain_timeout: Duration,
+    metrics_max_nodes: Option<usize>,
+    reconcile_duration_buckets: Option<Vec<f64>>,
+    readiness_interval: Duration,
+    max_controller_errors: Option<usize>,
+    controller_error_window: Duration,
+}
+
+/// Builds a [`Controller`]. Only the client is required; everything else
+/// defaults as in [`Options::new`].
+///
+/// ```no_run
+/// # async fn example() -> Result<(), node_provider_labeler::Error> {
+/// use node_provider_labeler::Controller;
+///
+/// let controller = Controller::builder()
+///     .client(kube::Client::try_default().await?)
+///     .label("zone", "{:first}")
+///     .node_selector("node-role.kubernetes.io/worker")
+///     .build()?;
+/// controller.run().await
+/// # }
+/// ```
+#[derive(Default)]
+pub struct ControllerBuilder {
+    client: Option<Client>,
+    state: Option<State>,
+    shutdown: Option<Shutdown>,
+    labels: Vec<String>,
+    annotations: Vec<String>,
+    taints: Vec<String>,
+    sinks: Vec<Arc<dyn Sink>>,
+    /// Replaces [`source::defaults`], for [`Options::sources`]
+    default_sources: Option<Vec<Arc<dyn ValueSource>>>,
+    sources: Vec<Arc<dyn ValueSource>>,
+    env_allow: Vec<String>,
+    transforms: Vec<Arc<dyn Transform>>,
+    maps: Maps,
+    hooks: Vec<Arc<dyn PatchHook>>,
+    requeue_duration: Option<Duration>,
+    enrichers: Vec<Arc<dyn Enricher>>,
+    enrichment_timeout: Option<Duration>,
+    label_machines: bool,
+    backup_originals: bool,
+    record_history: bool,
+    track_provider_id: bool,
+    stale_policy: StalePolicy,
+    require_node_ready: bool,
+    min_node_age: Option<Duration>,
+    skip_unschedulable: bool,
+    report_only: bool,
+    checksum: bool,
+    canary: Option<CanaryOptions>,
+    change_windows: Vec<ChangeWindow>,
+    startup_patches_per_minute: Option<u32>,
+    max_patch_rate: Option<f64>,
+    quarantine: Option<QuarantineOptions>,
+    provider_id_template: Option<String>,
+    topology_fallback: bool,
+    watch_timeout: Option<Duration>,
+    streaming_list: bool,
+    watcher_backoff: WatcherBackoff,
+    poll_interval: Option<Duration>,
+    resync_schedule: Option<Schedule>,
+    strip_cached_nodes: Option<bool>,
+    node_selector: Option<String>,
+    conflict_retries: Option<u32>,
+    exporter: Option<Exporter>,
+    drain_timeout: Option<Duration>,
+    metrics_max_nodes: Option<usize>,
+    reconcile_duration_buckets: Option<Vec<f64>>,
+    readiness_interval: Option<Duration>,
+    max_controller_errors: Option<usize>,
+    controller_error_window: Option<Duration>,
+}
+
+impl ControllerBuilder {
+    pub fn client(mut self, client: Client) -> Self {
+        self.client = Some(client);
+        self
+    }
+
+    /// Shares diagnostics and the metrics registry with the caller.
+    pub fn state(mut self, state: State) -> Self {
+        self.state = Some(state);
+        self
+    }
+
+    pub fn shutdown(mut self, shutdown: Shutdown) -> Self {
+        self.shutdown = Some(shutdown);
+        self
+    }
+
+    /// Adds a label rendered from `template`, or removed from nodes with an
+    /// empty template. The template takes the suffixes of `--label`, e.g.
+    /// "{:first}@selector=node-role.kubernetes.io/worker". Without labels,
+    /// annotations, taints, or sinks, the controller applies
+    /// "provider-id={:last}".
+    pub fn label(mut self, key: impl AsRef<str>, template: impl AsRef<str>) -> Self {
+        self.labels
+            .push(format!("{}={}", key.as_ref(), template.as_ref()));
+        self
+    }
+
+    /// Adds an annotation rendered from `template`, or removed from nodes
+    /// with an empty template. The template takes the suffixes of
+    /// `--annotation`.
+    pub fn annotation(mut self, key: impl AsRef<str>, template: impl AsRef<str>) -> Self {
+        self.annotations
+            .push(format!("{}={}", key.as_ref(), template.as_ref()));
+        self
+    }
+
+    /// Adds a taint whose value is rendered from `template`, with an effect of
+    /// "NoSchedule", "PreferNoSchedule", or "NoExecute". With an empty
+    /// template, the taint is removed from nodes.
+    pub fn taint(
+        mut self,
+        key: impl AsRef<str>,
+        template: impl AsRef<str>,
+        effect: impl AsRef<str>,
+    ) -> Self {
+        self.taints.push(format!(
+            "{}={}:{}",
+            key.as_ref(),
+            template.as_ref(),
+            effect.as_ref()
+        ));
+        self
+    }
+
+    /// Adds a destination for rendered values, run after the label,
+    /// annotation, and taint sinks and the sinks added before it, unless its
+    /// [`Sink::priority`] is lower.
+    pub fn sink(mut self, sink: impl Sink + 'static) -> Self {
+        self.sinks.push(Arc::new(sink));
+        self
+    }
+
+    /// Registers a source for `{<namespace>:<key>}` tokens, consulted after
+    /// the node label and annotation sources.
+    pub fn source(mut self, source: impl ValueSource + 'static) -> Self {
+        self.sources.push(Arc::new(source));
+        self
+    }
+
+    /// Allows `{env:<name>}` tokens the label, annotation, and taint templates
+    /// don't reference, e.g. in @skip conditions, to render the variable. It
+    /// must be set when the controller is built, like the referenced ones.
+    pub fn env_allow(mut self, name: impl Into<String>) -> Self {
+        self.env_allow.push(name.into());
+        self
+    }
+
+    /// Registers a transform for `{plugin:<name>(<token>)}` tokens. Names
+    /// must be unique.
+    pub fn transform(mut self, transform: impl Transform + 'static) -> Self {
+        self.transforms.push(Arc::new(transform));
+        self
+    }
+
+    /// Registers a lookup table for `|map(<name>)` filters, replacing one
+    /// with the same name.
+    pub fn map<K, V>(
+        mut self,
+        name: impl Into<String>,
+        entries: impl IntoIterator<Item = (K, V)>,
+    ) -> Self
+    where
+        K: Into<String>,
+        V: Into<String>,
+    {
+        let entries = entries
+            .into_iter()
+            .map(|(k, v)| (k.into(), v.into()))
+            .collect();
+        self.maps.insert(name.into(), entries);
+        self
+    }
+
+    /// Registers a hook to run before and after each node patch, in
+    /// registration order.
+    pub fn hook(mut self, hook: impl PatchHook + 'static) -> Self {
+        self.hooks.push(Arc::new(hook));
+        self
+    }
+
+    /// Only reconciles nodes matching the label selector, e.g.
+    /// "node-role.kubernetes.io/worker,topology.kubernetes.io/zone!=local".
+    pub fn node_selector(mut self, selector: impl Into<String>) -> Self {
+        self.node_selector = Some(selector.into());
+        self
+    }
+
+    pub fn requeue_duration(mut self, duration: Duration) -> Self {
+        self.requeue_duration = Some(duration);
+        self
+    }
+
+    #[cfg(feature = "azure")]
+    pub fn azure(self, azure: AzureEnricher) -> Self {
+        self.enricher(azure)
+    }
+
+    /// Adds an enricher, run before rendering in the order added.
+    pub fn enricher(mut self, enricher: impl Enricher + 'static) -> Self {
+        self.enrichers.push(Arc::new(enricher));
+        self
+    }
+
+    pub fn enrichment_timeout(mut self, timeout: Duration) -> Self {
+        self.enrichment_timeout = Some(timeout);
+        self
+    }
+
+    pub fn label_machines(mut self, label_machines: bool) -> Self {
+        self.label_machines = label_machines;
+        self
+    }
+
+    /// Backs up the values of pre-existing labels and annotations in the
+    /// [`crate::backup::BACKUP_ANNOTATION`] before taking them over, so
+    /// [`crate::backup::undo`] can restore them.
+    pub fn backup_originals(mut self, backup_originals: bool) -> Self {
+        self.backup_originals = backup_originals;
+        self
+    }
+
+    /// Records the previous value and change time of each changed label and
+    /// annotation in `<key>.previous` and `<key>.changed-at` annotations.
+    pub fn record_history(mut self, record_history: bool) -> Self {
+        self.record_history = record_history;
+        self
+    }
+
+    /// Records the provider ID the values were rendered from in the
+    /// [`PROVIDER_ID_ANNOTATION`], and publishes a `ProviderIDChanged` event
+    /// when a node comes back with another one, e.g. re-registered on new
+    /// hardware.
+    pub fn track_provider_id(mut self, track_provider_id: bool) -> Self {
+        self.track_provider_id = track_provider_id;
+        self
+    }
+
+    /// Sets what to do with the values of a node that lost its provider ID,
+    /// or whose provider ID no longer parses. Defaults to keeping them.
+    pub fn stale_policy(mut self, stale_policy: StalePolicy) -> Self {
+        self.stale_policy = stale_policy;
+        self
+    }
+
+    /// Defers patching a node until its Ready condition is True, so bootstrap
+    /// tooling that rewrites metadata while the node initializes doesn't race
+    /// the controller.
+    pub fn require_node_ready(mut self, require_node_ready: bool) -> Self {
+        self.require_node_ready = require_node_ready;
+        self
+    }
+
+    /// Defers patching a node until it is `age` old, giving cloud controllers
+    /// and bootstrap DaemonSets time to finish their own metadata writes.
+    pub fn min_node_age(mut self, age: Duration) -> Self {
+        self.min_node_age = Some(age);
+        self
+    }
+
+    /// Leaves nodes with `spec.unschedulable` set, i.e. cordoned nodes, alone
+    /// until they are uncordoned.
+    pub fn skip_unschedulable(mut self, skip_unschedulable: bool) -> Self {
+        self.skip_unschedulable = skip_unschedulable;
+        self
+    }
+
+    /// Only reports drift: renders the values and compares them with the
+    /// nodes', logging and counting the keys that differ in the
+    /// `drift_detected` metric, but never patches nodes or machines, nor
+    /// publishes events. Meant to run continuously as a compliance monitor
+    /// alongside whatever else writes the values.
+    pub fn report_only(mut self, report_only: bool) -> Self {
+        self.report_only = report_only;
+        self
+    }
+
+    /// Records a checksum of the configuration and the provider ID a node's
+    /// values were rendered with in the [`CHECKSUM_ANNOTATION`], and skips
+    /// reconciling nodes whose checksum matches, without enriching,
+    /// rendering, or diffing them. Steady-state reconciliations of large
+    /// clusters become nearly free, but values rendered from anything else,
+    /// e.g. the node's labels, enrichers, or sources, aren't refreshed, nor
+    /// are drifted values or Machines repaired, until the configuration or
+    /// provider ID changes or the annotation is removed. Can't be combined
+    /// with an [`Exporter`], which needs every node's values.
+    pub fn checksum(mut self, checksum: bool) -> Self {
+        self.checksum = checksum;
+        self
+    }
+
+    /// Rolls configuration changes out to canary nodes first, and to the rest
+    /// once the canaries reconciled without too many errors. Nodes record
+    /// the configuration they were rendered with in the
+    /// [`CONFIG_ANNOTATION`], so an unchanged configuration isn't held back.
+    pub fn canary(mut self, options: CanaryOptions) -> Self {
+        self.canary = Some(options);
+        self
+    }
+
+    /// Limits how many nodes are patched per minute until every node was
+    /// reconciled once after starting, separately from the client's rate
+    /// limits, so a redeploy into a large cluster doesn't get other clients
+    /// throttled by API priority and fairness.
+    pub fn startup_patches_per_minute(mut self, per_minute: u32) -> Self {
+        self.startup_patches_per_minute = Some(per_minute);
+        self
+    }
+
+    /// Paces patches across reconcilers at up to `per_second` patches per
+    /// second. While the API server throttles requests (HTTP 429, e.g. from
+    /// API priority and fairness), the rate is halved with each throttled
+    /// patch, and raised again as patches succeed.
+    pub fn max_patch_rate(mut self, per_second: f64) -> Self {
+        self.max_patch_rate = Some(per_second);
+        self
+    }
+
+    /// Quarantines a node once its reconciliations failed
+    /// `options.after_failures` times in a row, with a warning Event, so one
+    /// pathological node can't dominate the error budget and log volume. It's
+    /// left alone for `options.duration`, then reconciled again.
+    pub fn quarantine(mut self, options: QuarantineOptions) -> Self {
+        self.quarantine = Some(options);
+        self
+    }
+
+    /// Adds a window during which the controller may write to nodes. With
+    /// windows, changes are still computed outside of them, but only applied
+    /// once one opens.
+    pub fn change_window(mut self, window: ChangeWindow) -> Self {
+        self.change_windows.push(window);
+        self
+    }
+
+    /// Renders a provider ID for nodes without one and sets it, for
+    /// bare-metal clusters without a cloud-controller-manager, e.g.
+    /// "metal://{label:rack}/{:node}". Existing provider IDs are never
+    /// changed.
+    pub fn provider_id_template(mut self, template: impl Into<String>) -> Self {
+        self.provider_id_template = Some(template.into());
+        self
+    }
+
+    /// Renders nodes without a provider ID, e.g. on premises, from their
+    /// `topology.kubernetes.io/zone` and `region` labels: provider ID tokens
+    /// marked with a fallback, as in `{:first|zone}`, render the label, and
+    /// templates with other provider ID tokens are skipped. A provider ID
+    /// template takes precedence.
+    pub fn topology_fallback(mut self, topology_fallback: bool) -> Self {
+        self.topology_fallback = topology_fallback;
+        self
+    }
+
+    pub fn watch_timeout(mut self, timeout: Duration) -> Self {
+        self.watch_timeout = Some(timeout);
+        self
+    }
+
+    /// Fetches the initial node list, and relists, with a streaming watch
+    /// (the WatchList feature) instead of a list, which keeps memory and API
+    /// server load flat on large clusters. The API server must enable the
+    /// WatchList feature.
+    pub fn streaming_list(mut self, streaming_list: bool) -> Self {
+        self.streaming_list = streaming_list;
+        self
+    }
+
+    /// Sets how the node watch backs off reconnecting after errors, e.g. to
+    /// dampen reconnect storms against a flappy API server, or to recover
+    /// faster in tests.
+    pub fn watcher_backoff(mut self, backoff: WatcherBackoff) -> Self {
+        self.watcher_backoff = backoff;
+        self
+    }
+
+    /// Lists the nodes at this interval and reconciles all of them, instead
+    /// of watching nodes, for environments where the `watch` verb isn't
+    /// granted or long-lived connections are unreliable. Changes to nodes
+    /// are only picked up by the next list. Can't be combined with
+    /// [`ControllerBuilder::streaming_list`].
+    pub fn poll_interval(mut self, interval: Duration) -> Self {
+        self.poll_interval = Some(interval);
+        self
+    }
+
+    /// Lists all nodes at the times of the schedule and reconciles every one
+    /// of them, independently of the requeue duration, e.g. for a nightly
+    /// pass that guarantees convergence. Resynced nodes are rendered in full
+    /// even if their [`CHECKSUM_ANNOTATION`] matches.
+    pub fn resync_schedule(mut self, schedule: Schedule) -> Self {
+        self.resync_schedule = Some(schedule);
+        self
+    }
+
+    /// Whether to drop other managers' managed fields and `status.images`
+    /// from the nodes the controller caches, which it never reads (on by
+    /// default). They make up most of a node on clusters with large image
+    /// lists.
+    pub fn strip_cached_nodes(mut self, strip: bool) -> Self {
+        self.strip_cached_nodes = Some(strip);
+        self
+    }
+
+    pub fn conflict_retries(mut self, retries: u32) -> Self {
+        self.conflict_retries = Some(retries);
+        self
+    }
+
+    pub fn exporter(mut self, exporter: Exporter) -> Self {
+        self.exporter = Some(exporter);
+        self
+    }
+
+    pub fn drain_timeout(mut self, timeout: Duration) -> Self {
+        self.drain_timeout = Some(timeout);
+        self
+    }
+
+    pub fn metrics_max_nodes(mut self, max_nodes: usize) -> Self {
+        self.metrics_max_nodes = Some(max_nodes);
+        self
+    }
+
+    pub fn reconcile_duration_buckets(mut self, buckets: Vec<f64>) -> Self {
+        self.reconcile_duration_buckets = Some(buckets);
+        self
+    }
+
+    pub fn readiness_interval(mut self, interval: Duration) -> Self {
+        self.readiness_interval = Some(interval);
+        self
+    }
+
+    /// Fails the controller once more than `max` queue and runner errors
+    /// occurred within `window`, so the process exits and Kubernetes restarts
+    /// it with backoff, rather than running on with a dead controller.
+    pub fn max_controller_errors(mut self, max: usize, window: Duration) -> Self {
+        self.max_controller_errors = Some(max);
+        self.controller_error_window = Some(window);
+        self
+    }
+
+    /// Validates the configuration: a client is set, keys and templates
+    /// parse, keys are unique, and durations are at least a second where the
+    /// API takes whole seconds.
+    pub fn build(self) -> Result<Controller, Error> {
+        let client = self
+            .client
+            .ok_or_else(|| Error::Config("a client is required".into()))?;
+        let defaults = Options::new(client);
+
+        let requeue_duration = match self.requeue_duration {
+            Some(d) => whole_seconds("requeue duration", d)?,
+            None => defaults.requeue_duration,
+        };
+        let watch_timeout = self
+            .watch_timeout
+            .map(|d| whole_seconds("watch timeout", d))
+            .transpose()?
+            .map(|secs| u32::try_from(secs).unwrap_or(u32::MAX));
+        self.watcher_backoff.validate()?;
+        check_poll_interval(self.poll_interval, self.streaming_list)?;
+        check_checksum(self.checksum, self.exporter.is_some())?;
+        if matches!(&self.node_selector, Some(s) if s.trim().is_empty()) {
+            return Err(Error::Config("node selector must not be empty".into()));
+        }
+        let mut names = HashSet::new();
+        if let Some(t) = self.transforms.iter().find(|t| !names.insert(t.name())) {
+            return Err(Error::Config(format!("duplicate transform '{}'", t.name())));
+        }
+
+        if let Some(canary) = &self.canary {
+            canary.validate()?;
+        }
+        if self.startup_patches_per_minute == Some(0) {
+            return Err(Error::Config(
+                "startup patches per minute must be at least 1".into(),
+            ));
+        }
+        check_patch_rate(self.max_patch_rate)?;
+        if let Some(quarantine) = &self.quarantine {
+            quarantine.validate()?;
+        }
+
+        let labels = parse_renderers((!self.labels.is_empty()).then_some(self.labels))?;
+        let annotations =
+            parse_renderers((!self.annotations.is_empty()).then_some(self.annotations))?;
+        let taints = parse_taints((!self.taints.is_empty()).then_some(self.taints))?;
+        check_duplicates(&labels, &annotations, &taints)?;
+        let provider_id_template = self.provider_id_template.map(|t| t.parse()).transpose()?;
+        let env = env_vars(
+            &labels,
+            &annotations,
+            &taints,
+            &provider_id_template,
+            &self.env_allow,
+        )?;
+        check_maps(&referenced_maps(&labels, &annotations, &taints), &self.maps)?;
+
+        Ok(Controller {
+            client: defaults.client,
+            state: self.state.unwrap_or(defaults.state),
+            shutdown: self.shutdown.unwrap_or(defaults.shutdown),
+            labels,
+            annotations,
+            taints,
+            sinks: self.sinks,
+            sources: self
+                .default_sources
+                .unwrap_or(defaults.sources)
+                .into_iter()
+                .chain(self.sources)
+                .chain([env])
+                .collect(),
+            transforms: self.transforms,
+            maps: self.maps,
+            hooks: self.hooks,
+            requeue_duration,
+            enrichers: self.enrichers,
+            enrichment_timeout: self
+                .enrichment_timeout
+                .unwrap_or(defaults.enrichment_timeout),
+            label_machines: self.label_machines,
+            backup_originals: self.backup_originals,
+            record_history: self.record_history,
+            track_provider_id: self.track_provider_id,
+            stale_policy: self.stale_policy,
+            require_node_ready: self.require_node_ready,
+            min_node_age: self.min_node_age,
+            skip_unschedulable: self.skip_unschedulable,
+            report_only: self.report_only,
+            checksum: self.checksum,
+            canary: self.canary,
+            change_windows: self.change_windows,
+            startup_patches_per_minute: self.startup_patches_per_minute,
+            max_patch_rate: self.max_patch_rate,
+            quarantine: self.quarantine,
+            provider_id_template,
+            topology_fallback: self.topology_fallback,
+            watch_timeout,
+            streaming_list: self.streaming_list,
+            watcher_backoff: self.watcher_backoff,
+            poll_interval: self.poll_interval,
+            resync_schedule: self.resync_schedule,
+            strip_cached_nodes: self
+                .strip_cached_nodes
+                .unwrap_or(defaults.strip_cached_nodes),
+            node_selector: self.node_selector,
+            conflict_retries: self.conflict_retries.unwrap_or(defaults.conflict_retries),
+            exporter: self.exporter,
+            drain_timeout: self.drain_timeout.unwrap_or(defaults.drain_timeout),
+            metrics_max_nodes: self.metrics_max_nodes,
+            reconcile_duration_buckets: self.reconcile_duration_buckets,
+            readiness_interval: self
+                .readiness_interval
+                .unwrap_or(defaults.readiness_interval),
+            max_controller_errors: self.max_controller_errors,
+            controller_error_window: self
+                .controller_error_window
+                .unwrap_or(defaults.controller_error_window),
+        })
+    }
+}
+
+fn whole_seconds(name: &str, duration: Duration) -> Result<u64, Error> {
+    match duration.as_secs() {
+        0 => Err(Error::Config(format!("{name} must be at least 1s"))),
+        secs => Ok(secs),
+    }
+}
+
+/// Fails on keys configured more than once, listing every conflict, instead
+/// of letting the later template silently win. Taints conflict on key and
+/// effect, and annotations may not use the reserved [`BACKUP_ANNOTATION`],
+/// [`PROVIDER_ID_ANNOTATION`], [`STALE_ANNOTATION`], [`CONFIG_ANNOTATION`], or
+/// [`CHECKSUM_ANNOTATION`].
+pub(crate) fn check_duplicates(
+    labels: &Option<Vec<Renderer<LabelTemplate>>>,
+    annotations: &Option<Vec<Renderer<AnnotationTemplate>>>,
+    taints: &Option<Vec<TaintRenderer>>,
+) -> Result<(), Error> {
+    let mut counts = BTreeMap::<String, usize>::new();
+    let keys = renderer_keys(labels)
+        .into_iter()
+        .map(|key| format!("label '{key}'"))
+        .chain(
+            renderer_keys(annotations)
+                .into_iter()
+                .map(|key| format!("annotation '{key}'")),
+        )
+        .chain(
+            taints
+                .iter()
+                .flatten()
+                .map(|t| format!("taint '{}:{}'", t.key(), t.effect())),
+        );
+    for key in keys {
+        *counts.entry(key).or_default() += 1;
+    }
+
+    let mut conflicts = counts
+        .into_iter()
+        .filter(|(_, count)| *count > 1)
+        .map(|(key, count)| format!("{key} ({count} times)"))
+        .collect::<Vec<_>>();
+    for reserved in [
+        BACKUP_ANNOTATION,
+        PROVIDER_ID_ANNOTATION,
+        STALE_ANNOTATION,
+        CONFIG_ANNOTATION,
+        CHECKSUM_ANNOTATION,
+    ] {
+        if renderer_keys(annotations).iter().any(|k| k == reserved) {
+            conflicts.push(format!("annotation '{reserved}' is reserved"));
+        }
+    }
+    if conflicts.is_empty() {
+        return Ok(());
+    }
+    Err(Error::Config(format!(
+        "conflicting keys: {}",
+        conflicts.join(", ")
+    )))
+}
+
+impl Controller {
+    pub fn builder() -> ControllerBuilder {
+        ControllerBuilder::default()
+    }
+
+    /// Runs the controller until shutdown is requested.
+    pub async fn run(self) -> Result<(), Error> {
+        run_controller(self).await
+    }
+
+    /// Builds the context for driving single reconciliations with
+    /// [`reconcile`], without watching nodes. Metrics are registered with the
+    /// controller's state as when running.
+    #[cfg(any(test, feature = "reconcile"))]
+    pub async fn context(self) -> Result<Arc<Ctx>, Error> {
+        self.split().await.map(|(ctx, _)| ctx)
+    }
+
+    /// Splits the configuration into the reconciliation context and the
+    /// settings for running the controller.
+    async fn split(self) -> Result<(Arc<Ctx>, Runtime), Error> {
+        let diagnostics = self.state.diagnostics.clone();
+        let mut metrics = Metrics::default();
+        if let Some(max_nodes) = self.metrics_max_nodes {
+            metrics = metrics.with_node_metrics(max_nodes);
+        }
+        if let Some(buckets) = self.reconcile_duration_buckets {
+            metrics = metrics.with_reconcile_duration_buckets(buckets)?;
+        }
+        #[cfg(feature = "metrics")]
+        let metrics = metrics.register(&self.state.registry)?;
+
+        let annotations = self.annotations;
+        let taints = self.taints;
+        let labels = default_labels(self.labels, &annotations, &taints, &self.sinks);
+        let taint_keys = taint_keys(taints.iter().flatten());
+
+        {
+            let mut diagnostics = diagnostics.write().await;
+            diagnostics.labels = renderer_strings(&labels);
+            diagnostics.annotations = renderer_strings(&annotations);
+        }
+
+        debug!({ labels = ?labels, annotation = ?annotations, taints = ?taints }, "config");
+        let config = renderer_strings(&labels)
+            .into_iter()
+            .chain(renderer_strings(&annotations))
+            .chain(taints.iter().flatten().map(ToString::to_string))
+            .collect::<Vec<_>>()
+            .join("\n");
+        let canary = self.canary.map(|options| Canary::new(options, &config));
+        let checksum = self
+            .checksum
+            .then(|| format!("{:016x}", stable_hash(&config)));
+        let config = canary
+            .as_ref()
+            .map(|canary| Arc::new(ConfigSink(canary.config().into())) as Arc<dyn Sink>);
+        let checksum_sink = checksum
+            .clone()
+            .map(|hash| Arc::new(ChecksumSink(hash)) as Arc<dyn Sink>);
+        let patch_rate = self.max_patch_rate.map(|max| {
+            metrics.observe_patch_rate(max);
+            AdaptiveRate::new(max)
+        });
+        let startup = self
+            .startup_patches_per_minute
+            .map(|per_minute| StartupThrottle::new(per_minute, self.state.nodes.clone()));
+        let provider_id = self
+            .track_provider_id
+            .then(|| Arc::new(ProviderIDSink) as Arc<dyn Sink>);
+        let backup = self
+            .backup_originals
+            .then(|| Arc::new(BackupSink) as Arc<dyn Sink>);
+        let history = self
+            .record_history
+            .then(|| Arc::new(HistorySink) as Arc<dyn Sink>);
+        let sinks = by_priority(builtin_sinks(labels, annotations, taints).chain(self.sinks))
+            .chain(provider_id)
+            .chain(checksum_sink)
+            .chain(config)
+            .chain(backup)
+            .chain(history)
+            .collect();
+
+        let ctx = Ctx {
+            client: self.client,
+            sinks,
+            requeue_duration: self.requeue_duration,
+            metrics,
+            diagnostics,
+            enrichers: self.enrichers,
+            enrichment_timeout: self.enrichment_timeout,
+            label_machines: self.label_machines,
+            conflict_retries: self.conflict_retries,
+            exporter: self.exporter.map(Arc::new),
+            sources: self.sources,
+            transforms: self.transforms,
+            maps: self.maps,
+            hooks: self.hooks,
+            provider_id_template: self.provider_id_template,
+            topology_fallback: self.topology_fallback,
+            track_provider_id: self.track_provider_id,
+            stale_policy: self.stale_policy,
+            require_node_ready: self.require_node_ready,
+            min_node_age: self.min_node_age,
+            skip_unschedulable: self.skip_unschedulable,
+            report_only: self.report_only,
+            checksum,
+            resyncing: Mutex::default(),
+            taint_keys,
+            canary,
+            change_windows: self.change_windows,
+            now: OffsetDateTime::now_utc,
+            startup,
+            patch_rate,
+            breaker: self.quarantine.map(CircuitBreaker::new),
+        };
+        let runtime = Runtime {
+            shutdown: self.shutdown,
+            nodes: self.state.nodes.clone(),
+            watch_timeout: self.watch_timeout,
+            streaming_list: self.streaming_list,
+            watcher_backoff: self.watcher_backoff,
+            poll_interval: self.poll_interval,
+            resync_schedule: self.resync_schedule,
+            strip_cached_nodes: self.strip_cached_nodes,
+            node_selector: self.node_selector,
+            drain_timeout: self.drain_timeout,
+            readiness_interval: self.readiness_interval,
+            max_controller_errors: self.max_controller_errors,
+            controller_error_window: self.controller_error_window,
+        };
+
+        Ok((Arc::new(ctx), runtime))
+    }
+}
+
+/// How the node watch backs off reconnecting after errors: exponentially from
+/// `initial` up to `max`, with jitter, starting over from `initial` once no
+/// error occurred for `reset`. Defaults to client-go's reflector settings.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct WatcherBackoff {
+    pub initial: Duration,
+    pub max: Duration,
+    pub reset: Duration,
+}
+
+impl Default for WatcherBackoff {
+    fn default() -> Self {
+        Self {
+            initial: Duration::from_millis(800),
+            max: Duration::from_secs(30),
+            reset: Duration::from_secs(120),
+        }
+    }
+}
+
+impl WatcherBackoff {
+    fn validate(&self) -> Result<(), Error> {
+        if self.initial.is_zero() || self.initial > self.max {
+            return Err(Error::Config(format!(
+                "watcher backoff must start above 0 and at most at its maximum, got {:?} and {:?}",
+                self.initial, self.max
+            )));
+        }
+        Ok(())
+    }
+
+    fn strategy(&self) -> ResetTimerBackoff<ExponentialBackoff> {
+        ResetTimerBackoff::new(
+            ExponentialBackoff {
+                initial_interval: self.initial,
+                current_interval: self.initial,
+                max_interval: self.max,
+                randomization_factor: 1.0,
+                multiplier: 2.0,
+                max_elapsed_time: None,
+                ..ExponentialBackoff::default()
+            },
+            self.reset,
+        )
+    }
+}
+
+/// The settings for running a [`Controller`] besides its [`Ctx`].
+struct Runtime {
+    shutdown: Shutdown,
+    nodes: Arc<OnceLock<Store<Node>>>,
+    watch_timeout: Option<u32>,
+    streaming_list: bool,
+    watcher_backoff: WatcherBackoff,
+    poll_interval: Option<Duration>,
+    resync_schedule: Option<Schedule>,
+    strip_cached_nodes: bool,
+    node_selector: Option<String>,
+    drain_timeout: Duration,
+    readiness_interval: Duration,
+    max_controller_errors: Option<usize>,
+    controller_error_window: Duration,
+}
+
+impl Runtime {
+    fn watcher_config(&self) -> watcher::Config {
+        let mut watcher_config = watcher::Config::default();
+        if let Some(timeout) = self.watch_timeout {
+            watcher_config = watcher_config.timeout(timeout);
+        }
+        if let Some(selector) = &self.node_selector {
+            watcher_config = watcher_config.labels(selector);
+        }
+        if self.streaming_list {
+            watcher_config = watcher_config.streaming_lists();
+        }
+        watcher_config
+    }
+}
+
+/// Lists the nodes every interval, each list restarting the stream as a
+/// relisting watcher would. A failed list is retried at the next interval.
+fn poll_nodes(
+    api: Api<Node>,
+    params: ListParams,
+    interval: Duration,
+) -> impl futures::Stream<Item = Result<watcher::Event<Node>, watcher::Error>> + Send {
+    let mut ticks = tokio::time::interval(interval);
+    ticks.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    futures::stream::unfold(
+        (api, params, ticks),
+        |(api, params, mut ticks)| async move {
+            ticks.tick().await;
+            let event = list_nodes(&api, &params)
+                .await
+                .map(watcher::Event::Restarted)
+                .map_err(watcher::Error::InitialListFailed);
+            Some((event, (api, params, ticks)))
+        },
+    )
+}
+
+/// Lists all nodes whenever the schedule fires, each list restarting the
+/// stream as a relisting watcher would, so every node is reconciled. A failed
+/// list is retried the next time.
+fn resync_nodes(
+    api: Api<Node>,
+    params: ListParams,
+    schedule: Schedule,
+    ctx: Arc<Ctx>,
+) -> impl futures::Stream<Item = Result<watcher::Event<Node>, watcher::Error>> + Send {
+    futures::stream::unfold(
+        (api, params, schedule, ctx),
+        |(api, params, schedule, ctx)| async move {
+            let Some(wait) = schedule.next_within(OffsetDateTime::now_utc(), RESYNC_HORIZON) else {
+                warn!(
+                    { schedule = schedule.to_string() },
+                    "resync schedule never fires"
+                );
+                return None;
+            };
+            tokio::time::sleep(wait).await;
+            info!("resyncing all nodes");
+            let event = list_nodes(&api, &params)
+                .await
+                .map(|nodes| {
+                    ctx.resync(&nodes);
+                    watcher::Event::Restarted(nodes)
+                })
+                .map_err(watcher::Error::InitialListFailed);
+            Some((event, (api, params, schedule, ctx)))
+        },
+    )
+}
+
+/// Calls `beat` every interval while the stream is polled, by merging ticks
+/// into it that are dropped again. Beats stop when the consumer stops polling.
+fn with_heartbeat<S>(
+    stream: S,
+    interval: Duration,
+    beat: impl Fn() + Send + 'static,
+) -> impl futures::Stream<Item = S::Item> + Send
+where
+    S: futures::Stream + Send,
+    S::Item: Send,
+{
+    let mut ticks = tokio::time::interval(interval);
+    ticks.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    let ticks = futures::stream::unfold(ticks, |mut ticks| async move {
+        ticks.tick().await;
+        Some((None, ticks))
+    });
+    futures::stream::select(stream.map(Some), ticks).filter_map(move |item| {
+        if item.is_none() {
+            beat();
+        }
+        futures::future::ready(item)
+    })
+}
+
+/// Lists all nodes, page by page.
+async fn list_nodes(api: &Api<Node>, params: &ListParams) -> Result<Vec<Node>, kube::Error> {
+    let mut params = params.clone().limit(POLL_PAGE_SIZE);
+    let mut nodes = vec![];
+    loop {
+        let list = api.list(&params).await?;
+        nodes.extend(list.items);
+        match list.metadata.continue_ {
+            Some(token) if !token.is_empty() => params = params.continue_token(&token),
+            _ => return Ok(nodes),
+        }
+    }
+}
+
+fn check_checksum(checksum: bool, exporter: bool) -> Result<(), Error> {
+    if checksum && exporter {
+        return Err(Error::Config(
+            "checksums skip rendering the values of nodes the exporter needs".into(),
+        ));
+    }
+    Ok(())
+}
+
+fn check_poll_interval(interval: Option<Duration>, streaming_list: bool) -> Result<(), Error> {
+    match interval {
+        Some(interval) if interval.is_zero() => {
+            Err(Error::Config("poll interval must be greater than 0".into()))
+        }
+        Some(_) if streaming_list => Err(Error::Config(
+            "streaming lists require watching nodes, not polling them".into(),
+        )),
+        _ => Ok(()),
+    }
+}
+
+/// Drops what the controller never reads from a cached node: the managed
+/// fields of other managers, and the images on the node.
+fn strip_cached_node(node: &mut Node) {
+    if let Some(fields) = node.metadata.managed_fields.as_mut() {
+        fields.retain(|f| f.manager.as_deref() == Some(MANAGER));
+    }
+    if let Some(status) = node.status.as_mut() {
+        status.images = None;
+    }
+}
+
+async fn run_controller(config: Controller) -> Result<(), Error> {
+    const QUEUE_ERROR: &str = "queue";
+    const RUNNER_ERROR: &str = "runner";
+    const RECONCILE_ERROR: &str = "reconcile";
+    const NOT_FOUND_ERROR: &str = "object_not_found";
+
+    let (ctx, config) = config.split().await?;
+    let watcher_config = config.watcher_config();
+    let shutdown = config.shutdown;
+    let diagnostics = ctx.diagnostics.clone();
+    let metrics = ctx.metrics.clone();
+    let node: Api<Node> = Api::all(ctx.client.clone());
+
+    let inc_error_count = |kind: &'static str, e: String| {
+        let diagnostics = diagnostics.clone();
+        async move {
+            let mut diagnostics = diagnostics.write().await;
+            diagnostics.error_count.refresh_and_push_back(1);
+            diagnostics.record_error(kind, e);
+        }
+    };
+
+    // queue and runner errors within the window, failing the controller once
+    // there are too many
+    let controller_errors = std::sync::Mutex::new(TtlQueue::new(config.controller_error_window));
+    let failed = tokio::sync::Notify::new();
+    let inc_controller_errors = || {
+        let count = controller_errors.lock().unwrap().refresh_and_push_back(());
+        if config.max_controller_errors.is_some_and(|max| count > max) {
+            failed.notify_one();
+        }
+    };
+
+    let exporter = ctx.exporter.clone();
+    let breaker_ctx = ctx.clone();
+    let export_task = exporter
+        .clone()
+        .map(|exporter| tokio::spawn(async move { exporter.run(EXPORT_INTERVAL).await }));
+
+    let readiness_task = tokio::spawn(diagnostics::check_api(
+        ctx.client.clone(),
+        diagnostics.clone(),
+        config.readiness_interval,
+    ));
+
+    info!("starting controller");
+    let (store, writer) = reflector::store();
+    let strip = config.strip_cached_nodes;
+    let mut params = ListParams::default();
+    if let Some(selector) = &config.node_selector {
+        params = params.labels(selector);
+    }
+    let mut events = match config.poll_interval {
+        Some(interval) => {
+            info!({ interval = ?interval }, "polling nodes instead of watching them");
+            poll_nodes(node.clone(), params.clone(), interval).boxed()
+        }
+        None => watcher(node.clone(), watcher_config)
+            .backoff(config.watcher_backoff.strategy())
+            .boxed(),
+    };
+    if let Some(schedule) = config.resync_schedule {
+        info!(
+            { schedule = schedule.to_string() },
+            "resyncing nodes on schedule"
+        );
+        let resyncs = resync_nodes(node, params, schedule, ctx.clone());
+        events = futures::stream::select(events, resyncs).boxed();
+    }
+    let nodes = events
+        .modify(move |node| {
+            if strip {
+                strip_cached_node(node);
+            }
+        })
+        .reflect(writer)
+        .applied_objects();
+    // the heartbeat rides along the node stream, so it stops when the
+    // controller stops pulling from it, e.g. with its reconciliations wedged,
+    // even when no node events arrive
+    let heartbeat_metrics = metrics.clone();
+    let nodes = with_heartbeat(nodes, HEARTBEAT_INTERVAL, move || {
+        heartbeat_metrics.observe_heartbeat()
+    });
+    let node_controller = runtime::Controller::for_stream(nodes, store)
+        .with_config(Config::default().concurrency(2))
+        .graceful_shutdown_on(shutdown.clone().requested());
+    // a restarted controller keeps serving the store it started with
+    let _ = config.nodes.set(node_controller.store());
+    let controller = node_controller
+        .run(reconcile_instrumented, error_policy, ctx)
+        .for_each(|res| async {
+            match res {
+                Ok(o) => {
+                    let node_name = o.0.clone().name;
+                    debug!({ node = node_name }, "reconciled");
+                }
+                Err(e) => match e {
+                    QueueError(e) => {
+                        error!("queue error: {e}");
+                        inc_error_count(QUEUE_ERROR, e.to_string()).await;
+                        inc_controller_errors();
+                        metrics.observe_controller_failure(QUEUE_ERROR);
+                    }
+                    RunnerError(e) => {
+                        error!("runner error: {e}");
+                        inc_error_count(RUNNER_ERROR, e.to_string()).await;
+                        inc_controller_errors();
+                        metrics.observe_controller_failure(RUNNER_ERROR);
+                    }
+                    ReconcilerFailed(e, o) => {
+                        error!({ node = o.name }, "reconciliation failed: {e}");
+                        diagnostics
+                            .write()
+                            .await
+                            .record_error(RECONCILE_ERROR, e.to_string());
+                        metrics.observe_reconciliation_failure(&o.name, &e);
+                    }
+                    ObjectNotFound(o) => {
+                        warn!({ node = o.name }, "object not found");
+                        diagnostics
+                            .write()
+                            .await
+                            .record_error(NOT_FOUND_ERROR, format!("node {} not found", o.name));
+                        metrics.observe_object_not_found_error();
+                        metrics.observe_missing_provider_id(&o.name, false);
+                        metrics.observe_node_provider(&o.name, None);
+                        metrics.observe_drift(&o.name, &[]);
+                        if let Some(breaker) = &breaker_ctx.breaker {
+                            breaker.remove(&o.name);
+                            metrics.observe_quarantined_nodes(breaker.len());
+                        }
+                        if let Some(exporter) = &exporter {
+                            exporter.remove(&o.name).await;
+                        }
+                    }
+                },
+            }
+        });
+
+    // on shutdown, the controller stops picking up new reconciliations and
+    // waits for in-flight ones, up to the drain timeout
+    let drain_deadline = async {
+        shutdown.requested().await;
+        info!({ timeout = ?config.drain_timeout }, "draining in-flight reconciliations");
+        tokio::time::sleep(config.drain_timeout).await;
+    };
+
+    let res = tokio::select! {
+        _ = controller => Ok(()),
+        _ = drain_deadline => {
+            warn!("drain timeout exceeded, abandoning in-flight reconciliations");
+            Ok(())
+        }
+        _ = failed.notified() => Err(Error::Controller(format!(
+            "more than {} queue and runner errors within {:?}",
+            config.max_controller_errors.unwrap_or_default(),
+            config.controller_error_window
+        ))),
+    };
+
+    readiness_task.abort();
+    if let (Some(task), Some(exporter)) = (export_task, &exporter) {
+        task.abort();
+        if let Err(e) = exporter.flush().await {
+            warn!({ error = e.to_string() }, "unable to export node mapping");
+        }
+    }
+
+    info!("stopping");
+
+    res
+}
+
+fn check_patch_rate(rate: Option<f64>) -> Result<(), Error> {
+    match rate {
+        Some(rate) if !(rate.is_finite() && rate > 0.0) => Err(Error::Config(format!(
+            "max patch rate must be greater than 0, got {rate}"
+        ))),
+        _ => Ok(()),
+    }
+}
+
+/// The source of the `{env:<name>}` tokens, capturing the variables the
+/// templates reference and the allowed ones. Fails if one isn't set.
+fn env_vars(
+    labels: &Option<Vec<Renderer<LabelTemplate>>>,
+    annotations: &Option<Vec<Renderer<AnnotationTemplate>>>,
+    taints: &Option<Vec<TaintRenderer>>,
+    provider_id_template: &Option<ProviderIDTemplate>,
+    allow: &[String],
+) -> Result<Arc<dyn ValueSource>, Error> {
+    let names = referenced_env(labels, annotations, taints)
+        .into_iter()
+        .chain(
+            provider_id_template
+                .iter()
+                .flat_map(|t| env_names(&t.tokens())),
+        )
+        .chain(allow.iter().cloned());
+    Ok(Arc::new(EnvVars::capture(names)?))
+}
+
+/// The environment variables the templates' `{env:<name>}` tokens reference.
+fn referenced_env(
+    labels: &Option<Vec<Renderer<LabelTemplate>>>,
+    annotations: &Option<Vec<Renderer<AnnotationTemplate>>>,
+    taints: &Option<Vec<TaintRenderer>>,
+) -> BTreeSet<String> {
+    env_names(&template_tokens(labels, annotations, taints))
+}
+
+/// The names of the lookup tables the templates' `|map(<name>)` filters use.
+fn referenced_maps(
+    labels: &Option<Vec<Renderer<LabelTemplate>>>,
+    annotations: &Option<Vec<Renderer<AnnotationTemplate>>>,
+    taints: &Option<Vec<TaintRenderer>>,
+) -> BTreeSet<String> {
+    template_tokens(labels, annotations, taints)
+        .into_iter()
+        .flat_map(|token| token.filters)
+        .filter_map(|filter| match filter {
+            Filter::Map(name) => Some(name),
+            _ => None,
+        })
+        .collect()
+}
+
+fn template_tokens(
+    labels: &Option<Vec<Renderer<LabelTemplate>>>,
+    annotations: &Option<Vec<Renderer<AnnotationTemplate>>>,
+    taints: &Option<Vec<TaintRenderer>>,
+) -> Vec<Token> {
+    (labels.iter().flatten().flat_map(|r| r.template().tokens()))
+        .chain(
+            annotations
+                .iter()
+                .flatten()
+                .flat_map(|r| r.template().tokens()),
+        )
+        .chain(taints.iter().flatten().flat_map(|t| t.template().tokens()))
+        .collect()
+}
+
+/// Fails on `|map(<name>)` filters without a lookup table, which would only
+/// fail once a node renders them.
+fn check_maps(referenced: &BTreeSet<String>, maps: &Maps) -> Result<(), Error> {
+    let unknown = referenced
+        .iter()
+        .filter(|name| !maps.contains_key(*name))
+        .map(|name| format!("'{name}'"))
+        .collect::<Vec<_>>();
+    if unknown.is_empty() {
+        return Ok(());
+    }
+    Err(Error::Config(format!(
+        "unknown maps: {}",
+        unknown.join(", ")
+    )))
+}
+
+fn renderer_keys<T>(renderers: &Option<Vec<Renderer<T>>>) -> Vec<String>
+where
+    T: std::fmt::Debug + std::default::Default + Template + std::str::FromStr,
+    Error: std::convert::From<<T as std::str::FromStr>::Err>,
+{
+    renderers.iter().flatten().map(Renderer::key).collect()
+}
+
+fn renderer_strings<T>(renderers: &Option<Vec<Renderer<T>>>) -> Vec<String>
+where
+    T: std::fmt::Debug + std::default::Default + Template + std::str::FromStr + std::fmt::Display,
+    Error: std::convert::From<<T as std::str::FromStr>::Err>,
+{
+    renderers
+        .iter()
+        .flatten()
+        .map(ToString::to_string)
+        .collect()
+}
+
+pub(crate) fn parse_renderers<T>(
+    args: Option<Vec<String>>,
+) -> Result<Option<Vec<Renderer<T>>>, Error>
+where
+    T: std::fmt::Debug + std::default::Default + Template + std::str::FromStr,
+    Error: std::convert::From<<T as std::str::FromStr>::Err>,
+{
+    args.map(|list| {
+        list.iter()
+            .map(|s| s.parse::<Renderer<T>>())
+            .collect::<Result<Vec<_>, _>>()
+    })
+    .transpose()
+}
+
+pub(crate) fn parse_taints(args: Option<Vec<String>>) -> Result<Option<Vec<TaintRenderer>>, Error> {
+    args.map(|list| list.iter().map(|s| s.parse()).collect())
+        .transpose()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+    use kube::core::ErrorResponse;
+
+    fn api_error(code: u16) -> kube::Error {
+        kube::Error::Api(ErrorResponse {
+            status: "Failure".into(),
+            message: "error".into(),
+            reason: "Testing".into(),
+            code,
+        })
+    }
+
+    #[tokio::test]
+    async fn test_reconcile() {
+        use kube::client::Body;
+
+        let (service, mut handle) =
+            tower_test::mock::pair::<http::Request<Body>, http::Response<Body>>();
+        let ctx = Controller::builder()
+            .client(Client::new(service, "default"))
+            .label("zone", "{:first}")
+            .build()
+            .unwrap()
+            .context()
+            .await
+            .unwrap();
+        let node = testing::node("my-node")
+            .provider_id("fake://region/instance")
+            .build();
+
+        let api_server = tokio::spawn(async move {
+            let (request, send) = handle.next_request().await.expect("a patch");
+            assert_eq!(request.method(), http::Method::PATCH);
+            assert_eq!(request.uri().path(), "/api/v1/nodes/my-node");
+            let body = request.into_body().collect_bytes().await.unwrap();
+            let payload: Node = serde_json::from_slice(&body).unwrap();
+            assert_eq!(
+                payload.metadata.labels,
+                Some([("zone".to_string(), "region".to_string())].into())
+            );
+            send.send_response(
+                http::Response::builder()
+                    .body(Body::from(body.to_vec()))
+                    .unwrap(),
+            );
+        });
+        assert_eq!(
+            reconcile(Arc::new(node), ctx.clone()).await.unwrap(),
+            Action::requeue(Duration::from_secs(3600))
+        );
+        api_server.await.unwrap();
+
+        // the mock is gone, so this fails if the unchanged node is patched
+        let node = testing::node("my-node")
+            .provider_id("fake://region/instance")
+            .label("zone", "region")
+            .build();
+        assert!(reconcile(Arc::new(node), ctx).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_reconcile_unset() {
+        use kube::client::Body;
+
+        let (service, mut handle) =
+            tower_test::mock::pair::<http::Request<Body>, http::Response<Body>>();
+        let ctx = Controller::builder()
+            .client(Client::new(service, "default"))
+            .label("zone", "{:first}")
+            .label("legacy", "")
+            .build()
+            .unwrap()
+            .context()
+            .await
+            .unwrap();
+        let node = testing::node("my-node")
+            .provider_id("fake://region/instance")
+            .label("zone", "region")
+            .label("legacy", "x")
+            .build();
+
+        let api_server = tokio::spawn(async move {
+            let (request, send) = handle.next_request().await.expect("a patch");
+            assert_eq!(
+                request.headers()[http::header::CONTENT_TYPE],
+                "application/apply-patch+yaml"
+            );
+            let body = request.into_body().collect_bytes().await.unwrap();
+            let payload: Node = serde_json::from_slice(&body).unwrap();
+            assert_eq!(
+                payload.metadata.labels,
+                Some([("zone".to_string(), "region".to_string())].into())
+            );
+            send.send_response(
+                http::Response::builder()
+                    .body(Body::from(body.to_vec()))
+                    .unwrap(),
+            );
+
+            let (request, send) = handle.next_request().await.expect("a patch");
+            assert_eq!(request.uri().path(), "/api/v1/nodes/my-node");
+            assert_eq!(
+                request.headers()[http::header::CONTENT_TYPE],
+                "application/merge-patch+json"
+            );
+            let body = request.into_body().collect_bytes().await.unwrap();
+            let payload: serde_json::Value = serde_json::from_slice(&body).unwrap();
+            assert_eq!(
+                payload,
+                serde_json::json!({ "metadata": { "labels": { "legacy": null }, "annotations": {} } })
+            );
+            let node = testing::node("my-node").build();
+            send.send_response(
+                http::Response::builder()
+                    .body(Body::from(serde_json::to_vec(&node).unwrap()))
+                    .unwrap(),
+            );
+        });
+        assert!(reconcile(Arc::new(node), ctx.clone()).await.is_ok());
+        api_server.await.unwrap();
+
+        // the mock is gone, so this fails if a node without the key is patched
+        let node = testing::node("my-node")
+            .provider_id("fake://region/instance")
+            .label("zone", "region")
+            .build();
+        assert!(reconcile(Arc::new(node), ctx).await.is_ok());
+    }
+
+    /// A context labeling "zone={:first}", configured further by `configure`,
+    /// whose API server is gone: reconciliations that send a request fail
+    /// with the error [`requested`] matches.
+    async fn ctx_without_api(
+        configure: impl FnOnce(ControllerBuilder) -> ControllerBuilder,
+    ) -> Arc<Ctx> {
+        use kube::client::Body;
+
+        let (service, handle) =
+            tower_test::mock::pair::<http::Request<Body>, http::Response<Body>>();
+        drop(handle);
+        let builder = Controller::builder()
+            .client(Client::new(service, "default"))
+            .label("zone", "{:first}");
+        configure(builder).build().unwrap().context().await.unwrap()
+    }
+
+    /// Whether the reconciliation failed sending a request to the API server
+    /// of a [`ctx_without_api`] context.
+    fn requested(res: Result<Action, Error>) -> bool {
+        matches!(res, Err(Error::Kube(kube::Error::Service(_))))
+    }
+
+    #[tokio::test]
+    async fn test_reconcile_require_node_ready() {
+        let ctx = ctx_without_api(|b| b.require_node_ready(true)).await;
+        let node = || testing::node("my-node").provider_id("fake://region/instance");
+        let deferred = Action::requeue(Duration::from_secs(3600));
+
+        assert_eq!(
+            reconcile(Arc::new(node().build()), ctx.clone())
+                .await
+                .unwrap(),
+            deferred
+        );
+        assert_eq!(
+            reconcile(Arc::new(node().ready(false).build()), ctx.clone())
+                .await
+                .unwrap(),
+            deferred
+        );
+        assert!(requested(
+            reconcile(Arc::new(node().ready(true).build()), ctx).await
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_reconcile_min_node_age() {
+        use k8s_openapi::{apimachinery::pkg::apis::meta::v1::Time, chrono};
+
+        let ctx = ctx_without_api(|b| b.min_node_age(Duration::from_secs(300))).await;
+        let node = |age: i64| {
+            let mut node = testing::node("my-node")
+                .provider_id("fake://region/instance")
+                .build();
+            node.metadata.creation_timestamp =
+                Some(Time(chrono::Utc::now() - chrono::Duration::seconds(age)));
+            node
+        };
+
+        // requeued once the node is old enough
+        let remaining = younger_by(&node(60), Duration::from_secs(300)).unwrap();
+        assert!(remaining <= Duration::from_secs(240) && remaining > Duration::from_secs(230));
+        assert_ne!(
+            reconcile(Arc::new(node(60)), ctx.clone()).await.unwrap(),
+            Action::requeue(Duration::from_secs(3600))
+        );
+        assert_eq!(younger_by(&node(600), Duration::from_secs(300)), None);
+        assert!(requested(reconcile(Arc::new(node(600)), ctx).await));
+    }
+
+    #[tokio::test]
+    async fn test_reconcile_skip_unschedulable() {
+        let ctx = ctx_without_api(|b| b.skip_unschedulable(true)).await;
+        let node = |unschedulable| {
+            let mut node = testing::node("my-node")
+                .provider_id("fake://region/instance")
+                .build();
+            node.spec.as_mut().unwrap().unschedulable = unschedulable;
+            node
+        };
+
+        assert_eq!(
+            reconcile(Arc::new(node(Some(true))), ctx.clone())
+                .await
+                .unwrap(),
+            Action::requeue(Duration::from_secs(3600))
+        );
+        assert!(requested(
+            reconcile(Arc::new(node(Some(false))), ctx.clone()).await
+        ));
+        assert!(requested(reconcile(Arc::new(node(None)), ctx).await));
+    }
+
+    #[tokio::test]
+    async fn test_reconcile_report_only() {
+        let ctx = ctx_without_api(|b| {
+            b.provider_id_template("metal://{:node}")
+                .stale_policy(StalePolicy::Remove)
+                .report_only(true)
+        })
+        .await;
+
+        let drifted = testing::node("my-node")
+            .provider_id("fake://region/instance")
+            .label("zone", "other-region")
+            .build();
+        assert_eq!(
+            reconcile(Arc::new(drifted), ctx.clone()).await.unwrap(),
+            Action::requeue(Duration::from_secs(3600))
+        );
+        let mut without_provider_id = testing::node("my-node").build();
+        without_provider_id.metadata.resource_version = Some("42".into());
+        assert_eq!(
+            reconcile(Arc::new(without_provider_id), ctx).await.unwrap(),
+            Action::requeue(Duration::from_secs(3600))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_reconcile_checksum() {
+        let ctx = ctx_without_api(|b| b.checksum(true)).await;
+        let hash = ctx.checksum.clone().unwrap();
+        let provider_id = ProviderID::new("my-node", "fake://region/instance").unwrap();
+        let node = |checksum: &str| {
+            testing::node("my-node")
+                .provider_id("fake://region/instance")
+                .label("zone", "other-region")
+                .annotation(CHECKSUM_ANNOTATION, checksum)
+                .build()
+        };
+
+        let matching = Arc::new(node(&checksum(&hash, &provider_id)));
+        assert!(reconcile(matching.clone(), ctx.clone()).await.is_ok());
+        // a resync renders the node once, past its checksum
+        ctx.resync(&[(*matching).clone()]);
+        assert!(requested(reconcile(matching.clone(), ctx.clone()).await));
+        assert!(reconcile(matching, ctx.clone()).await.is_ok());
+        let other_provider_id = ProviderID::new("my-node", "fake://region/other").unwrap();
+        let stale = node(&checksum(&hash, &other_provider_id));
+        assert!(requested(reconcile(Arc::new(stale), ctx).await));
+
+        assert!(check_checksum(true, true).is_err());
+        assert!(check_checksum(true, false).is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_reconcile_quarantine() {
+        let ctx = ctx_without_api(|b| {
+            b.quarantine(QuarantineOptions {
+                after_failures: 2,
+                duration: Duration::from_secs(600),
+            })
+        })
+        .await;
+        let node = Arc::new(
+            testing::node("my-node")
+                .provider_id("fake://region/instance")
+                .build(),
+        );
+
+        let e = reconcile(node.clone(), ctx.clone()).await.unwrap_err();
+        assert!(matches!(e, Error::Kube(kube::Error::Service(_))));
+        assert_eq!(
+            error_policy(node.clone(), &e, ctx.clone()),
+            Action::requeue(TRANSIENT_ERROR_BACKOFF)
+        );
+        let e = reconcile(node.clone(), ctx.clone()).await.unwrap_err();
+        assert_ne!(
+            error_policy(node.clone(), &e, ctx.clone()),
+            Action::requeue(TRANSIENT_ERROR_BACKOFF)
+        );
+        // quarantined nodes aren't reconciled
+        assert_ne!(
+            reconcile(node, ctx).await.unwrap(),
+            Action::requeue(Duration::from_secs(3600))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_reconcile_change_window() {
+        use time::format_description::well_known::Rfc3339;
+
+        let mut ctx = ctx_without_api(|b| {
+            b.change_window(ChangeWindow::new("30 1 * * *", Duration::from_secs(600)).unwrap())
+        })
+        .await;
+        let node = || {
+            Arc::new(
+                testing::node("my-node")
+                    .provider_id("fake://region/instance")
+                    .build(),
+            )
+        };
+
+        // requeued for when the window opens, without writing
+        Arc::get_mut(&mut ctx).unwrap().now =
+            || OffsetDateTime::parse("2024-06-03T01:00:00Z", &Rfc3339).unwrap();
+        assert_eq!(
+            reconcile(node(), ctx.clone()).await.unwrap(),
+            Action::requeue(Duration::from_secs(30 * 60))
+        );
+
+        // and written to while it's open
+        Arc::get_mut(&mut ctx).unwrap().now =
+            || OffsetDateTime::parse("2024-06-03T01:35:00Z", &Rfc3339).unwrap();
+        assert!(requested(reconcile(node(), ctx).await));
+    }
+
+    #[tokio::test]
+    async fn test_reconcile_topology_fallback() {
+        use kube::client::Body;
+
+        let (service, mut handle) =
+            tower_test::mock::pair::<http::Request<Body>, http::Response<Body>>();
+        let ctx = Controller::builder()
+            .client(Client::new(service, "default"))
+            .label("zone", "{:first|zone}")
+            .label("id", "{:last}")
+            .topology_fallback(true)
+            .build()
+            .unwrap()
+            .context()
+            .await
+            .unwrap();
+        let node = testing::node("my-node")
+            .label("topology.kubernetes.io/zone", "dc1-a")
+            .build();
+
+        let api_server = tokio::spawn(async move {
+            let (request, send) = handle.next_request().await.expect("a patch");
+            let body = request.into_body().collect_bytes().await.unwrap();
+            let payload: Node = serde_json::from_slice(&body).unwrap();
+            // the template without a fallback is skipped
+            assert_eq!(
+                payload.metadata.labels,
+                Some([("zone".to_string(), "dc1-a".to_string())].into())
+            );
+            send.send_response(
+                http::Response::builder()
+                    .body(Body::from(body.to_vec()))
+                    .unwrap(),
+            );
+        });
+        assert!(reconcile(Arc::new(node), ctx).await.is_ok());
+        api_server.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_reconcile_provider_id_change() {
+        use kube::client::Body;
+
+        let (service, mut handle) =
+            tower_test::mock::pair::<http::Request<Body>, http::Response<Body>>();
+        let ctx = Controller::builder()
+            .client(Client::new(service, "default"))
+            .label("zone", "{:first}")
+            .track_provider_id(true)
+            .build()
+            .unwrap()
+            .context()
+            .await
+            .unwrap();
+        // re-registered on new hardware under the same name
+        let node = testing::node("my-node")
+            .provider_id("fake://region/new-instance")
+            .label("zone", "old-region")
+            .annotation(PROVIDER_ID_ANNOTATION, "fake://old-region/instance")
+            .build();
+
+        let api_server = tokio::spawn(async move {
+            let (request, send) = handle.next_request().await.expect("an event");
+            assert!(request.uri().path().ends_with("/events"));
+            let body = request.into_body().collect_bytes().await.unwrap();
+            let event: serde_json::Value = serde_json::from_slice(&body).unwrap();
+            assert_eq!(event["reason"], "ProviderIDChanged");
+            assert_eq!(
+                event["note"],
+                "Provider ID changed from fake://old-region/instance to \
+                 fake://region/new-instance; replacing managed values"
+            );
+            send.send_response(
+                http::Response::builder()
+                    .body(Body::from(body.to_vec()))
+                    .unwrap(),
+            );
+
+            // the stale values and the recorded provider ID in one patch
+            let (request, send) = handle.next_request().await.expect("a patch");
+            let body = request.into_body().collect_bytes().await.unwrap();
+            let payload: Node = serde_json::from_slice(&body).unwrap();
+            assert_eq!(payload.labels()["zone"], "region");
+            assert_eq!(
+                payload.annotations()[PROVIDER_ID_ANNOTATION],
+                "fake://region/new-instance"
+            );
+            send.send_response(
+                http::Response::builder()
+                    .body(Body::from(body.to_vec()))
+                    .unwrap(),
+            );
+        });
+        assert!(reconcile(Arc::new(node), ctx).await.is_ok());
+        api_server.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_stale_policy() {
+        use k8s_openapi::apimachinery::pkg::apis::meta::v1::{FieldsV1, ManagedFieldsEntry};
+        use kube::client::Body;
+
+        let context = |policy| {
+            let (service, handle) =
+                tower_test::mock::pair::<http::Request<Body>, http::Response<Body>>();
+            let ctx = Controller::builder()
+                .client(Client::new(service, "default"))
+                .label("zone", "{:first}")
+                .stale_policy(policy)
+                .build()
+                .unwrap()
+                .context();
+            (ctx, handle)
+        };
+        let node = |provider_id: Option<&str>| {
+            let mut node = testing::node("my-node")
+                .label("zone", "region")
+                .label("team", "core");
+            if let Some(provider_id) = provider_id {
+                node = node.provider_id(provider_id);
+            }
+            let mut node = node.build();
+            node.metadata.managed_fields = Some(vec![ManagedFieldsEntry {
+                manager: Some(MANAGER.into()),
+                fields_v1: Some(FieldsV1(serde_json::json!({
+                    "f:metadata": { "f:labels": { "f:zone": {} } },
+                }))),
+                ..Default::default()
+            }]);
+            Arc::new(node)
+        };
+        let respond = |send: tower_test::mock::SendResponse<http::Response<Body>>,
+                       body: Vec<u8>| {
+            send.send_response(http::Response::builder().body(Body::from(body)).unwrap())
+        };
+
+        // the values are kept, marked as stale
+        let (ctx, mut handle) = context(StalePolicy::Mark);
+        let ctx = ctx.await.unwrap();
+        let api_server = tokio::spawn(async move {
+            let (request, send) = handle.next_request().await.expect("an event");
+            assert!(request.uri().path().ends_with("/events"));
+            respond(
+                send,
+                request.into_body().collect_bytes().await.unwrap().to_vec(),
+            );
+
+            let (request, send) = handle.next_request().await.expect("a patch");
+            let body = request.into_body().collect_bytes().await.unwrap();
+            let payload: Node = serde_json::from_slice(&body).unwrap();
+            assert_eq!(
+                payload.metadata.labels,
+                Some([("zone".to_string(), "region".to_string())].into())
+            );
+            assert_eq!(
+                payload.annotations()[STALE_ANNOTATION],
+                "missing-provider-id"
+            );
+            respond(send, body.to_vec());
+        });
+        assert!(reconcile(node(None), ctx).await.is_ok());
+        api_server.await.unwrap();
+
+        // the values are removed, and the reconciliation still fails
+        let (ctx, mut handle) = context(StalePolicy::Remove);
+        let ctx = ctx.await.unwrap();
+        let api_server = tokio::spawn(async move {
+            let (request, send) = handle.next_request().await.expect("a patch");
+            let body = request.into_body().collect_bytes().await.unwrap();
+            let payload: Node = serde_json::from_slice(&body).unwrap();
+            assert_eq!(payload.metadata.labels, None);
+            assert_eq!(payload.metadata.annotations, None);
+            respond(send, body.to_vec());
+        });
+        assert!(matches!(
+            reconcile(node(Some("not a provider id")), ctx).await,
+            Err(Error::ProviderID(_))
+        ));
+        api_server.await.unwrap();
+
+        // the mock is gone, so these fail if the nodes are patched
+        let (ctx, _) = context(StalePolicy::Keep);
+        assert!(reconcile(node(None), ctx.await.unwrap()).await.is_ok());
+        let (ctx, _) = context(StalePolicy::Remove);
+        let unmanaged = Arc::new(testing::node("my-node").build());
+        assert!(reconcile(unmanaged, ctx.await.unwrap()).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_reconcile_taints() {
+        use kube::client::Body;
+
+        let (service, mut handle) =
+            tower_test::mock::pair::<http::Request<Body>, http::Response<Body>>();
+        let ctx = Controller::builder()
+            .client(Client::new(service, "default"))
+            .label("zone", "{:first}")
+            .taint("dedicated", "{:last}", "NoSchedule")
+            .build()
+            .unwrap()
+            .context()
+            .await
+            .unwrap();
+        let mut node = testing::node("my-node")
+            .provider_id("fake://region/instance")
+            .label("zone", "region")
+            .taint("node-role.kubernetes.io/control-plane", "", "NoSchedule")
+            .build();
+        node.metadata.resource_version = Some("42".into());
+
+        let api_server = tokio::spawn(async move {
+            let (request, send) = handle.next_request().await.expect("a patch");
+            assert_eq!(
+                request.headers()[http::header::CONTENT_TYPE],
+                "application/json-patch+json"
+            );
+            let body = request.into_body().collect_bytes().await.unwrap();
+            let payload: serde_json::Value = serde_json::from_slice(&body).unwrap();
+            // only the rendered taint is added, guarded by the resourceVersion
+            assert_eq!(
+                payload,
+                serde_json::json!([
+                    { "op": "replace", "path": "/metadata/resourceVersion", "value": "42" },
+                    {
+                        "op": "add",
+                        "path": "/spec/taints/-",
+                        "value": { "key": "dedicated", "value": "instance", "effect": "NoSchedule" },
+                    },
+                ])
+            );
+            let node = testing::node("my-node").build();
+            send.send_response(
+                http::Response::builder()
+                    .body(Body::from(serde_json::to_vec(&node).unwrap()))
+                    .unwrap(),
+            );
+
+            let (request, send) = handle.next_request().await.expect("a patch");
+            let body = request.into_body().collect_bytes().await.unwrap();
+            let payload: Node = serde_json::from_slice(&body).unwrap();
+            // the taints aren't applied, so the controller doesn't own them
+            assert_eq!(payload.spec, None);
+            send.send_response(
+                http::Response::builder()
+                    .body(Body::from(body.to_vec()))
+                    .unwrap(),
+            );
+        });
+        assert!(reconcile(Arc::new(node), ctx).await.is_ok());
+        api_server.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_cleanup() {
+        use k8s_openapi::apimachinery::pkg::apis::meta::v1::{FieldsV1, ManagedFieldsEntry};
+        use kube::client::Body;
+
+        let (service, mut handle) =
+            tower_test::mock::pair::<http::Request<Body>, http::Response<Body>>();
+        let mut node = testing::node("my-node")
+            .label("zone", "region")
+            .taint("node-role.kubernetes.io/control-plane", "", "NoSchedule")
+            .taint("dedicated", "instance", "NoSchedule")
+            .taint("dedicated", "instance", "NoExecute")
+            .build();
+        node.metadata.resource_version = Some("42".into());
+        node.metadata.managed_fields = Some(vec![ManagedFieldsEntry {
+            manager: Some(MANAGER.into()),
+            fields_v1: Some(FieldsV1(serde_json::json!({
+                "f:metadata": { "f:labels": { "f:zone": {} } },
+            }))),
+            ..Default::default()
+        }]);
+
+        let api_server = tokio::spawn(async move {
+            let (request, send) = handle.next_request().await.expect("a list");
+            assert_eq!(request.method(), http::Method::GET);
+            let list = serde_json::json!({
+                "apiVersion": "v1",
+                "kind": "NodeList",
+                "metadata": {},
+                "items": [node],
+            });
+            send.send_response(
+                http::Response::builder()
+                    .body(Body::from(serde_json::to_vec(&list).unwrap()))
+                    .unwrap(),
+            );
+
+            // the foreign control plane taint, and the other effect, stay
+            let (request, send) = handle.next_request().await.expect("a patch");
+            assert_eq!(
+                request.headers()[http::header::CONTENT_TYPE],
+                "application/json-patch+json"
+            );
+            let body = request.into_body().collect_bytes().await.unwrap();
+            let payload: serde_json::Value = serde_json::from_slice(&body).unwrap();
+            assert_eq!(
+                payload,
+                serde_json::json!([
+                    { "op": "replace", "path": "/metadata/resourceVersion", "value": "42" },
+                    { "op": "remove", "path": "/spec/taints/1" },
+                ])
+            );
+            let node = testing::node("my-node").build();
+            send.send_response(
+                http::Response::builder()
+                    .body(Body::from(serde_json::to_vec(&node).unwrap()))
+                    .unwrap(),
+            );
+
+            let (request, send) = handle.next_request().await.expect("a patch");
+            assert_eq!(
+                request.headers()[http::header::CONTENT_TYPE],
+                "application/apply-patch+yaml"
+            );
+            let body = request.into_body().collect_bytes().await.unwrap();
+            let payload: Node = serde_json::from_slice(&body).unwrap();
+            assert_eq!(payload.spec, None);
+            send.send_response(
+                http::Response::builder()
+                    .body(Body::from(body.to_vec()))
+                    .unwrap(),
+            );
+        });
+        let taints = ["dedicated={:last}:NoSchedule".parse().unwrap()];
+        let client = Client::new(service, "default");
+        assert_eq!(cleanup(client, &taints, false).await.unwrap(), ["my-node"]);
+        api_server.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_error_policy() {
+        use kube::{client::Body, core::ErrorResponse};
+
+        let (service, mut handle) =
+            tower_test::mock::pair::<http::Request<Body>, http::Response<Body>>();
+        let ctx = Controller::builder()
+            .client(Client::new(service, "default"))
+            .build()
+            .unwrap()
+            .context()
+            .await
+            .unwrap();
+        let node = Arc::new(testing::node("my-node").build());
+        let api_error = |code| {
+            Error::Kube(kube::Error::Api(ErrorResponse {
+                status: "Failure".into(),
+                message: "invalid label value".into(),
+                reason: "Testing".into(),
+                code,
+            }))
+        };
+        let policy = |error: Error| error_policy(node.clone(), &error, ctx.clone());
+
+        assert_eq!(
+            policy(api_error(503)),
+            Action::requeue(TRANSIENT_ERROR_BACKOFF)
+        );
+        assert_eq!(
+            policy(api_error(409)),
+            Action::requeue(TRANSIENT_ERROR_BACKOFF)
+        );
+        assert_eq!(
+            policy(Error::Enrichment("timed out".into())),
+            Action::requeue(TRANSIENT_ERROR_BACKOFF)
+        );
+        assert_eq!(
+            policy(Error::MissingField("azure:sku".into())),
+            Action::requeue(Duration::from_secs(3600))
+        );
+        assert_eq!(
+            policy(api_error(403)),
+            Action::requeue(Duration::from_secs(3600))
+        );
+
+        // rejected patches wait for the node to change, with an event
+        assert_eq!(policy(api_error(422)), Action::await_change());
+        let (request, _) = handle.next_request().await.expect("an event");
+        assert_eq!(request.method(), http::Method::POST);
+        assert!(request.uri().path().ends_with("/events"));
+        let body = request.into_body().collect_bytes().await.unwrap();
+        let event: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(event["reason"], "PatchRejected");
+        assert_eq!(event["note"], "invalid label value");
+    }
+
+    #[tokio::test]
+    async fn test_assign_provider_id() {
+        use kube::client::Body;
+
+        let (service, mut handle) =
+            tower_test::mock::pair::<http::Request<Body>, http::Response<Body>>();
+        let ctx = Controller::builder()
+            .client(Client::new(service, "default"))
+            .provider_id_template("metal://{label:rack}/{:node}")
+            .build()
+            .unwrap()
+            .context()
+            .await
+            .unwrap();
+        let mut node = testing::node("my-node").label("rack", "r12").build();
+        node.metadata.resource_version = Some("42".into());
+
+        let api_server = tokio::spawn(async move {
+            let (request, send) = handle.next_request().await.expect("a patch");
+            assert_eq!(request.method(), http::Method::PATCH);
+            assert_eq!(
+                request.headers()[http::header::CONTENT_TYPE],
+                "application/merge-patch+json"
+            );
+            let body = request.into_body().collect_bytes().await.unwrap();
+            let payload: serde_json::Value = serde_json::from_slice(&body).unwrap();
+            assert_eq!(
+                payload,
+                serde_json::json!({
+                    "metadata": { "resourceVersion": "42" },
+                    "spec": { "providerID": "metal://r12/my-node" },
+                })
+            );
+            send.send_response(
+                http::Response::builder()
+                    .body(Body::from(body.to_vec()))
+                    .unwrap(),
+            );
+        });
+        assert!(reconcile(Arc::new(node), ctx.clone()).await.is_ok());
+        api_server.await.unwrap();
+
+        // a node the template can't render for isn't patched
+        let mut node = testing::node("other-node").build();
+        node.metadata.resource_version = Some("42".into());
+        assert!(matches!(
+            reconcile(Arc::new(node), ctx).await,
+            Err(Error::MissingField(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_watcher_config() {
+        use kube::client::Body;
+
+        let runtime = |builder: ControllerBuilder| async {
+            let (service, _) =
+                tower_test::mock::pair::<http::Request<Body>, http::Response<Body>>();
+            let (_, runtime) = builder
+                .client(Client::new(service, "default"))
+                .build()
+                .unwrap()
+                .split()
+                .await
+                .unwrap();
+            runtime.watcher_config()
+        };
+
+        let config = runtime(Controller::builder()).await;
+        assert_eq!(
+            config.initial_list_strategy,
+            watcher::InitialListStrategy::ListWatch
+        );
+        assert_eq!(config.label_selector, None);
+
+        let config = runtime(
+            Controller::builder()
+                .node_selector("node-role.kubernetes.io/worker")
+                .streaming_list(true),
+        )
+        .await;
+        assert_eq!(
+            config.initial_list_strategy,
+            watcher::InitialListStrategy::StreamingList
+        );
+        assert_eq!(
+            config.label_selector.as_deref(),
+            Some("node-role.kubernetes.io/worker")
+        );
+    }
+
+    #[test]
+    fn test_watcher_backoff() {
+        use backoff::backoff::Backoff;
+
+        let backoff = WatcherBackoff {
+            initial: Duration::from_secs(1),
+            max: Duration::from_secs(4),
+            reset: Duration::from_secs(60),
+        };
+        assert!(backoff.validate().is_ok());
+        let mut strategy = backoff.strategy();
+        // up to twice the interval with jitter, capped at the maximum
+        for _ in 0..10 {
+            assert!(strategy.next_backoff().unwrap() <= Duration::from_secs(8));
+        }
+
+        for invalid in [
+            WatcherBackoff {
+                initial: Duration::ZERO,
+                ..backoff
+            },
+            WatcherBackoff {
+                initial: Duration::from_secs(5),
+                ..backoff
+            },
+        ] {
+            assert!(invalid.validate().is_err());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_poll_nodes() {
+        use kube::client::Body;
+
+        let (service, mut handle) =
+            tower_test::mock::pair::<http::Request<Body>, http::Response<Body>>();
+        let api_server = tokio::spawn(async move {
+            for (page, token, node) in [(None, "next", "first"), (Some("next"), "", "second")] {
+                let (request, send) = handle.next_request().await.expect("a list");
+                let query = request.uri().query().unwrap_or_default().to_string();
+                assert!(query.contains("limit=500"), "{query}");
+                assert!(query.contains("labelSelector=role%3Dworker"), "{query}");
+                match page {
+                    Some(page) => assert!(query.contains(&format!("continue={page}")), "{query}"),
+                    None => assert!(!query.contains("continue"), "{query}"),
+                }
+                let list = serde_json::json!({
+                    "apiVersion": "v1",
+                    "kind": "NodeList",
+                    "metadata": { "continue": token },
+                    "items": [testing::node(node).build()],
+                });
+                send.send_response(
+                    http::Response::builder()
+                        .body(Body::from(serde_json::to_vec(&list).unwrap()))
+                        .unwrap(),
+                );
+            }
+        });
+
+        let api = Api::all(Client::new(service, "default"));
+        let params = ListParams::default().labels("role=worker");
+        let mut events = poll_nodes(api, params, Duration::from_secs(60)).boxed();
+        match events.next().await {
+            Some(Ok(watcher::Event::Restarted(nodes))) => assert_eq!(
+                nodes.iter().map(|n| n.name_any()).collect::<Vec<_>>(),
+                ["first", "second"]
+            ),
+            other => panic!("unexpected event: {other:?}"),
+        }
+        api_server.await.unwrap();
+
+        assert!(check_poll_interval(Some(Duration::from_secs(60)), false).is_ok());
+        assert!(check_poll_interval(Some(Duration::ZERO), false).is_err());
+        assert!(check_poll_interval(Some(Duration::from_secs(60)), true).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_heartbeat() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        let beats = Arc::new(AtomicUsize::new(0));
+        let counter = beats.clone();
+        let interval = Duration::from_millis(20);
+        let (tx, rx) = futures::channel::mpsc::unbounded::<&str>();
+        let mut events = with_heartbeat(rx, interval, move || {
+            counter.fetch_add(1, Ordering::SeqCst);
+        })
+        .boxed();
+
+        // beats while the stream is polled, even without events
+        tx.unbounded_send("node").unwrap();
+        assert_eq!(events.next().await, Some("node"));
+        let _ = tokio::time::timeout(interval * 5, events.next()).await;
+        let polled = beats.load(Ordering::SeqCst);
+        assert!(polled >= 3, "{polled} beats");
+
+        // a stalled consumer, e.g. a wedged controller, stops them
+        tokio::time::sleep(interval * 5).await;
+        assert_eq!(beats.load(Ordering::SeqCst), polled);
+        tx.unbounded_send("node").unwrap();
+        assert_eq!(events.next().await, Some("node"));
+    }
+
+    #[test]
+    fn test_strip_cached_node() {
+        use k8s_openapi::{
+            api::core::v1::{ContainerImage, NodeStatus},
+            apimachinery::pkg::apis::meta::v1::ManagedFieldsEntry,
+        };
+
+        let mut node = testing::node("my-node").build();
+        let entry = |manager: &str| ManagedFieldsEntry {
+            manager: Some(manager.into()),
+            ..Default::default()
+        };
+        node.metadata.managed_fields = Some(vec![entry("kubelet"), entry(MANAGER)]);
+        node.status = Some(NodeStatus {
+            images: Some(vec![ContainerImage::default()]),
+            ..Default::default()
+        });
+
+        strip_cached_node(&mut node);
+        assert_eq!(node.metadata.managed_fields, Some(vec![entry(MANAGER)]));
+        assert_eq!(node.status.unwrap().images, None);
+    }
+
+    #[tokio::test]
+    async fn test_retry_on_conflict() {
+        // succeeds after conflicts
+        let mut calls = 0;
+        let res = retry_on_conflict(3, Duration::ZERO, || {
+            calls += 1;
+            let res = if calls < 3 {
+                Err(api_error(409))
+            } else {
+                Ok(calls)
+            };
+            async move { res }
+        })
+        .await;
+        assert_eq!(res.unwrap(), 3);
+
+        // gives up after retries
+        let mut calls = 0;
+        let res: Result<(), _> = retry_on_conflict(2, Duration::ZERO, || {
+            calls += 1;
+            async { Err(api_error(409)) }
+        })
+        .await;
+        assert!(matches!(res, Err(Error::Kube(kube::Error::Api(e))) if e.code == 409));
+        assert_eq!(calls, 3);
+
+        // other errors are not retried
+        let mut calls = 0;
+        let res: Result<(), _> = retry_on_conflict(2, Duration::ZERO, || {
+            calls += 1;
+            async { Err(api_error(422)) }
+        })
+        .await;
+        assert!(res.is_err());
+        assert_eq!(calls, 1);
+    }
+
+    #[tokio::test]
+    async fn test_unknown_maps() {
+        use kube::client::Body;
+
+        let builder = || {
+            let (service, _) =
+                tower_test::mock::pair::<http::Request<Body>, http::Response<Body>>();
+            Controller::builder()
+                .client(Client::new(service, "default"))
+                .label("site", "{:first|map(regions)}")
+        };
+        assert!(matches!(
+            builder().build(),
+            Err(Error::Config(e)) if e == "unknown maps: 'regions'"
+        ));
+        assert!(builder().map("regions", [("region", "eu")]).build().is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_env_vars() {
+        use kube::client::Body;
+
+        let builder = || {
+            let (service, _) =
+                tower_test::mock::pair::<http::Request<Body>, http::Response<Body>>();
+            Controller::builder().client(Client::new(service, "default"))
+        };
+        // referenced and allowed variables must be set at startup
+        let missing = |res: Result<Controller, Error>| matches!(res, Err(Error::Config(e)) if e == "environment variable NPL_TEST_UNSET is not set");
+        assert!(missing(
+            builder().label("cluster", "{env:NPL_TEST_UNSET}").build()
+        ));
+        assert!(missing(
+            builder()
+                .taint("cluster", "{env:NPL_TEST_UNSET}", "NoSchedule")
+                .build()
+        ));
+        assert!(missing(
+            builder()
+                .label("zone", "{:first}")
+                .env_allow("NPL_TEST_UNSET")
+                .build()
+        ));
+        assert!(builder().label("path", "{env:PATH}").build().is_ok());
+
+        let templates = Templates::parse(None, Some(vec!["path={env:PATH}".into()]), None).unwrap();
+        let node = testing::node("my-node")
+            .provider_id("fake://region/instance")
+            .build();
+        let patch = templates.render(&node).unwrap();
+        assert_eq!(
+            patch.annotations.get("path"),
+            std::env::var("PATH").ok().as_ref()
+        );
+    }
+
+    #[test]
+    fn test_builder_validation() {
+        assert!(matches!(
+            Controller::builder().label("zone", "{:first}").build(),
+            Err(Error::Config(_))
+        ));
+
+        let builder = |p: &[(&str, &str)]| {
+            p.iter()
+                .fold(Controller::builder(), |b, (k, v)| b.label(k, v))
+                .labels
+        };
+        let labels = parse_renderers::<LabelTemplate>(Some(builder(&[
+            ("zone", "{:first}"),
+            ("id", "{:last}"),
+        ])))
+        .unwrap()
+        .unwrap();
+        assert_eq!(
+            labels.iter().map(ToString::to_string).collect::<Vec<_>>(),
+            vec!["zone={:first}", "id={:last}"]
+        );
+        let duplicates = parse_renderers::<LabelTemplate>(Some(builder(&[
+            ("zone", "{:first}"),
+            ("zone", "{:last}"),
+        ])))
+        .unwrap();
+        assert!(matches!(
+            check_duplicates(&duplicates, &None, &None),
+            Err(Error::Config(_))
+        ));
+        assert!(matches!(
+            parse_renderers::<LabelTemplate>(Some(builder(&[("-zone", "{:first}")]))),
+            Err(Error::MetadataKey(_))
+        ));
+        assert!(parse_renderers::<LabelTemplate>(Some(builder(&[("zone", "{:first")]))).is_err());
+
+        assert!(whole_seconds("requeue duration", Duration::from_millis(500)).is_err());
+        assert_eq!(
+            whole_seconds("requeue duration", Duration::from_secs(90)).unwrap(),
+            90
+        );
+    }
+
+    #[tokio::test]
+    async fn test_options() {
+        use kube::client::Body;
+
+        let (service, _handle) =
+            tower_test::mock::pair::<http::Request<Body>, http::Response<Body>>();
+        let options = || Options {
+            label_templates: Some(vec!["zone={:first}@selector=pool=a".into()]),
+            sources: vec![],
+            ..Options::new(Client::new(service.clone(), "default"))
+        };
+
+        let controller = ControllerBuilder::from(options()).build().unwrap();
+        assert_eq!(
+            renderer_strings(&controller.labels),
+            ["zone={:first}@selector=pool=a"]
+        );
+        assert_eq!(controller.requeue_duration, 3600);
+        // only the env source, the options replace the default sources
+        assert_eq!(controller.sources.len(), 1);
+
+        // run validates the options like the builder
+        let Err(Error::Config(message)) = run(Options {
+            node_selector: Some(" ".into()),
+            ..options()
+        })
+        .await
+        else {
+            panic!("expected a config error");
+        };
+        assert_eq!(message, "node selector must not be empty");
+        assert!(matches!(
+            ControllerBuilder::from(Options {
+                requeue_duration: 0,
+                ..options()
+            })
+            .build(),
+            Err(Error::Config(_))
+        ));
+    }
+
+    #[test]
+    fn test_check_duplicates() {
+        let templates = |labels: &[&str], annotations: &[&str], taints: &[&str]| {
+            let list = |l: &[&str]| Some(l.iter().map(|s| s.to_string()).collect::<Vec<_>>());
+            Templates::parse(list(labels), list(annotations), list(taints))
+        };
+
+        // every conflict is reported at once
+        let Err(Error::Config(message)) = templates(
+            &["zone={:first}", "id={:last}", "zone={:last}", "id"],
+            &["zone={:first}", BACKUP_ANNOTATION],
+            &["dedicated:NoSchedule", "dedicated={:node}:NoSchedule"],
+        ) else {
+            panic!("expected conflicts");
+        };
+        assert_eq!(
+            message,
+            "conflicting keys: label 'id' (2 times), label 'zone' (2 times), \
+             taint 'dedicated:NoSchedule' (2 times), \
+             annotation 'node-provider-labeler/originals' is reserved"
+        );
+
+        // labels and annotations may share keys, taints their key
+        assert!(templates(
+            &["zone={:first}"],
+            &["zone={:first}"],
+            &["zone:NoSchedule", "zone:NoExecute"]
+        )
+        .is_ok());
+    }
+
+    #[test]
+    fn test_sink_priority() {
+        #[derive(Debug)]
+        struct ZoneSink(&'static str, i32);
+
+        impl Sink for ZoneSink {
+            fn render(
+                &self,
+                target: Target<'_>,
+                _ctx: &RenderContext,
+                patch: &mut TargetPatch,
+            ) -> Result<(), Error> {
+                let new = MetadataPairs::from([("zone".to_string(), self.0.to_string())]);
+                let old = target.metadata().labels.clone().unwrap_or_default();
+                patch.changed += crate::sink::changed_keys(&new, &old);
+                patch.labels.extend(new);
+                Ok(())
+            }
+
+            fn priority(&self) -> i32 {
+                self.1
+            }
+        }
+
+        let node = testing::node("my-node")
+            .provider_id("fake://region/instance")
+            .label("zone", "region")
+            .build();
+        let provider_id = node_provider_id(&node).unwrap();
+        let render_ctx = RenderContext::new(&provider_id).with_metadata(&node.metadata);
+        let render = |sinks: Vec<Arc<dyn Sink>>| {
+            let labels = Some(vec!["zone={:first}".parse().unwrap()]);
+            let sinks =
+                by_priority(builtin_sinks(labels, None, None).chain(sinks)).collect::<Vec<_>>();
+            render_sinks(&sinks, Target::Node(&node), &render_ctx).unwrap()
+        };
+
+        // the higher priority wins, whatever the order
+        let patch = render(vec![
+            Arc::new(ZoneSink("high", 1)),
+            Arc::new(ZoneSink("low", -1)),
+        ]);
+        assert_eq!(patch.labels["zone"], "high");
+        assert_eq!(patch.conflicts, 2);
+        assert_eq!(patch.changed, 1);
+
+        // the label sink's unchanged value wins, and nothing is left to change
+        let patch = render(vec![Arc::new(ZoneSink("low", -1))]);
+        assert_eq!(patch.labels["zone"], "region");
+        assert_eq!(patch.conflicts, 1);
+        assert_eq!(patch.changed, 0);
+
+        // at equal priority, the later sink wins
+        let patch = render(vec![Arc::new(ZoneSink("a", 0)), Arc::new(ZoneSink("b", 0))]);
+        assert_eq!(patch.labels["zone"], "b");
     }
 }
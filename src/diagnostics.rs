@@ -1,19 +1,240 @@
-use std::time::Duration;
+use kube::Client;
+use serde::Serialize;
+use std::{collections::BTreeMap, sync::Arc, time::Duration};
 use time::OffsetDateTime;
+use tokio::sync::RwLock;
+use tracing::{info, warn};
 use ttl_queue::TtlQueue;
 
 #[derive(Debug)]
-pub(crate) struct Diagnostics {
+pub struct Diagnostics {
     pub error_count: TtlQueue<u64>,
+    /// Unhealthy once more than this many errors occurred within the window
+    pub error_threshold: usize,
+    /// Unhealthy once no reconcile event occurred for this long
+    pub max_staleness: Option<Duration>,
     pub last_event: OffsetDateTime,
+    /// Whether the last API server connectivity check succeeded
+    pub api_ready: bool,
+    /// The error of the last failed API server connectivity check
+    pub api_error: Option<String>,
+    /// The configured label renderers, as key=template
+    pub labels: Vec<String>,
+    /// The configured annotation renderers, as key=template
+    pub annotations: Vec<String>,
+    /// Errors since startup by type
+    pub errors: BTreeMap<&'static str, ErrorStats>,
 }
 
+#[derive(Clone, Debug, Serialize)]
+pub struct ErrorStats {
+    pub count: u64,
+    pub last_error: String,
+    #[serde(with = "time::serde::rfc3339")]
+    pub last_error_at: OffsetDateTime,
+}
+
+/// A point-in-time snapshot of the diagnostics, served on /diagnostics
+#[derive(Debug, Serialize)]
+pub struct Report {
+    #[serde(with = "time::serde::rfc3339")]
+    last_event: OffsetDateTime,
+    /// Errors within the health error window
+    error_count: usize,
+    labels: Vec<String>,
+    annotations: Vec<String>,
+    watch: WatchStatus,
+    errors: BTreeMap<&'static str, ErrorStats>,
+}
+
+#[derive(Debug, Serialize)]
+struct WatchStatus {
+    api_ready: bool,
+    api_error: Option<String>,
+}
+
+const DEFAULT_ERROR_WINDOW: Duration = Duration::from_secs(60);
+
 impl Default for Diagnostics {
     fn default() -> Self {
+        Self::new(DEFAULT_ERROR_WINDOW, 0)
+    }
+}
+
+impl Diagnostics {
+    /// Creates diagnostics that report unhealthy once more than
+    /// `error_threshold` errors occurred within `error_window`.
+    pub fn new(error_window: Duration, error_threshold: usize) -> Self {
         Self {
-            // TODO: configurable duration
-            error_count: TtlQueue::new(Duration::from_secs(60)),
+            error_count: TtlQueue::new(error_window),
+            error_threshold,
+            max_staleness: None,
             last_event: OffsetDateTime::now_utc(),
+            api_ready: false,
+            api_error: None,
+            labels: vec![],
+            annotations: vec![],
+            errors: BTreeMap::new(),
+        }
+    }
+
+    /// Also reports unhealthy once no reconcile event occurred for
+    /// `max_staleness`, e.g. because the controller stream died
+    pub fn with_max_staleness(mut self, max_staleness: Duration) -> Self {
+        self.max_staleness = Some(max_staleness);
+        self
+    }
+
+    pub fn healthy(&mut self) -> bool {
+        let stale = self
+            .max_staleness
+            .is_some_and(|max| OffsetDateTime::now_utc() - self.last_event > max);
+        self.error_count.refresh() <= self.error_threshold && !stale
+    }
+
+    pub(crate) fn record_error(&mut self, kind: &'static str, error: String) {
+        let now = OffsetDateTime::now_utc();
+        self.errors
+            .entry(kind)
+            .and_modify(|stats| {
+                stats.count += 1;
+                stats.last_error.clone_from(&error);
+                stats.last_error_at = now;
+            })
+            .or_insert_with(|| ErrorStats {
+                count: 1,
+                last_error: error.clone(),
+                last_error_at: now,
+            });
+    }
+
+    pub fn report(&mut self) -> Report {
+        Report {
+            last_event: self.last_event,
+            error_count: self.error_count.refresh(),
+            labels: self.labels.clone(),
+            annotations: self.annotations.clone(),
+            watch: WatchStatus {
+                api_ready: self.api_ready,
+                api_error: self.api_error.clone(),
+            },
+            errors: self.errors.clone(),
+        }
+    }
+
+    fn record_api_check(&mut self, result: Result<(), String>) {
+        match result {
+            Ok(()) => {
+                if !self.api_ready {
+                    info!("api server is reachable");
+                }
+                self.api_ready = true;
+                self.api_error = None;
+            }
+            Err(e) => {
+                if self.api_ready {
+                    warn!({ error = e }, "api server is unreachable");
+                }
+                self.api_ready = false;
+                self.api_error = Some(e);
+            }
         }
     }
 }
+
+/// Checks API server connectivity every interval with a lightweight version
+/// request and records the result for the readiness check.
+pub(crate) async fn check_api(
+    client: Client,
+    diagnostics: Arc<RwLock<Diagnostics>>,
+    interval: Duration,
+) {
+    let mut ticker = tokio::time::interval(interval);
+    loop {
+        ticker.tick().await;
+        let result = match tokio::time::timeout(interval, client.apiserver_version()).await {
+            Ok(Ok(_)) => Ok(()),
+            Ok(Err(e)) => Err(e.to_string()),
+            Err(_) => Err(format!("no response within {interval:?}")),
+        };
+        diagnostics.write().await.record_api_check(result);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_record_api_check() {
+        let mut diagnostics = Diagnostics::default();
+        assert!(!diagnostics.api_ready);
+
+        diagnostics.record_api_check(Ok(()));
+        assert!(diagnostics.api_ready);
+
+        diagnostics.record_api_check(Err("connection refused".into()));
+        assert!(!diagnostics.api_ready);
+        assert_eq!(diagnostics.api_error.as_deref(), Some("connection refused"));
+
+        diagnostics.record_api_check(Ok(()));
+        assert!(diagnostics.api_ready);
+        assert!(diagnostics.api_error.is_none());
+    }
+
+    #[test]
+    fn test_report() {
+        let mut diagnostics = Diagnostics {
+            labels: vec!["provider-id={:last}".into()],
+            ..Default::default()
+        };
+        diagnostics.record_error("queue", "watch failed".into());
+        diagnostics.record_error("queue", "watch failed again".into());
+        diagnostics.record_error("reconcile", "patch failed".into());
+        diagnostics.error_count.refresh_and_push_back(1);
+
+        let report = serde_json::to_value(diagnostics.report()).unwrap();
+        assert_eq!(report["error_count"], 1);
+        assert_eq!(report["labels"][0], "provider-id={:last}");
+        assert_eq!(report["watch"]["api_ready"], false);
+        assert_eq!(report["errors"]["queue"]["count"], 2);
+        assert_eq!(
+            report["errors"]["queue"]["last_error"],
+            "watch failed again"
+        );
+        assert_eq!(report["errors"]["reconcile"]["count"], 1);
+        assert!(report["last_event"].as_str().unwrap().ends_with('Z'));
+    }
+
+    #[test]
+    fn test_healthy() {
+        let mut diagnostics = Diagnostics::default();
+        assert!(diagnostics.healthy());
+        diagnostics.error_count.refresh_and_push_back(1);
+        assert!(!diagnostics.healthy());
+
+        let mut diagnostics = Diagnostics::new(Duration::from_secs(60), 2);
+        diagnostics.error_count.refresh_and_push_back(1);
+        diagnostics.error_count.refresh_and_push_back(1);
+        assert!(diagnostics.healthy());
+        diagnostics.error_count.refresh_and_push_back(1);
+        assert!(!diagnostics.healthy());
+
+        let mut diagnostics = Diagnostics::new(Duration::from_millis(1), 0);
+        diagnostics.error_count.refresh_and_push_back(1);
+        std::thread::sleep(Duration::from_millis(5));
+        assert!(diagnostics.healthy());
+    }
+
+    #[test]
+    fn test_healthy_staleness() {
+        let mut diagnostics = Diagnostics::default().with_max_staleness(Duration::from_secs(3600));
+        assert!(diagnostics.healthy());
+
+        diagnostics.last_event = OffsetDateTime::now_utc() - Duration::from_secs(3601);
+        assert!(!diagnostics.healthy());
+
+        diagnostics.last_event = OffsetDateTime::now_utc();
+        assert!(diagnostics.healthy());
+    }
+}
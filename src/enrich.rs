@@ -0,0 +1,153 @@
+use crate::{provider_id::ProviderID, template::Fields, Error};
+use async_trait::async_trait;
+use k8s_openapi::api::core::v1::Node;
+use std::{
+    collections::HashMap,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tokio::sync::RwLock;
+use tracing::debug;
+
+/// Contributes `{<namespace>:<key>}` fields for a node before rendering, e.g.
+/// from a cloud API or an IPAM system.
+///
+/// The controller calls every enricher for each reconciliation, within the
+/// enrichment timeout. Wrap slow or rate-limited enrichers in
+/// [`CachedEnricher`].
+#[async_trait]
+pub trait Enricher: std::fmt::Debug + Send + Sync {
+    /// Identifies the enricher in logs and errors.
+    fn name(&self) -> &str;
+
+    /// Returns the fields for the node, keyed by "<namespace>:<key>".
+    async fn fields(&self, node: &Node, provider_id: &ProviderID) -> Result<Fields, Error>;
+}
+
+/// Caches the fields an enricher returns for each provider ID. Failures are
+/// not cached.
+#[derive(Debug)]
+pub struct CachedEnricher {
+    inner: Arc<dyn Enricher>,
+    ttl: Duration,
+    cache: RwLock<HashMap<String, (Instant, Fields)>>,
+}
+
+impl CachedEnricher {
+    pub fn new(inner: impl Enricher + 'static, ttl: Duration) -> Self {
+        Self {
+            inner: Arc::new(inner),
+            ttl,
+            cache: RwLock::new(HashMap::new()),
+        }
+    }
+}
+
+#[async_trait]
+impl Enricher for CachedEnricher {
+    fn name(&self) -> &str {
+        self.inner.name()
+    }
+
+    async fn fields(&self, node: &Node, provider_id: &ProviderID) -> Result<Fields, Error> {
+        let key = provider_id.to_string();
+        if let Some((fetched, fields)) = self.cache.read().await.get(&key) {
+            if fetched.elapsed() < self.ttl {
+                return Ok(fields.clone());
+            }
+        }
+
+        let fields = self.inner.fields(node, provider_id).await?;
+        self.cache
+            .write()
+            .await
+            .insert(key, (Instant::now(), fields.clone()));
+
+        Ok(fields)
+    }
+}
+
+/// Runs the enrichers in order, merging their fields. Later enrichers
+/// override earlier ones for the same field.
+pub(crate) async fn enrich(
+    enrichers: &[Arc<dyn Enricher>],
+    node: &Node,
+    provider_id: &ProviderID,
+    timeout: Duration,
+) -> Result<Fields, Error> {
+    let mut fields = Fields::new();
+    for enricher in enrichers {
+        debug!({ enricher = enricher.name() }, "enriching");
+        let res = tokio::time::timeout(timeout, enricher.fields(node, provider_id))
+            .await
+            .map_err(|_| {
+                Error::Enrichment(format!("{} timed out after {timeout:?}", enricher.name()))
+            })?;
+        fields.extend(res?);
+    }
+
+    Ok(fields)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[derive(Debug, Default)]
+    struct Counting {
+        calls: Arc<AtomicUsize>,
+        delay: Duration,
+    }
+
+    #[async_trait]
+    impl Enricher for Counting {
+        fn name(&self) -> &str {
+            "counting"
+        }
+
+        async fn fields(&self, node: &Node, _provider_id: &ProviderID) -> Result<Fields, Error> {
+            tokio::time::sleep(self.delay).await;
+            let calls = self.calls.fetch_add(1, Ordering::SeqCst) + 1;
+            Ok([
+                ("counting:calls".into(), calls.to_string()),
+                (
+                    "counting:node".into(),
+                    node.metadata.name.clone().unwrap_or_default(),
+                ),
+            ]
+            .into())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_enrich() {
+        let node = Node::default();
+        let provider_id = ProviderID::new("my-node", "fake://region/instance").unwrap();
+
+        let calls = Arc::new(AtomicUsize::new(0));
+        let cached: Vec<Arc<dyn Enricher>> = vec![Arc::new(CachedEnricher::new(
+            Counting {
+                calls: calls.clone(),
+                ..Default::default()
+            },
+            Duration::from_secs(60),
+        ))];
+        for _ in 0..3 {
+            let fields = enrich(&cached, &node, &provider_id, Duration::from_secs(1))
+                .await
+                .unwrap();
+            assert_eq!(fields.get("counting:calls").unwrap(), "1");
+        }
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+
+        let slow: Arc<dyn Enricher> = Arc::new(Counting {
+            delay: Duration::from_secs(5),
+            ..Default::default()
+        });
+        assert!(matches!(
+            enrich(&[slow], &node, &provider_id, Duration::from_millis(10)).await,
+            Err(Error::Enrichment(_))
+        ));
+    }
+}
@@ -0,0 +1,275 @@
+use crate::{provider_id::ProviderID, Error};
+use k8s_openapi::api::core::v1::{ConfigMap, Node};
+use kube::{
+    api::{ObjectMeta, Patch, PatchParams},
+    Api, Client, ResourceExt,
+};
+use serde::Serialize;
+use std::{
+    collections::BTreeMap,
+    sync::atomic::{AtomicBool, Ordering},
+    time::Duration,
+};
+use tokio::sync::RwLock;
+use tracing::{debug, warn};
+
+const MANAGER: &str = "node-provider-labeler";
+const DATA_KEY: &str = "nodes.json";
+
+/// The metadata values the controller manages on a node
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct NodeValues {
+    pub labels: BTreeMap<String, String>,
+    pub annotations: BTreeMap<String, String>,
+}
+
+impl NodeValues {
+    /// The values currently applied to the node by the controller, according
+    /// to the node's managed fields.
+    pub fn managed(node: &Node) -> Self {
+        let values = |current: Option<&BTreeMap<String, String>>, keys: Vec<String>| {
+            keys.into_iter()
+                .filter_map(|k| Some((k.clone(), current?.get(&k)?.clone())))
+                .collect()
+        };
+
+        Self {
+            labels: values(
+                node.metadata.labels.as_ref(),
+                managed_keys(&node.metadata, "f:labels"),
+            ),
+            annotations: values(
+                node.metadata.annotations.as_ref(),
+                managed_keys(&node.metadata, "f:annotations"),
+            ),
+        }
+    }
+}
+
+/// The metadata keys of `kind` ("f:labels" or "f:annotations") the
+/// controller owns, according to the object's managed fields.
+pub(crate) fn managed_keys(metadata: &ObjectMeta, kind: &str) -> Vec<String> {
+    metadata
+        .managed_fields
+        .iter()
+        .flatten()
+        .filter(|f| f.manager.as_deref() == Some(MANAGER))
+        .filter_map(|f| {
+            f.fields_v1
+                .as_ref()?
+                .0
+                .get("f:metadata")?
+                .get(kind)?
+                .as_object()
+        })
+        .flat_map(|fields| fields.keys())
+        .filter_map(|k| k.strip_prefix("f:"))
+        .map(String::from)
+        .collect()
+}
+
+/// A node's provider ID breakdown and the values the controller manages on
+/// it, for inventory tooling.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct NodeInfo {
+    pub name: String,
+    pub provider_id: Option<String>,
+    /// The provider, if the provider ID is valid
+    pub provider: Option<String>,
+    /// The parts of the provider ID's node ID, as used by `{<n>}` tokens
+    pub parts: Vec<String>,
+    pub labels: BTreeMap<String, String>,
+    pub annotations: BTreeMap<String, String>,
+}
+
+impl From<&Node> for NodeInfo {
+    fn from(node: &Node) -> Self {
+        let name = node.name_any();
+        let provider_id = node.spec.as_ref().and_then(|s| s.provider_id.clone());
+        let parsed = provider_id
+            .as_deref()
+            .and_then(|id| ProviderID::new(&name, id).ok());
+        let values = NodeValues::managed(node);
+
+        Self {
+            provider: parsed.as_ref().map(ProviderID::provider),
+            parts: parsed.map(|p| p.parts().to_vec()).unwrap_or_default(),
+            name,
+            provider_id,
+            labels: values.labels,
+            annotations: values.annotations,
+        }
+    }
+}
+
+/// Maintains a ConfigMap with the current node to rendered values mapping as
+/// JSON, for tooling that doesn't want to list and parse all nodes.
+#[derive(Debug)]
+pub struct Exporter {
+    api: Api<ConfigMap>,
+    name: String,
+    mapping: RwLock<BTreeMap<String, NodeValues>>,
+    dirty: AtomicBool,
+}
+
+impl Exporter {
+    /// Creates an exporter for the named ConfigMap, in the client's default
+    /// namespace unless `namespace` is given.
+    pub fn new(client: Client, namespace: Option<String>, name: String) -> Self {
+        let api = match namespace {
+            Some(ns) => Api::namespaced(client, &ns),
+            None => Api::default_namespaced(client),
+        };
+
+        Self {
+            api,
+            name,
+            mapping: RwLock::new(BTreeMap::new()),
+            dirty: AtomicBool::new(false),
+        }
+    }
+
+    pub(crate) async fn update(&self, node: &str, values: NodeValues) {
+        let mut mapping = self.mapping.write().await;
+        if mapping.get(node) != Some(&values) {
+            mapping.insert(node.to_string(), values);
+            self.dirty.store(true, Ordering::Relaxed);
+        }
+    }
+
+    pub(crate) async fn remove(&self, node: &str) {
+        if self.mapping.write().await.remove(node).is_some() {
+            self.dirty.store(true, Ordering::Relaxed);
+        }
+    }
+
+    /// Writes the ConfigMap whenever the mapping has changed, at most once per
+    /// interval.
+    pub(crate) async fn run(&self, interval: Duration) {
+        let mut interval = tokio::time::interval(interval);
+        loop {
+            interval.tick().await;
+            if let Err(e) = self.flush().await {
+                warn!({ error = e.to_string() }, "unable to export node mapping");
+            }
+        }
+    }
+
+    pub(crate) async fn flush(&self) -> Result<(), Error> {
+        if !self.dirty.swap(false, Ordering::Relaxed) {
+            return Ok(());
+        }
+
+        let result = self.write().await;
+        if result.is_err() {
+            // try again next time
+            self.dirty.store(true, Ordering::Relaxed);
+        }
+        result
+    }
+
+    async fn write(&self) -> Result<(), Error> {
+        let data = serde_json::to_string(&*self.mapping.read().await)?;
+
+        debug!({ configmap = self.name }, "exporting node mapping");
+        let cm = ConfigMap {
+            metadata: ObjectMeta {
+                name: Some(self.name.clone()),
+                ..Default::default()
+            },
+            data: Some(BTreeMap::from([(DATA_KEY.to_string(), data)])),
+            ..Default::default()
+        };
+        self.api
+            .patch(
+                &self.name,
+                &PatchParams::apply(MANAGER).force(),
+                &Patch::Apply(&cm),
+            )
+            .await?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn exporter() -> Exporter {
+        let config = kube::Config::new("http://localhost:6443".parse().unwrap());
+        let client = Client::try_from(config).unwrap();
+        Exporter::new(client, Some("default".into()), "node-mapping".into())
+    }
+
+    #[tokio::test]
+    async fn test_exporter_mapping() {
+        let exporter = exporter();
+        assert!(!exporter.dirty.load(Ordering::Relaxed));
+
+        let values = NodeValues {
+            labels: BTreeMap::from([("provider-id".to_string(), "instance".to_string())]),
+            annotations: BTreeMap::new(),
+        };
+
+        exporter.update("my-node-name", values.clone()).await;
+        assert!(exporter.dirty.swap(false, Ordering::Relaxed));
+
+        // unchanged values don't trigger a write
+        exporter.update("my-node-name", values.clone()).await;
+        assert!(!exporter.dirty.load(Ordering::Relaxed));
+
+        assert_eq!(
+            serde_json::to_string(&*exporter.mapping.read().await).unwrap(),
+            r#"{"my-node-name":{"labels":{"provider-id":"instance"},"annotations":{}}}"#
+        );
+
+        exporter.remove("other-node-name").await;
+        assert!(!exporter.dirty.load(Ordering::Relaxed));
+        exporter.remove("my-node-name").await;
+        assert!(exporter.dirty.load(Ordering::Relaxed));
+        assert!(exporter.mapping.read().await.is_empty());
+    }
+
+    #[test]
+    fn test_managed_values() {
+        use k8s_openapi::apimachinery::pkg::apis::meta::v1::{FieldsV1, ManagedFieldsEntry};
+
+        let entry = |manager: &str, fields: serde_json::Value| ManagedFieldsEntry {
+            manager: Some(manager.into()),
+            operation: Some("Apply".into()),
+            fields_v1: Some(FieldsV1(fields)),
+            ..Default::default()
+        };
+        let mut node = crate::testing::node("my-node")
+            .label("provider-id", "instance")
+            .label("zone", "region")
+            .annotation("url", "fake://region/instance")
+            .build();
+        node.metadata.managed_fields = Some(vec![
+            entry(
+                MANAGER,
+                serde_json::json!({"f:metadata": {
+                    "f:labels": {"f:provider-id": {}, "f:removed": {}},
+                    "f:annotations": {"f:url": {}},
+                }}),
+            ),
+            entry(
+                "kubelet",
+                serde_json::json!({"f:metadata": {"f:labels": {"f:zone": {}}}}),
+            ),
+        ]);
+
+        assert_eq!(
+            NodeValues::managed(&node),
+            NodeValues {
+                labels: BTreeMap::from([("provider-id".into(), "instance".into())]),
+                annotations: BTreeMap::from([("url".into(), "fake://region/instance".into())]),
+            }
+        );
+        assert_eq!(
+            NodeValues::managed(&crate::testing::node("unmanaged").build()),
+            NodeValues::default()
+        );
+    }
+}
@@ -0,0 +1,92 @@
+//! Case-style conversions for the `|kebab`, `|snake`, and `|camel` template
+//! filters.
+
+/// Splits the value into lowercase words at separators, i.e. anything but
+/// ASCII letters and digits, and where the case changes, e.g.
+/// "myResourceGroup" and "HTTPServer". Digits stay with the word before them.
+fn words(s: &str) -> Vec<String> {
+    let mut words = vec![];
+    for part in s.split(|c: char| !c.is_ascii_alphanumeric()) {
+        let chars = part.chars().collect::<Vec<_>>();
+        let mut word = String::new();
+        for (i, c) in chars.iter().enumerate() {
+            let prev = i.checked_sub(1).map(|i| chars[i]);
+            let next = chars.get(i + 1);
+            let boundary = c.is_ascii_uppercase()
+                && prev.is_some_and(|p| {
+                    p.is_ascii_lowercase()
+                        || p.is_ascii_digit()
+                        || p.is_ascii_uppercase() && next.is_some_and(|n| n.is_ascii_lowercase())
+                });
+            if boundary && !word.is_empty() {
+                words.push(std::mem::take(&mut word));
+            }
+            word.push(c.to_ascii_lowercase());
+        }
+        if !word.is_empty() {
+            words.push(word);
+        }
+    }
+    words
+}
+
+/// "Standard_D2s_v3" becomes "standard-d2s-v3".
+pub(crate) fn kebab(s: &str) -> String {
+    words(s).join("-")
+}
+
+/// "Standard_D2s_v3" becomes "standard_d2s_v3".
+pub(crate) fn snake(s: &str) -> String {
+    words(s).join("_")
+}
+
+/// "Standard_D2s_v3" becomes "standardD2sV3".
+pub(crate) fn camel(s: &str) -> String {
+    words(s)
+        .iter()
+        .enumerate()
+        .map(|(i, word)| {
+            let mut chars = word.chars();
+            match chars.next() {
+                Some(first) if i > 0 => first.to_ascii_uppercase().to_string() + chars.as_str(),
+                _ => word.clone(),
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_case_filters() {
+        for (input, kebab_case, snake_case, camel_case) in [
+            (
+                "Standard_D2s_v3",
+                "standard-d2s-v3",
+                "standard_d2s_v3",
+                "standardD2sV3",
+            ),
+            (
+                "myResourceGroup",
+                "my-resource-group",
+                "my_resource_group",
+                "myResourceGroup",
+            ),
+            ("HTTPServer", "http-server", "http_server", "httpServer"),
+            ("us-east-2a", "us-east-2a", "us_east_2a", "usEast2a"),
+            (
+                "team.core  Platform",
+                "team-core-platform",
+                "team_core_platform",
+                "teamCorePlatform",
+            ),
+            ("", "", "", ""),
+        ] {
+            assert_eq!(kebab(input), kebab_case, "{input}");
+            assert_eq!(snake(input), snake_case, "{input}");
+            assert_eq!(camel(input), camel_case, "{input}");
+        }
+    }
+}
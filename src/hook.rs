@@ -0,0 +1,235 @@
+use crate::{
+    sink::{MetadataPairs, TargetPatch},
+    Error,
+};
+use async_trait::async_trait;
+use k8s_openapi::api::core::v1::{Node, Taint};
+use serde::Serialize;
+use std::sync::Arc;
+use tracing::{debug, warn};
+
+/// A label or annotation a patch sets.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Change {
+    pub key: String,
+    /// The current value, or `None` if the key is new
+    pub old: Option<String>,
+    pub new: String,
+}
+
+/// The taint list before and after a patch.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct TaintChange {
+    pub old: Vec<Taint>,
+    pub new: Vec<Taint>,
+}
+
+/// What a node patch changes, as passed to [`PatchHook`]s. Values the patch
+/// leaves as they are aren't included.
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct PatchDiff {
+    pub node: String,
+    pub labels: Vec<Change>,
+    pub annotations: Vec<Change>,
+    /// The labels the patch removes
+    pub removed_labels: Vec<String>,
+    /// The annotations the patch removes
+    pub removed_annotations: Vec<String>,
+    /// The taints, if the patch changes them
+    pub taints: Option<TaintChange>,
+}
+
+impl PatchDiff {
+    pub fn new(node: &Node, patch: &TargetPatch) -> Self {
+        let old_taints = node
+            .spec
+            .as_ref()
+            .and_then(|spec| spec.taints.clone())
+            .unwrap_or_default();
+
+        Self {
+            node: node.metadata.name.clone().unwrap_or_default(),
+            labels: changes(node.metadata.labels.as_ref(), &patch.labels),
+            annotations: changes(node.metadata.annotations.as_ref(), &patch.annotations),
+            removed_labels: patch.unset_labels.iter().cloned().collect(),
+            removed_annotations: patch.unset_annotations.iter().cloned().collect(),
+            taints: patch
+                .taints
+                .as_ref()
+                .filter(|new| **new != old_taints)
+                .map(|new| TaintChange {
+                    old: old_taints,
+                    new: new.clone(),
+                }),
+        }
+    }
+}
+
+/// The keys of `new` whose value differs from `current`.
+fn changes(current: Option<&MetadataPairs>, new: &MetadataPairs) -> Vec<Change> {
+    new.iter()
+        .filter_map(|(key, value)| {
+            let old = current.and_then(|c| c.get(key));
+            (old != Some(value)).then(|| Change {
+                key: key.clone(),
+                old: old.cloned(),
+                new: value.clone(),
+            })
+        })
+        .collect()
+}
+
+/// Runs before and after each node patch, e.g. to validate or gate changes,
+/// or to invalidate an external cache.
+#[async_trait]
+pub trait PatchHook: std::fmt::Debug + Send + Sync {
+    /// Identifies the hook in logs and errors.
+    fn name(&self) -> &str;
+
+    /// Runs before the patch is applied. An error vetoes the patch and fails
+    /// the reconciliation, which is retried.
+    async fn before_patch(&self, _diff: &PatchDiff) -> Result<(), Error> {
+        Ok(())
+    }
+
+    /// Runs after the patch was applied. Errors are logged.
+    async fn after_patch(&self, _diff: &PatchDiff) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+/// Runs the hooks' [`PatchHook::before_patch`] in order, stopping at the
+/// first veto.
+pub(crate) async fn before(hooks: &[Arc<dyn PatchHook>], diff: &PatchDiff) -> Result<(), Error> {
+    for hook in hooks {
+        debug!({ hook = hook.name(), node = diff.node }, "running before patch hook");
+        hook.before_patch(diff)
+            .await
+            .map_err(|e| Error::Hook(format!("{} vetoed the patch: {e}", hook.name())))?;
+    }
+    Ok(())
+}
+
+/// Runs every hook's [`PatchHook::after_patch`], logging failures.
+pub(crate) async fn after(hooks: &[Arc<dyn PatchHook>], diff: &PatchDiff) {
+    for hook in hooks {
+        debug!({ hook = hook.name(), node = diff.node }, "running after patch hook");
+        if let Err(e) = hook.after_patch(diff).await {
+            warn!({ hook = hook.name(), node = diff.node, error = e.to_string() }, "after patch hook failed");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{self, taint};
+    use std::sync::Mutex;
+
+    #[derive(Debug, Default)]
+    struct Recorder {
+        veto: bool,
+        calls: Mutex<Vec<&'static str>>,
+    }
+
+    #[async_trait]
+    impl PatchHook for Recorder {
+        fn name(&self) -> &str {
+            "recorder"
+        }
+
+        async fn before_patch(&self, _diff: &PatchDiff) -> Result<(), Error> {
+            self.calls.lock().unwrap().push("before");
+            if self.veto {
+                return Err(Error::Config("not approved".into()));
+            }
+            Ok(())
+        }
+
+        async fn after_patch(&self, _diff: &PatchDiff) -> Result<(), Error> {
+            self.calls.lock().unwrap().push("after");
+            Err(Error::Config("ignored".into()))
+        }
+    }
+
+    #[test]
+    fn test_patch_diff() {
+        let node = testing::node("my-node")
+            .provider_id("fake://region/instance")
+            .label("zone", "region")
+            .label("id", "old")
+            .taint("other", "x", "NoSchedule")
+            .build();
+        let patch = testing::Pipeline::new()
+            .label("zone", "{:first}")
+            .label("id", "{:last}")
+            .annotation("url", "{:url}")
+            .render(&node)
+            .unwrap();
+
+        let diff = PatchDiff::new(&node, &patch);
+        assert_eq!(diff.node, "my-node");
+        assert_eq!(
+            diff.labels,
+            vec![Change {
+                key: "id".into(),
+                old: Some("old".into()),
+                new: "instance".into(),
+            }]
+        );
+        assert_eq!(
+            diff.annotations,
+            vec![Change {
+                key: "url".into(),
+                old: None,
+                new: "fake://region/instance".into(),
+            }]
+        );
+        assert_eq!(diff.taints, None);
+        assert!(diff.removed_labels.is_empty());
+
+        let patch = testing::Pipeline::new()
+            .label("id", "")
+            .label("missing", "")
+            .render(&node)
+            .unwrap();
+        assert_eq!(PatchDiff::new(&node, &patch).removed_labels, ["id"]);
+
+        let patch = testing::Pipeline::new()
+            .taint("dedicated", "{:last}", "NoSchedule")
+            .render(&node)
+            .unwrap();
+        assert_eq!(
+            PatchDiff::new(&node, &patch).taints,
+            Some(TaintChange {
+                old: vec![taint("other", "x", "NoSchedule")],
+                new: vec![
+                    taint("other", "x", "NoSchedule"),
+                    taint("dedicated", "instance", "NoSchedule"),
+                ],
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn test_hooks() {
+        let diff = PatchDiff::default();
+        let approve = Arc::new(Recorder::default());
+        let veto = Arc::new(Recorder {
+            veto: true,
+            ..Default::default()
+        });
+
+        let hooks: Vec<Arc<dyn PatchHook>> = vec![approve.clone(), veto.clone(), approve.clone()];
+        assert!(matches!(before(&hooks, &diff).await, Err(Error::Hook(_))));
+        assert_eq!(*approve.calls.lock().unwrap(), vec!["before"]);
+
+        // failures after the patch don't stop the other hooks
+        after(&hooks, &diff).await;
+        assert_eq!(*veto.calls.lock().unwrap(), vec!["before", "after"]);
+        assert_eq!(
+            *approve.calls.lock().unwrap(),
+            vec!["before", "after", "after"]
+        );
+    }
+}
@@ -0,0 +1,416 @@
+use async_trait::async_trait;
+use hmac::{Hmac, Mac};
+use node_provider_labeler::{
+    hook::{PatchDiff, PatchHook},
+    Error,
+};
+use serde_json::json;
+use sha2::Sha256;
+use std::{process::Stdio, time::Duration};
+use time::{format_description::well_known::Rfc3339, OffsetDateTime};
+use tokio::{io::AsyncWriteExt, process::Command, sync::mpsc};
+use tracing::{debug, warn};
+
+type HmacSha256 = Hmac<Sha256>;
+
+const BEFORE: &str = "before";
+const AFTER: &str = "after";
+
+/// Runs a program before and after each node patch, with the phase as its
+/// argument and the diff as JSON on stdin. Exiting non-zero before the patch
+/// vetoes it.
+#[derive(Debug)]
+pub(crate) struct ExecHook {
+    program: String,
+    timeout: Duration,
+}
+
+impl ExecHook {
+    pub(crate) fn new(program: String, timeout: Duration) -> Self {
+        Self { program, timeout }
+    }
+
+    async fn exec(&self, phase: &str, diff: &PatchDiff) -> Result<(), Error> {
+        let input = serde_json::to_vec(diff)?;
+        let run = async {
+            let mut child = Command::new(&self.program)
+                .arg(phase)
+                .stdin(Stdio::piped())
+                .stdout(Stdio::null())
+                .kill_on_drop(true)
+                .spawn()?;
+            if let Some(mut stdin) = child.stdin.take() {
+                // the program may exit without reading its input
+                match stdin.write_all(&input).await {
+                    Err(e) if e.kind() != std::io::ErrorKind::BrokenPipe => return Err(e),
+                    _ => (),
+                }
+            }
+            child.wait().await
+        };
+
+        let status = tokio::time::timeout(self.timeout, run)
+            .await
+            .map_err(|_| Error::Hook(format!("{} timed out", self.program)))?
+            .map_err(|e| Error::Hook(format!("unable to run {}: {e}", self.program)))?;
+        if !status.success() {
+            return Err(Error::Hook(format!(
+                "{} exited with {status}",
+                self.program
+            )));
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl PatchHook for ExecHook {
+    fn name(&self) -> &str {
+        "exec"
+    }
+
+    async fn before_patch(&self, diff: &PatchDiff) -> Result<(), Error> {
+        self.exec(BEFORE, diff).await
+    }
+
+    async fn after_patch(&self, diff: &PatchDiff) -> Result<(), Error> {
+        self.exec(AFTER, diff).await
+    }
+}
+
+/// Posts `{"phase": ..., "diff": ...}` to a URL before and after each node
+/// patch. A non-success response before the patch vetoes it.
+#[derive(Debug)]
+pub(crate) struct WebhookHook {
+    client: reqwest::Client,
+    url: String,
+}
+
+impl WebhookHook {
+    pub(crate) fn new(url: String, timeout: Duration) -> Result<Self, Error> {
+        let client = reqwest::Client::builder()
+            .timeout(timeout)
+            .build()
+            .map_err(|e| Error::Config(format!("invalid hook url '{url}': {e}")))?;
+        Ok(Self { client, url })
+    }
+
+    async fn post(&self, phase: &str, diff: &PatchDiff) -> Result<(), Error> {
+        self.client
+            .post(&self.url)
+            .json(&json!({ "phase": phase, "diff": diff }))
+            .send()
+            .await
+            .and_then(|res| res.error_for_status())
+            .map_err(|e| Error::Hook(e.to_string()))?;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl PatchHook for WebhookHook {
+    fn name(&self) -> &str {
+        "webhook"
+    }
+
+    async fn before_patch(&self, diff: &PatchDiff) -> Result<(), Error> {
+        self.post(BEFORE, diff).await
+    }
+
+    async fn after_patch(&self, diff: &PatchDiff) -> Result<(), Error> {
+        self.post(AFTER, diff).await
+    }
+}
+
+/// The header carrying the payload's HMAC-SHA256 signature, as
+/// "sha256=<hex>", when a secret is configured.
+const SIGNATURE_HEADER: &str = "x-npl-signature";
+
+/// How many notifications may wait for delivery before new ones are dropped.
+const NOTIFY_QUEUE: usize = 1024;
+
+/// Posts `{"time": ..., "diff": ...}` to a URL after each node patch, so
+/// inventory systems can follow changes. Notifications are queued and
+/// delivered in the background, so reconciliations never wait for them;
+/// failed deliveries are retried with exponential backoff, and dropped when
+/// the queue is full.
+#[derive(Debug)]
+pub(crate) struct NotifyHook {
+    queue: mpsc::Sender<Notification>,
+}
+
+#[derive(Debug)]
+struct Notification {
+    node: String,
+    body: Vec<u8>,
+}
+
+impl NotifyHook {
+    /// Starts delivering notifications on a background task.
+    pub(crate) fn new(
+        url: String,
+        secret: Option<&[u8]>,
+        retries: u32,
+        timeout: Duration,
+    ) -> Result<Self, Error> {
+        Ok(Self::start(
+            Notifier::new(url, secret, retries, timeout)?,
+            NOTIFY_QUEUE,
+        ))
+    }
+
+    fn start(notifier: Notifier, capacity: usize) -> Self {
+        let (queue, notifications) = mpsc::channel(capacity);
+        tokio::spawn(notifier.run(notifications));
+        Self { queue }
+    }
+}
+
+#[async_trait]
+impl PatchHook for NotifyHook {
+    fn name(&self) -> &str {
+        "notify"
+    }
+
+    async fn after_patch(&self, diff: &PatchDiff) -> Result<(), Error> {
+        let time = OffsetDateTime::now_utc()
+            .format(&Rfc3339)
+            .unwrap_or_default();
+        let body = serde_json::to_vec(&json!({ "time": time, "diff": diff }))?;
+        let notification = Notification {
+            node: diff.node.clone(),
+            body,
+        };
+        self.queue
+            .try_send(notification)
+            .map_err(|e| Error::Hook(format!("notification dropped: {e}")))
+    }
+}
+
+/// Delivers queued notifications one at a time.
+#[derive(Debug)]
+struct Notifier {
+    client: reqwest::Client,
+    url: String,
+    secret: Option<HmacSha256>,
+    retries: u32,
+    backoff: Duration,
+}
+
+impl Notifier {
+    fn new(
+        url: String,
+        secret: Option<&[u8]>,
+        retries: u32,
+        timeout: Duration,
+    ) -> Result<Self, Error> {
+        let client = reqwest::Client::builder()
+            .timeout(timeout)
+            .build()
+            .map_err(|e| Error::Config(format!("invalid notify url '{url}': {e}")))?;
+        let secret = secret
+            .map(HmacSha256::new_from_slice)
+            .transpose()
+            .map_err(|e| Error::Config(format!("invalid notify secret: {e}")))?;
+        Ok(Self {
+            client,
+            url,
+            secret,
+            retries,
+            backoff: Duration::from_secs(1),
+        })
+    }
+
+    async fn run(self, mut notifications: mpsc::Receiver<Notification>) {
+        while let Some(notification) = notifications.recv().await {
+            if let Err(e) = self.deliver(&notification).await {
+                warn!({ node = notification.node, error = e.to_string() }, "unable to deliver notification");
+            }
+        }
+    }
+
+    async fn deliver(&self, notification: &Notification) -> Result<(), Error> {
+        let mut attempt = 0;
+        loop {
+            match self.send(&notification.body).await {
+                Ok(()) => return Ok(()),
+                // client errors won't go away on their own
+                Err(e) if e.status().is_some_and(|s| s.is_client_error()) => {
+                    return Err(Error::Hook(e.to_string()))
+                }
+                Err(e) if attempt >= self.retries => return Err(Error::Hook(e.to_string())),
+                Err(e) => {
+                    let delay = self.backoff * 2u32.saturating_pow(attempt);
+                    debug!({ node = notification.node, error = e.to_string(), attempt }, "retrying notification in {delay:?}");
+                    tokio::time::sleep(delay).await;
+                    attempt += 1;
+                }
+            }
+        }
+    }
+
+    async fn send(&self, body: &[u8]) -> Result<(), reqwest::Error> {
+        let mut request = self
+            .client
+            .post(&self.url)
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .body(body.to_vec());
+        if let Some(key) = &self.secret {
+            request = request.header(SIGNATURE_HEADER, signature(key, body));
+        }
+        request.send().await?.error_for_status()?;
+        Ok(())
+    }
+}
+
+/// The HMAC-SHA256 of the body, as "sha256=<hex>".
+fn signature(key: &HmacSha256, body: &[u8]) -> String {
+    let mut mac = key.clone();
+    mac.update(body);
+    let hex: String = mac
+        .finalize()
+        .into_bytes()
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect();
+    format!("sha256={hex}")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::PermissionsExt;
+
+    #[tokio::test]
+    async fn test_exec_hook() {
+        let diff = PatchDiff::default();
+        let hook = |program: &str| ExecHook::new(program.into(), Duration::from_secs(5));
+
+        assert!(hook("true").before_patch(&diff).await.is_ok());
+
+        // the phase is the argument and the diff is on stdin
+        let script = std::env::temp_dir().join(format!("npl-hook-{}", std::process::id()));
+        std::fs::write(
+            &script,
+            "#!/bin/sh\ntest \"$1\" = after && grep -q '\"node\"'\n",
+        )
+        .unwrap();
+        std::fs::set_permissions(&script, std::fs::Permissions::from_mode(0o755)).unwrap();
+        let script_hook = hook(script.to_str().unwrap());
+        assert!(script_hook.after_patch(&diff).await.is_ok());
+        assert!(script_hook.before_patch(&diff).await.is_err());
+        std::fs::remove_file(&script).unwrap();
+
+        assert!(matches!(
+            hook("false").before_patch(&diff).await,
+            Err(Error::Hook(_))
+        ));
+        assert!(matches!(
+            hook("/nonexistent/hook").before_patch(&diff).await,
+            Err(Error::Hook(_))
+        ));
+    }
+
+    #[test]
+    fn test_signature() {
+        // RFC 4231 test case 2
+        let key = HmacSha256::new_from_slice(b"Jefe").unwrap();
+        assert_eq!(
+            signature(&key, b"what do ya want for nothing?"),
+            "sha256=5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_notify_hook() {
+        use axum::{extract, http::StatusCode, routing::post, Router};
+        use std::sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        };
+
+        // fails once, then checks the signature
+        let calls = Arc::new(AtomicUsize::new(0));
+        let key = HmacSha256::new_from_slice(b"s3cret").unwrap();
+        let app = Router::new().route(
+            "/",
+            post({
+                let calls = calls.clone();
+                move |headers: axum::http::HeaderMap, body: extract::Request| async move {
+                    let body = axum::body::to_bytes(body.into_body(), usize::MAX)
+                        .await
+                        .unwrap();
+                    if calls.fetch_add(1, Ordering::SeqCst) == 0 {
+                        return StatusCode::SERVICE_UNAVAILABLE;
+                    }
+                    if headers[SIGNATURE_HEADER] != signature(&key, &body) {
+                        return StatusCode::UNAUTHORIZED;
+                    }
+                    StatusCode::OK
+                }
+            }),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        let notifier = |secret: &[u8], retries| {
+            let mut notifier =
+                Notifier::new(url.clone(), Some(secret), retries, Duration::from_secs(5)).unwrap();
+            notifier.backoff = Duration::from_millis(1);
+            notifier
+        };
+        let notification = Notification {
+            node: "my-node".into(),
+            body: b"{}".to_vec(),
+        };
+        assert!(notifier(b"s3cret", 1).deliver(&notification).await.is_ok());
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+
+        // a rejected signature isn't retried
+        assert!(notifier(b"wrong", 3).deliver(&notification).await.is_err());
+        assert_eq!(calls.load(Ordering::SeqCst), 3);
+
+        // the hook never vetoes a patch, and delivers in the background
+        let hook = NotifyHook::start(notifier(b"s3cret", 0), 1);
+        let diff = PatchDiff::default();
+        assert!(hook.before_patch(&diff).await.is_ok());
+        assert!(hook.after_patch(&diff).await.is_ok());
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while calls.load(Ordering::SeqCst) < 4 {
+                tokio::time::sleep(Duration::from_millis(5)).await;
+            }
+        })
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_notify_hook_queue() {
+        use axum::{routing::post, Router};
+
+        // an endpoint that never answers
+        let app = Router::new().route("/", post(std::future::pending::<()>));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        // the patch doesn't wait for the delivery, and once the queue is full,
+        // notifications are dropped rather than waited for
+        let notifier = Notifier::new(url, None, 0, Duration::from_secs(60)).unwrap();
+        let hook = NotifyHook::start(notifier, 1);
+        let diff = PatchDiff::default();
+        let res = tokio::time::timeout(Duration::from_secs(1), async {
+            let mut results = vec![];
+            for _ in 0..3 {
+                results.push(hook.after_patch(&diff).await);
+            }
+            results
+        })
+        .await
+        .unwrap();
+        // the worker holds one, the queue another
+        assert!(res[0].is_ok());
+        assert!(res.iter().any(|r| matches!(r, Err(Error::Hook(_)))));
+    }
+}
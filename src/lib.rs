@@ -1,12 +1,53 @@
 use thiserror::Error;
 
+pub mod ast;
+#[cfg(feature = "azure")]
+pub mod azure;
+pub mod backup;
+pub mod breaker;
+pub mod canary;
+mod capi;
+#[cfg(feature = "cli")]
+pub mod config;
+pub mod controller;
+pub mod diagnostics;
+pub mod enrich;
+pub mod export;
+mod filter;
+pub mod hook;
+pub mod lifecycle;
+mod meta;
+#[cfg(feature = "metrics")]
+pub mod metrics;
+#[cfg(not(feature = "metrics"))]
+#[path = "metrics_disabled.rs"]
+mod metrics;
+pub mod pacing;
 pub mod provider_id;
+pub mod renderer;
+pub mod selector;
+pub mod shutdown;
+pub mod sink;
+pub mod source;
+pub mod startup;
 pub mod template;
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
+pub mod transform;
+#[cfg(feature = "wasm")]
+pub mod wasm;
+pub mod window;
+
+pub use controller::{run, Controller, ControllerBuilder, Ctx, Options, State};
 
 #[derive(Error, Debug)]
 pub enum Error {
     #[error("kube error: {0}")]
     Kube(#[from] kube::Error),
+    #[error("KubeconfigError: {0}")]
+    Kubeconfig(#[from] kube::config::KubeconfigError),
+    #[error("ConfigError: {0}")]
+    Config(String),
     #[error("MissingObjectKey: {0}")]
     MissingObjectKey(&'static str),
     #[error("ProviderIDError: {0}")]
@@ -15,10 +56,86 @@ pub enum Error {
     ParseInt(#[from] std::num::ParseIntError),
     #[error("TemplateParseError: {0}")]
     TemplateParser(String),
+    #[error("MissingFieldError: {0}")]
+    MissingField(String),
     #[error("MetadataKeyError: {0}")]
     MetadataKey(String),
+    #[error("LabelValueError: {0}")]
+    LabelValue(String),
     #[error("JoinError: {0}")]
     JoinError(#[from] tokio::task::JoinError),
     #[error("ServerError: {0}")]
     ServerError(#[from] std::io::Error),
+    #[cfg(feature = "metrics")]
+    #[error("MetricsError: {0}")]
+    Metrics(#[from] prometheus::Error),
+    #[error("SerializationError: {0}")]
+    Serialization(#[from] serde_json::Error),
+    #[error("AzureError: {0}")]
+    Azure(String),
+    #[error("TlsError: {0}")]
+    Tls(String),
+    #[error("OtlpError: {0}")]
+    Otlp(String),
+    #[error("PushgatewayError: {0}")]
+    Pushgateway(String),
+    #[error("EnrichmentError: {0}")]
+    Enrichment(String),
+    #[error("PluginError: {0}")]
+    Plugin(String),
+    #[error("HookError: {0}")]
+    Hook(String),
+    #[error("ControllerError: {0}")]
+    Controller(String),
+}
+
+impl Error {
+    /// A coarse classification of the error, e.g. to tell configuration bugs
+    /// from infrastructure problems in metrics and alerts.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Error::Kube(kube::Error::Api(e)) if e.code == 409 => "patch_conflict",
+            Error::Kube(_) | Error::Kubeconfig(_) => "kube_api",
+            Error::TemplateParser(_)
+            | Error::MissingField(_)
+            | Error::MetadataKey(_)
+            | Error::LabelValue(_) => "template",
+            Error::ProviderID(_) | Error::ParseInt(_) => "provider_id_parse",
+            Error::Azure(_) => "azure",
+            Error::Enrichment(_) => "enrichment",
+            Error::Plugin(_) => "plugin",
+            Error::Hook(_) => "hook",
+            Error::Config(_) | Error::Tls(_) => "config",
+            _ => "other",
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_error_kind() {
+        let api_error = |code| {
+            Error::Kube(kube::Error::Api(kube::core::ErrorResponse {
+                status: "Failure".into(),
+                message: "error".into(),
+                reason: "Testing".into(),
+                code,
+            }))
+        };
+
+        assert_eq!(api_error(409).kind(), "patch_conflict");
+        assert_eq!(api_error(500).kind(), "kube_api");
+        assert_eq!(Error::MissingField("azure:sku".into()).kind(), "template");
+        assert_eq!(
+            Error::ProviderID(provider_id::ProviderIDError::Invalid).kind(),
+            "provider_id_parse"
+        );
+        assert_eq!(Error::Enrichment("timed out".into()).kind(), "enrichment");
+        assert_eq!(Error::Plugin("unknown plugin".into()).kind(), "plugin");
+        assert_eq!(Error::Hook("vetoed".into()).kind(), "hook");
+        assert_eq!(Error::MissingObjectKey("name").kind(), "other");
+    }
 }
@@ -0,0 +1,103 @@
+//! Whether a node is a spot (or preemptible) or an on-demand instance, for
+//! the `{:lifecycle}` token.
+use crate::{provider_id::ProviderID, template::Fields};
+use std::collections::BTreeMap;
+
+pub const SPOT: &str = "spot";
+pub const ON_DEMAND: &str = "on-demand";
+
+/// The lifecycle of a node from an enrichment field named
+/// `<provider>:lifecycle`, e.g. `azure:lifecycle`, or the well-known labels
+/// its provider and Karpenter set. A provider that labels only spot nodes
+/// (GKE, AKS) implies on-demand without the label. `None` if the node
+/// doesn't say, e.g. a self-managed EC2 node.
+pub fn lifecycle(
+    provider_id: &ProviderID,
+    labels: Option<&BTreeMap<String, String>>,
+    fields: &Fields,
+) -> Option<String> {
+    let provider = provider_id.provider();
+    if let Some(value) = fields.get(&format!("{provider}:lifecycle")) {
+        return Some(value.clone());
+    }
+
+    let empty = BTreeMap::new();
+    let labels = labels.unwrap_or(&empty);
+    let label = |key: &str| labels.get(key).map(String::as_str);
+
+    let lifecycle = match label("karpenter.sh/capacity-type") {
+        Some("spot") => Some(SPOT),
+        Some("on-demand") => Some(ON_DEMAND),
+        _ => match provider.as_str() {
+            "aws" => match label("eks.amazonaws.com/capacityType") {
+                Some("SPOT") => Some(SPOT),
+                Some("ON_DEMAND") => Some(ON_DEMAND),
+                _ => None,
+            },
+            "gce" => {
+                let spot = label("cloud.google.com/gke-spot") == Some("true")
+                    || label("cloud.google.com/gke-preemptible") == Some("true");
+                Some(if spot { SPOT } else { ON_DEMAND })
+            }
+            "azure" => match label("kubernetes.azure.com/scalesetpriority") {
+                Some("spot") => Some(SPOT),
+                _ => Some(ON_DEMAND),
+            },
+            _ => None,
+        },
+    };
+    lifecycle.map(String::from)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lifecycle() {
+        let labels = |pairs: &[(&str, &str)]| {
+            pairs
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect::<BTreeMap<_, _>>()
+        };
+        let no_fields = Fields::new();
+        let aws = ProviderID::new("node", "aws://us-east-2a/i-0abc").unwrap();
+        let gce = ProviderID::new("node", "gce://project/us-central1-a/node").unwrap();
+        let azure = ProviderID::new("node", "azure:///subscriptions/sub/resourceGroups/rg/providers/Microsoft.Compute/virtualMachines/vm").unwrap();
+        let metal = ProviderID::new("node", "metal://rack/node").unwrap();
+
+        let spot = labels(&[("eks.amazonaws.com/capacityType", "SPOT")]);
+        assert_eq!(lifecycle(&aws, Some(&spot), &no_fields).unwrap(), SPOT);
+        let on_demand = labels(&[("eks.amazonaws.com/capacityType", "ON_DEMAND")]);
+        assert_eq!(
+            lifecycle(&aws, Some(&on_demand), &no_fields).unwrap(),
+            ON_DEMAND
+        );
+        // self-managed nodes don't say
+        assert_eq!(lifecycle(&aws, None, &no_fields), None);
+
+        let preemptible = labels(&[("cloud.google.com/gke-preemptible", "true")]);
+        assert_eq!(
+            lifecycle(&gce, Some(&preemptible), &no_fields).unwrap(),
+            SPOT
+        );
+        assert_eq!(lifecycle(&gce, None, &no_fields).unwrap(), ON_DEMAND);
+
+        let spot = labels(&[("kubernetes.azure.com/scalesetpriority", "spot")]);
+        assert_eq!(lifecycle(&azure, Some(&spot), &no_fields).unwrap(), SPOT);
+        assert_eq!(lifecycle(&azure, None, &no_fields).unwrap(), ON_DEMAND);
+
+        // Karpenter labels nodes on any provider
+        let karpenter = labels(&[("karpenter.sh/capacity-type", "spot")]);
+        assert_eq!(
+            lifecycle(&metal, Some(&karpenter), &no_fields).unwrap(),
+            SPOT
+        );
+        assert_eq!(lifecycle(&metal, None, &no_fields), None);
+
+        // cloud APIs take precedence
+        let fields = Fields::from([("azure:lifecycle".to_string(), SPOT.to_string())]);
+        assert_eq!(lifecycle(&azure, None, &fields).unwrap(), SPOT);
+    }
+}
@@ -0,0 +1,146 @@
+use crate::client::ClientArgs;
+use k8s_openapi::api::core::v1::Node;
+use kube::{api::ListParams, Api};
+use node_provider_labeler::{export::NodeInfo, Error};
+use std::process::ExitCode;
+
+const NONE: &str = "<none>";
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default)]
+pub(crate) enum Output {
+    #[default]
+    Table,
+    Json,
+    Yaml,
+}
+
+#[derive(clap::Args, Debug)]
+pub(crate) struct ListArgs {
+    /// The output format
+    #[arg(short, long, value_enum, default_value_t)]
+    output: Output,
+    /// Only list nodes matching this label selector
+    #[arg(short = 'l', long, value_name = "SELECTOR")]
+    selector: Option<String>,
+    #[command(flatten)]
+    client: ClientArgs,
+}
+
+pub(crate) async fn list(args: &ListArgs) -> ExitCode {
+    match nodes(args)
+        .await
+        .and_then(|nodes| format(&nodes, args.output))
+    {
+        Ok(output) => {
+            print!("{output}");
+            ExitCode::SUCCESS
+        }
+        Err(e) => {
+            eprintln!("unable to list nodes: {e}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+async fn nodes(args: &ListArgs) -> Result<Vec<NodeInfo>, Error> {
+    let api: Api<Node> = Api::all(args.client.client().await?);
+    let mut params = ListParams::default();
+    if let Some(selector) = &args.selector {
+        params = params.labels(selector);
+    }
+    Ok(api
+        .list(&params)
+        .await?
+        .iter()
+        .map(NodeInfo::from)
+        .collect())
+}
+
+fn format(nodes: &[NodeInfo], output: Output) -> Result<String, Error> {
+    match output {
+        Output::Table => Ok(table(nodes)),
+        Output::Json => Ok(serde_json::to_string_pretty(nodes)? + "\n"),
+        Output::Yaml => serde_yaml::to_string(nodes).map_err(|e| Error::Config(e.to_string())),
+    }
+}
+
+/// Formats the nodes in aligned columns, like `kubectl get`.
+fn table(nodes: &[NodeInfo]) -> String {
+    let or_none = |s: String| if s.is_empty() { NONE.to_string() } else { s };
+    let mut rows = vec![["NODE", "PROVIDER", "PARTS", "LABELS"].map(String::from)];
+    rows.extend(nodes.iter().map(|node| {
+        [
+            node.name.clone(),
+            node.provider.clone().unwrap_or_else(|| NONE.into()),
+            or_none(node.parts.join(",")),
+            or_none(
+                node.labels
+                    .iter()
+                    .map(|(k, v)| format!("{k}={v}"))
+                    .collect::<Vec<_>>()
+                    .join(","),
+            ),
+        ]
+    }));
+
+    let mut widths = [0; 4];
+    for row in &rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.len());
+        }
+    }
+
+    let mut output = String::new();
+    for row in rows {
+        let line = row
+            .iter()
+            .zip(widths)
+            .map(|(cell, width)| format!("{cell:width$}"))
+            .collect::<Vec<_>>()
+            .join("   ");
+        output.push_str(line.trim_end());
+        output.push('\n');
+    }
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use k8s_openapi::api::core::v1::NodeSpec;
+    use kube::api::ObjectMeta;
+
+    #[test]
+    fn test_list_format() {
+        let node = |name: &str, provider_id: Option<&str>| Node {
+            metadata: ObjectMeta {
+                name: Some(name.into()),
+                ..Default::default()
+            },
+            spec: Some(NodeSpec {
+                provider_id: provider_id.map(String::from),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        let mut aws = NodeInfo::from(&node("worker-1", Some("aws://us-east-2/i-0abc")));
+        aws.labels.insert("provider-id".into(), "i-0abc".into());
+        let nodes = vec![aws, NodeInfo::from(&node("control-plane", None))];
+
+        assert_eq!(nodes[0].provider.as_deref(), Some("aws"));
+        assert_eq!(nodes[0].parts, vec!["us-east-2", "i-0abc"]);
+        assert_eq!(
+            table(&nodes),
+            "NODE            PROVIDER   PARTS              LABELS\n\
+             worker-1        aws        us-east-2,i-0abc   provider-id=i-0abc\n\
+             control-plane   <none>     <none>             <none>\n"
+        );
+
+        let json: serde_json::Value =
+            serde_json::from_str(&format(&nodes, Output::Json).unwrap()).unwrap();
+        assert_eq!(json[0]["provider_id"], "aws://us-east-2/i-0abc");
+        assert!(format(&nodes, Output::Yaml)
+            .unwrap()
+            .contains("- name: worker-1\n"));
+    }
+}
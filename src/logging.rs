@@ -0,0 +1,79 @@
+use node_provider_labeler::Error;
+use tracing_subscriber::{fmt, prelude::*, reload, EnvFilter, Registry};
+
+/// Adjusts the log filter of the running subscriber.
+pub(crate) type FilterHandle = reload::Handle<EnvFilter, Registry>;
+
+const DEFAULT_FILTER: &str = "info";
+
+/// Builds the log filter from `--log-level`, falling back to `RUST_LOG` and
+/// then to "info". Accepts a plain level or `RUST_LOG`-style directives, e.g.
+/// "info,kube_runtime=warn,node_provider_labeler=debug".
+fn filter(log_level: Option<&str>) -> Result<EnvFilter, Error> {
+    let directives = match log_level {
+        Some(level) => level.to_string(),
+        None => std::env::var(EnvFilter::DEFAULT_ENV).unwrap_or(DEFAULT_FILTER.to_string()),
+    };
+
+    parse(&directives)
+}
+
+fn parse(directives: &str) -> Result<EnvFilter, Error> {
+    EnvFilter::builder()
+        .parse(directives)
+        .map_err(|e| Error::Config(format!("invalid log filter '{directives}': {e}")))
+}
+
+/// Installs the global tracing subscriber, returning a handle to change its
+/// filter at runtime.
+pub(crate) fn init(log_level: Option<&str>) -> Result<FilterHandle, Error> {
+    let (filter, handle) = reload::Layer::new(filter(log_level)?);
+    // the filter only applies to log output so tokio-console still receives
+    // runtime traces
+    let registry = tracing_subscriber::registry().with(fmt::layer().with_filter(filter));
+    #[cfg(feature = "console")]
+    let registry = registry.with(console_subscriber::spawn());
+    registry.init();
+    Ok(handle)
+}
+
+/// Replaces the log filter, returning the directives now in effect.
+pub(crate) fn set_filter(handle: &FilterHandle, directives: &str) -> Result<String, Error> {
+    let filter = parse(directives.trim())?;
+    let applied = filter.to_string();
+    handle
+        .reload(filter)
+        .map_err(|e| Error::Config(format!("unable to set log filter: {e}")))?;
+    Ok(applied)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_filter() {
+        assert_eq!(filter(Some("debug")).unwrap().to_string(), "debug");
+        assert_eq!(
+            filter(Some("info,kube_runtime=warn")).unwrap().to_string(),
+            "kube_runtime=warn,info"
+        );
+        assert!(filter(Some("info,kube_runtime=loud")).is_err());
+    }
+
+    #[test]
+    fn test_set_filter() {
+        let (_layer, handle) = reload::Layer::<_, Registry>::new(parse("info").unwrap());
+        assert_eq!(
+            set_filter(&handle, "debug,kube=warn\n").unwrap(),
+            "kube=warn,debug"
+        );
+        handle
+            .with_current(|f| assert_eq!(f.to_string(), "kube=warn,debug"))
+            .unwrap();
+        assert!(set_filter(&handle, "kube=loud").is_err());
+        handle
+            .with_current(|f| assert_eq!(f.to_string(), "kube=warn,debug"))
+            .unwrap();
+    }
+}
@@ -1,64 +1,543 @@
-mod controller;
-mod diagnostics;
-mod meta;
-mod metrics;
-
-use axum::{extract, http::StatusCode, routing::get, Router};
-use clap::Parser;
-use diagnostics::Diagnostics;
-use futures::TryFutureExt;
-use node_provider_labeler::Error;
-use prometheus::{Encoder, TextEncoder};
-use std::{future::IntoFuture, process::ExitCode, sync::Arc};
-use tokio::{net::TcpListener, sync::RwLock, task::JoinHandle};
-use tracing::{error, warn};
+mod client;
+mod commands;
+mod hooks;
+mod list;
+mod logging;
+mod middleware;
+mod otlp;
+mod policy;
+#[cfg(feature = "pprof")]
+mod profiling;
+mod pushgateway;
+mod ratelimit;
+mod server;
+
+use clap::{Args, CommandFactory, Parser, Subcommand};
+#[cfg(feature = "azure")]
+use node_provider_labeler::azure;
+use node_provider_labeler::{
+    breaker::QuarantineOptions,
+    canary::{CanaryOptions, CanarySize},
+    config, controller,
+    diagnostics::Diagnostics,
+    export,
+    hook::PatchHook,
+    metrics, shutdown, source, template,
+    window::{ChangeWindow, Schedule},
+    Error, State,
+};
+use std::{path::PathBuf, process::ExitCode, sync::Arc, time::Duration};
+use tokio::{sync::RwLock, task::JoinHandle};
+use tracing::error;
 
 #[derive(Parser, Debug)]
-#[command(version, about, long_about = None)]
-struct Args {
+#[command(
+    version,
+    about,
+    long_about = None,
+    args_conflicts_with_subcommands = true,
+    args_override_self = true,
+    after_help = "Flags can also be read from a YAML file with --config=<FILE>. See \
+                  init-config and schema."
+)]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+    // bare flags run the controller, as before there were commands
+    #[command(flatten)]
+    run: RunArgs,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Run the controller (the default)
+    Run(Box<RunArgs>),
+    /// Check the label, annotation, and taint templates
+    Validate(TemplateArgs),
+    /// Print the labels, annotations, and taints the templates render for a
+    /// node
+    Render(commands::RenderArgs),
+    /// Remove the labels, annotations, and taints the controller manages from
+    /// all nodes
+    Cleanup(commands::CleanupArgs),
+    /// Like cleanup, then restore the values backed up with
+    /// --backup-originals
+    Undo(commands::CleanupArgs),
+    /// List nodes with their provider ID parts and the labels the controller
+    /// manages on them
+    List(list::ListArgs),
+    /// Print a ValidatingAdmissionPolicy that stops anyone but the
+    /// controller from changing the labels and annotations it manages
+    GeneratePolicy(policy::PolicyArgs),
+    /// Print the version
+    Version,
+    /// Print shell completions
+    Completions { shell: clap_complete::Shell },
+    /// Print a starter config file with every flag of the run command,
+    /// commented out
+    InitConfig,
+    /// Print the JSON Schema of the config file
+    Schema,
+}
+
+// the label, annotation, and taint templates shared by the commands
+#[derive(Args, Debug)]
+pub(crate) struct TemplateArgs {
     /// The label key and optional template to use for the label value.
     /// The default is "provider-id={:last}" if there are no other labels or annotations configured.
-    /// Repeat to add multiple labels.
+    /// Repeat to add multiple labels. End with @selector=<selector> to only label matching nodes,
+    /// @skip=<condition> to skip nodes the condition holds for, or @overwrite=<policy> (always,
+    /// if-absent, or if-managed) to leave values on nodes alone. An empty template removes the
+    /// label, e.g. a deprecated one. Add @disabled=true to keep a label configured but not apply it.
     ///
     /// Examples:
     /// * --label=label-key
     /// * --label=label-key={:last} --label=other-label-key={0}-{1}
+    /// * --label='gpu-id={1}@selector=node.kubernetes.io/instance-type in (p4d.24xlarge)'
+    /// * --label='zone={:first}@skip={label:kubernetes.io/os}==windows'
+    /// * --label='zone={:first}@overwrite=if-managed'
+    /// * --label=legacy-key=
     #[arg(short, long, verbatim_doc_comment)]
     label: Option<Vec<String>>,
     /// The annotation key and optional template to use for the annotation value
-    /// Repeat to add multiple annotations.
+    /// Repeat to add multiple annotations. End with @selector=<selector> to only annotate matching
+    /// nodes, @skip=<condition> to skip nodes the condition holds for, or @overwrite=<policy> to
+    /// leave values on nodes alone. An empty template removes the annotation. Add @disabled=true
+    /// to keep an annotation configured but not apply it.
     ///
     /// Examples:
     /// * --annotation=annotation-key
     /// * --annotation=annotation-key={:last} --annotation=other-annotation-key={0}-{1}
+    /// * --annotation=legacy-key=
     #[arg(short, long, verbatim_doc_comment)]
     annotation: Option<Vec<String>>,
-    /// Requeue reconciliation of a node after this duration in seconds
-    #[arg(long, default_value_t = 3600)]
-    requeue_duration: u64,
+    /// The taint key, optional template for the taint value, and effect
+    /// (NoSchedule, PreferNoSchedule, or NoExecute). Repeat to add multiple
+    /// taints. An empty template removes the taint. Add @disabled=true before
+    /// the effect to keep a taint configured but not apply it.
+    ///
+    /// Examples:
+    /// * --taint=taint-key:NoSchedule
+    /// * --taint=taint-key={:first}:PreferNoSchedule
+    /// * --taint=legacy-key=:NoSchedule
+    #[arg(long, verbatim_doc_comment)]
+    taint: Option<Vec<String>>,
+    /// A lookup table for the |map(<name>) filter, read from a YAML or JSON
+    /// file of keys to values, e.g. a mounted ConfigMap key. Repeat to add
+    /// multiple tables.
+    ///
+    /// Examples:
+    /// * --map=regions=/etc/maps/regions.yaml with --label=site={:first|map(regions)}
+    #[arg(long, value_name = "NAME=PATH", verbatim_doc_comment)]
+    map: Option<Vec<String>>,
 }
 
-#[derive(Clone, Debug, Default)]
-struct State {
-    diagnostics: Arc<RwLock<Diagnostics>>,
-    /// Metrics registry
-    registry: prometheus::Registry,
+impl TemplateArgs {
+    pub(crate) fn parse(&self) -> Result<controller::Templates, Error> {
+        controller::Templates::parse(
+            self.label.clone(),
+            self.annotation.clone(),
+            self.taint.clone(),
+        )?
+        .with_maps(self.maps()?)
+    }
+
+    /// Loads the --map tables, given as name=path.
+    pub(crate) fn maps(&self) -> Result<template::Maps, Error> {
+        let mut maps = template::Maps::new();
+        for map in self.map.iter().flatten() {
+            let (name, path) = map
+                .split_once('=')
+                .ok_or_else(|| Error::Config(format!("invalid map '{map}'")))?;
+            if maps.contains_key(name) {
+                return Err(Error::Config(format!("duplicate map '{name}'")));
+            }
+            let contents = std::fs::read_to_string(path)
+                .map_err(|e| Error::Config(format!("unable to read map '{name}': {e}")))?;
+            let entries = serde_yaml::from_str(&contents)
+                .map_err(|e| Error::Config(format!("invalid map '{name}': {e}")))?;
+            maps.insert(name.to_string(), entries);
+        }
+        Ok(maps)
+    }
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default)]
+enum StalePolicy {
+    /// Leave the values as they are
+    #[default]
+    Keep,
+    /// Remove the values
+    Remove,
+    /// Keep the values, annotated with node-provider-labeler/stale
+    Mark,
 }
 
-impl State {
-    fn metrics(&self) -> Vec<prometheus::proto::MetricFamily> {
-        self.registry.gather()
+impl From<StalePolicy> for controller::StalePolicy {
+    fn from(policy: StalePolicy) -> Self {
+        match policy {
+            StalePolicy::Keep => controller::StalePolicy::Keep,
+            StalePolicy::Remove => controller::StalePolicy::Remove,
+            StalePolicy::Mark => controller::StalePolicy::Mark,
+        }
     }
 }
 
+#[derive(Args, Debug)]
+#[command(args_override_self = true)]
+struct RunArgs {
+    /// The log level or filter directives, e.g. "debug" or
+    /// "info,kube_runtime=warn,node_provider_labeler=debug".
+    /// Defaults to $RUST_LOG, or "info" if unset.
+    #[arg(long, value_name = "FILTER", verbatim_doc_comment)]
+    log_level: Option<String>,
+    #[command(flatten)]
+    templates: TemplateArgs,
+    /// Requeue reconciliation of a node after this duration in seconds
+    #[arg(long, default_value_t = 3600)]
+    requeue_duration: u64,
+    /// Count errors toward /health over a rolling window of this many seconds
+    #[arg(long, default_value_t = 60)]
+    health_error_window: u64,
+    /// Report /health as unhealthy once more than this many errors occurred
+    /// within --health-error-window
+    #[arg(long, default_value_t = 0)]
+    health_error_threshold: usize,
+    /// Report /health as unhealthy once no node was reconciled for this many
+    /// times the --requeue-duration, e.g. because the controller stopped
+    #[arg(long, value_name = "N")]
+    health_max_staleness: Option<u32>,
+    /// Check API server connectivity for /readyz every this many seconds
+    #[arg(long, default_value_t = 10)]
+    readiness_interval: u64,
+    /// Exit with an error once more than this many controller queue and
+    /// runner errors occurred within --controller-error-window, so Kubernetes
+    /// restarts the process with backoff
+    #[arg(long, value_name = "ERRORS")]
+    max_controller_errors: Option<usize>,
+    /// The window in seconds for --max-controller-errors
+    #[arg(long, default_value_t = 60)]
+    controller_error_window: u64,
+    /// Retry patches rejected with a conflict this many times, with backoff,
+    /// before failing the reconciliation
+    #[arg(long, default_value_t = 3)]
+    conflict_retries: u32,
+    /// On shutdown, wait this duration in seconds for in-flight
+    /// reconciliations to complete
+    #[arg(long, default_value_t = 20)]
+    drain_timeout: u64,
+    /// Enrich Azure nodes with the tags and SKU of their VM or VMSS instance,
+    /// available in templates as {azure:sku} and {azure:tag:<name>}.
+    /// Authenticates via Azure Workload Identity.
+    #[cfg(feature = "azure")]
+    #[arg(long)]
+    azure_enrichment: bool,
+    /// An Azure tag to expose to templates when --azure-enrichment is set.
+    /// Repeat to add multiple tags.
+    #[cfg(feature = "azure")]
+    #[arg(long)]
+    azure_tag: Option<Vec<String>>,
+    /// Cache Azure resource lookups for this duration in seconds
+    #[cfg(feature = "azure")]
+    #[arg(long, default_value_t = 600)]
+    azure_cache_ttl: u64,
+    /// Fail a reconciliation if enrichment (e.g. --azure-enrichment) takes
+    /// longer than this duration in seconds
+    #[arg(long, default_value_t = 10)]
+    enrichment_timeout: u64,
+    /// A WebAssembly module implementing a template transform, callable as
+    /// {plugin:<name>(<token>)}. Repeat to add multiple plugins.
+    ///
+    /// Examples:
+    /// * --plugin=my_transform=/plugins/my_transform.wasm
+    #[cfg(feature = "wasm")]
+    #[arg(long, value_name = "NAME=PATH", verbatim_doc_comment)]
+    plugin: Option<Vec<String>>,
+    /// Run this program before and after each node patch, with "before" or
+    /// "after" as its argument and the changes as JSON on stdin. Exiting
+    /// non-zero before the patch vetoes it.
+    #[arg(long, value_name = "PROGRAM")]
+    hook_exec: Option<String>,
+    /// POST the changes as JSON to this URL before and after each node patch.
+    /// A non-success response before the patch vetoes it.
+    #[arg(long, value_name = "URL")]
+    hook_url: Option<String>,
+    /// POST each node's changes as JSON to this URL after they are applied,
+    /// e.g. to keep an inventory system in sync. Failed deliveries are retried
+    /// but don't fail the reconciliation.
+    #[arg(long, value_name = "URL")]
+    notify_url: Option<String>,
+    /// Sign --notify-url payloads with HMAC-SHA256 using the secret in this
+    /// file, sent as "sha256=<hex>" in the X-Npl-Signature header
+    #[arg(long, value_name = "PATH")]
+    notify_secret_file: Option<PathBuf>,
+    /// Retry a failed --notify-url delivery this many times, with
+    /// exponential backoff from 1s
+    #[arg(long, default_value_t = 3)]
+    notify_retries: u32,
+    /// Fail a --hook-exec, --hook-url, or --notify-url call that takes longer
+    /// than this duration in seconds
+    #[arg(long, default_value_t = 10)]
+    hook_timeout: u64,
+    /// Also apply labels and annotations to the Cluster API Machine that owns
+    /// each node
+    #[arg(long)]
+    label_machines: bool,
+    /// Before taking over a label or annotation that already exists, back up
+    /// its value in the node-provider-labeler/originals annotation, for the
+    /// undo command
+    #[arg(long)]
+    backup_originals: bool,
+    /// Record the previous value and change time of each changed label and
+    /// annotation in <key>.previous and <key>.changed-at annotations
+    #[arg(long)]
+    record_history: bool,
+    /// Record the provider ID each node's values were rendered from in the
+    /// node-provider-labeler/provider-id annotation, and publish a
+    /// ProviderIDChanged event when a node comes back with another one
+    #[arg(long)]
+    track_provider_id: bool,
+    /// Don't patch nodes until their Ready condition is True, to avoid racing
+    /// bootstrap tooling that rewrites metadata while nodes initialize
+    #[arg(long)]
+    require_node_ready: bool,
+    /// Don't patch nodes until they are this many seconds old, giving cloud
+    /// controllers and bootstrap DaemonSets time to finish their own metadata
+    /// writes
+    #[arg(long, value_name = "SECONDS")]
+    min_node_age: Option<u64>,
+    /// Don't patch cordoned nodes, i.e. with spec.unschedulable set, until
+    /// they are uncordoned
+    #[arg(long)]
+    skip_unschedulable: bool,
+    /// Only report drift: log and count the keys whose values on nodes
+    /// differ from the rendered ones in the drift_detected metric, without
+    /// ever patching nodes or publishing events. For running as a compliance
+    /// monitor alongside another writer.
+    #[arg(long)]
+    report_only: bool,
+    /// Record a checksum of the configuration and provider ID each node's
+    /// values were rendered with in the node-provider-labeler/checksum
+    /// annotation, and skip reconciling nodes whose checksum matches. Values
+    /// rendered from anything else, e.g. node labels, aren't refreshed until
+    /// the configuration or provider ID changes.
+    #[arg(long, conflicts_with = "export_configmap")]
+    checksum: bool,
+    /// Roll configuration changes out to this percentage of nodes first, and
+    /// to the rest once they were verified for --canary-window
+    #[arg(long, value_name = "PERCENT", conflicts_with = "canary_nodes")]
+    canary_percent: Option<u8>,
+    /// Roll configuration changes out to this many nodes first, and to the
+    /// rest once they were verified for --canary-window
+    #[arg(long, value_name = "N")]
+    canary_nodes: Option<usize>,
+    /// Verify canary nodes for this duration in seconds
+    #[arg(long, default_value_t = 300)]
+    canary_window: u64,
+    /// Hold a configuration change back from the remaining nodes if more than
+    /// this share, from 0 to 1, of canary reconciliations failed
+    #[arg(long, value_name = "RATE", default_value_t = 0.0)]
+    canary_max_error_rate: f64,
+    /// Only write to nodes in windows opening at the times of this cron
+    /// schedule, in UTC, e.g. "0 2 * * 1-5". Changes are still computed, and
+    /// applied once a window opens. Repeat to add multiple windows.
+    #[arg(long, value_name = "CRON")]
+    change_window: Option<Vec<String>>,
+    /// Keep change windows open for this duration in seconds
+    #[arg(long, default_value_t = 3600)]
+    change_window_duration: u64,
+    /// Patch at most this many nodes per minute until every node was
+    /// reconciled once after starting, separately from --client-qps
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u32).range(1..))]
+    startup_patches_per_minute: Option<u32>,
+    /// Patch at most this many objects per second across reconcilers,
+    /// slowing down while the API server throttles requests (HTTP 429) and
+    /// speeding back up as patches succeed
+    #[arg(long, value_name = "PER_SECOND")]
+    max_patch_rate: Option<f64>,
+    /// Quarantine a node once this many of its reconciliations failed in a
+    /// row: it's left alone for --quarantine-duration, with a warning Event
+    #[arg(long, value_name = "FAILURES")]
+    quarantine_after: Option<u32>,
+    /// Leave quarantined nodes alone for this duration in seconds
+    #[arg(long, default_value_t = 3600)]
+    quarantine_duration: u64,
+    /// What to do with the values on a node that lost its spec.providerID,
+    /// or whose provider ID no longer parses
+    #[arg(long, value_enum, default_value_t)]
+    stale_policy: StalePolicy,
+    /// For nodes without a spec.providerID, e.g. on bare metal, render one
+    /// from this template and set it. Only {:node} and {<namespace>:<key>}
+    /// tokens are allowed. Existing provider IDs are never changed.
+    ///
+    /// Examples:
+    /// * --provider-id-template=metal://{label:rack}/{:node}
+    #[arg(long, value_name = "TEMPLATE", verbatim_doc_comment)]
+    provider_id_template: Option<String>,
+    /// An environment variable {env:<name>} tokens may render besides those
+    /// the --label, --annotation, --taint, and --provider-id-template
+    /// templates reference, e.g. in @skip conditions. Only these variables
+    /// are read, and each must be set. Repeat to allow multiple variables.
+    #[arg(long, value_name = "NAME")]
+    env_allow: Option<Vec<String>>,
+    /// For nodes without a spec.providerID, render provider ID tokens marked
+    /// with a fallback, e.g. {:first|zone}, from the node's
+    /// topology.kubernetes.io/zone or region label. Templates with other
+    /// provider ID tokens are skipped.
+    #[arg(long)]
+    topology_fallback: bool,
+    /// Maintain a ConfigMap with this name containing the node to rendered
+    /// values mapping as JSON
+    #[arg(long, value_name = "NAME")]
+    export_configmap: Option<String>,
+    /// The namespace of the --export-configmap ConfigMap. Defaults to the
+    /// controller's namespace.
+    #[arg(long, value_name = "NAMESPACE", requires = "export_configmap")]
+    export_configmap_namespace: Option<String>,
+    /// Fetch the initial node list with a streaming watch instead of a list,
+    /// to cut memory and API server load on startup. Requires the API
+    /// server's WatchList feature.
+    #[arg(long)]
+    streaming_list: bool,
+    /// Back off reconnecting the node watch after errors from this many
+    /// seconds, doubling up to --watcher-backoff-max
+    #[arg(long, value_name = "SECONDS", default_value_t = 0.8)]
+    watcher_backoff_initial: f64,
+    /// The maximum watch reconnect backoff in seconds
+    #[arg(long, value_name = "SECONDS", default_value_t = 30.0)]
+    watcher_backoff_max: f64,
+    /// Start the watch reconnect backoff over once no error occurred for this
+    /// many seconds
+    #[arg(long, value_name = "SECONDS", default_value_t = 120.0)]
+    watcher_backoff_reset: f64,
+    /// List and reconcile all nodes every this many seconds instead of
+    /// watching them, where the watch verb isn't granted or long-lived
+    /// connections are unreliable
+    #[arg(
+        long,
+        value_name = "SECONDS",
+        value_parser = clap::value_parser!(u64).range(1..),
+        conflicts_with = "streaming_list"
+    )]
+    poll_interval: Option<u64>,
+    /// Also list and reconcile every node at the times of this cron
+    /// schedule, in UTC, e.g. "0 3 * * *" for a nightly pass that guarantees
+    /// convergence, whatever the requeue duration. Resynced nodes are
+    /// rendered in full, even with --checksum.
+    #[arg(long, value_name = "CRON")]
+    resync_schedule: Option<String>,
+    /// Cache nodes in full. By default, other managers' managed fields and
+    /// the node's image list are dropped from cached nodes to save memory.
+    #[arg(long)]
+    cache_full_nodes: bool,
+    /// Label reconciliation metrics by node
+    #[arg(long)]
+    metrics_per_node: bool,
+    /// The maximum number of distinct nodes to label metrics with when
+    /// --metrics-per-node is set. Further nodes are counted as "_other".
+    #[arg(long, default_value_t = 1000)]
+    metrics_max_nodes: usize,
+    /// A prefix for all metric names, e.g. "npl" for "npl_reconciliations"
+    #[arg(long)]
+    metrics_prefix: Option<String>,
+    /// Comma-separated, increasing upper bounds in seconds for the
+    /// reconcile_duration histogram buckets
+    #[arg(long, value_delimiter = ',', value_name = "BUCKETS")]
+    reconcile_duration_buckets: Option<Vec<f64>>,
+    /// Also push metrics over OTLP/HTTP to the OpenTelemetry collector at
+    /// this endpoint, e.g. "http://otel-collector:4318"
+    #[arg(long, value_name = "URL")]
+    otlp_endpoint: Option<String>,
+    /// A header to send with OTLP requests, e.g. "authorization=Bearer abc".
+    /// Repeat to add multiple headers.
+    #[arg(long, value_name = "KEY=VALUE", requires = "otlp_endpoint")]
+    otlp_header: Option<Vec<String>>,
+    /// Push OTLP metrics every this many seconds
+    #[arg(long, default_value_t = 60)]
+    otlp_interval: u64,
+    /// Also push metrics to the Prometheus Pushgateway at this URL, e.g.
+    /// "http://pushgateway:9091", for runs too short to be scraped
+    #[arg(long, value_name = "URL")]
+    pushgateway_url: Option<String>,
+    /// The job label to push metrics under
+    #[arg(
+        long,
+        default_value = "node-provider-labeler",
+        requires = "pushgateway_url"
+    )]
+    pushgateway_job: String,
+    /// The instance label to push metrics under, e.g. the pod name. Not set
+    /// by default.
+    #[arg(long, requires = "pushgateway_url")]
+    pushgateway_instance: Option<String>,
+    /// Push metrics to the Pushgateway every this many seconds
+    #[arg(long, default_value_t = 60)]
+    pushgateway_interval: u64,
+    #[command(flatten)]
+    client: client::ClientArgs,
+    #[command(flatten)]
+    server: server::ServerArgs,
+}
+
 #[tokio::main]
 async fn main() -> ExitCode {
-    tracing_subscriber::fmt::init();
-    let args = Args::parse();
-    let state = State::default();
+    let args = match config::expand(std::env::args_os(), Cli::command()) {
+        Ok(args) => args,
+        Err(e) => {
+            eprintln!("{e}");
+            return ExitCode::FAILURE;
+        }
+    };
+    let cli = Cli::parse_from(args);
+    match cli.command.unwrap_or(Command::Run(Box::new(cli.run))) {
+        Command::Run(args) => run(*args).await,
+        Command::Validate(args) => commands::validate(&args),
+        Command::Render(args) => commands::render(&args),
+        Command::Cleanup(args) => commands::cleanup(&args).await,
+        Command::Undo(args) => commands::undo(&args).await,
+        Command::List(args) => list::list(&args).await,
+        Command::GeneratePolicy(args) => policy::generate_policy(&args),
+        Command::Version => commands::version(),
+        Command::Completions { shell } => commands::completions(shell),
+        Command::InitConfig => commands::init_config(),
+        Command::Schema => commands::schema(),
+    }
+}
+
+async fn run(args: RunArgs) -> ExitCode {
+    let log_filter = match logging::init(args.log_level.as_deref()) {
+        Ok(handle) => handle,
+        Err(e) => {
+            eprintln!("unable to initialize logging: {e}");
+            return ExitCode::FAILURE;
+        }
+    };
+    let registry = match metrics_registry(args.metrics_prefix.as_deref()) {
+        Ok(registry) => registry,
+        Err(e) => {
+            error!(
+                { error = e.to_string() },
+                "unable to create metrics registry"
+            );
+            return ExitCode::FAILURE;
+        }
+    };
+    let mut diagnostics = Diagnostics::new(
+        Duration::from_secs(args.health_error_window),
+        args.health_error_threshold,
+    );
+    if let Some(n) = args.health_max_staleness {
+        diagnostics =
+            diagnostics.with_max_staleness(Duration::from_secs(args.requeue_duration) * n);
+    }
+    let state = State {
+        registry,
+        diagnostics: Arc::new(RwLock::new(diagnostics)),
+        nodes: Default::default(),
+    };
 
     tracing::info!("initializing kubernetes client");
-    let client = match kube::Client::try_default().await {
+    let client = match args.client.client().await {
         Ok(client) => client,
         Err(e) => {
             error!({ error = e.to_string() }, "unable to create kube client");
@@ -66,34 +545,266 @@ async fn main() -> ExitCode {
         }
     };
 
-    let app = Router::new()
-        .route("/health", get(health))
-        .route("/metrics", get(metrics))
-        .with_state(state.clone());
-    let listener = TcpListener::bind("0.0.0.0:8080").await.unwrap();
-    let server = axum::serve(listener, app)
-        .with_graceful_shutdown(async {
-            tokio::signal::ctrl_c().await.unwrap();
+    #[cfg(feature = "wasm")]
+    let transforms = match plugins(args.plugin.unwrap_or_default()) {
+        Ok(transforms) => transforms,
+        Err(e) => {
+            error!({ error = e.to_string() }, "unable to load plugins");
+            return ExitCode::FAILURE;
+        }
+    };
+    #[cfg(not(feature = "wasm"))]
+    let transforms = vec![];
+
+    let maps = match args.templates.maps() {
+        Ok(maps) => maps,
+        Err(e) => {
+            error!({ error = e.to_string() }, "unable to load maps");
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let change_windows = match args
+        .change_window
+        .iter()
+        .flatten()
+        .map(|w| ChangeWindow::new(w, Duration::from_secs(args.change_window_duration)))
+        .collect::<Result<Vec<_>, _>>()
+    {
+        Ok(windows) => windows,
+        Err(e) => {
+            error!({ error = e.to_string() }, "invalid change window");
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let resync_schedule = match args
+        .resync_schedule
+        .as_deref()
+        .map(Schedule::new)
+        .transpose()
+    {
+        Ok(schedule) => schedule,
+        Err(e) => {
+            error!({ error = e.to_string() }, "invalid resync schedule");
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let watcher_backoff = match [
+        args.watcher_backoff_initial,
+        args.watcher_backoff_max,
+        args.watcher_backoff_reset,
+    ]
+    .map(Duration::try_from_secs_f64)
+    {
+        [Ok(initial), Ok(max), Ok(reset)] => controller::WatcherBackoff {
+            initial,
+            max,
+            reset,
+        },
+        _ => {
+            error!("invalid watcher backoff, expected non-negative seconds");
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let hook_timeout = Duration::from_secs(args.hook_timeout);
+    let mut patch_hooks: Vec<Arc<dyn PatchHook>> = vec![];
+    if let Some(program) = args.hook_exec {
+        patch_hooks.push(Arc::new(hooks::ExecHook::new(program, hook_timeout)));
+    }
+    if let Some(url) = args.hook_url {
+        match hooks::WebhookHook::new(url, hook_timeout) {
+            Ok(hook) => patch_hooks.push(Arc::new(hook)),
+            Err(e) => {
+                error!({ error = e.to_string() }, "unable to configure hook");
+                return ExitCode::FAILURE;
+            }
+        }
+    }
+    if let Some(url) = args.notify_url {
+        let secret = match args
+            .notify_secret_file
+            .as_deref()
+            .map(std::fs::read_to_string)
+        {
+            Some(Ok(secret)) => Some(secret.trim_end().to_string()),
+            Some(Err(e)) => {
+                error!({ error = e.to_string() }, "unable to read notify secret");
+                return ExitCode::FAILURE;
+            }
+            None => None,
+        };
+        let secret = secret.as_deref().map(str::as_bytes);
+        match hooks::NotifyHook::new(url, secret, args.notify_retries, hook_timeout) {
+            Ok(hook) => patch_hooks.push(Arc::new(hook)),
+            Err(e) => {
+                error!(
+                    { error = e.to_string() },
+                    "unable to configure notifications"
+                );
+                return ExitCode::FAILURE;
+            }
+        }
+    }
+
+    #[cfg(feature = "azure")]
+    let azure = if args.azure_enrichment {
+        match azure::AzureEnricher::from_env(
+            args.azure_tag.unwrap_or_default(),
+            Duration::from_secs(args.azure_cache_ttl),
+        ) {
+            Ok(azure) => Some(azure),
+            Err(e) => {
+                error!(
+                    { error = e.to_string() },
+                    "unable to configure azure enrichment"
+                );
+                return ExitCode::FAILURE;
+            }
+        }
+    } else {
+        None
+    };
+
+    let exporter = args
+        .export_configmap
+        .map(|name| export::Exporter::new(client.clone(), args.export_configmap_namespace, name));
+
+    let otlp = match args
+        .otlp_endpoint
+        .map(|endpoint| {
+            otlp::OtlpExporter::new(
+                &endpoint,
+                &args.otlp_header.unwrap_or_default(),
+                state.registry.clone(),
+            )
         })
-        .into_future()
-        .map_err(Error::from);
-    let controller = controller::run(
+        .transpose()
+    {
+        Ok(otlp) => otlp,
+        Err(e) => {
+            error!({ error = e.to_string() }, "unable to configure otlp export");
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let pushgateway = match args
+        .pushgateway_url
+        .map(|url| {
+            pushgateway::PushgatewayExporter::new(
+                &url,
+                &args.pushgateway_job,
+                args.pushgateway_instance.as_deref(),
+                state.registry.clone(),
+            )
+        })
+        .transpose()
+    {
+        Ok(pushgateway) => pushgateway,
+        Err(e) => {
+            error!(
+                { error = e.to_string() },
+                "unable to configure pushgateway export"
+            );
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let shutdown = shutdown::Shutdown::install();
+    let server = server::serve(args.server, state.clone(), log_filter, shutdown.clone());
+    let controller = node_provider_labeler::run(controller::Options {
         client,
         state,
-        args.label,
-        args.annotation,
-        args.requeue_duration,
-    );
+        shutdown: shutdown.clone(),
+        label_templates: args.templates.label,
+        annotation_templates: args.templates.annotation,
+        taint_templates: args.templates.taint,
+        sinks: vec![],
+        sources: source::defaults(),
+        env_allow: args.env_allow.unwrap_or_default(),
+        transforms,
+        maps,
+        hooks: patch_hooks,
+        requeue_duration: args.requeue_duration,
+        #[cfg(feature = "azure")]
+        azure,
+        enrichers: vec![],
+        enrichment_timeout: Duration::from_secs(args.enrichment_timeout),
+        label_machines: args.label_machines,
+        backup_originals: args.backup_originals,
+        record_history: args.record_history,
+        track_provider_id: args.track_provider_id,
+        stale_policy: args.stale_policy.into(),
+        require_node_ready: args.require_node_ready,
+        min_node_age: args.min_node_age.map(Duration::from_secs),
+        skip_unschedulable: args.skip_unschedulable,
+        report_only: args.report_only,
+        checksum: args.checksum,
+        canary: args
+            .canary_percent
+            .map(CanarySize::Percent)
+            .or(args.canary_nodes.map(CanarySize::Nodes))
+            .map(|size| CanaryOptions {
+                size,
+                window: Duration::from_secs(args.canary_window),
+                max_error_rate: args.canary_max_error_rate,
+            }),
+        change_windows,
+        startup_patches_per_minute: args.startup_patches_per_minute,
+        max_patch_rate: args.max_patch_rate,
+        quarantine: args
+            .quarantine_after
+            .map(|after_failures| QuarantineOptions {
+                after_failures,
+                duration: Duration::from_secs(args.quarantine_duration),
+            }),
+        provider_id_template: args.provider_id_template,
+        topology_fallback: args.topology_fallback,
+        watch_timeout: args.client.watch_timeout(),
+        streaming_list: args.streaming_list,
+        watcher_backoff,
+        poll_interval: args.poll_interval.map(Duration::from_secs),
+        resync_schedule,
+        strip_cached_nodes: !args.cache_full_nodes,
+        node_selector: None,
+        conflict_retries: args.conflict_retries,
+        exporter,
+        drain_timeout: Duration::from_secs(args.drain_timeout),
+        metrics_max_nodes: args.metrics_per_node.then_some(args.metrics_max_nodes),
+        reconcile_duration_buckets: args.reconcile_duration_buckets,
+        readiness_interval: Duration::from_secs(args.readiness_interval),
+        max_controller_errors: args.max_controller_errors,
+        controller_error_window: Duration::from_secs(args.controller_error_window),
+    });
 
     tracing::info!("starting controller");
     tracing::info!("starting server");
 
+    let otlp_interval = Duration::from_secs(args.otlp_interval);
+    let otlp_shutdown = shutdown.clone();
+    let otlp = tokio::spawn(async move {
+        match otlp {
+            Some(otlp) => otlp.run(otlp_interval, otlp_shutdown).await,
+            None => Ok(()),
+        }
+    });
+    let pushgateway_interval = Duration::from_secs(args.pushgateway_interval);
+    let pushgateway = tokio::spawn(async move {
+        match pushgateway {
+            Some(pushgateway) => pushgateway.run(pushgateway_interval, shutdown).await,
+            None => Ok(()),
+        }
+    });
     let controller = tokio::spawn(controller);
     let server = tokio::spawn(server);
 
     match tokio::try_join!(
         run_task("server", server),
-        run_task("controller", controller)
+        run_task("controller", controller),
+        run_task("otlp", otlp),
+        run_task("pushgateway", pushgateway)
     ) {
         Ok(_) => {}
         Err(_) => {
@@ -104,6 +815,54 @@ async fn main() -> ExitCode {
     ExitCode::SUCCESS
 }
 
+fn metrics_registry(prefix: Option<&str>) -> Result<prometheus::Registry, Error> {
+    let registry = match prefix.map(|p| p.trim_end_matches('_')) {
+        Some(prefix) => {
+            let valid = prefix
+                .chars()
+                .enumerate()
+                .all(|(i, c)| c.is_ascii_alphabetic() || c == '_' || (i > 0 && c.is_ascii_digit()));
+            if prefix.is_empty() || !valid {
+                return Err(Error::Config(format!("invalid metrics prefix '{prefix}'")));
+            }
+            prometheus::Registry::new_custom(Some(prefix.to_string()), None)?
+        }
+        None => prometheus::Registry::new(),
+    };
+
+    registry.register(Box::new(metrics::RuntimeCollector::new(
+        tokio::runtime::Handle::current(),
+    )))?;
+    #[cfg(target_os = "linux")]
+    registry.register(Box::new(
+        prometheus::process_collector::ProcessCollector::for_self(),
+    ))?;
+
+    Ok(registry)
+}
+
+/// Loads the --plugin modules, given as name=path.
+#[cfg(feature = "wasm")]
+fn plugins(
+    plugins: Vec<String>,
+) -> Result<Vec<Arc<dyn node_provider_labeler::transform::Transform>>, Error> {
+    use node_provider_labeler::wasm::WasmTransform;
+
+    let mut names = std::collections::HashSet::new();
+    plugins
+        .iter()
+        .map(|plugin| {
+            let (name, path) = plugin
+                .split_once('=')
+                .ok_or_else(|| Error::Config(format!("invalid plugin '{plugin}'")))?;
+            if !names.insert(name) {
+                return Err(Error::Config(format!("duplicate plugin '{name}'")));
+            }
+            Ok(Arc::new(WasmTransform::from_file(name, path)?) as Arc<_>)
+        })
+        .collect()
+}
+
 async fn run_task(name: &str, handle: JoinHandle<Result<(), Error>>) -> Result<(), Error> {
     match handle.await {
         Ok(Ok(())) => Ok(()),
@@ -117,27 +876,3 @@ async fn run_task(name: &str, handle: JoinHandle<Result<(), Error>>) -> Result<(
         }
     }
 }
-
-async fn metrics(extract::State(state): extract::State<State>) -> (StatusCode, Vec<u8>) {
-    let m = state.metrics();
-    let encoder = TextEncoder::new();
-    let mut buffer = vec![];
-    if let Err(e) = encoder.encode(&m, &mut buffer) {
-        warn!({ error = e.to_string() }, "error encoding metrics");
-        return (
-            StatusCode::INTERNAL_SERVER_ERROR,
-            "internal server error".into(),
-        );
-    }
-
-    (StatusCode::OK, buffer)
-}
-
-async fn health(extract::State(state): extract::State<State>) -> (StatusCode, &'static str) {
-    let err_count = state.diagnostics.write().await.error_count.refresh();
-    if err_count > 0 {
-        (StatusCode::INTERNAL_SERVER_ERROR, "Unhealthy")
-    } else {
-        (StatusCode::OK, "OK")
-    }
-}
@@ -32,14 +32,8 @@ impl Name {
         }
 
         // beginning and ending with an alphanumeric character ([a-z0-9A-Z])
-        if !s
-            .chars()
-            .next()
-            .map_or(false, |c| c.is_ascii_alphanumeric())
-            || !s
-                .chars()
-                .last()
-                .map_or(false, |c| c.is_ascii_alphanumeric())
+        if !s.chars().next().is_some_and(|c| c.is_ascii_alphanumeric())
+            || !s.chars().last().is_some_and(|c| c.is_ascii_alphanumeric())
         {
             return Err(eyre::eyre!(
                 "must start and end with an alphanumeric character"
@@ -57,6 +51,14 @@ impl Name {
     }
 }
 
+/// Checks a label value: empty, or a valid [`Name`].
+pub(crate) fn validate_label_value(s: &str) -> eyre::Result<()> {
+    if s.is_empty() {
+        return Ok(());
+    }
+    Name::validate(s)
+}
+
 #[derive(Clone, Debug, PartialEq)]
 pub struct Prefix(String);
 
@@ -106,11 +108,11 @@ impl Prefix {
             if !label
                 .chars()
                 .next()
-                .map_or(false, |c| c.is_ascii_alphanumeric())
+                .is_some_and(|c| c.is_ascii_alphanumeric())
                 || !label
                     .chars()
                     .last()
-                    .map_or(false, |c| c.is_ascii_alphanumeric())
+                    .is_some_and(|c| c.is_ascii_alphanumeric())
             {
                 return Err(eyre::eyre!(
                     "must start and end with an alphanumeric character"
@@ -414,4 +416,15 @@ mod tests {
             }
         }
     }
+
+    #[test]
+    fn test_validate_label_value() {
+        assert!(validate_label_value("").is_ok());
+        assert!(validate_label_value("us-east-2a").is_ok());
+        assert_eq!(
+            validate_label_value("us east").unwrap_err().to_string(),
+            "invalid character ' '"
+        );
+        assert!(validate_label_value("truncated-").is_err());
+    }
 }
@@ -1,13 +1,109 @@
-use prometheus::{HistogramVec, IntCounter, IntCounterVec, Opts};
+use crate::Error;
+use prometheus::{
+    core::{Collector, Desc},
+    proto::MetricFamily,
+    CounterVec, Gauge, HistogramVec, IntCounter, IntCounterVec, IntGauge, IntGaugeVec, Opts,
+};
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
 use tokio::time::Instant;
+use tracing::warn;
+
+pub(crate) const DEFAULT_RECONCILE_DURATION_BUCKETS: &[f64] =
+    &[0.01, 0.1, 0.25, 0.5, 1., 5., 15., 60.];
+const DEFAULT_PATCH_DURATION_BUCKETS: &[f64] = &[0.005, 0.025, 0.1, 0.25, 0.5, 1., 2.5, 10.];
+const DEFAULT_RENDER_DURATION_BUCKETS: &[f64] = &[0.0001, 0.001, 0.01, 0.1, 1.];
+// node label value used once the per-node cardinality cap is reached
+const OVERFLOW_NODE: &str = "_other";
 
 #[derive(Clone)]
 pub(crate) struct Metrics {
     pub reconciliations: IntCounter,
-    pub reconciliation_failures: IntCounter,
+    pub reconciliation_failures: IntCounterVec,
     pub controller_failures: IntCounterVec,
     pub object_not_found: IntCounter,
     pub reconcile_duration: HistogramVec,
+    pub nodes_without_provider_id: IntGauge,
+    pub nodes_by_provider: IntGaugeVec,
+    pub quarantined_nodes: IntGauge,
+    pub drift_detected: IntGaugeVec,
+    pub patches: IntCounterVec,
+    pub patch_errors: IntCounterVec,
+    pub patch_rate: Gauge,
+    pub patch_duration: HistogramVec,
+    pub key_conflicts: IntCounterVec,
+    pub render_duration: HistogramVec,
+    pub render_failures: IntCounterVec,
+    pub heartbeats: IntCounter,
+    pub last_heartbeat: IntGauge,
+    pub nodes: Option<NodeMetrics>,
+    missing_provider_ids: Arc<Mutex<HashSet<String>>>,
+    // the provider and parse outcome each node is counted under
+    node_providers: Arc<Mutex<HashMap<String, (String, &'static str)>>>,
+    // the keys each node drifted on
+    node_drift: Arc<Mutex<HashMap<String, HashSet<String>>>>,
+}
+
+/// Reconciliation counters labeled by node, capped to a maximum number of
+/// distinct nodes. Reconciliations of nodes beyond the cap are counted under
+/// a single overflow node, and in `overflows`, so the cap being reached can be
+/// alerted on.
+#[derive(Clone)]
+pub(crate) struct NodeMetrics {
+    pub reconciliations: IntCounterVec,
+    pub reconciliation_failures: IntCounterVec,
+    pub overflows: IntCounter,
+    max_nodes: usize,
+    seen: Arc<Mutex<HashSet<String>>>,
+}
+
+impl NodeMetrics {
+    pub(crate) fn new(max_nodes: usize) -> Self {
+        Self {
+            reconciliations: IntCounterVec::new(
+                Opts::new("node_reconciliations", "Number of reconciliations per node"),
+                &["node"],
+            )
+            .unwrap(),
+            reconciliation_failures: IntCounterVec::new(
+                Opts::new(
+                    "node_reconciliation_failures",
+                    "Number of reconciliation failures per node",
+                ),
+                &["node"],
+            )
+            .unwrap(),
+            overflows: IntCounter::new(
+                "node_metrics_overflows_total",
+                "Number of per-node observations counted under the overflow node",
+            )
+            .unwrap(),
+            max_nodes,
+            seen: Arc::new(Mutex::new(HashSet::new())),
+        }
+    }
+
+    fn node_label<'a>(&self, node: &'a str) -> &'a str {
+        let mut seen = self.seen.lock().unwrap();
+        if seen.contains(node) {
+            return node;
+        }
+        if seen.len() < self.max_nodes {
+            seen.insert(node.to_string());
+            return node;
+        }
+        if self.overflows.get() == 0 {
+            warn!(
+                { max_nodes = self.max_nodes, node },
+                "per-node metrics cap reached, counting further nodes as {OVERFLOW_NODE}"
+            );
+        }
+        self.overflows.inc();
+        OVERFLOW_NODE
+    }
 }
 
 impl Default for Metrics {
@@ -15,9 +111,12 @@ impl Default for Metrics {
         Self {
             reconciliations: IntCounter::new("reconciliations", "Number of reconciliations")
                 .unwrap(),
-            reconciliation_failures: IntCounter::new(
-                "reconciliation_failures",
-                "Number of reconciliation failures",
+            reconciliation_failures: IntCounterVec::new(
+                Opts::new(
+                    "reconciliation_failures",
+                    "Number of reconciliation failures",
+                ),
+                &["kind"],
             )
             .unwrap(),
             controller_failures: IntCounterVec::new(
@@ -30,17 +129,130 @@ impl Default for Metrics {
                 "Number of object not found errors",
             )
             .unwrap(),
-            reconcile_duration: HistogramVec::new(
-                prometheus::HistogramOpts::new("reconcile_duration", "Reconciliation duration")
-                    .buckets(vec![0.01, 0.1, 0.25, 0.5, 1., 5., 15., 60.]),
-                &[],
+            reconcile_duration: reconcile_duration(DEFAULT_RECONCILE_DURATION_BUCKETS.to_vec())
+                .unwrap(),
+            nodes_without_provider_id: IntGauge::new(
+                "nodes_without_provider_id",
+                "Number of nodes without a provider ID",
+            )
+            .unwrap(),
+            quarantined_nodes: IntGauge::new(
+                "quarantined_nodes",
+                "Number of nodes quarantined for failing reconciliation repeatedly",
+            )
+            .unwrap(),
+            drift_detected: IntGaugeVec::new(
+                Opts::new(
+                    "drift_detected",
+                    "Number of nodes whose value of a key differs from the rendered one",
+                ),
+                &["key"],
+            )
+            .unwrap(),
+            nodes_by_provider: IntGaugeVec::new(
+                Opts::new(
+                    "nodes_by_provider",
+                    "Number of nodes by provider and provider ID parse outcome",
+                ),
+                &["provider", "outcome"],
+            )
+            .unwrap(),
+            patches: IntCounterVec::new(
+                Opts::new("patches_total", "Number of metadata patches"),
+                &["object", "result"],
+            )
+            .unwrap(),
+            patch_errors: IntCounterVec::new(
+                Opts::new("patch_errors_total", "Number of failed metadata patches"),
+                &["object", "type"],
+            )
+            .unwrap(),
+            patch_duration: HistogramVec::new(
+                prometheus::HistogramOpts::new(
+                    "patch_duration_seconds",
+                    "Duration of metadata patch requests",
+                )
+                .buckets(DEFAULT_PATCH_DURATION_BUCKETS.to_vec()),
+                &["object", "outcome"],
+            )
+            .unwrap(),
+            patch_rate: Gauge::new(
+                "patch_rate",
+                "Patches per second allowed by the adaptive patch rate",
+            )
+            .unwrap(),
+            key_conflicts: IntCounterVec::new(
+                Opts::new(
+                    "key_conflicts_total",
+                    "Number of rendered values a later sink overwrote",
+                ),
+                &["object"],
+            )
+            .unwrap(),
+            render_duration: HistogramVec::new(
+                prometheus::HistogramOpts::new(
+                    "render_duration_seconds",
+                    "Duration of rendering the values of an object",
+                )
+                .buckets(DEFAULT_RENDER_DURATION_BUCKETS.to_vec()),
+                &["object"],
+            )
+            .unwrap(),
+            render_failures: IntCounterVec::new(
+                Opts::new(
+                    "render_failures_total",
+                    "Number of failures rendering a key's value",
+                ),
+                &["object", "key"],
+            )
+            .unwrap(),
+            heartbeats: IntCounter::new(
+                "controller_heartbeats_total",
+                "Number of controller loop heartbeats",
             )
             .unwrap(),
+            last_heartbeat: IntGauge::new(
+                "controller_last_heartbeat_timestamp_seconds",
+                "Unix time of the last controller loop heartbeat",
+            )
+            .unwrap(),
+            nodes: None,
+            missing_provider_ids: Arc::new(Mutex::new(HashSet::new())),
+            node_providers: Arc::new(Mutex::new(HashMap::new())),
+            node_drift: Arc::new(Mutex::new(HashMap::new())),
         }
     }
 }
 
+fn reconcile_duration(buckets: Vec<f64>) -> Result<HistogramVec, prometheus::Error> {
+    if buckets.is_empty() || buckets.windows(2).any(|w| w[0] >= w[1]) {
+        return Err(prometheus::Error::Msg(
+            "histogram buckets must be non-empty and increasing".into(),
+        ));
+    }
+    HistogramVec::new(
+        prometheus::HistogramOpts::new("reconcile_duration", "Reconciliation duration")
+            .buckets(buckets),
+        &[],
+    )
+}
+
 impl Metrics {
+    /// Overrides the reconcile_duration histogram buckets
+    pub(crate) fn with_reconcile_duration_buckets(
+        mut self,
+        buckets: Vec<f64>,
+    ) -> Result<Self, prometheus::Error> {
+        self.reconcile_duration = reconcile_duration(buckets)?;
+        Ok(self)
+    }
+
+    /// Enables per-node counters for up to `max_nodes` distinct nodes
+    pub(crate) fn with_node_metrics(mut self, max_nodes: usize) -> Self {
+        self.nodes = Some(NodeMetrics::new(max_nodes));
+        self
+    }
+
     pub(crate) fn register(
         self,
         registry: &prometheus::Registry,
@@ -49,19 +261,62 @@ impl Metrics {
         registry.register(Box::new(self.reconciliation_failures.clone()))?;
         registry.register(Box::new(self.reconcile_duration.clone()))?;
         registry.register(Box::new(self.controller_failures.clone()))?;
+        registry.register(Box::new(self.object_not_found.clone()))?;
+        registry.register(Box::new(self.nodes_without_provider_id.clone()))?;
+        registry.register(Box::new(self.nodes_by_provider.clone()))?;
+        registry.register(Box::new(self.quarantined_nodes.clone()))?;
+        registry.register(Box::new(self.drift_detected.clone()))?;
+        registry.register(Box::new(self.patches.clone()))?;
+        registry.register(Box::new(self.patch_errors.clone()))?;
+        registry.register(Box::new(self.patch_rate.clone()))?;
+        registry.register(Box::new(self.patch_duration.clone()))?;
+        registry.register(Box::new(self.key_conflicts.clone()))?;
+        registry.register(Box::new(self.render_duration.clone()))?;
+        registry.register(Box::new(self.render_failures.clone()))?;
+        registry.register(Box::new(self.heartbeats.clone()))?;
+        registry.register(Box::new(self.last_heartbeat.clone()))?;
+        if let Some(nodes) = &self.nodes {
+            registry.register(Box::new(nodes.reconciliations.clone()))?;
+            registry.register(Box::new(nodes.reconciliation_failures.clone()))?;
+            registry.register(Box::new(nodes.overflows.clone()))?;
+        }
         Ok(self)
     }
 
-    pub(crate) fn observe_reconciliation(&self) -> ReconciliationTimer {
+    pub(crate) fn observe_reconciliation(&self, node: &str) -> ReconciliationTimer {
         self.reconciliations.inc();
+        if let Some(nodes) = &self.nodes {
+            nodes
+                .reconciliations
+                .with_label_values(&[nodes.node_label(node)])
+                .inc();
+        }
         ReconciliationTimer {
             start: Instant::now(),
             metric: self.reconcile_duration.clone(),
+            span: tracing::Span::current(),
+        }
+    }
+
+    pub(crate) fn observe_reconciliation_failure(&self, node: &str, error: &Error) {
+        self.reconciliation_failures
+            .with_label_values(&[error.kind()])
+            .inc();
+        if let Some(nodes) = &self.nodes {
+            nodes
+                .reconciliation_failures
+                .with_label_values(&[nodes.node_label(node)])
+                .inc();
         }
     }
 
-    pub(crate) fn observe_reconciliation_failure(&self) {
-        self.reconciliation_failures.inc();
+    /// Records a tick of the controller loop, independent of node events
+    pub(crate) fn observe_heartbeat(&self) {
+        self.heartbeats.inc();
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap_or_default();
+        self.last_heartbeat.set(now.as_secs() as i64);
     }
 
     pub(crate) fn observe_controller_failure(&self, err_type: &str) {
@@ -70,19 +325,459 @@ impl Metrics {
             .inc();
     }
 
+    /// Records whether the node is missing a provider ID. Returns true if the
+    /// node wasn't already known to be missing one.
+    pub(crate) fn observe_missing_provider_id(&self, node: &str, missing: bool) -> bool {
+        let mut nodes = self.missing_provider_ids.lock().unwrap();
+        let changed = if missing {
+            nodes.insert(node.to_string())
+        } else {
+            nodes.remove(node);
+            false
+        };
+        self.nodes_without_provider_id.set(nodes.len() as i64);
+        changed
+    }
+
+    /// Counts the node under its provider and the outcome of parsing its
+    /// provider ID, or stops counting it if it's gone.
+    pub(crate) fn observe_node_provider(&self, node: &str, provider: Option<(&str, &'static str)>) {
+        let mut nodes = self.node_providers.lock().unwrap();
+        let new = provider.map(|(provider, outcome)| (provider.to_string(), outcome));
+        if nodes.get(node) == new.as_ref() {
+            return;
+        }
+        let old = match new {
+            Some(new) => nodes.insert(node.to_string(), new),
+            None => nodes.remove(node),
+        };
+        if let Some((provider, outcome)) = old {
+            self.nodes_by_provider
+                .with_label_values(&[&provider, outcome])
+                .dec();
+        }
+        if let Some((provider, outcome)) = provider {
+            self.nodes_by_provider
+                .with_label_values(&[provider, outcome])
+                .inc();
+        }
+    }
+
+    /// Records the result of patching an object's metadata
+    pub(crate) fn observe_patch<T>(&self, object: &str, result: &Result<T, Error>) {
+        match result {
+            Ok(_) => self.patches.with_label_values(&[object, "success"]).inc(),
+            Err(e) => {
+                self.patches.with_label_values(&[object, "error"]).inc();
+                self.patch_errors
+                    .with_label_values(&[object, patch_error_type(e)])
+                    .inc();
+            }
+        }
+    }
+
+    /// Records how long a single patch request took, labeled by its outcome:
+    /// "success" or the error type.
+    pub(crate) fn observe_patch_latency<T>(
+        &self,
+        object: &str,
+        duration: Duration,
+        result: &Result<T, kube::Error>,
+    ) {
+        let outcome = match result {
+            Ok(_) => "success",
+            Err(kube::Error::Api(e)) => api_error_type(e.code),
+            Err(_) => "transport",
+        };
+        self.patch_duration
+            .with_label_values(&[object, outcome])
+            .observe(duration.as_secs_f64());
+    }
+
+    pub(crate) fn observe_quarantined_nodes(&self, nodes: usize) {
+        self.quarantined_nodes.set(nodes as i64);
+    }
+
+    /// Records the keys whose values on the node differ from the rendered
+    /// ones, replacing the keys recorded for it before.
+    pub(crate) fn observe_drift(&self, node: &str, keys: &[String]) {
+        let mut nodes = self.node_drift.lock().unwrap();
+        let new = keys.iter().cloned().collect::<HashSet<_>>();
+        let old = if new.is_empty() {
+            nodes.remove(node)
+        } else {
+            nodes.insert(node.to_string(), new.clone())
+        }
+        .unwrap_or_default();
+        for key in old.difference(&new) {
+            self.drift_detected.with_label_values(&[key]).dec();
+        }
+        for key in new.difference(&old) {
+            self.drift_detected.with_label_values(&[key]).inc();
+        }
+    }
+
+    pub(crate) fn observe_patch_rate(&self, rate: f64) {
+        self.patch_rate.set(rate);
+    }
+
+    pub(crate) fn observe_key_conflicts(&self, object: &str, conflicts: usize) {
+        if conflicts > 0 {
+            self.key_conflicts
+                .with_label_values(&[object])
+                .inc_by(conflicts as u64);
+        }
+    }
+
     pub(crate) fn observe_object_not_found_error(&self) {
         self.object_not_found.inc();
     }
+
+    /// Records how long rendering an object's values took, and the key that
+    /// failed to render, empty if the failure wasn't tied to one.
+    pub(crate) fn observe_render(
+        &self,
+        object: &str,
+        duration: Duration,
+        failed_key: Option<&str>,
+    ) {
+        self.render_duration
+            .with_label_values(&[object])
+            .observe(duration.as_secs_f64());
+        if let Some(key) = failed_key {
+            self.render_failures.with_label_values(&[object, key]).inc();
+        }
+    }
 }
 
+fn patch_error_type(e: &Error) -> &'static str {
+    match e {
+        Error::Kube(kube::Error::Api(e)) => api_error_type(e.code),
+        Error::Kube(_) => "transport",
+        _ => "other",
+    }
+}
+
+fn api_error_type(code: u16) -> &'static str {
+    match code {
+        409 => "conflict",
+        403 => "forbidden",
+        404 => "not_found",
+        422 => "invalid",
+        429 => "throttled",
+        500..=599 => "server",
+        _ => "api",
+    }
+}
+
+/// Observes the reconciliation duration when dropped, and records it as
+/// `duration_ms` on the span the reconciliation started in.
 pub struct ReconciliationTimer {
     start: Instant,
     metric: HistogramVec,
+    span: tracing::Span,
 }
 
 impl Drop for ReconciliationTimer {
     fn drop(&mut self) {
-        let elapsed = self.start.elapsed().as_millis() as f64 / 1000.0;
-        self.metric.with_label_values(&[]).observe(elapsed);
+        let elapsed = self.start.elapsed().as_millis();
+        self.metric
+            .with_label_values(&[])
+            .observe(elapsed as f64 / 1000.0);
+        self.span.record("duration_ms", elapsed as u64);
+    }
+}
+
+/// Exposes tokio runtime metrics, read from the runtime on every gather.
+/// Worker utilization is the rate of `tokio_worker_busy_seconds_total`.
+pub struct RuntimeCollector {
+    handle: tokio::runtime::Handle,
+    workers: IntGauge,
+    alive_tasks: IntGauge,
+    global_queue_depth: IntGauge,
+    worker_busy: CounterVec,
+    worker_parks: IntCounterVec,
+}
+
+impl RuntimeCollector {
+    /// Creates a collector for the runtime behind `handle`.
+    pub fn new(handle: tokio::runtime::Handle) -> Self {
+        Self {
+            handle,
+            workers: IntGauge::new("tokio_workers", "Number of tokio runtime worker threads")
+                .unwrap(),
+            alive_tasks: IntGauge::new("tokio_alive_tasks", "Number of alive tokio tasks").unwrap(),
+            global_queue_depth: IntGauge::new(
+                "tokio_global_queue_depth",
+                "Number of tasks in the tokio runtime's global queue",
+            )
+            .unwrap(),
+            worker_busy: CounterVec::new(
+                Opts::new(
+                    "tokio_worker_busy_seconds_total",
+                    "Time tokio worker threads spent busy",
+                ),
+                &["worker"],
+            )
+            .unwrap(),
+            worker_parks: IntCounterVec::new(
+                Opts::new(
+                    "tokio_worker_parks_total",
+                    "Number of times tokio worker threads parked",
+                ),
+                &["worker"],
+            )
+            .unwrap(),
+        }
+    }
+}
+
+impl Collector for RuntimeCollector {
+    fn desc(&self) -> Vec<&Desc> {
+        [
+            self.workers.desc(),
+            self.alive_tasks.desc(),
+            self.global_queue_depth.desc(),
+            self.worker_busy.desc(),
+            self.worker_parks.desc(),
+        ]
+        .concat()
+    }
+
+    fn collect(&self) -> Vec<MetricFamily> {
+        let metrics = self.handle.metrics();
+        self.workers.set(metrics.num_workers() as i64);
+        self.alive_tasks.set(metrics.num_alive_tasks() as i64);
+        self.global_queue_depth
+            .set(metrics.global_queue_depth() as i64);
+
+        // the runtime reports running totals, so advance the counters by
+        // the difference
+        for worker in 0..metrics.num_workers() {
+            let label = worker.to_string();
+            let busy = self.worker_busy.with_label_values(&[&label]);
+            let total = metrics.worker_total_busy_duration(worker).as_secs_f64();
+            busy.inc_by((total - busy.get()).max(0.0));
+            let parks = self.worker_parks.with_label_values(&[&label]);
+            parks.inc_by(
+                metrics
+                    .worker_park_count(worker)
+                    .saturating_sub(parks.get()),
+            );
+        }
+
+        [
+            self.workers.collect(),
+            self.alive_tasks.collect(),
+            self.global_queue_depth.collect(),
+            self.worker_busy.collect(),
+            self.worker_parks.collect(),
+        ]
+        .concat()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_node_metrics_cap() {
+        let metrics = Metrics::default().with_node_metrics(2);
+        let registry = prometheus::Registry::new();
+        let metrics = metrics.register(&registry).unwrap();
+
+        for node in ["node-a", "node-b", "node-c", "node-a", "node-d"] {
+            let _timer = metrics.observe_reconciliation(node);
+        }
+        let error = Error::MissingField("azure:sku".into());
+        metrics.observe_reconciliation_failure("node-b", &error);
+        metrics.observe_reconciliation_failure("node-c", &error);
+
+        let nodes = metrics.nodes.as_ref().unwrap();
+        let count = |vec: &IntCounterVec, node: &str| vec.with_label_values(&[node]).get();
+        assert_eq!(count(&nodes.reconciliations, "node-a"), 2);
+        assert_eq!(count(&nodes.reconciliations, "node-b"), 1);
+        assert_eq!(count(&nodes.reconciliations, OVERFLOW_NODE), 2);
+        assert_eq!(count(&nodes.reconciliation_failures, "node-b"), 1);
+        assert_eq!(count(&nodes.reconciliation_failures, OVERFLOW_NODE), 1);
+        assert_eq!(count(&metrics.reconciliation_failures, "template"), 2);
+        assert_eq!(metrics.reconciliations.get(), 5);
+        assert_eq!(nodes.overflows.get(), 3);
+    }
+
+    #[test]
+    fn test_reconcile_duration_buckets() {
+        let registry = prometheus::Registry::new_custom(Some("npl".into()), None).unwrap();
+        let metrics = Metrics::default()
+            .with_reconcile_duration_buckets(vec![0.5, 1.0])
+            .unwrap()
+            .register(&registry)
+            .unwrap();
+        drop(metrics.observe_reconciliation("node-a"));
+
+        let families = registry.gather();
+        let family = families
+            .iter()
+            .find(|f| f.get_name() == "npl_reconcile_duration")
+            .unwrap();
+        let buckets = family.get_metric()[0].get_histogram().get_bucket();
+        assert_eq!(
+            buckets
+                .iter()
+                .map(|b| b.get_upper_bound())
+                .collect::<Vec<_>>(),
+            vec![0.5, 1.0]
+        );
+        assert!(families.iter().all(|f| f.get_name().starts_with("npl_")));
+
+        // buckets must be increasing
+        assert!(Metrics::default()
+            .with_reconcile_duration_buckets(vec![1.0, 0.5])
+            .is_err());
+    }
+
+    #[test]
+    fn test_observe_patch() {
+        let metrics = Metrics::default();
+        let api_error = |code| {
+            Err::<(), _>(Error::Kube(kube::Error::Api(kube::core::ErrorResponse {
+                status: "Failure".into(),
+                message: "error".into(),
+                reason: "Testing".into(),
+                code,
+            })))
+        };
+
+        metrics.observe_patch("node", &Ok::<_, Error>(()));
+        metrics.observe_patch("node", &api_error(409));
+        metrics.observe_patch("node", &api_error(422));
+        metrics.observe_patch("machine", &api_error(503));
+
+        let count = |vec: &IntCounterVec, labels: &[&str]| vec.with_label_values(labels).get();
+        assert_eq!(count(&metrics.patches, &["node", "success"]), 1);
+        assert_eq!(count(&metrics.patches, &["node", "error"]), 2);
+        assert_eq!(count(&metrics.patches, &["machine", "error"]), 1);
+        assert_eq!(count(&metrics.patch_errors, &["node", "conflict"]), 1);
+        assert_eq!(count(&metrics.patch_errors, &["node", "invalid"]), 1);
+        assert_eq!(count(&metrics.patch_errors, &["machine", "server"]), 1);
+
+        metrics.observe_patch_latency("node", Duration::from_millis(20), &Ok(()));
+        let kube_error = |code| match api_error(code) {
+            Err(Error::Kube(e)) => Err::<(), _>(e),
+            _ => unreachable!(),
+        };
+        metrics.observe_patch_latency("node", Duration::from_secs(3), &kube_error(429));
+        let samples = |labels: &[&str]| {
+            metrics
+                .patch_duration
+                .with_label_values(labels)
+                .get_sample_count()
+        };
+        assert_eq!(samples(&["node", "success"]), 1);
+        assert_eq!(samples(&["node", "throttled"]), 1);
+    }
+
+    #[test]
+    fn test_observe_render() {
+        let metrics = Metrics::default();
+        metrics.observe_render("node", Duration::from_millis(2), None);
+        metrics.observe_render("node", Duration::from_millis(5), Some("zone"));
+        metrics.observe_render("machine", Duration::from_millis(5), Some("zone"));
+
+        let histogram = metrics.render_duration.with_label_values(&["node"]);
+        assert_eq!(histogram.get_sample_count(), 2);
+        let failures = |labels: &[&str]| metrics.render_failures.with_label_values(labels).get();
+        assert_eq!(failures(&["node", "zone"]), 1);
+        assert_eq!(failures(&["machine", "zone"]), 1);
+    }
+
+    #[test]
+    fn test_missing_provider_id() {
+        let metrics = Metrics::default();
+
+        assert!(metrics.observe_missing_provider_id("node-a", true));
+        assert!(!metrics.observe_missing_provider_id("node-a", true));
+        assert!(metrics.observe_missing_provider_id("node-b", true));
+        assert_eq!(metrics.nodes_without_provider_id.get(), 2);
+
+        assert!(!metrics.observe_missing_provider_id("node-a", false));
+        assert_eq!(metrics.nodes_without_provider_id.get(), 1);
+
+        // missing again after being fixed
+        assert!(metrics.observe_missing_provider_id("node-a", true));
+        assert_eq!(metrics.nodes_without_provider_id.get(), 2);
+    }
+
+    #[test]
+    fn test_observe_node_provider() {
+        let metrics = Metrics::default();
+        let count = |labels: &[&str]| metrics.nodes_by_provider.with_label_values(labels).get();
+
+        metrics.observe_node_provider("node-a", Some(("aws", "ok")));
+        metrics.observe_node_provider("node-a", Some(("aws", "ok")));
+        metrics.observe_node_provider("node-b", Some(("aws", "ok")));
+        metrics.observe_node_provider("node-c", Some(("", "missing")));
+        assert_eq!(count(&["aws", "ok"]), 2);
+        assert_eq!(count(&["", "missing"]), 1);
+
+        metrics.observe_node_provider("node-c", Some(("gce", "ok")));
+        metrics.observe_node_provider("node-b", None);
+        assert_eq!(count(&["aws", "ok"]), 1);
+        assert_eq!(count(&["gce", "ok"]), 1);
+        assert_eq!(count(&["", "missing"]), 0);
+    }
+
+    #[test]
+    fn test_observe_drift() {
+        let metrics = Metrics::default();
+        let count = |key: &str| metrics.drift_detected.with_label_values(&[key]).get();
+        let keys = |keys: &[&str]| keys.iter().map(|k| k.to_string()).collect::<Vec<_>>();
+
+        metrics.observe_drift("node-a", &keys(&["zone", "region"]));
+        metrics.observe_drift("node-a", &keys(&["zone", "region"]));
+        metrics.observe_drift("node-b", &keys(&["zone"]));
+        assert_eq!(count("zone"), 2);
+        assert_eq!(count("region"), 1);
+
+        metrics.observe_drift("node-a", &keys(&["zone"]));
+        metrics.observe_drift("node-b", &[]);
+        assert_eq!(count("zone"), 1);
+        assert_eq!(count("region"), 0);
+    }
+
+    #[tokio::test]
+    async fn test_runtime_collector() {
+        let registry = prometheus::Registry::new();
+        registry
+            .register(Box::new(RuntimeCollector::new(
+                tokio::runtime::Handle::current(),
+            )))
+            .unwrap();
+
+        let families = registry.gather();
+        let family = |name: &str| families.iter().find(|f| f.get_name() == name).unwrap();
+        assert_eq!(
+            family("tokio_workers").get_metric()[0]
+                .get_gauge()
+                .get_value(),
+            1.0
+        );
+        assert_eq!(
+            family("tokio_worker_parks_total").get_metric()[0].get_label()[0].get_value(),
+            "0"
+        );
+        assert!(families
+            .iter()
+            .any(|f| f.get_name() == "tokio_worker_busy_seconds_total"));
+    }
+
+    #[test]
+    fn test_observe_heartbeat() {
+        let metrics = Metrics::default();
+        metrics.observe_heartbeat();
+        metrics.observe_heartbeat();
+        assert_eq!(metrics.heartbeats.get(), 2);
+        assert!(metrics.last_heartbeat.get() > 0);
     }
 }
@@ -0,0 +1,99 @@
+//! Stands in for the Prometheus metrics when the `metrics` feature is
+//! disabled. Only the state the controller relies on beyond metrics, such as
+//! which nodes are missing a provider ID, is kept.
+
+use crate::Error;
+use std::{
+    collections::HashSet,
+    sync::{Arc, Mutex},
+};
+use tokio::time::Instant;
+
+#[derive(Clone, Default)]
+pub(crate) struct Metrics {
+    missing_provider_ids: Arc<Mutex<HashSet<String>>>,
+}
+
+impl Metrics {
+    pub(crate) fn with_reconcile_duration_buckets(self, _buckets: Vec<f64>) -> Result<Self, Error> {
+        Ok(self)
+    }
+
+    pub(crate) fn with_node_metrics(self, _max_nodes: usize) -> Self {
+        self
+    }
+
+    pub(crate) fn observe_reconciliation(&self, _node: &str) -> ReconciliationTimer {
+        ReconciliationTimer {
+            start: Instant::now(),
+            span: tracing::Span::current(),
+        }
+    }
+
+    pub(crate) fn observe_reconciliation_failure(&self, _node: &str, _error: &Error) {}
+
+    pub(crate) fn observe_heartbeat(&self) {}
+
+    pub(crate) fn observe_controller_failure(&self, _err_type: &str) {}
+
+    /// Records whether the node is missing a provider ID. Returns true if the
+    /// node wasn't already known to be missing one.
+    pub(crate) fn observe_missing_provider_id(&self, node: &str, missing: bool) -> bool {
+        let mut nodes = self.missing_provider_ids.lock().unwrap();
+        if missing {
+            nodes.insert(node.to_string())
+        } else {
+            nodes.remove(node);
+            false
+        }
+    }
+
+    pub(crate) fn observe_node_provider(
+        &self,
+        _node: &str,
+        _provider: Option<(&str, &'static str)>,
+    ) {
+    }
+
+    pub(crate) fn observe_patch<T>(&self, _object: &str, _result: &Result<T, Error>) {}
+
+    pub(crate) fn observe_patch_latency<T>(
+        &self,
+        _object: &str,
+        _duration: std::time::Duration,
+        _result: &Result<T, kube::Error>,
+    ) {
+    }
+
+    pub(crate) fn observe_quarantined_nodes(&self, _nodes: usize) {}
+
+    pub(crate) fn observe_drift(&self, _node: &str, _keys: &[String]) {}
+
+    pub(crate) fn observe_patch_rate(&self, _rate: f64) {}
+
+    pub(crate) fn observe_key_conflicts(&self, _object: &str, _conflicts: usize) {}
+
+    pub(crate) fn observe_object_not_found_error(&self) {}
+
+    pub(crate) fn observe_render(
+        &self,
+        _object: &str,
+        _duration: std::time::Duration,
+        _failed_key: Option<&str>,
+    ) {
+    }
+}
+
+/// Records the reconciliation duration as `duration_ms` on the span the
+/// reconciliation started in when dropped.
+pub struct ReconciliationTimer {
+    start: Instant,
+    span: tracing::Span,
+}
+
+impl Drop for ReconciliationTimer {
+    fn drop(&mut self) {
+        self.span
+            .record("duration_ms", self.start.elapsed().as_millis() as u64);
+    }
+}
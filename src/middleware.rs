@@ -0,0 +1,232 @@
+use http::{Method, Request, Response, StatusCode};
+use kube::client::Body;
+use std::{
+    future::Future,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+use tower::{retry::budget::Budget, BoxError, Layer, Service, ServiceExt};
+use tracing::{debug, info};
+
+type BoxFuture<T> = Pin<Box<dyn Future<Output = T> + Send>>;
+
+// how long requests count toward the retry budget
+const BUDGET_TTL: Duration = Duration::from_secs(10);
+
+/// Retries API requests that failed transiently with exponential backoff,
+/// while retries stay within a percentage of the recent requests. Needs a
+/// cloneable service, e.g. behind a buffer.
+#[derive(Clone, Debug)]
+pub(crate) struct RetryLayer {
+    retries: u32,
+    budget: Arc<Budget>,
+    backoff: Duration,
+}
+
+impl RetryLayer {
+    pub(crate) fn new(retries: u32, budget_percent: f32) -> Self {
+        Self {
+            retries,
+            budget: Arc::new(Budget::new(BUDGET_TTL, 1, budget_percent / 100.0)),
+            backoff: Duration::from_millis(100),
+        }
+    }
+}
+
+impl<S> Layer<S> for RetryLayer {
+    type Service = Retry<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        Retry {
+            inner,
+            layer: self.clone(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub(crate) struct Retry<S> {
+    inner: S,
+    layer: RetryLayer,
+}
+
+impl<S, B> Service<Request<Body>> for Retry<S>
+where
+    S: Service<Request<Body>, Response = Response<B>> + Clone + Send + 'static,
+    S::Future: Send,
+    S::Error: Into<BoxError>,
+    B: Send + 'static,
+{
+    type Response = Response<B>;
+    type Error = BoxError;
+    type Future = BoxFuture<Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx).map_err(Into::into)
+    }
+
+    fn call(&mut self, req: Request<Body>) -> Self::Future {
+        // the first attempt goes to the service that was polled ready
+        let clone = self.inner.clone();
+        let mut inner = std::mem::replace(&mut self.inner, clone);
+        let layer = self.layer.clone();
+
+        Box::pin(async move {
+            let (parts, body) = req.into_parts();
+            let body = body.collect_bytes().await?;
+            let request = || {
+                let mut req = Request::new(Body::from(body.to_vec()));
+                *req.method_mut() = parts.method.clone();
+                *req.uri_mut() = parts.uri.clone();
+                *req.version_mut() = parts.version;
+                *req.headers_mut() = parts.headers.clone();
+                *req.extensions_mut() = parts.extensions.clone();
+                req
+            };
+
+            layer.budget.deposit();
+            let mut result = inner.call(request()).await.map_err(Into::into);
+            let mut attempt = 0;
+            loop {
+                if attempt >= layer.retries
+                    || !transient(&parts.method, &result)
+                    || layer.budget.withdraw().is_err()
+                {
+                    return result;
+                }
+                let delay = layer.backoff * 2u32.saturating_pow(attempt);
+                debug!({ method = parts.method.as_str(), uri = parts.uri.to_string(), attempt }, "retrying API request in {delay:?}");
+                tokio::time::sleep(delay).await;
+                attempt += 1;
+
+                let ready = inner.ready().await.map_err(Into::into)?;
+                result = ready.call(request()).await.map_err(Into::into);
+            }
+        })
+    }
+}
+
+/// Throttled requests weren't processed, so any can be resent. Other
+/// failures are only retried for reads.
+fn transient<B>(method: &Method, result: &Result<Response<B>, BoxError>) -> bool {
+    let read = matches!(*method, Method::GET | Method::HEAD);
+    match result {
+        Ok(res) => {
+            res.status() == StatusCode::TOO_MANY_REQUESTS
+                || read
+                    && matches!(
+                        res.status(),
+                        StatusCode::BAD_GATEWAY
+                            | StatusCode::SERVICE_UNAVAILABLE
+                            | StatusCode::GATEWAY_TIMEOUT
+                    )
+        }
+        Err(_) => read,
+    }
+}
+
+/// Logs every API request with its response status and latency.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct LogLayer;
+
+impl<S> Layer<S> for LogLayer {
+    type Service = Log<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        Log { inner }
+    }
+}
+
+pub(crate) struct Log<S> {
+    inner: S,
+}
+
+impl<S, B> Service<Request<Body>> for Log<S>
+where
+    S: Service<Request<Body>, Response = Response<B>>,
+    S::Future: Send + 'static,
+    S::Error: Into<BoxError>,
+{
+    type Response = Response<B>;
+    type Error = BoxError;
+    type Future = BoxFuture<Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx).map_err(Into::into)
+    }
+
+    fn call(&mut self, req: Request<Body>) -> Self::Future {
+        let method = req.method().clone();
+        let uri = req.uri().to_string();
+        let start = Instant::now();
+        let response = self.inner.call(req);
+
+        Box::pin(async move {
+            let result = response.await.map_err(Into::into);
+            let elapsed_ms = start.elapsed().as_millis() as u64;
+            match &result {
+                Ok(res) => {
+                    info!({ method = method.as_str(), uri, status = res.status().as_u16(), elapsed_ms }, "API request")
+                }
+                Err(e) => {
+                    info!({ method = method.as_str(), uri, error = e.to_string(), elapsed_ms }, "API request failed")
+                }
+            }
+            result
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn status<S>(retry: &mut Retry<S>, method: Method) -> u16
+    where
+        Retry<S>: Service<Request<Body>, Response = Response<Body>, Error = BoxError>,
+    {
+        let req = Request::builder()
+            .method(method)
+            .uri("/api/v1/nodes")
+            .body(Body::from(b"{}".to_vec()))
+            .unwrap();
+        let res = retry.ready().await.unwrap().call(req).await.unwrap();
+        res.status().as_u16()
+    }
+
+    #[tokio::test]
+    async fn test_retry() {
+        let (service, mut handle) = tower_test::mock::pair::<Request<Body>, Response<Body>>();
+        let mut layer = RetryLayer::new(2, 100.0);
+        layer.backoff = Duration::ZERO;
+        let mut retry = layer.layer(service);
+
+        // answers each request with the next status, echoing the body
+        let statuses = [503, 200, 503, 503, 503, 429, 200, 500];
+        let server = tokio::spawn(async move {
+            let mut bodies = vec![];
+            for status in statuses {
+                let (req, send) = handle.next_request().await.unwrap();
+                bodies.push(req.into_body().collect_bytes().await.unwrap());
+                send.send_response(
+                    Response::builder()
+                        .status(status)
+                        .body(Body::empty())
+                        .unwrap(),
+                );
+            }
+            bodies
+        });
+        // reads are retried until they succeed, or up to the limit
+        assert_eq!(status(&mut retry, Method::GET).await, 200);
+        assert_eq!(status(&mut retry, Method::GET).await, 503);
+        // writes only when throttled
+        assert_eq!(status(&mut retry, Method::PATCH).await, 200);
+        assert_eq!(status(&mut retry, Method::PATCH).await, 500);
+
+        let bodies = server.await.unwrap();
+        assert!(bodies.iter().all(|b| b.as_ref() == b"{}"));
+    }
+}
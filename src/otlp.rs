@@ -0,0 +1,296 @@
+use node_provider_labeler::shutdown::Shutdown;
+use node_provider_labeler::Error;
+use prometheus::proto::{LabelPair, Metric, MetricFamily, MetricType};
+use serde_json::{json, Value};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tracing::{debug, warn};
+
+const SERVICE_NAME: &str = "node-provider-labeler";
+const METRICS_PATH: &str = "/v1/metrics";
+// OTLP aggregation temporality
+const CUMULATIVE: u8 = 2;
+
+/// Pushes the contents of the Prometheus registry to an OpenTelemetry
+/// collector using OTLP/HTTP with JSON encoding.
+#[derive(Debug)]
+pub(crate) struct OtlpExporter {
+    client: reqwest::Client,
+    url: String,
+    headers: Vec<(String, String)>,
+    registry: prometheus::Registry,
+    start: SystemTime,
+}
+
+impl OtlpExporter {
+    /// Creates an exporter for the collector at `endpoint`, e.g.
+    /// `http://otel-collector:4318`. Headers are given as `key=value`.
+    pub(crate) fn new(
+        endpoint: &str,
+        headers: &[String],
+        registry: prometheus::Registry,
+    ) -> Result<Self, Error> {
+        let endpoint = endpoint.trim_end_matches('/');
+        let url = if endpoint.ends_with(METRICS_PATH) {
+            endpoint.to_string()
+        } else {
+            format!("{endpoint}{METRICS_PATH}")
+        };
+
+        let headers = headers
+            .iter()
+            .map(|h| match h.split_once('=') {
+                Some((k, v)) if !k.trim().is_empty() => Ok((k.trim().into(), v.trim().into())),
+                _ => Err(Error::Config(format!(
+                    "invalid otlp header '{h}', expected key=value"
+                ))),
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(Self {
+            client: reqwest::Client::new(),
+            url,
+            headers,
+            registry,
+            start: SystemTime::now(),
+        })
+    }
+
+    /// Pushes metrics every interval, and a final time once shutdown is
+    /// requested.
+    pub(crate) async fn run(&self, interval: Duration, shutdown: Shutdown) -> Result<(), Error> {
+        let mut interval = tokio::time::interval(interval);
+        // the first tick completes immediately and there is nothing to push yet
+        interval.tick().await;
+        let shutdown = shutdown.requested();
+        tokio::pin!(shutdown);
+
+        loop {
+            tokio::select! {
+                _ = interval.tick() => {
+                    if let Err(e) = self.push().await {
+                        warn!({ error = e.to_string() }, "unable to push otlp metrics");
+                    }
+                }
+                _ = &mut shutdown => break,
+            }
+        }
+
+        if let Err(e) = self.push().await {
+            warn!({ error = e.to_string() }, "unable to push otlp metrics");
+        }
+
+        Ok(())
+    }
+
+    async fn push(&self) -> Result<(), Error> {
+        let body = export_request(&self.registry.gather(), self.start, SystemTime::now());
+        let mut request = self.client.post(&self.url).json(&body);
+        for (k, v) in &self.headers {
+            request = request.header(k, v);
+        }
+
+        request
+            .send()
+            .await
+            .and_then(reqwest::Response::error_for_status)
+            .map_err(|e| Error::Otlp(e.to_string()))?;
+        debug!({ url = self.url }, "pushed otlp metrics");
+
+        Ok(())
+    }
+}
+
+fn unix_nanos(t: SystemTime) -> String {
+    t.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos()
+        .to_string()
+}
+
+/// Converts gathered metric families to an OTLP `ExportMetricsServiceRequest`.
+fn export_request(families: &[MetricFamily], start: SystemTime, now: SystemTime) -> Value {
+    let start = unix_nanos(start);
+    let now = unix_nanos(now);
+    let metrics = families
+        .iter()
+        .filter_map(|f| metric(f, &start, &now))
+        .collect::<Vec<_>>();
+
+    json!({
+        "resourceMetrics": [{
+            "resource": {
+                "attributes": [attribute("service.name", SERVICE_NAME)],
+            },
+            "scopeMetrics": [{
+                "scope": { "name": SERVICE_NAME, "version": env!("CARGO_PKG_VERSION") },
+                "metrics": metrics,
+            }],
+        }],
+    })
+}
+
+fn metric(family: &MetricFamily, start: &str, now: &str) -> Option<Value> {
+    let point = |m: &Metric| {
+        json!({
+            "attributes": attributes(m.get_label()),
+            "startTimeUnixNano": start,
+            "timeUnixNano": now,
+        })
+    };
+
+    let data = match family.get_field_type() {
+        MetricType::COUNTER => {
+            let points = family.get_metric().iter().map(|m| {
+                let mut p = point(m);
+                p["asDouble"] = json!(m.get_counter().get_value());
+                p
+            });
+            json!({ "sum": {
+                "dataPoints": points.collect::<Vec<_>>(),
+                "aggregationTemporality": CUMULATIVE,
+                "isMonotonic": true,
+            }})
+        }
+        MetricType::GAUGE => {
+            let points = family.get_metric().iter().map(|m| {
+                let mut p = point(m);
+                p["asDouble"] = json!(m.get_gauge().get_value());
+                p
+            });
+            json!({ "gauge": { "dataPoints": points.collect::<Vec<_>>() } })
+        }
+        MetricType::HISTOGRAM => {
+            let points = family.get_metric().iter().map(|m| {
+                let h = m.get_histogram();
+                let buckets = h
+                    .get_bucket()
+                    .iter()
+                    .filter(|b| b.get_upper_bound().is_finite())
+                    .collect::<Vec<_>>();
+
+                // prometheus buckets are cumulative, OTLP buckets are not
+                let mut counts = vec![];
+                let mut previous = 0;
+                for b in &buckets {
+                    counts.push((b.get_cumulative_count() - previous).to_string());
+                    previous = b.get_cumulative_count();
+                }
+                counts.push((h.get_sample_count() - previous).to_string());
+
+                let mut p = point(m);
+                p["count"] = json!(h.get_sample_count().to_string());
+                p["sum"] = json!(h.get_sample_sum());
+                p["bucketCounts"] = json!(counts);
+                p["explicitBounds"] = json!(buckets
+                    .iter()
+                    .map(|b| b.get_upper_bound())
+                    .collect::<Vec<_>>());
+                p
+            });
+            json!({ "histogram": {
+                "dataPoints": points.collect::<Vec<_>>(),
+                "aggregationTemporality": CUMULATIVE,
+            }})
+        }
+        _ => return None,
+    };
+
+    let mut metric = json!({
+        "name": family.get_name(),
+        "description": family.get_help(),
+    });
+    metric
+        .as_object_mut()
+        .expect("metric is an object")
+        .extend(data.as_object().expect("data is an object").clone());
+
+    Some(metric)
+}
+
+fn attributes(labels: &[LabelPair]) -> Vec<Value> {
+    labels
+        .iter()
+        .map(|l| attribute(l.get_name(), l.get_value()))
+        .collect()
+}
+
+fn attribute(key: &str, value: &str) -> Value {
+    json!({ "key": key, "value": { "stringValue": value } })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use prometheus::{HistogramOpts, HistogramVec, IntCounterVec, IntGauge, Opts, Registry};
+
+    #[test]
+    fn test_export_request() {
+        let registry = Registry::new();
+        let counter =
+            IntCounterVec::new(Opts::new("patches_total", "patches"), &["result"]).unwrap();
+        let gauge = IntGauge::new("nodes_without_provider_id", "nodes").unwrap();
+        let histogram = HistogramVec::new(
+            HistogramOpts::new("reconcile_duration", "duration").buckets(vec![0.1, 1.0]),
+            &[],
+        )
+        .unwrap();
+        registry.register(Box::new(counter.clone())).unwrap();
+        registry.register(Box::new(gauge.clone())).unwrap();
+        registry.register(Box::new(histogram.clone())).unwrap();
+
+        counter.with_label_values(&["success"]).inc_by(3);
+        gauge.set(2);
+        for v in [0.05, 0.5, 0.7, 5.0] {
+            histogram.with_label_values(&[]).observe(v);
+        }
+
+        let request = export_request(&registry.gather(), UNIX_EPOCH, UNIX_EPOCH);
+        let metrics = &request["resourceMetrics"][0]["scopeMetrics"][0]["metrics"];
+        let find = |name: &str| {
+            metrics
+                .as_array()
+                .unwrap()
+                .iter()
+                .find(|m| m["name"] == name)
+                .unwrap()
+                .clone()
+        };
+
+        let patches = find("patches_total");
+        assert_eq!(patches["sum"]["isMonotonic"], true);
+        let point = &patches["sum"]["dataPoints"][0];
+        assert_eq!(point["asDouble"], 3.0);
+        assert_eq!(point["attributes"][0], attribute("result", "success"));
+
+        let nodes = find("nodes_without_provider_id");
+        assert_eq!(nodes["gauge"]["dataPoints"][0]["asDouble"], 2.0);
+
+        let duration = find("reconcile_duration");
+        let point = &duration["histogram"]["dataPoints"][0];
+        assert_eq!(point["count"], "4");
+        assert_eq!(point["explicitBounds"], json!([0.1, 1.0]));
+        assert_eq!(point["bucketCounts"], json!(["1", "2", "1"]));
+    }
+
+    #[test]
+    fn test_new() {
+        let registry = Registry::new();
+        let exporter = OtlpExporter::new(
+            "http://collector:4318/",
+            &["authorization=Bearer token".into()],
+            registry.clone(),
+        )
+        .unwrap();
+        assert_eq!(exporter.url, "http://collector:4318/v1/metrics");
+        assert_eq!(
+            exporter.headers,
+            vec![("authorization".to_string(), "Bearer token".to_string())]
+        );
+
+        let exporter =
+            OtlpExporter::new("http://collector:4318/v1/metrics", &[], registry.clone()).unwrap();
+        assert_eq!(exporter.url, "http://collector:4318/v1/metrics");
+
+        assert!(OtlpExporter::new("http://collector:4318", &["nokey".into()], registry).is_err());
+    }
+}
@@ -0,0 +1,120 @@
+//! Adaptive pacing of patches across reconcilers: the rate backs off
+//! multiplicatively while the API server throttles the controller (HTTP 429,
+//! e.g. from API priority and fairness), and recovers additively as patches
+//! succeed again.
+use crate::Error;
+use std::{sync::Mutex, time::Duration};
+use tokio::time::Instant;
+use tracing::{debug, warn};
+
+// the share of the rate kept on throttling
+const DECREASE: f64 = 0.5;
+// successful patches to recover from the minimum to the maximum rate
+const RECOVERY_PATCHES: f64 = 20.0;
+// the minimum rate, as a share of the maximum
+const MIN_SHARE: f64 = 0.01;
+
+#[derive(Debug)]
+struct State {
+    rate: f64,
+    // when the next patch may go out
+    next: Instant,
+}
+
+/// Paces patches at a rate between a maximum and a hundredth of it.
+#[derive(Debug)]
+pub struct AdaptiveRate {
+    max: f64,
+    state: Mutex<State>,
+}
+
+impl AdaptiveRate {
+    /// Starts at `max` patches per second.
+    pub(crate) fn new(max: f64) -> Self {
+        Self {
+            max,
+            state: Mutex::new(State {
+                rate: max,
+                next: Instant::now(),
+            }),
+        }
+    }
+
+    /// The current rate in patches per second.
+    pub fn rate(&self) -> f64 {
+        self.state.lock().unwrap().rate
+    }
+
+    /// Waits until the next patch may go out.
+    pub(crate) async fn wait(&self) {
+        if let Some(wait) = self.reserve(Instant::now()) {
+            tokio::time::sleep(wait).await;
+        }
+    }
+
+    /// Reserves the next slot for a patch, returning how long to wait for it.
+    fn reserve(&self, now: Instant) -> Option<Duration> {
+        let mut state = self.state.lock().unwrap();
+        let slot = state.next.max(now);
+        state.next = slot + Duration::from_secs_f64(1.0 / state.rate);
+        Some(slot - now).filter(|wait| !wait.is_zero())
+    }
+
+    /// Adapts the rate to the result of a patch, returning the new rate.
+    /// Throttled patches lower it, successful ones raise it, and other
+    /// errors leave it alone.
+    pub(crate) fn observe<T>(&self, result: &Result<T, Error>) -> f64 {
+        let mut state = self.state.lock().unwrap();
+        match result {
+            Err(Error::Kube(kube::Error::Api(e))) if e.code == 429 => {
+                state.rate = (state.rate * DECREASE).max(self.max * MIN_SHARE);
+                warn!(
+                    { rate = state.rate },
+                    "throttled by the API server, slowing down patches"
+                );
+            }
+            Ok(_) if state.rate < self.max => {
+                state.rate = (state.rate + self.max / RECOVERY_PATCHES).min(self.max);
+                debug!({ rate = state.rate }, "speeding patches back up");
+            }
+            _ => (),
+        }
+        state.rate
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use kube::error::ErrorResponse;
+
+    fn api_error(code: u16) -> Result<(), Error> {
+        Err(Error::Kube(kube::Error::Api(ErrorResponse {
+            status: "Failure".into(),
+            message: String::new(),
+            reason: String::new(),
+            code,
+        })))
+    }
+
+    #[test]
+    fn test_adaptive_rate() {
+        let rate = AdaptiveRate::new(10.0);
+        let now = Instant::now();
+        assert_eq!(rate.reserve(now), None);
+        assert_eq!(rate.reserve(now), Some(Duration::from_millis(100)));
+
+        assert_eq!(rate.observe(&api_error(429)), 5.0);
+        assert_eq!(rate.observe(&api_error(429)), 2.5);
+        assert_eq!(rate.observe(&api_error(409)), 2.5);
+        assert_eq!(rate.observe(&Ok(())), 3.0);
+        for _ in 0..20 {
+            rate.observe(&api_error(429));
+        }
+        assert_eq!(rate.rate(), 0.1);
+        for _ in 0..20 {
+            rate.observe(&Ok(()));
+        }
+        assert_eq!(rate.rate(), 10.0);
+    }
+}
@@ -0,0 +1,181 @@
+use crate::TemplateArgs;
+use node_provider_labeler::Error;
+use serde_json::{json, Value};
+use std::process::ExitCode;
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default)]
+pub(crate) enum Action {
+    /// Reject the change
+    #[default]
+    Deny,
+    /// Allow the change, warning the client
+    Warn,
+    /// Allow the change, recording it in the audit log
+    Audit,
+}
+
+impl Action {
+    fn as_str(self) -> &'static str {
+        match self {
+            Action::Deny => "Deny",
+            Action::Warn => "Warn",
+            Action::Audit => "Audit",
+        }
+    }
+}
+
+#[derive(clap::Args, Debug)]
+pub(crate) struct PolicyArgs {
+    /// The name of the ValidatingAdmissionPolicy and its binding
+    #[arg(long, default_value = "node-provider-labeler")]
+    name: String,
+    /// The user the controller runs as, which may change the keys
+    #[arg(
+        long,
+        value_name = "USER",
+        default_value = "system:serviceaccount:node-provider-labeler:node-provider-labeler"
+    )]
+    controller_user: String,
+    /// What to do with changes to the managed keys
+    #[arg(long, value_enum, default_value_t)]
+    action: Action,
+    #[command(flatten)]
+    templates: TemplateArgs,
+}
+
+pub(crate) fn generate_policy(args: &PolicyArgs) -> ExitCode {
+    match policy(args) {
+        Ok(output) => {
+            print!("{output}");
+            ExitCode::SUCCESS
+        }
+        Err(e) => {
+            eprintln!("unable to generate policy: {e}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+/// A ValidatingAdmissionPolicy and binding, as YAML, rejecting changes to
+/// the values of the labels and annotations the templates manage by anyone
+/// but the controller.
+fn policy(args: &PolicyArgs) -> Result<String, Error> {
+    let templates = args.templates.parse()?;
+    let validations = templates
+        .label_keys()
+        .iter()
+        .map(|key| validation("labels", key))
+        .chain(
+            templates
+                .annotation_keys()
+                .iter()
+                .map(|key| validation("annotations", key)),
+        )
+        .collect::<Vec<_>>();
+
+    let policy = json!({
+        "apiVersion": "admissionregistration.k8s.io/v1",
+        "kind": "ValidatingAdmissionPolicy",
+        "metadata": { "name": args.name },
+        "spec": {
+            "failurePolicy": "Fail",
+            "matchConstraints": {
+                "resourceRules": [{
+                    "apiGroups": [""],
+                    "apiVersions": ["v1"],
+                    "operations": ["UPDATE"],
+                    "resources": ["nodes"],
+                }],
+            },
+            "matchConditions": [{
+                "name": "not-the-controller",
+                "expression": format!("request.userInfo.username != '{}'", args.controller_user),
+            }],
+            "validations": validations,
+        },
+    });
+    let binding = json!({
+        "apiVersion": "admissionregistration.k8s.io/v1",
+        "kind": "ValidatingAdmissionPolicyBinding",
+        "metadata": { "name": args.name },
+        "spec": {
+            "policyName": args.name,
+            "validationActions": [args.action.as_str()],
+        },
+    });
+
+    let yaml = |v: &Value| serde_yaml::to_string(v).map_err(|e| Error::Config(e.to_string()));
+    Ok(format!("{}---\n{}", yaml(&policy)?, yaml(&binding)?))
+}
+
+/// Allows the change if the key didn't exist, or keeps its value. Keys are
+/// validated label and annotation keys, so they need no escaping.
+fn validation(kind: &str, key: &str) -> Value {
+    let old = format!("oldObject.metadata.{kind}");
+    let new = format!("object.metadata.{kind}");
+    json!({
+        "expression": format!(
+            "!has({old}) || !('{key}' in {old}) || \
+             (has({new}) && '{key}' in {new} && {new}['{key}'] == {old}['{key}'])"
+        ),
+        "message": format!(
+            "{} {key} is managed by node-provider-labeler",
+            kind.trim_end_matches('s')
+        ),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Cli, Command};
+    use clap::Parser;
+
+    #[test]
+    fn test_policy() {
+        let cli = Cli::try_parse_from([
+            "npl",
+            "generate-policy",
+            "--action=warn",
+            "--label=example.com/zone={:first}",
+            "--annotation=id={:last}",
+        ])
+        .unwrap();
+        let Some(Command::GeneratePolicy(args)) = cli.command else {
+            panic!("expected generate-policy");
+        };
+        let output = policy(&args).unwrap();
+        let docs = output
+            .split("---\n")
+            .map(|doc| serde_yaml::from_str::<Value>(doc).unwrap())
+            .collect::<Vec<_>>();
+
+        assert_eq!(docs[0]["kind"], "ValidatingAdmissionPolicy");
+        let validations = docs[0]["spec"]["validations"].as_array().unwrap();
+        assert_eq!(validations.len(), 2);
+        assert_eq!(
+            validations[0]["expression"],
+            "!has(oldObject.metadata.labels) || !('example.com/zone' in oldObject.metadata.labels) || \
+             (has(object.metadata.labels) && 'example.com/zone' in object.metadata.labels && \
+             object.metadata.labels['example.com/zone'] == oldObject.metadata.labels['example.com/zone'])"
+        );
+        assert_eq!(
+            validations[1]["message"],
+            "annotation id is managed by node-provider-labeler"
+        );
+        assert_eq!(
+            docs[0]["spec"]["matchConditions"][0]["expression"],
+            "request.userInfo.username != 'system:serviceaccount:node-provider-labeler:node-provider-labeler'"
+        );
+        assert_eq!(docs[1]["spec"]["validationActions"][0], "Warn");
+
+        // the default label is protected without other templates
+        let cli = Cli::try_parse_from(["npl", "generate-policy"]).unwrap();
+        let Some(Command::GeneratePolicy(args)) = cli.command else {
+            panic!("expected generate-policy");
+        };
+        assert!(policy(&args)
+            .unwrap()
+            .contains("message: label provider-id is managed"));
+    }
+}
@@ -0,0 +1,83 @@
+use axum::{
+    extract::Query,
+    http::{header, StatusCode},
+    response::{IntoResponse, Response},
+    routing::get,
+    Router,
+};
+use pprof::protos::Message;
+use serde::Deserialize;
+use std::time::Duration;
+
+#[global_allocator]
+static ALLOC: tikv_jemallocator::Jemalloc = tikv_jemallocator::Jemalloc;
+
+// heap profiling is compiled in but stays inactive until --pprof activates it
+#[allow(non_upper_case_globals)]
+#[export_name = "malloc_conf"]
+pub static malloc_conf: &[u8] = b"prof:true,prof_active:false,lg_prof_sample:19\0";
+
+const DEFAULT_CPU_SECONDS: u64 = 30;
+const MAX_CPU_SECONDS: u64 = 300;
+const CPU_FREQUENCY: i32 = 99;
+
+/// Activates heap profiling, which samples allocations from then on.
+pub(crate) async fn activate() {
+    jemalloc_pprof::activate_jemalloc_profiling().await;
+}
+
+/// Routes for `go tool pprof`-compatible CPU and heap profiles.
+pub(crate) fn router() -> Router {
+    Router::new()
+        .route("/debug/pprof/profile", get(cpu_profile))
+        .route("/debug/pprof/heap", get(heap_profile))
+}
+
+#[derive(Deserialize)]
+struct CpuParams {
+    seconds: Option<u64>,
+}
+
+async fn cpu_profile(Query(params): Query<CpuParams>) -> Response {
+    let seconds = params
+        .seconds
+        .unwrap_or(DEFAULT_CPU_SECONDS)
+        .clamp(1, MAX_CPU_SECONDS);
+
+    // the profiler guard isn't Send, so sample on a blocking thread
+    let profile = tokio::task::spawn_blocking(move || {
+        let guard = pprof::ProfilerGuardBuilder::default()
+            .frequency(CPU_FREQUENCY)
+            .blocklist(&["libc", "libgcc", "pthread", "vdso"])
+            .build()?;
+        std::thread::sleep(Duration::from_secs(seconds));
+        let profile = guard.report().build()?.pprof()?;
+        Ok::<_, pprof::Error>(profile.encode_to_vec())
+    })
+    .await;
+
+    match profile {
+        Ok(Ok(body)) => pprof_response(body),
+        Ok(Err(e)) => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+    }
+}
+
+async fn heap_profile() -> Response {
+    let Some(ctl) = jemalloc_pprof::PROF_CTL.as_ref() else {
+        return (StatusCode::NOT_FOUND, "heap profiling is unavailable").into_response();
+    };
+    let mut ctl = ctl.lock().await;
+    if !ctl.activated() {
+        return (StatusCode::FORBIDDEN, "heap profiling is not activated").into_response();
+    }
+
+    match ctl.dump_pprof() {
+        Ok(body) => pprof_response(body),
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+    }
+}
+
+fn pprof_response(body: Vec<u8>) -> Response {
+    ([(header::CONTENT_TYPE, "application/octet-stream")], body).into_response()
+}
@@ -62,6 +62,11 @@ impl ProviderID {
             .to_string()
     }
 
+    /// The `/`-separated parts of the node ID, as used by `{<n>}` tokens.
+    pub fn parts(&self) -> &[String] {
+        &self.id_parts
+    }
+
     pub fn nth(&self, n: usize) -> Option<String> {
         self.id_parts.get(n).map(String::to_string)
     }
@@ -0,0 +1,130 @@
+use node_provider_labeler::shutdown::Shutdown;
+use node_provider_labeler::Error;
+use prometheus::{Encoder, TextEncoder};
+use std::time::Duration;
+use tracing::{debug, warn};
+
+/// Pushes the contents of the Prometheus registry to a Pushgateway, grouped
+/// by job and, optionally, instance, for runs too short to be scraped.
+#[derive(Debug)]
+pub(crate) struct PushgatewayExporter {
+    client: reqwest::Client,
+    url: String,
+    registry: prometheus::Registry,
+}
+
+impl PushgatewayExporter {
+    /// Creates an exporter for the Pushgateway at `endpoint`, e.g.
+    /// `http://pushgateway:9091`, pushing to the group of `job` and
+    /// `instance`.
+    pub(crate) fn new(
+        endpoint: &str,
+        job: &str,
+        instance: Option<&str>,
+        registry: prometheus::Registry,
+    ) -> Result<Self, Error> {
+        let mut url = format!("{}/metrics", endpoint.trim_end_matches('/'));
+        for (label, value) in [("job", Some(job)), ("instance", instance)] {
+            let Some(value) = value else {
+                continue;
+            };
+            if value.is_empty() || value.contains('/') {
+                return Err(Error::Config(format!(
+                    "invalid pushgateway {label} '{value}', expected a non-empty value without '/'"
+                )));
+            }
+            url = format!("{url}/{label}/{value}");
+        }
+
+        Ok(Self {
+            client: reqwest::Client::new(),
+            url,
+            registry,
+        })
+    }
+
+    /// Pushes metrics every interval, and a final time once shutdown is
+    /// requested.
+    pub(crate) async fn run(&self, interval: Duration, shutdown: Shutdown) -> Result<(), Error> {
+        let mut interval = tokio::time::interval(interval);
+        // the first tick completes immediately and there is nothing to push yet
+        interval.tick().await;
+        let shutdown = shutdown.requested();
+        tokio::pin!(shutdown);
+
+        loop {
+            tokio::select! {
+                _ = interval.tick() => {
+                    if let Err(e) = self.push().await {
+                        warn!({ error = e.to_string() }, "unable to push metrics to the pushgateway");
+                    }
+                }
+                _ = &mut shutdown => break,
+            }
+        }
+
+        if let Err(e) = self.push().await {
+            warn!(
+                { error = e.to_string() },
+                "unable to push metrics to the pushgateway"
+            );
+        }
+
+        Ok(())
+    }
+
+    /// Replaces the metrics of the group with the registry's.
+    async fn push(&self) -> Result<(), Error> {
+        let encoder = TextEncoder::new();
+        let mut body = vec![];
+        encoder.encode(&self.registry.gather(), &mut body)?;
+
+        self.client
+            .put(&self.url)
+            .header(reqwest::header::CONTENT_TYPE, encoder.format_type())
+            .body(body)
+            .send()
+            .await
+            .and_then(reqwest::Response::error_for_status)
+            .map_err(|e| Error::Pushgateway(e.to_string()))?;
+        debug!({ url = self.url }, "pushed metrics to the pushgateway");
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use prometheus::Registry;
+
+    #[test]
+    fn test_new() {
+        let registry = Registry::new();
+        let exporter = PushgatewayExporter::new(
+            "http://pushgateway:9091/",
+            "node-provider-labeler",
+            Some("my-pod"),
+            registry.clone(),
+        )
+        .unwrap();
+        assert_eq!(
+            exporter.url,
+            "http://pushgateway:9091/metrics/job/node-provider-labeler/instance/my-pod"
+        );
+
+        let exporter =
+            PushgatewayExporter::new("http://pushgateway:9091", "npl", None, registry.clone())
+                .unwrap();
+        assert_eq!(exporter.url, "http://pushgateway:9091/metrics/job/npl");
+
+        assert!(
+            PushgatewayExporter::new("http://pushgateway:9091", "", None, registry.clone())
+                .is_err()
+        );
+        assert!(
+            PushgatewayExporter::new("http://pushgateway:9091", "npl", Some("a/b"), registry)
+                .is_err()
+        );
+    }
+}
@@ -0,0 +1,140 @@
+use std::{
+    future::Future,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{ready, Context, Poll},
+    time::{Duration, Instant},
+};
+use tokio::time::Sleep;
+use tower::{Layer, Service};
+
+/// Limits API requests to a sustained rate with bursts, like client-go's
+/// QPS/burst settings.
+#[derive(Clone, Debug)]
+pub(crate) struct RateLimitLayer {
+    qps: f64,
+    burst: u32,
+}
+
+impl RateLimitLayer {
+    pub(crate) fn new(qps: f64, burst: u32) -> Self {
+        Self { qps, burst }
+    }
+}
+
+impl<S> Layer<S> for RateLimitLayer {
+    type Service = RateLimit<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        RateLimit {
+            inner,
+            bucket: Arc::new(Mutex::new(TokenBucket::new(self.qps, self.burst))),
+            reserved: false,
+            sleep: None,
+        }
+    }
+}
+
+pub(crate) struct RateLimit<S> {
+    inner: S,
+    bucket: Arc<Mutex<TokenBucket>>,
+    // a token has been taken for the next call
+    reserved: bool,
+    sleep: Option<Pin<Box<Sleep>>>,
+}
+
+impl<S, Req> Service<Req> for RateLimit<S>
+where
+    S: Service<Req>,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = S::Future;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        loop {
+            if self.reserved {
+                return self.inner.poll_ready(cx);
+            }
+
+            if let Some(sleep) = self.sleep.as_mut() {
+                ready!(sleep.as_mut().poll(cx));
+                self.sleep = None;
+            }
+
+            match self.bucket.lock().unwrap().try_acquire(Instant::now()) {
+                None => self.reserved = true,
+                Some(wait) => self.sleep = Some(Box::pin(tokio::time::sleep(wait))),
+            }
+        }
+    }
+
+    fn call(&mut self, req: Req) -> Self::Future {
+        self.reserved = false;
+        self.inner.call(req)
+    }
+}
+
+#[derive(Debug)]
+struct TokenBucket {
+    rate: f64,
+    burst: f64,
+    tokens: f64,
+    last: Instant,
+}
+
+impl TokenBucket {
+    fn new(rate: f64, burst: u32) -> Self {
+        // always allow at least one request at a time
+        let burst = f64::from(burst.max(1));
+        Self {
+            rate,
+            burst,
+            tokens: burst,
+            last: Instant::now(),
+        }
+    }
+
+    /// Takes a token, or returns how long to wait until one is available.
+    fn try_acquire(&mut self, now: Instant) -> Option<Duration> {
+        let elapsed = now.saturating_duration_since(self.last).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.burst);
+        self.last = now;
+
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            None
+        } else {
+            Some(Duration::from_secs_f64((1.0 - self.tokens) / self.rate))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_token_bucket() {
+        let mut bucket = TokenBucket::new(2.0, 3);
+        let now = bucket.last;
+
+        // burst
+        assert_eq!(bucket.try_acquire(now), None);
+        assert_eq!(bucket.try_acquire(now), None);
+        assert_eq!(bucket.try_acquire(now), None);
+        assert_eq!(bucket.try_acquire(now), Some(Duration::from_millis(500)));
+
+        // refill at 2 per second
+        let now = now + Duration::from_millis(500);
+        assert_eq!(bucket.try_acquire(now), None);
+        assert_eq!(bucket.try_acquire(now), Some(Duration::from_millis(500)));
+
+        // never more than burst
+        let now = now + Duration::from_secs(60);
+        assert_eq!(bucket.try_acquire(now), None);
+        assert_eq!(bucket.try_acquire(now), None);
+        assert_eq!(bucket.try_acquire(now), None);
+        assert!(bucket.try_acquire(now).is_some());
+    }
+}
@@ -0,0 +1,570 @@
+use crate::{
+    meta::MetadataKey,
+    provider_id::ProviderID,
+    selector::LabelSelector,
+    source,
+    template::{AnnotationTemplate, RenderContext, Template},
+    Error,
+};
+use k8s_openapi::api::core::v1::Node;
+use kube::ResourceExt;
+use std::str::FromStr;
+
+const DEFAULT_KEY_NAME: &str = "provider-id";
+const DEFAULT_TEMPLATE: &str = "{:last}";
+const SELECTOR_SEPARATOR: &str = "@selector=";
+const SKIP_SEPARATOR: &str = "@skip=";
+const OVERWRITE_SEPARATOR: &str = "@overwrite=";
+const DISABLED_SEPARATOR: &str = "@disabled=";
+
+/// A condition that leaves a renderer's value unset for a node: a template,
+/// true when it renders anything but "", "false", or "0", or a template
+/// compared to a value with `==` or `!=`, e.g.
+/// "{label:kubernetes.io/os}==windows". A template that references a missing
+/// field renders "".
+#[derive(Debug)]
+pub struct Skip {
+    template: AnnotationTemplate,
+    comparison: Option<(bool, String)>,
+}
+
+impl FromStr for Skip {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (template, comparison) = match s.split_once("!=") {
+            Some((template, value)) => (template, Some((false, value.to_string()))),
+            None => match s.split_once("==") {
+                Some((template, value)) => (template, Some((true, value.to_string()))),
+                None => (s, None),
+            },
+        };
+        if template.is_empty() {
+            return Err(Error::Config(format!("invalid skip expression '{s}'")));
+        }
+        Ok(Self {
+            template: template.parse()?,
+            comparison,
+        })
+    }
+}
+
+impl std::fmt::Display for Skip {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.template)?;
+        match &self.comparison {
+            Some((true, value)) => write!(f, "=={value}"),
+            Some((false, value)) => write!(f, "!={value}"),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Skip {
+    /// Whether the condition holds for the node the context renders for.
+    pub fn holds(&self, ctx: &RenderContext) -> Result<bool, Error> {
+        let value = match self.template.render(ctx) {
+            Ok(value) => value,
+            Err(Error::MissingField(_) | Error::MissingObjectKey(_)) => String::new(),
+            Err(e) => return Err(e),
+        };
+        Ok(match &self.comparison {
+            Some((equal, expected)) => (value == *expected) == *equal,
+            None => !matches!(value.as_str(), "" | "false" | "0"),
+        })
+    }
+}
+
+/// When a renderer may overwrite a value already on the target.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Overwrite {
+    /// Always apply the rendered value
+    #[default]
+    Always,
+    /// Only set the key if the target doesn't have it
+    IfAbsent,
+    /// Only overwrite values the controller set, leaving values others set,
+    /// e.g. manual overrides, alone
+    IfManaged,
+}
+
+impl FromStr for Overwrite {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "always" => Ok(Self::Always),
+            "if-absent" => Ok(Self::IfAbsent),
+            "if-managed" => Ok(Self::IfManaged),
+            _ => Err(Error::Config(format!(
+                "invalid overwrite policy '{s}', expected always, if-absent, or if-managed"
+            ))),
+        }
+    }
+}
+
+impl std::fmt::Display for Overwrite {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::Always => "always",
+            Self::IfAbsent => "if-absent",
+            Self::IfManaged => "if-managed",
+        })
+    }
+}
+
+impl Overwrite {
+    /// Whether the value on the target has to be kept, given whether the
+    /// target has the key and whether the controller manages it.
+    pub fn keeps(&self, present: bool, managed: bool) -> bool {
+        match self {
+            Self::Always => false,
+            Self::IfAbsent => present,
+            Self::IfManaged => present && !managed,
+        }
+    }
+}
+
+/// Splits the "@selector=", "@skip=", "@overwrite=", and "@disabled="
+/// suffixes, in any order, off a renderer spec.
+fn split_suffixes(s: &str) -> (&str, [Option<&str>; 4]) {
+    const MARKERS: [&str; 4] = [
+        SELECTOR_SEPARATOR,
+        SKIP_SEPARATOR,
+        OVERWRITE_SEPARATOR,
+        DISABLED_SEPARATOR,
+    ];
+    let mut markers = MARKERS
+        .into_iter()
+        .enumerate()
+        .filter_map(|(n, marker)| s.find(marker).map(|i| (i, n)))
+        .collect::<Vec<_>>();
+    markers.sort();
+    let mut values = [None; 4];
+    for (m, (i, n)) in markers.iter().enumerate() {
+        let end = markers.get(m + 1).map_or(s.len(), |(next, _)| *next);
+        values[*n] = Some(&s[i + MARKERS[*n].len()..end]);
+    }
+    let spec = markers.first().map_or(s, |(i, _)| &s[..*i]);
+    (spec, values)
+}
+
+/// A metadata key and the template rendering its value, as given to
+/// `--label` and `--annotation`, optionally only for nodes matching a label
+/// selector, or unless a skip condition holds, and overwriting values on the
+/// node according to an [`Overwrite`] policy. Without a template, as in
+/// "legacy-key=", it removes the key instead. A disabled renderer stays
+/// configured but neither renders nor changes its key.
+///
+/// ```
+/// use node_provider_labeler::{renderer::Renderer, template::LabelTemplate};
+///
+/// let renderer: Renderer<LabelTemplate> = "zone={:first}".parse().unwrap();
+/// assert_eq!(renderer.key(), "zone");
+/// ```
+#[derive(Debug)]
+pub struct Renderer<T>
+where
+    T: std::fmt::Debug + std::default::Default + Template + std::str::FromStr,
+    Error: std::convert::From<<T as std::str::FromStr>::Err>,
+{
+    key: MetadataKey,
+    template: T,
+    selector: Option<LabelSelector>,
+    skip: Option<Skip>,
+    overwrite: Overwrite,
+    unset: bool,
+    disabled: bool,
+}
+
+impl<T> Renderer<T>
+where
+    T: std::fmt::Debug + std::default::Default + Template + std::str::FromStr,
+    Error: std::convert::From<<T as std::str::FromStr>::Err>,
+{
+    /// Creates a renderer, validating the key and the template.
+    pub fn new(key: &str, template: &str) -> Result<Self, Error> {
+        let key = key
+            .parse::<MetadataKey>()
+            .map_err(|e| Error::MetadataKey(e.to_string()))?;
+        let template = T::from_str(template)?;
+
+        Ok(Self {
+            key,
+            template,
+            selector: None,
+            skip: None,
+            overwrite: Overwrite::Always,
+            unset: false,
+            disabled: false,
+        })
+    }
+
+    /// Creates a renderer that removes the key, validating it.
+    pub fn unset(key: &str) -> Result<Self, Error> {
+        let key = key
+            .parse::<MetadataKey>()
+            .map_err(|e| Error::MetadataKey(e.to_string()))?;
+
+        Ok(Self {
+            key,
+            template: T::default(),
+            selector: None,
+            skip: None,
+            overwrite: Overwrite::Always,
+            unset: true,
+            disabled: false,
+        })
+    }
+
+    /// Whether the renderer removes its key rather than setting it.
+    pub fn unsets(&self) -> bool {
+        self.unset
+    }
+
+    /// Only renders the value for nodes whose labels match the selector.
+    pub fn with_selector(mut self, selector: LabelSelector) -> Self {
+        self.selector = Some(selector);
+        self
+    }
+
+    pub fn selector(&self) -> Option<&LabelSelector> {
+        self.selector.as_ref()
+    }
+
+    /// Whether the renderer applies to the node the context renders for.
+    pub fn selects(&self, ctx: &RenderContext) -> bool {
+        let Some(selector) = &self.selector else {
+            return true;
+        };
+        let labels = ctx.metadata.and_then(|m| m.labels.clone());
+        selector.matches(&labels.unwrap_or_default())
+    }
+
+    /// Leaves the value unset for nodes the condition holds for.
+    pub fn with_skip(mut self, skip: Skip) -> Self {
+        self.skip = Some(skip);
+        self
+    }
+
+    pub fn skip(&self) -> Option<&Skip> {
+        self.skip.as_ref()
+    }
+
+    /// Sets when the rendered value may overwrite one on the node.
+    pub fn with_overwrite(mut self, overwrite: Overwrite) -> Self {
+        self.overwrite = overwrite;
+        self
+    }
+
+    pub fn overwrite(&self) -> Overwrite {
+        self.overwrite
+    }
+
+    /// Keeps the renderer configured, but leaves its key on every node as it
+    /// is, e.g. to hold a rule back during an incident without losing it.
+    pub fn with_disabled(mut self, disabled: bool) -> Self {
+        self.disabled = disabled;
+        self
+    }
+
+    pub fn disabled(&self) -> bool {
+        self.disabled
+    }
+
+    /// Whether the skip condition holds for the node the context renders for.
+    pub fn skips(&self, ctx: &RenderContext) -> Result<bool, Error> {
+        match &self.skip {
+            Some(skip) => skip.holds(ctx),
+            None => Ok(false),
+        }
+    }
+
+    /// The metadata key, e.g. "example.com/zone".
+    pub fn key(&self) -> String {
+        self.key.to_string()
+    }
+
+    pub fn template(&self) -> &T {
+        &self.template
+    }
+
+    /// Renders the value within a context.
+    pub fn render(&self, ctx: &RenderContext) -> Result<String, Error> {
+        self.template.render(ctx)
+    }
+
+    /// Renders the value for a node from its provider ID and the default value
+    /// sources, as the controller does without enrichment.
+    pub fn render_for(&self, node: &Node) -> Result<String, Error> {
+        let provider_id = node_provider_id(node)?;
+
+        let sources = source::defaults();
+
+        self.render(
+            &RenderContext::new(&provider_id)
+                .with_metadata(&node.metadata)
+                .with_sources(&sources),
+        )
+    }
+}
+
+/// Parses the node's provider ID.
+pub(crate) fn node_provider_id(node: &Node) -> Result<ProviderID, Error> {
+    let provider_id = node
+        .spec
+        .as_ref()
+        .and_then(|spec| spec.provider_id.as_ref())
+        .ok_or_else(|| Error::MissingObjectKey(".spec.providerID"))?;
+
+    Ok(ProviderID::new(&node.name_any(), provider_id)?)
+}
+
+impl<T> Default for Renderer<T>
+where
+    T: std::fmt::Debug + std::default::Default + Template + std::str::FromStr,
+    Error: std::convert::From<<T as std::str::FromStr>::Err>,
+{
+    fn default() -> Self {
+        Self {
+            key: DEFAULT_KEY_NAME.parse::<MetadataKey>().unwrap(),
+            template: T::from_str(DEFAULT_TEMPLATE).unwrap_or_default(),
+            selector: None,
+            skip: None,
+            overwrite: Overwrite::Always,
+            unset: false,
+            disabled: false,
+        }
+    }
+}
+
+impl<T> FromStr for Renderer<T>
+where
+    T: std::fmt::Debug + std::default::Default + Template + std::str::FromStr,
+    Error: std::convert::From<<T as std::str::FromStr>::Err>,
+{
+    type Err = Error;
+
+    /// Parses "key=template", defaulting the template to "{:last}" and an
+    /// empty string to "provider-id={:last}". An empty template, as in
+    /// "key=", removes the key. A "@selector=<selector>" suffix
+    /// restricts it to the nodes matching the label selector, a
+    /// "@skip=<condition>" suffix leaves it unset where the [`Skip`] holds,
+    /// an "@overwrite=<policy>" suffix sets the [`Overwrite`] policy, and
+    /// "@disabled=true" disables it.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.is_empty() {
+            return Ok(Self::default());
+        }
+        let (s, [selector, skip, overwrite, disabled]) = split_suffixes(s);
+        let parts = s.splitn(2, '=').collect::<Vec<&str>>();
+        let mut renderer = match parts.get(1) {
+            Some(&"") => Self::unset(parts[0])?,
+            Some(template) => Self::new(parts[0], template)?,
+            None => Self::new(parts[0], DEFAULT_TEMPLATE)?,
+        };
+        if let Some(selector) = selector {
+            renderer = renderer.with_selector(selector.parse::<LabelSelector>()?);
+        }
+        if let Some(skip) = skip {
+            renderer = renderer.with_skip(skip.parse::<Skip>()?);
+        }
+        if let Some(overwrite) = overwrite {
+            renderer = renderer.with_overwrite(overwrite.parse::<Overwrite>()?);
+        }
+        if let Some(disabled) = disabled {
+            let disabled = disabled.parse::<bool>().map_err(|_| {
+                Error::Config(format!(
+                    "invalid disabled flag '{disabled}', expected true or false"
+                ))
+            })?;
+            renderer = renderer.with_disabled(disabled);
+        }
+        Ok(renderer)
+    }
+}
+
+impl<T> std::fmt::Display for Renderer<T>
+where
+    T: std::fmt::Debug + std::default::Default + Template + std::str::FromStr + std::fmt::Display,
+    Error: std::convert::From<<T as std::str::FromStr>::Err>,
+{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}=", self.key)?;
+        if !self.unset {
+            write!(f, "{}", self.template)?;
+        }
+        if let Some(selector) = &self.selector {
+            write!(f, "{SELECTOR_SEPARATOR}{selector}")?;
+        }
+        if let Some(skip) = &self.skip {
+            write!(f, "{SKIP_SEPARATOR}{skip}")?;
+        }
+        if self.overwrite != Overwrite::Always {
+            write!(f, "{OVERWRITE_SEPARATOR}{}", self.overwrite)?;
+        }
+        if self.disabled {
+            write!(f, "{DISABLED_SEPARATOR}true")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::template::{AnnotationTemplate, LabelTemplate};
+    use crate::testing;
+
+    fn node(provider_id: Option<&str>) -> Node {
+        let node = testing::node("my-node");
+        match provider_id {
+            Some(provider_id) => node.provider_id(provider_id).build(),
+            None => node.build(),
+        }
+    }
+
+    #[test]
+    fn test_parse() {
+        let r: Renderer<LabelTemplate> = "".parse().unwrap();
+        assert_eq!(r.to_string(), "provider-id={:last}");
+        let r: Renderer<LabelTemplate> = "example.com/zone".parse().unwrap();
+        assert_eq!(r.to_string(), "example.com/zone={:last}");
+        let r: Renderer<AnnotationTemplate> = "id={:url}".parse().unwrap();
+        assert_eq!(r.key(), "id");
+        assert_eq!(r.template().to_string(), "{:url}");
+
+        assert!(matches!(
+            "-zone={:first}".parse::<Renderer<LabelTemplate>>(),
+            Err(Error::MetadataKey(_))
+        ));
+        assert!(Renderer::<LabelTemplate>::new("zone", "{:first").is_err());
+
+        let r: Renderer<LabelTemplate> = "gpu-id={1}@selector=gpu in (a100, h100)".parse().unwrap();
+        assert_eq!(r.template().to_string(), "{1}");
+        assert_eq!(r.to_string(), "gpu-id={1}@selector=gpu in (a100, h100)");
+        let r: Renderer<LabelTemplate> = "gpu@selector=gpu".parse().unwrap();
+        assert_eq!(r.to_string(), "gpu={:last}@selector=gpu");
+        assert!("gpu={1}@selector="
+            .parse::<Renderer<LabelTemplate>>()
+            .is_err());
+
+        let r: Renderer<LabelTemplate> = "legacy-key=".parse().unwrap();
+        assert!(r.unsets());
+        assert_eq!(r.to_string(), "legacy-key=");
+        let r: Renderer<LabelTemplate> = "legacy-key=@selector=gpu".parse().unwrap();
+        assert!(r.unsets());
+        assert_eq!(r.to_string(), "legacy-key=@selector=gpu");
+        assert!(!"zone={:first}"
+            .parse::<Renderer<LabelTemplate>>()
+            .unwrap()
+            .unsets());
+        assert!("-legacy-key=".parse::<Renderer<LabelTemplate>>().is_err());
+    }
+
+    #[test]
+    fn test_selects() {
+        let provider_id = ProviderID::new("my-node", "fake://region/instance").unwrap();
+        let node = testing::node("my-node").label("gpu", "a100").build();
+        let ctx = RenderContext::new(&provider_id).with_metadata(&node.metadata);
+
+        let r = |s: &str| s.parse::<Renderer<LabelTemplate>>().unwrap();
+        assert!(r("zone={:first}").selects(&ctx));
+        assert!(r("zone={:first}@selector=gpu=a100").selects(&ctx));
+        assert!(!r("zone={:first}@selector=gpu notin (a100)").selects(&ctx));
+        assert!(!r("zone={:first}@selector=gpu").selects(&RenderContext::new(&provider_id)));
+    }
+
+    #[test]
+    fn test_skips() {
+        let provider_id = ProviderID::new("my-node", "fake://region/instance").unwrap();
+        let node = testing::node("my-node")
+            .label("kubernetes.io/os", "windows")
+            .label("spot", "false")
+            .build();
+        let sources = source::defaults();
+        let ctx = RenderContext::new(&provider_id)
+            .with_metadata(&node.metadata)
+            .with_sources(&sources);
+
+        let r = |s: &str| s.parse::<Renderer<LabelTemplate>>().unwrap();
+        assert!(!r("zone={:first}").skips(&ctx).unwrap());
+        assert!(r("zone={:first}@skip={label:kubernetes.io/os}==windows")
+            .skips(&ctx)
+            .unwrap());
+        assert!(!r("zone={:first}@skip={label:kubernetes.io/os}!=windows")
+            .skips(&ctx)
+            .unwrap());
+        assert!(r("zone={:first}@skip={:first}").skips(&ctx).unwrap());
+        // missing fields and "false" are falsy
+        assert!(!r("zone={:first}@skip={label:missing}").skips(&ctx).unwrap());
+        assert!(!r("zone={:first}@skip={label:spot}").skips(&ctx).unwrap());
+
+        let r = r("gpu={1}@skip={:first}==other@selector=gpu");
+        assert_eq!(r.selector().unwrap().to_string(), "gpu");
+        assert_eq!(r.skip().unwrap().to_string(), "{:first}==other");
+        assert_eq!(r.to_string(), "gpu={1}@selector=gpu@skip={:first}==other");
+
+        assert!("gpu={1}@skip=".parse::<Renderer<LabelTemplate>>().is_err());
+    }
+
+    #[test]
+    fn test_overwrite() {
+        let r = |s: &str| s.parse::<Renderer<LabelTemplate>>();
+        assert_eq!(r("zone={:first}").unwrap().overwrite(), Overwrite::Always);
+        let zone = r("zone={:first}@overwrite=if-managed@selector=gpu").unwrap();
+        assert_eq!(zone.overwrite(), Overwrite::IfManaged);
+        assert_eq!(zone.selector().unwrap().to_string(), "gpu");
+        assert_eq!(
+            zone.to_string(),
+            "zone={:first}@selector=gpu@overwrite=if-managed"
+        );
+        assert_eq!(
+            r("zone={:first}@overwrite=always").unwrap().to_string(),
+            "zone={:first}"
+        );
+        assert!(r("zone={:first}@overwrite=never").is_err());
+
+        assert!(!Overwrite::Always.keeps(true, false));
+        assert!(Overwrite::IfAbsent.keeps(true, true));
+        assert!(!Overwrite::IfAbsent.keeps(false, false));
+        assert!(Overwrite::IfManaged.keeps(true, false));
+        assert!(!Overwrite::IfManaged.keeps(true, true));
+        assert!(!Overwrite::IfManaged.keeps(false, false));
+    }
+
+    #[test]
+    fn test_disabled() {
+        let r = |s: &str| s.parse::<Renderer<LabelTemplate>>();
+        assert!(!r("zone={:first}").unwrap().disabled());
+        let zone = r("zone={:first}@disabled=true@overwrite=if-absent").unwrap();
+        assert!(zone.disabled());
+        assert_eq!(
+            zone.to_string(),
+            "zone={:first}@overwrite=if-absent@disabled=true"
+        );
+        assert_eq!(
+            r("zone={:first}@disabled=false").unwrap().to_string(),
+            "zone={:first}"
+        );
+        assert!(r("legacy-key=@disabled=true").unwrap().unsets());
+        assert!(r("zone={:first}@disabled=yes").is_err());
+    }
+
+    #[test]
+    fn test_render_for() {
+        let r = Renderer::<LabelTemplate>::new("zone", "{:node}-{:first}").unwrap();
+        assert_eq!(
+            r.render_for(&node(Some("fake://region/instance"))).unwrap(),
+            "my-node-region"
+        );
+        assert!(matches!(
+            r.render_for(&node(None)),
+            Err(Error::MissingObjectKey(_))
+        ));
+        assert!(matches!(
+            r.render_for(&node(Some("invalid"))),
+            Err(Error::ProviderID(_))
+        ));
+    }
+}
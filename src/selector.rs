@@ -0,0 +1,159 @@
+//! Label selectors evaluated against a node's labels, for renderers that only
+//! apply to some nodes, e.g. "node.kubernetes.io/instance-type in (p4d.24xlarge)".
+
+use crate::Error;
+use std::{collections::BTreeMap, str::FromStr};
+
+#[derive(Clone, Debug, PartialEq)]
+enum Requirement {
+    Exists(String),
+    DoesNotExist(String),
+    Equals(String, String),
+    NotEquals(String, String),
+    In(String, Vec<String>),
+    NotIn(String, Vec<String>),
+}
+
+impl Requirement {
+    fn matches(&self, labels: &BTreeMap<String, String>) -> bool {
+        match self {
+            Requirement::Exists(key) => labels.contains_key(key),
+            Requirement::DoesNotExist(key) => !labels.contains_key(key),
+            Requirement::Equals(key, value) => labels.get(key) == Some(value),
+            Requirement::NotEquals(key, value) => labels.get(key) != Some(value),
+            Requirement::In(key, values) => labels.get(key).is_some_and(|v| values.contains(v)),
+            Requirement::NotIn(key, values) => !labels.get(key).is_some_and(|v| values.contains(v)),
+        }
+    }
+}
+
+impl FromStr for Requirement {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || Error::Config(format!("invalid selector requirement '{s}'"));
+        let s = s.trim();
+        let set = |rest: &str| -> Result<Vec<String>, Error> {
+            let values = rest
+                .trim()
+                .strip_prefix('(')
+                .and_then(|r| r.strip_suffix(')'))
+                .ok_or_else(invalid)?;
+            Ok(values.split(',').map(|v| v.trim().to_string()).collect())
+        };
+
+        let requirement = if let Some(key) = s.strip_prefix('!') {
+            Requirement::DoesNotExist(key.trim().to_string())
+        } else if let Some((key, rest)) = s.split_once(" notin ") {
+            Requirement::NotIn(key.trim().to_string(), set(rest)?)
+        } else if let Some((key, rest)) = s.split_once(" in ") {
+            Requirement::In(key.trim().to_string(), set(rest)?)
+        } else if let Some((key, value)) = s.split_once("!=") {
+            Requirement::NotEquals(key.trim().to_string(), value.trim().to_string())
+        } else if let Some((key, value)) = s.split_once("==").or_else(|| s.split_once('=')) {
+            Requirement::Equals(key.trim().to_string(), value.trim().to_string())
+        } else {
+            Requirement::Exists(s.to_string())
+        };
+
+        let key = match &requirement {
+            Requirement::Exists(key)
+            | Requirement::DoesNotExist(key)
+            | Requirement::Equals(key, _)
+            | Requirement::NotEquals(key, _)
+            | Requirement::In(key, _)
+            | Requirement::NotIn(key, _) => key,
+        };
+        if key.is_empty() || key.contains(char::is_whitespace) {
+            return Err(invalid());
+        }
+        Ok(requirement)
+    }
+}
+
+/// A label selector in the syntax of `kubectl --selector`: comma-separated
+/// requirements that must all match, each one of `key`, `!key`, `key=value`,
+/// `key!=value`, `key in (a,b)`, or `key notin (a,b)`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct LabelSelector {
+    source: String,
+    requirements: Vec<Requirement>,
+}
+
+impl LabelSelector {
+    /// Whether the labels match every requirement.
+    pub fn matches(&self, labels: &BTreeMap<String, String>) -> bool {
+        self.requirements.iter().all(|r| r.matches(labels))
+    }
+}
+
+impl FromStr for LabelSelector {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        // commas separate requirements, except within the value sets
+        let mut requirements = vec![];
+        let mut depth = 0;
+        let mut start = 0;
+        for (i, c) in s.char_indices() {
+            match c {
+                '(' => depth += 1,
+                ')' => depth -= 1,
+                ',' if depth == 0 => {
+                    requirements.push(s[start..i].parse()?);
+                    start = i + 1;
+                }
+                _ => (),
+            }
+        }
+        requirements.push(s[start..].parse()?);
+
+        Ok(Self {
+            source: s.to_string(),
+            requirements,
+        })
+    }
+}
+
+impl std::fmt::Display for LabelSelector {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.source)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_label_selector() {
+        let labels = BTreeMap::from([
+            (
+                "node.kubernetes.io/instance-type".to_string(),
+                "p4d.24xlarge".to_string(),
+            ),
+            ("kubernetes.io/os".to_string(), "linux".to_string()),
+        ]);
+        let matches = |s: &str| s.parse::<LabelSelector>().unwrap().matches(&labels);
+
+        assert!(matches(
+            "node.kubernetes.io/instance-type in (p4d.24xlarge, p5.48xlarge)"
+        ));
+        assert!(matches(
+            "kubernetes.io/os=linux,node.kubernetes.io/instance-type"
+        ));
+        assert!(matches("kubernetes.io/os==linux"));
+        assert!(matches("kubernetes.io/os notin (windows),!gpu"));
+        assert!(matches("gpu!=true"));
+        assert!(!matches("kubernetes.io/os!=linux"));
+        assert!(!matches(
+            "node.kubernetes.io/instance-type in (m5.large),kubernetes.io/os"
+        ));
+        assert!(!matches("gpu in (true)"));
+        assert!(!matches("!kubernetes.io/os"));
+
+        for invalid in ["", "a in b", "a in (b", "=value", "a b"] {
+            assert!(invalid.parse::<LabelSelector>().is_err(), "{invalid}");
+        }
+    }
+}
@@ -0,0 +1,797 @@
+use crate::logging::{self, FilterHandle};
+use axum::{
+    extract,
+    http::{header, HeaderMap, StatusCode},
+    middleware::{self, Next},
+    response::{IntoResponse, Response},
+    routing::{get, put},
+    Json, Router,
+};
+use hyper_util::{
+    rt::{TokioExecutor, TokioIo},
+    server::conn::auto,
+    service::TowerToHyperService,
+};
+use node_provider_labeler::{
+    diagnostics::Report, export::NodeInfo, shutdown::Shutdown, Error, State,
+};
+use prometheus::{Encoder, TextEncoder};
+use std::{
+    net::SocketAddr,
+    os::unix::fs::FileTypeExt,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::{TcpListener, TcpStream, UnixListener, UnixStream},
+};
+#[cfg(feature = "rustls-tls")]
+use tokio_rustls::{
+    rustls::{
+        crypto::ring,
+        pki_types::{CertificateDer, PrivateKeyDer},
+        server::WebPkiClientVerifier,
+        RootCertStore, ServerConfig,
+    },
+    TlsAcceptor,
+};
+use tracing::{debug, info, warn};
+
+#[derive(clap::Args, Debug)]
+pub(crate) struct ServerArgs {
+    /// The address to serve on
+    #[arg(long, value_name = "ADDR", default_value = "0.0.0.0:8080")]
+    listen_addr: SocketAddr,
+    /// Serve on this unix socket path instead of --listen-addr, e.g. for
+    /// sidecar scraping where no TCP port may be exposed
+    #[arg(long, value_name = "PATH")]
+    listen_unix: Option<PathBuf>,
+    /// Serve /metrics, /diagnostics and /debug/log-level on this separate
+    /// address, leaving /health and /readyz on the main listener
+    #[arg(long, value_name = "ADDR")]
+    metrics_listen_addr: Option<SocketAddr>,
+    /// Serve HTTPS using this PEM-encoded certificate chain
+    #[arg(long, value_name = "FILE", requires = "tls_key_file")]
+    tls_cert_file: Option<PathBuf>,
+    /// The PEM-encoded private key for --tls-cert-file
+    #[arg(long, value_name = "FILE", requires = "tls_cert_file")]
+    tls_key_file: Option<PathBuf>,
+    /// Accept /metrics clients presenting a certificate signed by a CA in this
+    /// PEM bundle. Requires --tls-cert-file.
+    #[arg(long, value_name = "FILE", requires = "tls_cert_file")]
+    metrics_client_ca_file: Option<PathBuf>,
+    /// Accept /metrics clients sending the bearer token contained in this
+    /// file. The file is re-read on every request so the token can be rotated.
+    #[arg(long, value_name = "FILE")]
+    metrics_bearer_token_file: Option<PathBuf>,
+    /// Serve PUT /debug/log-level to change the log filter at runtime. The
+    /// endpoint uses the /metrics authentication, which must be configured.
+    #[arg(long)]
+    log_level_endpoint: bool,
+    /// Serve CPU and heap profiles on /debug/pprof/profile and
+    /// /debug/pprof/heap, behind the /metrics authentication
+    #[cfg(feature = "pprof")]
+    #[arg(long)]
+    pprof: bool,
+}
+
+impl ServerArgs {
+    fn metrics_auth(&self) -> MetricsAuth {
+        MetricsAuth {
+            bearer_token_file: self.metrics_bearer_token_file.clone(),
+            client_certs: self.metrics_client_ca_file.is_some(),
+        }
+    }
+
+    #[cfg(feature = "rustls-tls")]
+    fn tls_acceptor(&self) -> Result<Option<TlsAcceptor>, Error> {
+        Ok(self
+            .tls_config()?
+            .map(|config| TlsAcceptor::from(Arc::new(config))))
+    }
+
+    #[cfg(not(feature = "rustls-tls"))]
+    fn tls_acceptor(&self) -> Result<Option<TlsAcceptor>, Error> {
+        match self.tls_cert_file {
+            Some(_) => Err(Error::Config(
+                "--tls-cert-file requires a build with the rustls-tls feature".to_string(),
+            )),
+            None => Ok(None),
+        }
+    }
+
+    #[cfg(feature = "rustls-tls")]
+    fn tls_config(&self) -> Result<Option<ServerConfig>, Error> {
+        let (Some(cert_file), Some(key_file)) = (&self.tls_cert_file, &self.tls_key_file) else {
+            return Ok(None);
+        };
+
+        let provider = Arc::new(ring::default_provider());
+        let builder = ServerConfig::builder_with_provider(provider.clone())
+            .with_safe_default_protocol_versions()
+            .map_err(|e| Error::Tls(e.to_string()))?;
+
+        let builder = match &self.metrics_client_ca_file {
+            Some(ca_file) => {
+                let mut roots = RootCertStore::empty();
+                for cert in load_certs(ca_file)? {
+                    roots.add(cert).map_err(|e| Error::Tls(e.to_string()))?;
+                }
+                // clients without a certificate are still accepted so /health
+                // stays open; /metrics checks for a verified certificate
+                let verifier = WebPkiClientVerifier::builder_with_provider(roots.into(), provider)
+                    .allow_unauthenticated()
+                    .build()
+                    .map_err(|e| Error::Tls(e.to_string()))?;
+                builder.with_client_cert_verifier(verifier)
+            }
+            None => builder.with_no_client_auth(),
+        };
+
+        let config = builder
+            .with_single_cert(load_certs(cert_file)?, load_key(key_file)?)
+            .map_err(|e| Error::Tls(e.to_string()))?;
+
+        Ok(Some(config))
+    }
+}
+
+#[cfg(feature = "rustls-tls")]
+fn load_certs(path: &Path) -> Result<Vec<CertificateDer<'static>>, Error> {
+    let mut reader = std::io::BufReader::new(open(path)?);
+    rustls_pemfile::certs(&mut reader)
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| Error::Tls(format!("reading {}: {e}", path.display())))
+}
+
+#[cfg(feature = "rustls-tls")]
+fn load_key(path: &Path) -> Result<PrivateKeyDer<'static>, Error> {
+    let mut reader = std::io::BufReader::new(open(path)?);
+    rustls_pemfile::private_key(&mut reader)
+        .map_err(|e| Error::Tls(format!("reading {}: {e}", path.display())))?
+        .ok_or_else(|| Error::Tls(format!("no private key found in {}", path.display())))
+}
+
+#[cfg(feature = "rustls-tls")]
+fn open(path: &Path) -> Result<std::fs::File, Error> {
+    std::fs::File::open(path).map_err(|e| Error::Tls(format!("opening {}: {e}", path.display())))
+}
+
+/// Stands in for the rustls acceptor in builds without it, where HTTPS is
+/// refused at startup.
+#[cfg(not(feature = "rustls-tls"))]
+#[derive(Clone)]
+enum TlsAcceptor {}
+
+/// Marks requests on connections that presented a client certificate
+/// verified against --metrics-client-ca-file.
+#[derive(Clone, Copy, Debug)]
+struct ClientCertificate;
+
+/// The ways a /metrics client may authenticate. With none configured the
+/// endpoint is open.
+#[derive(Clone, Debug, Default)]
+struct MetricsAuth {
+    bearer_token_file: Option<PathBuf>,
+    client_certs: bool,
+}
+
+impl MetricsAuth {
+    fn enabled(&self) -> bool {
+        self.bearer_token_file.is_some() || self.client_certs
+    }
+
+    async fn authorize(&self, headers: &HeaderMap, client_cert: bool) -> bool {
+        if !self.enabled() {
+            return true;
+        }
+
+        if self.client_certs && client_cert {
+            return true;
+        }
+
+        let Some(path) = &self.bearer_token_file else {
+            return false;
+        };
+        let Some(token) = headers
+            .get(header::AUTHORIZATION)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.strip_prefix("Bearer "))
+        else {
+            return false;
+        };
+
+        match tokio::fs::read_to_string(path).await {
+            // an empty token would let "Bearer " through
+            Ok(expected) if expected.trim().is_empty() => {
+                warn!(
+                    { path = path.display().to_string() },
+                    "metrics bearer token file is empty"
+                );
+                false
+            }
+            Ok(expected) => constant_time_eq(token.trim().as_bytes(), expected.trim().as_bytes()),
+            Err(e) => {
+                warn!(
+                    { error = e.to_string() },
+                    "unable to read metrics bearer token"
+                );
+                false
+            }
+        }
+    }
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+async fn require_auth(
+    extract::State(auth): extract::State<Arc<MetricsAuth>>,
+    request: extract::Request,
+    next: Next,
+) -> Response {
+    let client_cert = request.extensions().get::<ClientCertificate>().is_some();
+    if auth.authorize(request.headers(), client_cert).await {
+        return next.run(request).await;
+    }
+
+    (
+        StatusCode::UNAUTHORIZED,
+        [(header::WWW_AUTHENTICATE, "Bearer")],
+        "unauthorized",
+    )
+        .into_response()
+}
+
+/// Routes for the kubelet: /health and /readyz
+fn health_router(state: State) -> Router {
+    Router::new()
+        .route("/health", get(health))
+        .route("/readyz", get(readyz))
+        .with_state(state)
+}
+
+/// Routes for Prometheus and operators: /metrics, /diagnostics, /api/v1/nodes
+/// and the optional debug routes, all behind the metrics authentication
+fn metrics_router(state: State, auth: MetricsAuth, debug: Option<Router>) -> Router {
+    let auth = middleware::from_fn_with_state(Arc::new(auth), require_auth);
+    let app = Router::new()
+        .route("/metrics", get(metrics))
+        .route("/diagnostics", get(diagnostics))
+        .route("/api/v1/nodes", get(nodes))
+        .route_layer(auth.clone())
+        .with_state(state);
+
+    match debug {
+        Some(debug) => app.merge(debug.route_layer(auth)),
+        None => app,
+    }
+}
+
+fn log_level_router(log_filter: FilterHandle) -> Router {
+    Router::new()
+        .route("/debug/log-level", put(set_log_level))
+        .with_state(log_filter)
+}
+
+#[cfg(test)]
+fn router(state: State, auth: MetricsAuth, log_filter: Option<FilterHandle>) -> Router {
+    health_router(state.clone()).merge(metrics_router(
+        state,
+        auth,
+        log_filter.map(log_level_router),
+    ))
+}
+
+/// Serves /health, /readyz, /metrics and /diagnostics until shutdown is
+/// requested, over HTTPS when a certificate is configured. With
+/// --metrics-listen-addr, the metrics routes get their own listener.
+pub(crate) async fn serve(
+    args: ServerArgs,
+    state: State,
+    log_filter: FilterHandle,
+    shutdown: Shutdown,
+) -> Result<(), Error> {
+    let auth = args.metrics_auth();
+    if args.log_level_endpoint && !auth.enabled() {
+        return Err(Error::Config(
+            "--log-level-endpoint requires --metrics-bearer-token-file or --metrics-client-ca-file"
+                .to_string(),
+        ));
+    }
+    let tls = args.tls_acceptor()?;
+
+    let health = health_router(state.clone());
+    let debug = args
+        .log_level_endpoint
+        .then(|| log_level_router(log_filter));
+    #[cfg(feature = "pprof")]
+    let debug = if args.pprof {
+        crate::profiling::activate().await;
+        let pprof = crate::profiling::router();
+        Some(match debug {
+            Some(debug) => debug.merge(pprof),
+            None => pprof,
+        })
+    } else {
+        debug
+    };
+    let metrics = metrics_router(state, auth, debug);
+    let bind = match args.listen_unix {
+        Some(path) => Bind::Unix(path),
+        None => Bind::Tcp(args.listen_addr),
+    };
+
+    match args.metrics_listen_addr {
+        Some(metrics_addr) => {
+            tokio::try_join!(
+                serve_on(bind, health, tls.clone(), shutdown.clone()),
+                serve_on(Bind::Tcp(metrics_addr), metrics, tls, shutdown),
+            )?;
+            Ok(())
+        }
+        None => serve_on(bind, health.merge(metrics), tls, shutdown).await,
+    }
+}
+
+enum Bind {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+}
+
+/// Binds the unix socket, replacing a socket left behind by a previous run,
+/// which would fail the bind. Anything else at the path is left alone.
+fn bind_unix(path: &Path) -> Result<UnixListener, Error> {
+    match std::fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.file_type().is_socket() => std::fs::remove_file(path)?,
+        Ok(_) => {
+            return Err(Error::Config(format!(
+                "--listen-unix path {} exists and is not a socket",
+                path.display()
+            )))
+        }
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => (),
+        Err(e) => return Err(e.into()),
+    }
+    Ok(UnixListener::bind(path)?)
+}
+
+async fn serve_on(
+    bind: Bind,
+    app: Router,
+    tls: Option<TlsAcceptor>,
+    shutdown: Shutdown,
+) -> Result<(), Error> {
+    match bind {
+        Bind::Unix(path) => {
+            let listener = Listener::Unix(bind_unix(&path)?);
+            let res = serve_connections(listener, app, tls, shutdown).await;
+            let _ = std::fs::remove_file(&path);
+            res
+        }
+        Bind::Tcp(addr) => {
+            let listener = TcpListener::bind(addr).await?;
+            match tls {
+                Some(tls) => {
+                    serve_connections(Listener::Tcp(listener), app, Some(tls), shutdown).await
+                }
+                None => Ok(axum::serve(listener, app)
+                    .with_graceful_shutdown(shutdown.requested())
+                    .await?),
+            }
+        }
+    }
+}
+
+enum Listener {
+    Tcp(TcpListener),
+    Unix(UnixListener),
+}
+
+/// Accepts connections until shutdown is requested, serving each on its own
+/// task.
+async fn serve_connections(
+    listener: Listener,
+    app: Router,
+    tls: Option<TlsAcceptor>,
+    shutdown: Shutdown,
+) -> Result<(), Error> {
+    let shutdown = shutdown.requested();
+    tokio::pin!(shutdown);
+
+    loop {
+        let accepted = tokio::select! {
+            accepted = listener.accept() => accepted,
+            _ = &mut shutdown => return Ok(()),
+        };
+        let app = app.clone();
+        let tls = tls.clone();
+        match accepted {
+            Ok(Connection::Tcp(stream, remote)) => {
+                tokio::spawn(serve_connection(stream, remote.to_string(), app, tls));
+            }
+            Ok(Connection::Unix(stream)) => {
+                tokio::spawn(serve_connection(stream, "unix".to_string(), app, tls));
+            }
+            Err(e) => warn!({ error = e.to_string() }, "error accepting connection"),
+        }
+    }
+}
+
+enum Connection {
+    Tcp(TcpStream, SocketAddr),
+    Unix(UnixStream),
+}
+
+impl Listener {
+    async fn accept(&self) -> std::io::Result<Connection> {
+        match self {
+            Listener::Tcp(l) => l.accept().await.map(|(s, a)| Connection::Tcp(s, a)),
+            Listener::Unix(l) => l.accept().await.map(|(s, _)| Connection::Unix(s)),
+        }
+    }
+}
+
+async fn serve_connection<S>(stream: S, remote: String, app: Router, tls: Option<TlsAcceptor>)
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    match tls {
+        Some(acceptor) => serve_https(stream, &remote, app, acceptor).await,
+        None => serve_http(stream, &remote, app).await,
+    }
+}
+
+#[cfg(feature = "rustls-tls")]
+async fn serve_https<S>(stream: S, remote: &str, app: Router, acceptor: TlsAcceptor)
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let stream = match acceptor.accept(stream).await {
+        Ok(stream) => stream,
+        Err(e) => {
+            debug!({ remote = remote, error = e.to_string() }, "tls handshake failed");
+            return;
+        }
+    };
+
+    let client_cert = stream
+        .get_ref()
+        .1
+        .peer_certificates()
+        .is_some_and(|certs| !certs.is_empty());
+    let app = if client_cert {
+        app.layer(axum::Extension(ClientCertificate))
+    } else {
+        app
+    };
+
+    serve_http(stream, remote, app).await
+}
+
+#[cfg(not(feature = "rustls-tls"))]
+async fn serve_https<S>(_: S, _: &str, _: Router, acceptor: TlsAcceptor) {
+    match acceptor {}
+}
+
+async fn serve_http<S>(stream: S, remote: &str, app: Router)
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    if let Err(e) = auto::Builder::new(TokioExecutor::new())
+        .serve_connection(TokioIo::new(stream), TowerToHyperService::new(app))
+        .await
+    {
+        debug!({ remote = remote, error = e.to_string() }, "error serving connection");
+    }
+}
+
+async fn metrics(extract::State(state): extract::State<State>) -> (StatusCode, Vec<u8>) {
+    let m = state.metrics();
+    let encoder = TextEncoder::new();
+    let mut buffer = vec![];
+    if let Err(e) = encoder.encode(&m, &mut buffer) {
+        warn!({ error = e.to_string() }, "error encoding metrics");
+        return (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "internal server error".into(),
+        );
+    }
+
+    (StatusCode::OK, buffer)
+}
+
+async fn set_log_level(
+    extract::State(handle): extract::State<FilterHandle>,
+    directives: String,
+) -> (StatusCode, String) {
+    match logging::set_filter(&handle, &directives) {
+        Ok(applied) => {
+            info!({ filter = applied }, "log filter changed");
+            (StatusCode::OK, applied)
+        }
+        Err(e) => (StatusCode::BAD_REQUEST, e.to_string()),
+    }
+}
+
+async fn health(extract::State(state): extract::State<State>) -> (StatusCode, &'static str) {
+    if state.diagnostics.write().await.healthy() {
+        (StatusCode::OK, "OK")
+    } else {
+        (StatusCode::INTERNAL_SERVER_ERROR, "Unhealthy")
+    }
+}
+
+async fn diagnostics(extract::State(state): extract::State<State>) -> Json<Report> {
+    Json(state.diagnostics.write().await.report())
+}
+
+/// The observed nodes, by name, with their provider ID breakdown and the
+/// values the controller manages on them.
+async fn nodes(extract::State(state): extract::State<State>) -> Response {
+    let Some(store) = state.nodes.get() else {
+        return (StatusCode::SERVICE_UNAVAILABLE, "controller not started").into_response();
+    };
+    let mut nodes = store
+        .state()
+        .iter()
+        .map(|node| NodeInfo::from(node.as_ref()))
+        .collect::<Vec<_>>();
+    nodes.sort_by(|a, b| a.name.cmp(&b.name));
+    Json(nodes).into_response()
+}
+
+/// Ready once the API server is reachable, so the pod is marked unready when
+/// it loses connectivity rather than silently doing nothing.
+async fn readyz(extract::State(state): extract::State<State>) -> (StatusCode, &'static str) {
+    if state.diagnostics.read().await.api_ready {
+        (StatusCode::OK, "OK")
+    } else {
+        (StatusCode::SERVICE_UNAVAILABLE, "Unready")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{body::Body, http::Request};
+    use tower::ServiceExt;
+
+    async fn status(
+        app: &Router,
+        path: &str,
+        token: Option<&str>,
+        client_cert: bool,
+    ) -> StatusCode {
+        request(app, Request::get(path), token, client_cert, Body::empty()).await
+    }
+
+    async fn request(
+        app: &Router,
+        mut request: axum::http::request::Builder,
+        token: Option<&str>,
+        client_cert: bool,
+        body: Body,
+    ) -> StatusCode {
+        if let Some(token) = token {
+            request = request.header(header::AUTHORIZATION, format!("Bearer {token}"));
+        }
+        if client_cert {
+            request = request.extension(ClientCertificate);
+        }
+        app.clone()
+            .oneshot(request.body(body).unwrap())
+            .await
+            .unwrap()
+            .status()
+    }
+
+    #[tokio::test]
+    async fn test_metrics_auth() {
+        let app = router(State::default(), MetricsAuth::default(), None);
+        assert_eq!(status(&app, "/metrics", None, false).await, StatusCode::OK);
+
+        let token_file = std::env::temp_dir().join("npl-test-metrics-token");
+        std::fs::write(&token_file, "s3cret\n").unwrap();
+        let app = router(
+            State::default(),
+            MetricsAuth {
+                bearer_token_file: Some(token_file.clone()),
+                client_certs: true,
+            },
+            None,
+        );
+
+        assert_eq!(status(&app, "/health", None, false).await, StatusCode::OK);
+        assert_eq!(
+            status(&app, "/readyz", None, false).await,
+            StatusCode::SERVICE_UNAVAILABLE
+        );
+        assert_eq!(
+            status(&app, "/metrics", None, false).await,
+            StatusCode::UNAUTHORIZED
+        );
+        assert_eq!(
+            status(&app, "/metrics", Some("wrong"), false).await,
+            StatusCode::UNAUTHORIZED
+        );
+        assert_eq!(
+            status(&app, "/metrics", Some("s3cret"), false).await,
+            StatusCode::OK
+        );
+        assert_eq!(status(&app, "/metrics", None, true).await, StatusCode::OK);
+        assert_eq!(
+            status(&app, "/diagnostics", None, false).await,
+            StatusCode::UNAUTHORIZED
+        );
+        assert_eq!(
+            status(&app, "/diagnostics", None, true).await,
+            StatusCode::OK
+        );
+
+        // an empty token file lets nobody in, not an empty token
+        std::fs::write(&token_file, "\n").unwrap();
+        assert_eq!(
+            status(&app, "/metrics", Some(""), false).await,
+            StatusCode::UNAUTHORIZED
+        );
+        assert_eq!(
+            status(&app, "/metrics", Some(" "), false).await,
+            StatusCode::UNAUTHORIZED
+        );
+
+        std::fs::remove_file(token_file).unwrap();
+        assert_eq!(
+            status(&app, "/metrics", Some("s3cret"), false).await,
+            StatusCode::UNAUTHORIZED
+        );
+    }
+
+    #[tokio::test]
+    async fn test_log_level_endpoint() {
+        let (_layer, handle) =
+            tracing_subscriber::reload::Layer::<_, tracing_subscriber::Registry>::new(
+                tracing_subscriber::EnvFilter::new("info"),
+            );
+        let auth = MetricsAuth {
+            bearer_token_file: None,
+            client_certs: true,
+        };
+        let put = |body: &'static str| (Request::put("/debug/log-level"), Body::from(body));
+
+        let app = router(State::default(), auth.clone(), None);
+        let (req, body) = put("debug");
+        assert_eq!(
+            request(&app, req, None, true, body).await,
+            StatusCode::NOT_FOUND
+        );
+
+        let app = router(State::default(), auth, Some(handle.clone()));
+        let (req, body) = put("debug");
+        assert_eq!(
+            request(&app, req, None, false, body).await,
+            StatusCode::UNAUTHORIZED
+        );
+        let (req, body) = put("kube=loud");
+        assert_eq!(
+            request(&app, req, None, true, body).await,
+            StatusCode::BAD_REQUEST
+        );
+        let (req, body) = put("debug");
+        assert_eq!(request(&app, req, None, true, body).await, StatusCode::OK);
+        handle
+            .with_current(|f| assert_eq!(f.to_string(), "debug"))
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_split_routers() {
+        let health = health_router(State::default());
+        let metrics = metrics_router(State::default(), MetricsAuth::default(), None);
+
+        assert_eq!(
+            status(&health, "/health", None, false).await,
+            StatusCode::OK
+        );
+        assert_eq!(
+            status(&health, "/metrics", None, false).await,
+            StatusCode::NOT_FOUND
+        );
+        assert_eq!(
+            status(&metrics, "/metrics", None, false).await,
+            StatusCode::OK
+        );
+        assert_eq!(
+            status(&metrics, "/health", None, false).await,
+            StatusCode::NOT_FOUND
+        );
+    }
+
+    #[tokio::test]
+    async fn test_nodes() {
+        use k8s_openapi::api::core::v1::{Node, NodeSpec};
+        use kube::runtime::{reflector::store::Writer, watcher};
+
+        let state = State::default();
+        let app = metrics_router(state.clone(), MetricsAuth::default(), None);
+        assert_eq!(
+            status(&app, "/api/v1/nodes", None, false).await,
+            StatusCode::SERVICE_UNAVAILABLE
+        );
+
+        let mut writer = Writer::<Node>::default();
+        for (name, provider_id) in [("b", "aws://us-east-2/i-0abc"), ("a", "fake://x")] {
+            let mut node = Node {
+                spec: Some(NodeSpec {
+                    provider_id: Some(provider_id.into()),
+                    ..Default::default()
+                }),
+                ..Default::default()
+            };
+            node.metadata.name = Some(name.into());
+            writer.apply_watcher_event(&watcher::Event::Applied(node));
+        }
+        state.nodes.set(writer.as_reader()).unwrap();
+
+        let res = app
+            .oneshot(Request::get("/api/v1/nodes").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(res.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let nodes: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(nodes[0]["name"], "a");
+        assert_eq!(nodes[1]["name"], "b");
+        assert_eq!(nodes[1]["provider"], "aws");
+        assert_eq!(
+            nodes[1]["parts"],
+            serde_json::json!(["us-east-2", "i-0abc"])
+        );
+    }
+
+    #[tokio::test]
+    async fn test_serve_unix() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let path = std::env::temp_dir().join(format!("npl-test-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let listener = Listener::Unix(UnixListener::bind(&path).unwrap());
+        let (tx, shutdown) = Shutdown::manual();
+        let app = router(State::default(), MetricsAuth::default(), None);
+        let server = tokio::spawn(serve_connections(listener, app, None, shutdown));
+
+        let mut stream = UnixStream::connect(&path).await.unwrap();
+        stream
+            .write_all(b"GET /health HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+            .await
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 200"), "{response}");
+
+        tx.send(true).unwrap();
+        server.await.unwrap().unwrap();
+
+        // the socket left behind is replaced
+        drop(bind_unix(&path).unwrap());
+        std::fs::remove_file(&path).unwrap();
+
+        // anything else is not
+        std::fs::write(&path, "data").unwrap();
+        assert!(matches!(bind_unix(&path), Err(Error::Config(_))));
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "data");
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_constant_time_eq() {
+        assert!(constant_time_eq(b"token", b"token"));
+        assert!(!constant_time_eq(b"token", b"tokex"));
+        assert!(!constant_time_eq(b"token", b"token2"));
+        assert!(!constant_time_eq(b"", b"token"));
+    }
+}
@@ -0,0 +1,55 @@
+use std::sync::Arc;
+use tokio::{
+    signal::unix::{signal, SignalKind},
+    sync::watch,
+};
+use tracing::info;
+
+/// A shutdown signal shared by the server and the controller, triggered by
+/// SIGINT or SIGTERM.
+#[derive(Clone, Debug)]
+pub struct Shutdown {
+    rx: watch::Receiver<bool>,
+    // keeps a never-triggered signal from resolving when the sender drops
+    _tx: Option<Arc<watch::Sender<bool>>>,
+}
+
+impl Shutdown {
+    /// Installs the signal handlers. Must be called within a tokio runtime.
+    pub fn install() -> Self {
+        let (tx, rx) = watch::channel(false);
+        tokio::spawn(async move {
+            let mut sigterm =
+                signal(SignalKind::terminate()).expect("unable to install SIGTERM handler");
+            tokio::select! {
+                _ = tokio::signal::ctrl_c() => {}
+                _ = sigterm.recv() => {}
+            }
+            info!("received shutdown signal");
+            let _ = tx.send(true);
+        });
+        Self { rx, _tx: None }
+    }
+
+    /// Returns a signal triggered by sending `true` through the sender, for
+    /// callers managing their own lifecycle. Dropping the sender also
+    /// triggers it.
+    pub fn manual() -> (watch::Sender<bool>, Self) {
+        let (tx, rx) = watch::channel(false);
+        (tx, Self { rx, _tx: None })
+    }
+
+    /// Returns a signal that is never triggered.
+    pub fn never() -> Self {
+        let (tx, rx) = watch::channel(false);
+        Self {
+            rx,
+            _tx: Some(Arc::new(tx)),
+        }
+    }
+
+    /// Resolves once shutdown has been requested.
+    pub async fn requested(mut self) {
+        let _ = self.rx.wait_for(|s| *s).await;
+    }
+}
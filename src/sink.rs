@@ -0,0 +1,973 @@
+use crate::{
+    export::managed_keys,
+    meta,
+    provider_id::ProviderID,
+    renderer::Renderer,
+    template::{
+        stable_hash, AnnotationTemplate, LabelTemplate, RenderContext, Template, PROVIDER_ID_KEY,
+    },
+    Error,
+};
+use k8s_openapi::api::core::v1::{Node, Taint};
+use kube::api::ObjectMeta;
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    str::FromStr,
+};
+use time::{format_description::well_known::Rfc3339, OffsetDateTime};
+
+pub type MetadataPairs = BTreeMap<String, String>;
+
+const MANAGER: &str = "node-provider-labeler";
+
+const TAINT_EFFECTS: &[&str] = &["NoSchedule", "PreferNoSchedule", "NoExecute"];
+
+/// The object rendered values are applied to: a node, or the Cluster API
+/// Machine owning it.
+#[derive(Clone, Copy, Debug)]
+pub enum Target<'a> {
+    Node(&'a Node),
+    Machine(&'a ObjectMeta),
+}
+
+impl<'a> Target<'a> {
+    pub fn metadata(&self) -> &'a ObjectMeta {
+        match self {
+            Target::Node(node) => &node.metadata,
+            Target::Machine(metadata) => metadata,
+        }
+    }
+}
+
+/// The values sinks rendered for a target, applied in a single patch so the
+/// controller's field manager keeps owning all of them. Keys to remove go out
+/// in a second patch, since applying a patch can't remove keys other managers
+/// own.
+#[derive(Debug, Default, PartialEq)]
+pub struct TargetPatch {
+    pub labels: MetadataPairs,
+    pub annotations: MetadataPairs,
+    /// The labels to remove, of those the target has
+    pub unset_labels: BTreeSet<String>,
+    /// The annotations to remove, of those the target has
+    pub unset_annotations: BTreeSet<String>,
+    /// The complete taint list, or `None` to leave the taints alone
+    pub taints: Option<Vec<Taint>>,
+    /// How many rendered values differ from the target's current ones
+    pub changed: usize,
+    /// How many labels and annotations a sink overwrote with a different
+    /// value than an earlier sink rendered
+    pub conflicts: usize,
+    /// The key whose value failed to render, if a sink failed on one
+    pub failed_key: Option<String>,
+}
+
+impl TargetPatch {
+    /// Records the key whose value failed to render, returning the error.
+    pub fn fail(&mut self, key: String, e: Error) -> Error {
+        self.failed_key = Some(key);
+        e
+    }
+}
+
+/// A destination for rendered values. The controller runs every sink for a
+/// target and patches it once if any of them changed something.
+pub trait Sink: std::fmt::Debug + Send + Sync {
+    fn render(
+        &self,
+        target: Target<'_>,
+        ctx: &RenderContext,
+        patch: &mut TargetPatch,
+    ) -> Result<(), Error>;
+
+    /// Sinks run in order of priority, so a sink with a higher priority wins
+    /// the keys it sets along with another. At equal priority, the sink
+    /// registered later wins. The built-in sinks have priority 0.
+    fn priority(&self) -> i32 {
+        0
+    }
+}
+
+/// Applies rendered labels.
+#[derive(Debug)]
+pub struct LabelSink(pub Vec<Renderer<LabelTemplate>>);
+
+impl Sink for LabelSink {
+    fn render(
+        &self,
+        target: Target<'_>,
+        ctx: &RenderContext,
+        patch: &mut TargetPatch,
+    ) -> Result<(), Error> {
+        let current = target.metadata().labels.as_ref();
+        let managed = managed_keys(target.metadata(), "f:labels");
+        let (new, old) = calculate_metadata_pairs(current, &managed, &self.0, ctx)
+            .map_err(|(key, e)| patch.fail(key, e))?;
+        for r in &self.0 {
+            if let Some(value) = new.get(&r.key()) {
+                validate_label_value("label", r, value).map_err(|e| patch.fail(r.key(), e))?;
+            }
+        }
+        let unset =
+            unset_keys(current, &managed, &self.0, ctx).map_err(|(key, e)| patch.fail(key, e))?;
+        patch.changed += changed_keys(&new, &old) + unset.len();
+        patch.labels.extend(new);
+        patch.unset_labels.extend(unset);
+        Ok(())
+    }
+}
+
+/// Applies rendered annotations.
+#[derive(Debug)]
+pub struct AnnotationSink(pub Vec<Renderer<AnnotationTemplate>>);
+
+impl Sink for AnnotationSink {
+    fn render(
+        &self,
+        target: Target<'_>,
+        ctx: &RenderContext,
+        patch: &mut TargetPatch,
+    ) -> Result<(), Error> {
+        let current = target.metadata().annotations.as_ref();
+        let managed = managed_keys(target.metadata(), "f:annotations");
+        let (new, old) = calculate_metadata_pairs(current, &managed, &self.0, ctx)
+            .map_err(|(key, e)| patch.fail(key, e))?;
+        let unset =
+            unset_keys(current, &managed, &self.0, ctx).map_err(|(key, e)| patch.fail(key, e))?;
+        patch.changed += changed_keys(&new, &old) + unset.len();
+        patch.annotations.extend(new);
+        patch.unset_annotations.extend(unset);
+        Ok(())
+    }
+}
+
+/// A taint key, the template rendering its value, and its effect, parsed
+/// from "key=template:Effect", e.g. "example.com/zone={:first}:NoSchedule".
+/// Without a template, as in "legacy-key=:NoSchedule", it removes the taint.
+#[derive(Debug)]
+pub struct TaintRenderer {
+    renderer: Renderer<LabelTemplate>,
+    effect: String,
+}
+
+impl FromStr for TaintRenderer {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (renderer, effect) = s
+            .rsplit_once(':')
+            .filter(|(_, effect)| TAINT_EFFECTS.contains(effect))
+            .ok_or_else(|| {
+                Error::Config(format!(
+                    "invalid taint '{s}', expected key=template:{}",
+                    TAINT_EFFECTS.join("|")
+                ))
+            })?;
+
+        Ok(Self {
+            renderer: renderer.parse()?,
+            effect: effect.into(),
+        })
+    }
+}
+
+impl TaintRenderer {
+    /// The taint key, e.g. "example.com/zone".
+    pub fn key(&self) -> String {
+        self.renderer.key()
+    }
+
+    /// The taint effect, e.g. "NoSchedule".
+    pub fn effect(&self) -> &str {
+        &self.effect
+    }
+
+    /// The template rendering the taint value.
+    pub fn template(&self) -> &LabelTemplate {
+        self.renderer.template()
+    }
+}
+
+impl std::fmt::Display for TaintRenderer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:{}", self.renderer, self.effect)
+    }
+}
+
+/// Applies rendered taints to nodes. Machines are left alone.
+///
+/// Node taints are an atomic list, so the patch carries the node's other
+/// taints along with the rendered ones. The controller writes them with
+/// [`taint_patch`], which only changes the rendered ones.
+#[derive(Debug)]
+pub struct TaintSink(pub Vec<TaintRenderer>);
+
+impl Sink for TaintSink {
+    fn render(
+        &self,
+        target: Target<'_>,
+        ctx: &RenderContext,
+        patch: &mut TargetPatch,
+    ) -> Result<(), Error> {
+        let Target::Node(node) = target else {
+            return Ok(());
+        };
+        let current = node
+            .spec
+            .as_ref()
+            .and_then(|spec| spec.taints.clone())
+            .unwrap_or_default();
+        let mut taints = patch.taints.take().unwrap_or(current);
+        let managed = owns_taints(node);
+
+        for t in &self.0 {
+            let key = t.renderer.key();
+            let present = taints
+                .iter()
+                .any(|taint| taint.key == key && taint.effect == t.effect);
+            if t.renderer.disabled() || t.renderer.overwrite().keeps(present, managed) {
+                continue;
+            }
+            if unsets(&t.renderer, ctx).map_err(|e| patch.fail(key.clone(), e))? {
+                let len = taints.len();
+                taints.retain(|taint| !(taint.key == key && taint.effect == t.effect));
+                patch.changed += len - taints.len();
+                continue;
+            }
+            let Some(value) = render_value(&t.renderer, ctx)
+                .and_then(|value| {
+                    if let Some(value) = &value {
+                        validate_label_value("taint", &t.renderer, value)?;
+                    }
+                    Ok(value)
+                })
+                .map_err(|e| patch.fail(key.clone(), e))?
+            else {
+                continue;
+            };
+            let existing = taints
+                .iter()
+                .position(|taint| taint.key == key && taint.effect == t.effect);
+            match existing {
+                // keep the existing taint, and its timeAdded, if it is unchanged
+                Some(i) if taints[i].value.as_deref() == Some(value.as_str()) => continue,
+                Some(i) => {
+                    taints.remove(i);
+                }
+                None => (),
+            }
+            patch.changed += 1;
+            taints.push(Taint {
+                key,
+                value: Some(value),
+                effect: t.effect.clone(),
+                time_added: None,
+            });
+        }
+
+        patch.taints = Some(taints);
+        Ok(())
+    }
+}
+
+/// The JSON patch changing the node's taints to `taints`, removing the ones
+/// that went away and appending the new ones, or `None` if they're the same.
+/// The patch carries the node's resourceVersion, so it fails with a conflict
+/// if the node changed since it was read, rather than writing the taints of a
+/// stale copy over someone else's.
+pub fn taint_patch(node: &Node, taints: &[Taint]) -> Result<Option<json_patch::Patch>, Error> {
+    let current = node.spec.as_ref().and_then(|spec| spec.taints.as_ref());
+    let mut ops = vec![];
+    match current {
+        None if taints.is_empty() => (),
+        None => {
+            ops.push(serde_json::json!({ "op": "add", "path": "/spec/taints", "value": taints }))
+        }
+        Some(current) => {
+            // from the back, so the indexes of the remaining ones hold
+            for (i, _) in current
+                .iter()
+                .enumerate()
+                .rev()
+                .filter(|(_, t)| !taints.contains(t))
+            {
+                ops.push(
+                    serde_json::json!({ "op": "remove", "path": format!("/spec/taints/{i}") }),
+                );
+            }
+            for taint in taints.iter().filter(|t| !current.contains(t)) {
+                ops.push(
+                    serde_json::json!({ "op": "add", "path": "/spec/taints/-", "value": taint }),
+                );
+            }
+        }
+    }
+    if ops.is_empty() {
+        return Ok(None);
+    }
+    let resource_version = node
+        .metadata
+        .resource_version
+        .as_ref()
+        .ok_or_else(|| Error::MissingObjectKey(".metadata.resourceVersion"))?;
+    ops.insert(
+        0,
+        serde_json::json!({ "op": "replace", "path": "/metadata/resourceVersion", "value": resource_version }),
+    );
+    Ok(Some(serde_json::from_value(serde_json::Value::Array(ops))?))
+}
+
+/// The keys and effects of the taints, e.g. for [`without_taints`].
+pub fn taint_keys<'a>(
+    taints: impl IntoIterator<Item = &'a TaintRenderer>,
+) -> Vec<(String, String)> {
+    taints
+        .into_iter()
+        .map(|t| (t.key(), t.effect.clone()))
+        .collect()
+}
+
+/// The node's taints without those with one of the keys and effects, e.g. to
+/// remove the taints the controller manages.
+pub fn without_taints(node: &Node, keys: &[(String, String)]) -> Vec<Taint> {
+    node.spec
+        .as_ref()
+        .and_then(|spec| spec.taints.clone())
+        .unwrap_or_default()
+        .into_iter()
+        .filter(|taint| {
+            !keys
+                .iter()
+                .any(|(key, effect)| *key == taint.key && *effect == taint.effect)
+        })
+        .collect()
+}
+
+/// Whether the controller owns the node's taints.
+pub(crate) fn owns_taints(node: &Node) -> bool {
+    node.metadata
+        .managed_fields
+        .iter()
+        .flatten()
+        .filter(|f| f.manager.as_deref() == Some(MANAGER))
+        .filter_map(|f| f.fields_v1.as_ref())
+        .any(|fields| fields.0["f:spec"].get("f:taints").is_some())
+}
+
+/// Records the previous value of each label and annotation the earlier sinks
+/// change, and when it changed, in the `<key>.previous` and `<key>.changed-at`
+/// annotations. Runs after the other sinks.
+///
+/// The records of unchanged keys are carried along so the controller keeps
+/// owning them. Keys too long for the suffixes are skipped.
+#[derive(Debug, Default)]
+pub struct HistorySink;
+
+const PREVIOUS_SUFFIX: &str = ".previous";
+const CHANGED_AT_SUFFIX: &str = ".changed-at";
+
+impl Sink for HistorySink {
+    fn render(
+        &self,
+        target: Target<'_>,
+        _ctx: &RenderContext,
+        patch: &mut TargetPatch,
+    ) -> Result<(), Error> {
+        let metadata = target.metadata();
+        let current_annotations = metadata.annotations.as_ref();
+        let now = OffsetDateTime::now_utc();
+        let now = now
+            .replace_nanosecond(0)
+            .unwrap_or(now)
+            .format(&Rfc3339)
+            .map_err(|e| Error::Config(format!("unable to format timestamp: {e}")))?;
+
+        let mut history = MetadataPairs::new();
+        for (current, new) in [
+            (metadata.labels.as_ref(), &patch.labels),
+            (current_annotations, &patch.annotations),
+        ] {
+            for (key, value) in new {
+                let Some((previous, changed_at)) = history_keys(key) else {
+                    continue;
+                };
+                match current.and_then(|c| c.get(key)) {
+                    Some(old) if old != value => {
+                        history.insert(previous, old.clone());
+                        history.insert(changed_at, now.clone());
+                    }
+                    _ => {
+                        for k in [previous, changed_at] {
+                            if let Some(v) = current_annotations.and_then(|a| a.get(&k)) {
+                                history.insert(k, v.clone());
+                            }
+                        }
+                    }
+                }
+            }
+        }
+
+        patch.annotations.extend(history);
+        Ok(())
+    }
+}
+
+/// Holds the provider ID the values of a node were rendered from.
+pub const PROVIDER_ID_ANNOTATION: &str = "node-provider-labeler/provider-id";
+
+/// Records the provider ID the values were rendered from in the
+/// [`PROVIDER_ID_ANNOTATION`], telling a node re-registered with another
+/// provider ID apart from one whose values drifted. Machines and nodes
+/// rendered with topology fallback are left alone.
+#[derive(Debug, Default)]
+pub struct ProviderIDSink;
+
+impl Sink for ProviderIDSink {
+    fn render(
+        &self,
+        target: Target<'_>,
+        ctx: &RenderContext,
+        patch: &mut TargetPatch,
+    ) -> Result<(), Error> {
+        if !matches!(target, Target::Node(_)) || ctx.topology_fallback {
+            return Ok(());
+        }
+        let new = MetadataPairs::from([(
+            PROVIDER_ID_ANNOTATION.to_string(),
+            ctx.provider_id.to_string(),
+        )]);
+        let old = target.metadata().annotations.clone().unwrap_or_default();
+        patch.changed += changed_keys(&new, &old);
+        patch.annotations.extend(new);
+        Ok(())
+    }
+}
+
+/// Holds a checksum of the configuration and the provider ID the values of a
+/// node were rendered with.
+pub const CHECKSUM_ANNOTATION: &str = "node-provider-labeler/checksum";
+
+/// The checksum recorded in the [`CHECKSUM_ANNOTATION`], of the hash of the
+/// configuration, e.g. the label, annotation, and taint specs, and the
+/// provider ID.
+pub fn checksum(config: &str, provider_id: &ProviderID) -> String {
+    format!("{:016x}", stable_hash(&format!("{config}\n{provider_id}")))
+}
+
+/// Records the [`checksum`] of the configuration with the hash and the
+/// node's provider ID in the [`CHECKSUM_ANNOTATION`], so reconciliations of
+/// an unchanged node can skip rendering. Machines and nodes rendered with
+/// topology fallback are left alone.
+#[derive(Debug)]
+pub struct ChecksumSink(pub String);
+
+impl Sink for ChecksumSink {
+    fn render(
+        &self,
+        target: Target<'_>,
+        ctx: &RenderContext,
+        patch: &mut TargetPatch,
+    ) -> Result<(), Error> {
+        if !matches!(target, Target::Node(_)) || ctx.topology_fallback {
+            return Ok(());
+        }
+        let new = MetadataPairs::from([(
+            CHECKSUM_ANNOTATION.to_string(),
+            checksum(&self.0, ctx.provider_id),
+        )]);
+        let old = target.metadata().annotations.clone().unwrap_or_default();
+        patch.changed += changed_keys(&new, &old);
+        patch.annotations.extend(new);
+        Ok(())
+    }
+}
+
+/// The history annotation keys for the key, if its name leaves room for the
+/// suffixes.
+fn history_keys(key: &str) -> Option<(String, String)> {
+    let name = key.rsplit_once('/').map_or(key, |(_, name)| name);
+    (name.len() + CHANGED_AT_SUFFIX.len() <= 63).then(|| {
+        (
+            format!("{key}{PREVIOUS_SUFFIX}"),
+            format!("{key}{CHANGED_AT_SUFFIX}"),
+        )
+    })
+}
+
+/// Fails unless the value is a valid label value, which the API server would
+/// otherwise reject with the whole patch.
+fn validate_label_value(
+    kind: &str,
+    renderer: &Renderer<LabelTemplate>,
+    value: &str,
+) -> Result<(), Error> {
+    meta::validate_label_value(value).map_err(|e| {
+        Error::LabelValue(format!(
+            "{kind} '{}' template '{}' rendered invalid value '{value}': {e}",
+            renderer.key(),
+            renderer.template()
+        ))
+    })
+}
+
+/// Counts the keys whose rendered value differs from the current one.
+pub(crate) fn changed_keys(new: &MetadataPairs, old: &MetadataPairs) -> usize {
+    new.iter().filter(|(k, v)| old.get(*k) != Some(v)).count()
+}
+
+/// Renders the value, or `None` if the renderer is disabled or removes its
+/// key, its selector doesn't match the node or its skip condition holds, the
+/// template leaves it unset, or it needs the provider ID of a node rendered
+/// with topology fallback.
+fn render_value<T>(renderer: &Renderer<T>, ctx: &RenderContext) -> Result<Option<String>, Error>
+where
+    T: std::fmt::Debug + std::default::Default + Template + std::str::FromStr,
+    Error: std::convert::From<<T as std::str::FromStr>::Err>,
+{
+    if renderer.disabled() || renderer.unsets() || !renderer.selects(ctx) || renderer.skips(ctx)? {
+        return Ok(None);
+    }
+    match renderer.template().render_value(ctx) {
+        Ok(value) => Ok(value),
+        Err(Error::MissingObjectKey(PROVIDER_ID_KEY)) if ctx.topology_fallback => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// Whether the renderer removes its key from the node the context renders
+/// for, i.e. it unsets the key, its selector matches, and its skip condition
+/// doesn't hold.
+fn unsets<T>(renderer: &Renderer<T>, ctx: &RenderContext) -> Result<bool, Error>
+where
+    T: std::fmt::Debug + std::default::Default + Template + std::str::FromStr,
+    Error: std::convert::From<<T as std::str::FromStr>::Err>,
+{
+    Ok(!renderer.disabled()
+        && renderer.unsets()
+        && renderer.selects(ctx)
+        && !renderer.skips(ctx)?)
+}
+
+/// The keys of the current values the renderers remove, unless their
+/// overwrite policy keeps them, or the key whose skip condition failed with
+/// the error. `managed` are the keys the controller manages.
+fn unset_keys<T>(
+    current: Option<&MetadataPairs>,
+    managed: &[String],
+    renderers: &[Renderer<T>],
+    ctx: &RenderContext,
+) -> Result<BTreeSet<String>, (String, Error)>
+where
+    T: std::fmt::Debug + std::default::Default + Template + std::str::FromStr,
+    Error: std::convert::From<<T as std::str::FromStr>::Err>,
+{
+    let mut keys = BTreeSet::new();
+    for r in renderers {
+        let key = r.key();
+        if current.is_some_and(|c| c.contains_key(&key))
+            && !r.overwrite().keeps(true, managed.contains(&key))
+            && unsets(r, ctx).map_err(|e| (key.clone(), e))?
+        {
+            keys.insert(key);
+        }
+    }
+    Ok(keys)
+}
+
+/// Renders the values, returning them along with the current values of the
+/// same keys, or the key that failed to render with the error. Current values
+/// a renderer's overwrite policy keeps, or of disabled renderers, are
+/// returned as they are if the controller manages them, listed in `managed`,
+/// so applying them keeps the controller's ownership, and left out otherwise.
+fn calculate_metadata_pairs<T>(
+    current: Option<&MetadataPairs>,
+    managed: &[String],
+    renderers: &[Renderer<T>],
+    ctx: &RenderContext,
+) -> Result<(MetadataPairs, MetadataPairs), (String, Error)>
+where
+    T: std::fmt::Debug + std::default::Default + Template + std::str::FromStr,
+    Error: std::convert::From<<T as std::str::FromStr>::Err>,
+{
+    let mut old = MetadataPairs::new();
+    let mut new = MetadataPairs::new();
+
+    for r in renderers {
+        let key = r.key();
+        let value = render_value(r, ctx).map_err(|e| (key.clone(), e))?;
+        if value.is_none() && !r.disabled() {
+            continue;
+        }
+        let existing = current.and_then(|c| c.get(&key));
+        let is_managed = managed.contains(&key);
+        let value = value.filter(|_| !r.overwrite().keeps(existing.is_some(), is_managed));
+        let Some(value) = value else {
+            if let Some(v) = existing.filter(|_| is_managed) {
+                old.insert(key.clone(), v.clone());
+                new.insert(key, v.clone());
+            }
+            continue;
+        };
+        if let Some(v) = existing.cloned() {
+            old.insert(key.clone(), v);
+        }
+        new.insert(key, value);
+    }
+
+    Ok((new, old))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::provider_id::ProviderID;
+    use crate::testing::{self, taint};
+
+    #[test]
+    fn test_calculate_metadata_pairs() {
+        let provider_id = ProviderID::new("my-node-name", "fake://region/instance").unwrap();
+        let render_ctx = RenderContext::new(&provider_id);
+
+        {
+            // no renderers
+            let renderers: Vec<Renderer<LabelTemplate>> = vec![];
+            let current = MetadataPairs::new();
+            let (old, new) =
+                calculate_metadata_pairs(Some(&current), &[], &renderers, &render_ctx).unwrap();
+            assert_eq!(old, new);
+            assert!(new.is_empty());
+        }
+
+        {
+            // new node with single default renderer
+            let renderer: Renderer<LabelTemplate> = Renderer::default();
+            let renderers = vec![renderer];
+            let current = MetadataPairs::new();
+            let (new, old) =
+                calculate_metadata_pairs(Some(&current), &[], &renderers, &render_ctx).unwrap();
+            assert_ne!(new, old);
+            assert!(!new.is_empty());
+            assert_eq!("instance", new.get("provider-id").unwrap());
+        }
+
+        {
+            // already reconciled node
+            let renderers: Vec<Renderer<AnnotationTemplate>> = vec![
+                Renderer::from_str("some={:last}").unwrap(),
+                Renderer::from_str("other={:first}").unwrap(),
+            ];
+            let mut current = MetadataPairs::new();
+            current.insert("some".to_string(), "instance".to_string());
+            current.insert("other".to_string(), "region".to_string());
+            let (new, old) =
+                calculate_metadata_pairs(Some(&current), &[], &renderers, &render_ctx).unwrap();
+            assert_eq!(new, old);
+            assert!(!new.is_empty());
+            assert_eq!("instance", new.get("some").unwrap());
+            assert_eq!("region", new.get("other").unwrap());
+        }
+
+        {
+            // node with one key missing
+            let renderers: Vec<Renderer<AnnotationTemplate>> = vec![
+                Renderer::from_str("some={:last}").unwrap(),
+                Renderer::from_str("other={:first}").unwrap(),
+            ];
+            let mut current = MetadataPairs::new();
+            current.insert("some".to_string(), "instance".to_string());
+            let (new, old) =
+                calculate_metadata_pairs(Some(&current), &[], &renderers, &render_ctx).unwrap();
+            assert_ne!(new, old);
+            assert!(!new.is_empty());
+            assert_eq!("instance", new.get("some").unwrap());
+            assert_eq!("region", new.get("other").unwrap());
+        }
+
+        {
+            // node with one different value
+            let renderers: Vec<Renderer<AnnotationTemplate>> = vec![
+                Renderer::from_str("some={:last}").unwrap(),
+                Renderer::from_str("other={:first}").unwrap(),
+            ];
+            let mut current = MetadataPairs::new();
+            current.insert("some".to_string(), "instance".to_string());
+            current.insert("other".to_string(), "notregion".to_string());
+            let (new, old) =
+                calculate_metadata_pairs(Some(&current), &[], &renderers, &render_ctx).unwrap();
+            assert_ne!(new, old);
+            assert!(!new.is_empty());
+            assert_eq!("instance", new.get("some").unwrap());
+            assert_eq!("region", new.get("other").unwrap());
+        }
+    }
+
+    #[test]
+    fn test_changed_keys() {
+        let pairs = |p: &[(&str, &str)]| {
+            p.iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect::<MetadataPairs>()
+        };
+
+        let old = pairs(&[("a", "1"), ("b", "2")]);
+        assert_eq!(changed_keys(&old, &old), 0);
+        assert_eq!(changed_keys(&pairs(&[("a", "1"), ("b", "3")]), &old), 1);
+        assert_eq!(changed_keys(&pairs(&[("a", "1"), ("c", "3")]), &old), 1);
+        assert_eq!(
+            changed_keys(&pairs(&[("a", "1"), ("b", "2")]), &pairs(&[])),
+            2
+        );
+    }
+
+    #[test]
+    fn test_taint_renderer() {
+        let t: TaintRenderer = "example.com/zone={:first}:NoSchedule".parse().unwrap();
+        assert_eq!(t.to_string(), "example.com/zone={:first}:NoSchedule");
+        let t: TaintRenderer = "dedicated:NoExecute".parse().unwrap();
+        assert_eq!(t.to_string(), "dedicated={:last}:NoExecute");
+
+        assert!(matches!(
+            "dedicated={:last}".parse::<TaintRenderer>(),
+            Err(Error::Config(_))
+        ));
+        assert!(matches!(
+            "dedicated={:last}:Sometimes".parse::<TaintRenderer>(),
+            Err(Error::Config(_))
+        ));
+    }
+
+    #[test]
+    fn test_sinks() {
+        let provider_id = ProviderID::new("my-node-name", "fake://region/instance").unwrap();
+        let render_ctx = RenderContext::new(&provider_id);
+        let node = testing::node("my-node-name")
+            .label("zone", "region")
+            .taint("other", "x", "NoSchedule")
+            .taint("dedicated", "old", "NoSchedule")
+            .build();
+        let sinks: Vec<Box<dyn Sink>> = vec![
+            Box::new(LabelSink(vec!["zone={:first}".parse().unwrap()])),
+            Box::new(AnnotationSink(vec!["id={:last}".parse().unwrap()])),
+            Box::new(TaintSink(vec!["dedicated={:last}:NoSchedule"
+                .parse()
+                .unwrap()])),
+        ];
+
+        let mut patch = TargetPatch::default();
+        for sink in &sinks {
+            sink.render(Target::Node(&node), &render_ctx, &mut patch)
+                .unwrap();
+        }
+        assert_eq!(patch.labels, [("zone".into(), "region".into())].into());
+        assert_eq!(patch.annotations, [("id".into(), "instance".into())].into());
+        assert_eq!(
+            patch.taints,
+            Some(vec![
+                taint("other", "x", "NoSchedule"),
+                taint("dedicated", "instance", "NoSchedule"),
+            ])
+        );
+        // the annotation and the taint changed, the label didn't
+        assert_eq!(patch.changed, 2);
+
+        // taints don't apply to machines
+        let mut patch = TargetPatch::default();
+        for sink in &sinks {
+            sink.render(Target::Machine(&node.metadata), &render_ctx, &mut patch)
+                .unwrap();
+        }
+        assert_eq!(patch.taints, None);
+        assert_eq!(patch.changed, 1);
+    }
+
+    #[test]
+    fn test_unset() {
+        let node = testing::node("my-node")
+            .provider_id("fake://region/instance")
+            .label("legacy", "x")
+            .label("gpu", "a100")
+            .annotation("legacy", "y")
+            .taint("legacy", "z", "NoSchedule")
+            .taint("legacy", "z", "NoExecute")
+            .build();
+        let patch = testing::Pipeline::new()
+            .label("legacy", "")
+            .label("absent", "")
+            .label("zone", "{:first}")
+            .annotation("legacy", "@selector=gpu=h100")
+            .taint("legacy", "", "NoSchedule")
+            .render(&node)
+            .unwrap();
+
+        assert_eq!(patch.labels, [("zone".into(), "region".into())].into());
+        assert_eq!(patch.unset_labels, ["legacy".to_string()].into());
+        // the selector doesn't match
+        assert!(patch.unset_annotations.is_empty());
+        assert!(!patch.annotations.contains_key("legacy"));
+        assert_eq!(patch.taints, Some(vec![taint("legacy", "z", "NoExecute")]));
+        assert_eq!(patch.changed, 3);
+    }
+
+    #[test]
+    fn test_overwrite() {
+        use k8s_openapi::apimachinery::pkg::apis::meta::v1::{FieldsV1, ManagedFieldsEntry};
+
+        let mut node = testing::node("my-node")
+            .provider_id("fake://region/instance")
+            .label("zone", "manual")
+            .label("id", "old")
+            .label("pool", "old")
+            .taint("dedicated", "manual", "NoSchedule")
+            .build();
+        node.metadata.managed_fields = Some(vec![ManagedFieldsEntry {
+            manager: Some(MANAGER.into()),
+            fields_v1: Some(FieldsV1(serde_json::json!({
+                "f:metadata": { "f:labels": { "f:id": {}, "f:pool": {} } },
+            }))),
+            ..Default::default()
+        }]);
+        let patch = testing::Pipeline::new()
+            .label("zone", "{:first}@overwrite=if-managed")
+            .label("id", "{:last}@overwrite=if-managed")
+            .label("pool", "{:last}@overwrite=if-absent")
+            .label("region", "{:first}@overwrite=if-absent")
+            .taint("dedicated", "{:last}@overwrite=if-absent", "NoSchedule")
+            .render(&node)
+            .unwrap();
+
+        // the manual zone is left alone, the managed pool kept as it is
+        testing::assert_pairs(
+            &patch.labels,
+            &[("id", "instance"), ("pool", "old"), ("region", "region")],
+        );
+        assert_eq!(
+            patch.taints,
+            Some(vec![taint("dedicated", "manual", "NoSchedule")])
+        );
+        assert_eq!(patch.changed, 2);
+    }
+
+    #[test]
+    fn test_disabled() {
+        use k8s_openapi::apimachinery::pkg::apis::meta::v1::{FieldsV1, ManagedFieldsEntry};
+
+        let mut node = testing::node("my-node")
+            .provider_id("fake://region/instance")
+            .label("zone", "old")
+            .label("id", "manual")
+            .label("legacy", "value")
+            .build();
+        node.metadata.managed_fields = Some(vec![ManagedFieldsEntry {
+            manager: Some(MANAGER.into()),
+            fields_v1: Some(FieldsV1(serde_json::json!({
+                "f:metadata": { "f:labels": { "f:zone": {} } },
+            }))),
+            ..Default::default()
+        }]);
+        let patch = testing::Pipeline::new()
+            .label("zone", "{:first}@disabled=true")
+            .label("id", "{:last}@disabled=true")
+            .label("legacy", "@disabled=true")
+            .label("pool", "{:last}")
+            .taint("dedicated", "{:last}@disabled=true", "NoSchedule")
+            .render(&node)
+            .unwrap();
+
+        // the managed zone is kept as it is, nothing else disabled touched
+        testing::assert_pairs(&patch.labels, &[("pool", "instance"), ("zone", "old")]);
+        assert!(patch.unset_labels.is_empty());
+        assert_eq!(patch.taints, Some(vec![]));
+        assert_eq!(patch.changed, 1);
+    }
+
+    #[test]
+    fn test_label_value_validation() {
+        let provider_id = ProviderID::new("my-node-name", "fake://region/instance").unwrap();
+        let node = testing::node("my-node-name")
+            .label("team", "platform core")
+            .build();
+        let sources = crate::source::defaults();
+        let render_ctx = RenderContext::new(&provider_id)
+            .with_metadata(&node.metadata)
+            .with_sources(&sources);
+        let render = |sink: &dyn Sink| {
+            sink.render(
+                Target::Node(&node),
+                &render_ctx,
+                &mut TargetPatch::default(),
+            )
+        };
+
+        let Err(Error::LabelValue(message)) =
+            render(&LabelSink(vec!["team={label:team}".parse().unwrap()]))
+        else {
+            panic!("expected an invalid label value");
+        };
+        assert_eq!(
+            message,
+            "label 'team' template '{label:team}' rendered invalid value 'platform core': \
+             invalid character ' '"
+        );
+        assert!(matches!(
+            render(&TaintSink(vec!["team={label:team}:NoSchedule"
+                .parse()
+                .unwrap()])),
+            Err(Error::LabelValue(_))
+        ));
+        // annotations take any value
+        assert!(render(&AnnotationSink(vec!["team={label:team}".parse().unwrap()])).is_ok());
+
+        // the failed key is recorded on the patch
+        let mut patch = TargetPatch::default();
+        let sink = LabelSink(vec![
+            "zone={:first}".parse().unwrap(),
+            "team={label:team}".parse().unwrap(),
+        ]);
+        assert!(sink
+            .render(Target::Node(&node), &render_ctx, &mut patch)
+            .is_err());
+        assert_eq!(patch.failed_key.as_deref(), Some("team"));
+    }
+
+    #[test]
+    fn test_history_sink() {
+        let provider_id = ProviderID::new("my-node-name", "fake://region/instance").unwrap();
+        let render_ctx = RenderContext::new(&provider_id);
+        let node = testing::node("my-node-name")
+            .label("zone", "old-region")
+            .label("id", "instance")
+            .annotation("id.previous", "older")
+            .annotation("id.changed-at", "2024-01-01T00:00:00Z")
+            .build();
+        let long_key = format!("example.com/{}", "k".repeat(60));
+        let sinks: Vec<Box<dyn Sink>> = vec![
+            Box::new(LabelSink(vec![
+                "zone={:first}".parse().unwrap(),
+                "id={:last}".parse().unwrap(),
+                "new={:last}".parse().unwrap(),
+                format!("{long_key}={{:last}}").parse().unwrap(),
+            ])),
+            Box::new(HistorySink),
+        ];
+
+        let mut patch = TargetPatch::default();
+        for sink in &sinks {
+            sink.render(Target::Node(&node), &render_ctx, &mut patch)
+                .unwrap();
+        }
+        let changed_at = patch.annotations.remove("zone.changed-at").unwrap();
+        assert!(OffsetDateTime::parse(&changed_at, &Rfc3339).is_ok());
+        // unchanged keys keep their history, new keys have none
+        assert_eq!(
+            patch.annotations,
+            [
+                ("id.changed-at".into(), "2024-01-01T00:00:00Z".into()),
+                ("id.previous".into(), "older".into()),
+                ("zone.previous".into(), "old-region".into()),
+            ]
+            .into()
+        );
+        // recording history doesn't count as a change of its own
+        assert_eq!(patch.changed, 3);
+    }
+}
@@ -0,0 +1,202 @@
+use crate::{
+    ast::{Token, TokenKind},
+    template::RenderContext,
+    Error,
+};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    sync::Arc,
+};
+
+/// Resolves `{<namespace>:<key>}` template tokens, e.g. from node fields,
+/// cloud APIs, or static configuration.
+///
+/// Sources are registered on the controller and consulted in order after the
+/// enrichment fields; the first source for the token's namespace that returns
+/// a value wins.
+pub trait ValueSource: std::fmt::Debug + Send + Sync {
+    /// The token namespace this source resolves, e.g. "label".
+    fn namespace(&self) -> &str;
+
+    /// The value for `key`, or `None` if the source has none for this node.
+    fn value(&self, key: &str, ctx: &RenderContext) -> Option<String>;
+}
+
+/// The sources the controller registers unless configured otherwise. The
+/// controller adds an [`EnvVars`] source with the variables its templates
+/// reference.
+pub fn defaults() -> Vec<Arc<dyn ValueSource>> {
+    vec![Arc::new(NodeLabels), Arc::new(NodeAnnotations)]
+}
+
+/// Resolves `{label:<name>}` to the value of the node's label.
+#[derive(Debug)]
+pub struct NodeLabels;
+
+impl ValueSource for NodeLabels {
+    fn namespace(&self) -> &str {
+        "label"
+    }
+
+    fn value(&self, key: &str, ctx: &RenderContext) -> Option<String> {
+        ctx.metadata?.labels.as_ref()?.get(key).cloned()
+    }
+}
+
+/// Resolves `{annotation:<name>}` to the value of the node's annotation.
+#[derive(Debug)]
+pub struct NodeAnnotations;
+
+impl ValueSource for NodeAnnotations {
+    fn namespace(&self) -> &str {
+        "annotation"
+    }
+
+    fn value(&self, key: &str, ctx: &RenderContext) -> Option<String> {
+        ctx.metadata?.annotations.as_ref()?.get(key).cloned()
+    }
+}
+
+/// Resolves tokens in a namespace to fixed values, e.g. `{cluster:name}`.
+#[derive(Debug)]
+pub struct StaticValues {
+    namespace: String,
+    values: BTreeMap<String, String>,
+}
+
+impl StaticValues {
+    pub fn new(namespace: impl Into<String>, values: BTreeMap<String, String>) -> Self {
+        Self {
+            namespace: namespace.into(),
+            values,
+        }
+    }
+}
+
+impl ValueSource for StaticValues {
+    fn namespace(&self) -> &str {
+        &self.namespace
+    }
+
+    fn value(&self, key: &str, _ctx: &RenderContext) -> Option<String> {
+        self.values.get(key).cloned()
+    }
+}
+
+/// Resolves `{env:<name>}` to the value of the environment variable, e.g.
+/// `{env:CLUSTER_NAME}`. The variables are read once, when the source is
+/// created, so values don't change while the controller runs. Only the named
+/// ones are captured, rather than the whole environment.
+#[derive(Debug)]
+pub struct EnvVars(StaticValues);
+
+impl EnvVars {
+    /// Captures the named variables from the process environment, failing if
+    /// one isn't set or isn't valid Unicode.
+    pub fn capture<S: AsRef<str>>(names: impl IntoIterator<Item = S>) -> Result<Self, Error> {
+        let vars = names
+            .into_iter()
+            .map(|name| {
+                let name = name.as_ref();
+                let value = std::env::var_os(name)
+                    .ok_or_else(|| {
+                        Error::Config(format!("environment variable {name} is not set"))
+                    })?
+                    .into_string()
+                    .map_err(|_| {
+                        Error::Config(format!("environment variable {name} is not valid Unicode"))
+                    })?;
+                Ok((name.to_string(), value))
+            })
+            .collect::<Result<_, Error>>()?;
+        Ok(Self::new(vars))
+    }
+
+    pub fn new(vars: BTreeMap<String, String>) -> Self {
+        Self(StaticValues::new("env", vars))
+    }
+}
+
+impl ValueSource for EnvVars {
+    fn namespace(&self) -> &str {
+        self.0.namespace()
+    }
+
+    fn value(&self, key: &str, ctx: &RenderContext) -> Option<String> {
+        self.0.value(key, ctx)
+    }
+}
+
+/// The names of the variables `{env:<name>}` tokens reference, including
+/// the inputs of `{plugin:<name>(env:<name>)}` tokens.
+pub fn env_names<'a>(tokens: impl IntoIterator<Item = &'a Token>) -> BTreeSet<String> {
+    tokens
+        .into_iter()
+        .filter_map(|token| match &token.kind {
+            TokenKind::Field { namespace, key } if namespace == "env" => Some(key.clone()),
+            TokenKind::Plugin { input, .. } => input.strip_prefix("env:").map(str::to_string),
+            _ => None,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{provider_id::ProviderID, template::LabelTemplate};
+    use kube::api::ObjectMeta;
+
+    #[test]
+    fn test_sources() {
+        let provider_id = ProviderID::new("my-node", "fake://region/instance").unwrap();
+        let metadata = ObjectMeta {
+            labels: Some([("topology.kubernetes.io/zone".into(), "zone-a".into())].into()),
+            ..Default::default()
+        };
+        let ctx = RenderContext::new(&provider_id).with_metadata(&metadata);
+
+        assert_eq!(
+            NodeLabels.value("topology.kubernetes.io/zone", &ctx),
+            Some("zone-a".into())
+        );
+        assert_eq!(NodeLabels.value("missing", &ctx), None);
+        assert_eq!(NodeAnnotations.value("anything", &ctx), None);
+        assert_eq!(
+            NodeLabels.value(
+                "topology.kubernetes.io/zone",
+                &RenderContext::new(&provider_id)
+            ),
+            None
+        );
+
+        let cluster = StaticValues::new("cluster", [("name".into(), "prod".into())].into());
+        assert_eq!(cluster.namespace(), "cluster");
+        assert_eq!(cluster.value("name", &ctx), Some("prod".into()));
+
+        let env = EnvVars::new([("CLUSTER_NAME".into(), "prod".into())].into());
+        assert_eq!(env.namespace(), "env");
+        assert_eq!(env.value("CLUSTER_NAME", &ctx), Some("prod".into()));
+        assert_eq!(env.value("MISSING", &ctx), None);
+    }
+
+    #[test]
+    fn test_env_vars() {
+        let provider_id = ProviderID::new("my-node", "fake://region/instance").unwrap();
+        let ctx = RenderContext::new(&provider_id);
+        let template: LabelTemplate = "{env:PATH}-{plugin:site(env:HOME)}-{label:env}"
+            .parse()
+            .unwrap();
+        let names = env_names(&template.tokens());
+        assert_eq!(names, ["HOME".to_string(), "PATH".to_string()].into());
+
+        // only the named variables are captured
+        let env = EnvVars::capture(["PATH"]).unwrap();
+        assert_eq!(env.value("PATH", &ctx), std::env::var("PATH").ok());
+        assert_eq!(env.value("USER", &ctx), None);
+
+        assert!(matches!(
+            EnvVars::capture(["NPL_TEST_MISSING"]),
+            Err(Error::Config(e)) if e == "environment variable NPL_TEST_MISSING is not set"
+        ));
+    }
+}
@@ -0,0 +1,156 @@
+//! Throttling of the initial sync: after a (re)start, the backlog of nodes is
+//! patched at a limited pace, separate from the client's steady-state rate
+//! limits, so a redeploy into a large cluster doesn't get other clients
+//! throttled by API priority and fairness.
+use k8s_openapi::api::core::v1::Node;
+use kube::{runtime::reflector::Store, ResourceExt};
+use std::{
+    collections::HashSet,
+    sync::{Arc, Mutex, OnceLock},
+    time::Duration,
+};
+use tokio::time::Instant;
+use tracing::info;
+
+#[derive(Debug)]
+struct State {
+    // when the next patch may go out
+    next: Instant,
+    // the nodes of the initial sync yet to be reconciled, once known
+    pending: Option<HashSet<String>>,
+    done: bool,
+}
+
+/// Paces node patches until every node known when the first reconciliation
+/// finished was reconciled once.
+#[derive(Debug)]
+pub struct StartupThrottle {
+    interval: Duration,
+    nodes: Arc<OnceLock<Store<Node>>>,
+    state: Mutex<State>,
+}
+
+impl StartupThrottle {
+    /// Allows `per_minute` patches per minute, evenly spaced, reading the
+    /// nodes of the initial sync from the controller's store.
+    pub(crate) fn new(per_minute: u32, nodes: Arc<OnceLock<Store<Node>>>) -> Self {
+        Self {
+            interval: Duration::from_secs(60) / per_minute.max(1),
+            nodes,
+            state: Mutex::new(State {
+                next: Instant::now(),
+                pending: None,
+                done: false,
+            }),
+        }
+    }
+
+    /// Waits until the next patch may go out, if the initial sync is ongoing.
+    pub(crate) async fn wait(&self) {
+        if let Some(wait) = self.reserve(Instant::now()) {
+            tokio::time::sleep(wait).await;
+        }
+    }
+
+    /// Reserves the next slot for a patch, returning how long to wait for it.
+    fn reserve(&self, now: Instant) -> Option<Duration> {
+        let mut state = self.state.lock().unwrap();
+        if state.done {
+            return None;
+        }
+        let slot = state.next.max(now);
+        state.next = slot + self.interval;
+        Some(slot - now).filter(|wait| !wait.is_zero())
+    }
+
+    /// Records that the node was reconciled, ending the initial sync once
+    /// all of its nodes were, or were deleted.
+    pub(crate) fn reconciled(&self, node: &Node) {
+        let mut state = self.state.lock().unwrap();
+        if state.done {
+            return;
+        }
+        let Some(store) = self.nodes.get() else {
+            return;
+        };
+        let current = store
+            .state()
+            .iter()
+            .map(|n| n.name_any())
+            .collect::<HashSet<_>>();
+        let pending = state.pending.get_or_insert_with(|| current.clone());
+        pending.remove(&node.name_any());
+        // deleted nodes are never reconciled
+        pending.retain(|name| current.contains(name));
+        if pending.is_empty() {
+            info!("initial sync finished, lifting the startup patch rate");
+            state.done = true;
+        }
+    }
+
+    /// Whether the initial sync finished.
+    pub fn done(&self) -> bool {
+        self.state.lock().unwrap().done
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+    use kube::runtime::{reflector, watcher};
+
+    #[test]
+    fn test_startup_throttle() {
+        let (store, mut writer) = reflector::store();
+        let first = testing::node("first").build();
+        let second = testing::node("second").build();
+        writer.apply_watcher_event(&watcher::Event::Restarted(vec![
+            first.clone(),
+            second.clone(),
+        ]));
+        let nodes = Arc::new(OnceLock::new());
+        let throttle = StartupThrottle::new(30, nodes.clone());
+
+        // two seconds apart
+        let now = Instant::now();
+        assert_eq!(throttle.reserve(now), None);
+        assert_eq!(throttle.reserve(now), Some(Duration::from_secs(2)));
+        assert_eq!(throttle.reserve(now), Some(Duration::from_secs(4)));
+        assert_eq!(throttle.reserve(now + Duration::from_secs(60)), None);
+
+        // without the store, the initial sync doesn't end
+        throttle.reconciled(&first);
+        assert!(!throttle.done());
+
+        let _ = nodes.set(store);
+        throttle.reconciled(&first);
+        throttle.reconciled(&first);
+        assert!(!throttle.done());
+        throttle.reconciled(&second);
+        assert!(throttle.done());
+        assert_eq!(throttle.reserve(now), None);
+    }
+
+    #[test]
+    fn test_startup_throttle_deleted_node() {
+        let (store, mut writer) = reflector::store();
+        let first = testing::node("first").build();
+        let second = testing::node("second").build();
+        writer.apply_watcher_event(&watcher::Event::Restarted(vec![
+            first.clone(),
+            second.clone(),
+        ]));
+        let nodes = Arc::new(OnceLock::new());
+        let _ = nodes.set(store);
+        let throttle = StartupThrottle::new(30, nodes);
+
+        throttle.reconciled(&first);
+        assert!(!throttle.done());
+
+        // the second node is deleted before its first reconciliation
+        writer.apply_watcher_event(&watcher::Event::Deleted(second));
+        throttle.reconciled(&first);
+        assert!(throttle.done());
+    }
+}
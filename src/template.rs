@@ -1,36 +1,363 @@
-use crate::{provider_id::ProviderID, Error};
-use pest::Parser;
+use crate::{
+    ast::{self, Token},
+    filter, lifecycle,
+    provider_id::ProviderID,
+    source::ValueSource,
+    transform::Transform,
+    Error,
+};
+use kube::api::ObjectMeta;
+use pest::{iterators::Pair, Parser};
 use pest_derive::Parser;
-use std::str::FromStr;
+use std::{collections::BTreeMap, str::FromStr, sync::Arc};
 
 #[derive(Parser)]
 #[grammar = "template.pest"]
 struct TemplateParser;
 
+/// Additional values, keyed by "<namespace>:<key>", available to templates via
+/// `{<namespace>:<key>}` tokens (e.g. `{azure:sku}`).
+pub type Fields = BTreeMap<String, String>;
+
+static NO_FIELDS: Fields = BTreeMap::new();
+
+/// Lookup tables by name, available to templates via the `|map(<name>)`
+/// filter (e.g. region to site code).
+pub type Maps = BTreeMap<String, BTreeMap<String, String>>;
+
+static NO_MAPS: Maps = BTreeMap::new();
+
+/// What a template is rendered against: the node's provider ID and, when
+/// available, the node's metadata, enrichment fields, value sources,
+/// transforms, and lookup tables. New sources are added here rather than to
+/// [`Template::render`].
+#[derive(Clone, Copy, Debug)]
+#[non_exhaustive]
+pub struct RenderContext<'a> {
+    pub provider_id: &'a ProviderID,
+    pub metadata: Option<&'a ObjectMeta>,
+    pub fields: &'a Fields,
+    pub sources: &'a [Arc<dyn ValueSource>],
+    pub transforms: &'a [Arc<dyn Transform>],
+    pub maps: &'a Maps,
+    /// The node has no provider ID: tokens marked with a fallback, e.g.
+    /// `{:first|zone}`, render from its topology labels, and others fail
+    /// with [`Error::MissingObjectKey`] for [`PROVIDER_ID_KEY`].
+    pub topology_fallback: bool,
+}
+
+/// The field the provider ID tokens are rendered from.
+pub const PROVIDER_ID_KEY: &str = ".spec.providerID";
+
+impl<'a> RenderContext<'a> {
+    pub fn new(provider_id: &'a ProviderID) -> Self {
+        Self {
+            provider_id,
+            metadata: None,
+            fields: &NO_FIELDS,
+            sources: &[],
+            transforms: &[],
+            maps: &NO_MAPS,
+            topology_fallback: false,
+        }
+    }
+
+    /// Adds the metadata of the node being rendered.
+    pub fn with_metadata(mut self, metadata: &'a ObjectMeta) -> Self {
+        self.metadata = Some(metadata);
+        self
+    }
+
+    /// Adds enrichment fields, e.g. from Azure.
+    pub fn with_fields(mut self, fields: &'a Fields) -> Self {
+        self.fields = fields;
+        self
+    }
+
+    /// Adds value sources to resolve `{<namespace>:<key>}` tokens that no
+    /// enrichment field matches.
+    pub fn with_sources(mut self, sources: &'a [Arc<dyn ValueSource>]) -> Self {
+        self.sources = sources;
+        self
+    }
+
+    /// Adds transforms to resolve `{plugin:<name>(<token>)}` tokens.
+    pub fn with_transforms(mut self, transforms: &'a [Arc<dyn Transform>]) -> Self {
+        self.transforms = transforms;
+        self
+    }
+
+    /// Adds lookup tables to resolve `|map(<name>)` filters.
+    pub fn with_maps(mut self, maps: &'a Maps) -> Self {
+        self.maps = maps;
+        self
+    }
+
+    /// Renders provider ID tokens from topology labels instead, for nodes
+    /// without a provider ID.
+    pub fn with_topology_fallback(mut self) -> Self {
+        self.topology_fallback = true;
+        self
+    }
+
+    /// Resolves a provider ID token from its fallback topology label, e.g.
+    /// `topology.kubernetes.io/zone` for `{:first|zone}`, when rendering
+    /// without a provider ID.
+    fn fallback(&self, token: Pair<Rule>) -> Option<Result<String, Error>> {
+        if !self.topology_fallback {
+            return None;
+        }
+        let Some(topology) = token.into_inner().find(|t| t.as_rule() == Rule::topology) else {
+            return Some(Err(Error::MissingObjectKey(PROVIDER_ID_KEY)));
+        };
+        let label = format!("topology.kubernetes.io/{}", topology.as_str());
+        let value = self
+            .metadata
+            .and_then(|m| m.labels.as_ref())
+            .and_then(|labels| labels.get(&label))
+            .cloned()
+            .ok_or_else(|| Error::MissingField(format!("label:{label}")));
+        Some(value)
+    }
+
+    /// Resolves a `{<namespace>:<key>}` token.
+    fn field(&self, ns: &str, key: &str) -> Option<String> {
+        self.fields
+            .get(&format!("{ns}:{key}"))
+            .cloned()
+            .or_else(|| {
+                self.sources
+                    .iter()
+                    .filter(|s| s.namespace() == ns)
+                    .find_map(|s| s.value(key, self))
+            })
+    }
+}
+
 pub trait Template {
-    fn render(&self, provider_id: &ProviderID) -> Result<String, Error>;
+    fn render(&self, ctx: &RenderContext) -> Result<String, Error>;
+
+    /// Renders the value, or `None` if the key should be left unset, e.g. a
+    /// label value too long with [`Overflow::Skip`].
+    fn render_value(&self, ctx: &RenderContext) -> Result<Option<String>, Error> {
+        self.render(ctx).map(Some)
+    }
 }
 
-#[derive(Default, Debug)]
-pub struct LabelTemplate(String);
+/// The longest label value the API server accepts.
+pub const MAX_LABEL_VALUE_LENGTH: usize = 63;
+
+// a hashed value ends with 8 hex digits
+const HASH_LENGTH: usize = 8;
+
+/// What to do with a label value longer than its maximum length.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Overflow {
+    /// Cut the value to the maximum length.
+    #[default]
+    Truncate,
+    /// Cut the value and end it with a hash of the whole value, so values
+    /// with the same beginning stay distinct.
+    Hash,
+    /// Leave the label unset.
+    Skip,
+    /// Fail the reconciliation.
+    Error,
+}
+
+impl FromStr for Overflow {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "truncate" => Ok(Self::Truncate),
+            "hash" => Ok(Self::Hash),
+            "skip" => Ok(Self::Skip),
+            "error" => Ok(Self::Error),
+            _ => Err(Error::TemplateParser(format!(
+                "invalid overflow '{s}', expected truncate, hash, skip, or error"
+            ))),
+        }
+    }
+}
+
+impl std::fmt::Display for Overflow {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::Truncate => "truncate",
+            Self::Hash => "hash",
+            Self::Skip => "skip",
+            Self::Error => "error",
+        })
+    }
+}
+
+/// A label value template, optionally followed by options limiting the
+/// length of the value, e.g. "{:all};max-length=40;overflow=hash".
+#[derive(Debug)]
+pub struct LabelTemplate {
+    template: String,
+    max_length: usize,
+    overflow: Overflow,
+}
+
+impl Default for LabelTemplate {
+    fn default() -> Self {
+        Self {
+            template: String::new(),
+            max_length: MAX_LABEL_VALUE_LENGTH,
+            overflow: Overflow::default(),
+        }
+    }
+}
 
 impl FromStr for LabelTemplate {
     type Err = Error;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        validate_template(s, Rule::label)?;
-        Ok(Self(s.to_string()))
+        let mut parts = s.split(';');
+        let template = parts.next().unwrap_or_default();
+        validate_template(template, Rule::label)?;
+
+        let mut label = Self {
+            template: template.to_string(),
+            ..Default::default()
+        };
+        for option in parts {
+            match option.split_once('=') {
+                Some(("max-length", value)) => {
+                    label.max_length = value
+                        .parse()
+                        .ok()
+                        .filter(|n| (1..=MAX_LABEL_VALUE_LENGTH).contains(n))
+                        .ok_or_else(|| {
+                            Error::TemplateParser(format!(
+                                "invalid max-length '{value}', expected 1 to {MAX_LABEL_VALUE_LENGTH}"
+                            ))
+                        })?;
+                }
+                Some(("overflow", value)) => label.overflow = value.parse()?,
+                _ => {
+                    return Err(Error::TemplateParser(format!(
+                        "unknown label template option '{option}', expected max-length or overflow"
+                    )))
+                }
+            }
+        }
+        if label.overflow == Overflow::Hash && label.max_length < HASH_LENGTH {
+            return Err(Error::TemplateParser(format!(
+                "max-length must be at least {HASH_LENGTH} to hash"
+            )));
+        }
+        Ok(label)
+    }
+}
+
+impl std::fmt::Display for LabelTemplate {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.template)?;
+        if self.max_length != MAX_LABEL_VALUE_LENGTH {
+            write!(f, ";max-length={}", self.max_length)?;
+        }
+        if self.overflow != Overflow::default() {
+            write!(f, ";overflow={}", self.overflow)?;
+        }
+        Ok(())
+    }
+}
+
+impl LabelTemplate {
+    /// The tokens of the template, without the length options.
+    pub fn tokens(&self) -> Vec<Token> {
+        parse_tokens(&self.template, Rule::label)
     }
 }
 
 impl Template for LabelTemplate {
-    fn render(&self, provider_id: &ProviderID) -> Result<String, Error> {
-        do_render(&self.0, provider_id, Rule::label).map(|s| {
-            let mut s = s.replace("://", "_").replace('/', "_");
-            s.truncate(63);
-            s
+    fn render(&self, ctx: &RenderContext) -> Result<String, Error> {
+        self.render_value(ctx)?.ok_or_else(|| {
+            Error::LabelValue(format!(
+                "template '{self}' rendered a value longer than {} characters",
+                self.max_length
+            ))
         })
     }
+
+    fn render_value(&self, ctx: &RenderContext) -> Result<Option<String>, Error> {
+        let s = do_render(&self.template, ctx, Rule::label)?
+            .replace("://", "_")
+            .replace('/', "_");
+        if s.len() <= self.max_length {
+            return Ok(Some(s));
+        }
+        match self.overflow {
+            Overflow::Truncate => Ok(Some(truncate(&s, self.max_length).to_string())),
+            Overflow::Hash => {
+                let hash = format!("{:08x}", stable_hash(&s) as u32);
+                match self.max_length.checked_sub(HASH_LENGTH + 1) {
+                    Some(len) if len > 0 => Ok(Some(format!("{}-{hash}", truncate(&s, len)))),
+                    _ => Ok(Some(hash)),
+                }
+            }
+            Overflow::Skip => Ok(None),
+            Overflow::Error => Err(Error::LabelValue(format!(
+                "template '{self}' rendered '{s}', longer than {} characters",
+                self.max_length
+            ))),
+        }
+    }
+}
+
+/// Applies the `|<filter>` suffixes of a token to its value, in order.
+fn apply_filters(
+    filters: &[Pair<Rule>],
+    value: String,
+    ctx: &RenderContext,
+) -> Result<String, Error> {
+    filters.iter().try_fold(value, |value, filter| {
+        let filter = filter.clone().into_inner().next().unwrap();
+        let value = match filter.as_rule() {
+            Rule::kebab => filter::kebab(&value),
+            Rule::snake => filter::snake(&value),
+            Rule::camel => filter::camel(&value),
+            Rule::split => {
+                let mut inner = filter.clone().into_inner();
+                let delimiter = inner.next().unwrap().as_str();
+                let idx = inner.next().unwrap().as_str().parse::<usize>()?;
+                value
+                    .split(delimiter)
+                    .nth(idx)
+                    .map(String::from)
+                    .ok_or_else(|| Error::MissingField(format!("{value}|{}", filter.as_str())))?
+            }
+            Rule::map => {
+                let name = filter.clone().into_inner().next().unwrap().as_str();
+                let map = ctx
+                    .maps
+                    .get(name)
+                    .ok_or_else(|| Error::TemplateParser(format!("unknown map '{name}'")))?;
+                map.get(&value)
+                    .cloned()
+                    .ok_or_else(|| Error::MissingField(format!("{value}|{}", filter.as_str())))?
+            }
+            _ => value,
+        };
+        Ok(value)
+    })
+}
+
+/// Cuts the value to at most `len` characters, dropping separators it would
+/// end with. Label values are ASCII.
+fn truncate(s: &str, len: usize) -> &str {
+    s[..len.min(s.len())].trim_end_matches(|c: char| !c.is_ascii_alphanumeric())
+}
+
+/// A 64-bit FNV-1a hash, stable across releases and platforms unlike the
+/// standard library's hasher, e.g. for values that end up on nodes.
+pub(crate) fn stable_hash(s: &str) -> u64 {
+    s.bytes().fold(0xcbf29ce484222325, |hash, b| {
+        (hash ^ u64::from(b)).wrapping_mul(0x100000001b3)
+    })
 }
 
 #[derive(Default, Debug)]
@@ -45,9 +372,83 @@ impl FromStr for AnnotationTemplate {
     }
 }
 
+impl std::fmt::Display for AnnotationTemplate {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl AnnotationTemplate {
+    /// The tokens of the template.
+    pub fn tokens(&self) -> Vec<Token> {
+        parse_tokens(&self.0, Rule::annotation)
+    }
+}
+
 impl Template for AnnotationTemplate {
-    fn render(&self, provider_id: &ProviderID) -> Result<String, Error> {
-        do_render(&self.0, provider_id, Rule::annotation)
+    fn render(&self, ctx: &RenderContext) -> Result<String, Error> {
+        do_render(&self.0, ctx, Rule::annotation)
+    }
+}
+
+/// Renders a provider ID for nodes that have none, e.g.
+/// "metal://{label:rack}/{:node}". Only `{:node}` and `{<namespace>:<key>}`
+/// tokens are allowed, since there is no provider ID to take parts from.
+#[derive(Debug)]
+pub struct ProviderIDTemplate(String);
+
+impl FromStr for ProviderIDTemplate {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut pairs = TemplateParser::parse(Rule::annotation, s)
+            .map_err(|e| Error::TemplateParser(e.to_string()))?;
+        let from_provider_id = pairs.next().unwrap().into_inner().any(|token| {
+            !matches!(
+                token.as_rule(),
+                Rule::node | Rule::field | Rule::char | Rule::EOI
+            )
+        });
+        if from_provider_id {
+            return Err(Error::TemplateParser(format!(
+                "provider ID template '{s}' may only use {{:node}} and {{<namespace>:<key>}} tokens"
+            )));
+        }
+        if !s.contains("://") {
+            return Err(Error::TemplateParser(format!(
+                "provider ID template '{s}' must have the form <provider>://<node id>"
+            )));
+        }
+        Ok(Self(s.to_string()))
+    }
+}
+
+impl std::fmt::Display for ProviderIDTemplate {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl ProviderIDTemplate {
+    /// The tokens of the template.
+    pub fn tokens(&self) -> Vec<Token> {
+        parse_tokens(&self.0, Rule::annotation)
+    }
+
+    /// Renders the provider ID for the node, failing unless it is valid.
+    pub fn render(
+        &self,
+        node_name: &str,
+        metadata: &ObjectMeta,
+        sources: &[Arc<dyn ValueSource>],
+    ) -> Result<ProviderID, Error> {
+        // the template only uses the node name of the provider ID
+        let placeholder = ProviderID::new(node_name, "none://none")?;
+        let ctx = RenderContext::new(&placeholder)
+            .with_metadata(metadata)
+            .with_sources(sources);
+        let rendered = do_render(&self.0, &ctx, Rule::annotation)?;
+        Ok(ProviderID::new(node_name, &rendered)?)
     }
 }
 
@@ -57,37 +458,94 @@ fn validate_template(template: &str, rule: Rule) -> Result<(), Error> {
         .map_err(|e| Error::TemplateParser(e.to_string()))
 }
 
-fn do_render(template: &str, provider_id: &ProviderID, rule: Rule) -> Result<String, Error> {
+/// The tokens of a template validated with the rule.
+fn parse_tokens(template: &str, rule: Rule) -> Vec<Token> {
+    TemplateParser::parse(rule, template)
+        .ok()
+        .and_then(|mut pairs| pairs.next())
+        .map(ast::tokens)
+        .unwrap_or_default()
+}
+
+fn do_render(template: &str, ctx: &RenderContext, rule: Rule) -> Result<String, Error> {
+    let provider_id = ctx.provider_id;
     let mut pairs =
         TemplateParser::parse(rule, template).map_err(|e| Error::TemplateParser(e.to_string()))?;
     let pair = pairs.next().unwrap();
     let mut output = String::new();
 
     for token in pair.into_inner() {
-        match token.as_rule() {
-            Rule::last => output.push_str(&provider_id.last()),
-            Rule::first => output.push_str(&provider_id.nth(0).unwrap()),
-            Rule::all => output.push_str(&provider_id.node_id()),
-            Rule::provider => output.push_str(&provider_id.provider()),
-            Rule::url => {
-                output.push_str(&provider_id.to_string());
-            }
-            Rule::node => output.push_str(&provider_id.node_name()),
-            Rule::nth => {
-                let nth = token.into_inner().next().unwrap().as_str();
-                let idx = nth.parse::<usize>()?;
-                output.push_str(&provider_id.nth(idx).unwrap());
-            }
-            Rule::label_char => output.push_str(token.as_str()),
-            Rule::char => output.push_str(token.as_str()),
-            Rule::EOI => (),
-            _ => {
-                return Err(Error::TemplateParser(format!(
-                    "unable to parse template '{}'",
-                    template
-                )))
-            }
-        }
+        let filters = token
+            .clone()
+            .into_inner()
+            .filter(|t| t.as_rule() == Rule::filter)
+            .collect::<Vec<_>>();
+        let fallback = match token.as_rule() {
+            Rule::last
+            | Rule::first
+            | Rule::all
+            | Rule::provider
+            | Rule::url
+            | Rule::nth
+            | Rule::bucket => ctx.fallback(token.clone()),
+            _ => None,
+        };
+        let value = match fallback {
+            Some(value) => value?,
+            None => match token.as_rule() {
+                Rule::last => provider_id.last(),
+                Rule::first => provider_id.nth(0).unwrap(),
+                Rule::all => provider_id.node_id(),
+                Rule::provider => provider_id.provider(),
+                Rule::url => provider_id.to_string(),
+                Rule::node => provider_id.node_name(),
+                Rule::bucket => {
+                    let buckets = token.into_inner().next().unwrap().as_str();
+                    let buckets = buckets.parse::<u64>()?;
+                    (stable_hash(&provider_id.to_string()) % buckets).to_string()
+                }
+                Rule::lifecycle => {
+                    let labels = ctx.metadata.and_then(|m| m.labels.as_ref());
+                    lifecycle::lifecycle(provider_id, labels, ctx.fields)
+                        .ok_or_else(|| Error::MissingField(":lifecycle".into()))?
+                }
+                Rule::nth => {
+                    let nth = token.into_inner().next().unwrap().as_str();
+                    let idx = nth.parse::<usize>()?;
+                    provider_id.nth(idx).unwrap()
+                }
+                Rule::field => {
+                    let mut inner = token.into_inner();
+                    let ns = inner.next().unwrap().as_str();
+                    let key = inner.next().unwrap().as_str();
+                    ctx.field(ns, key)
+                        .ok_or_else(|| Error::MissingField(format!("{ns}:{key}")))?
+                }
+                Rule::plugin => {
+                    let mut inner = token.into_inner();
+                    let name = inner.next().unwrap().as_str();
+                    let input = inner.next().unwrap().as_str();
+                    let transform = ctx
+                        .transforms
+                        .iter()
+                        .find(|t| t.name() == name)
+                        .ok_or_else(|| Error::Plugin(format!("unknown plugin '{name}'")))?;
+                    // the input is rendered unsanitized; labels are sanitized
+                    // after the transform
+                    let value = do_render(&format!("{{{input}}}"), ctx, Rule::annotation)?;
+                    transform.apply(&value)?
+                }
+                Rule::label_char | Rule::char => token.as_str().to_string(),
+                Rule::EOI => continue,
+                _ => {
+                    return Err(Error::TemplateParser(format!(
+                        "unable to parse template '{}'",
+                        template
+                    )))
+                }
+            },
+        };
+        output.push_str(&apply_filters(&filters, value, ctx)?);
     }
 
     Ok(output)
@@ -112,8 +570,14 @@ mod tests {
         let _ = t("{1}");
         let _ = t("{:node}");
         let _ = t("{:last}-{:first}_{:all}.{:last}");
+        let _ = t("{azure:sku}");
+        let _ = t("{azure:tag:team}-{:last}");
+        let _ = t("{plugin:upper(:last)}");
+        let _ = t("{plugin:my_transform(0)}-{plugin:upper(label:zone)}");
 
         assert!(LabelTemplate::from_str("{:incorrect}").is_err());
+        assert!(LabelTemplate::from_str("{plugin:upper(:incorrect)}").is_err());
+        assert!(LabelTemplate::from_str("{plugin:upper()}").is_err());
         assert!(LabelTemplate::from_str("n0tall/ow#D").is_err());
     }
 
@@ -122,7 +586,7 @@ mod tests {
         let t = |template: &str, id: &ProviderID| {
             LabelTemplate::from_str(template)
                 .unwrap()
-                .render(id)
+                .render(&RenderContext::new(id))
                 .unwrap()
         };
 
@@ -167,7 +631,7 @@ mod tests {
         let a = |template: &str, id: &ProviderID| {
             AnnotationTemplate::from_str(template)
                 .unwrap()
-                .render(id)
+                .render(&RenderContext::new(id))
                 .unwrap()
         };
 
@@ -209,4 +673,427 @@ mod tests {
             "i-1234567890abcdef0-us-east-2 us-east-2/i-1234567890abcdef0/i-1234567890abcdef0"
         );
     }
+
+    #[test]
+    fn test_label_template_overflow() {
+        let id = ProviderID::new("my-node", "fake://region/instance-0123456789").unwrap();
+        let ctx = RenderContext::new(&id);
+        let render = |template: &str| {
+            LabelTemplate::from_str(template)
+                .unwrap()
+                .render_value(&ctx)
+        };
+
+        // values that fit are kept as they are
+        assert_eq!(
+            render("{:last};max-length=19").unwrap().unwrap(),
+            "instance-0123456789"
+        );
+        // truncated values don't end with a separator
+        assert_eq!(render("{:last};max-length=9").unwrap().unwrap(), "instance");
+        let hashed = render("{:last};max-length=16;overflow=hash")
+            .unwrap()
+            .unwrap();
+        assert_eq!(hashed.len(), 16);
+        assert!(hashed.starts_with("instanc-"));
+        assert_ne!(
+            hashed,
+            render("{:all};max-length=16;overflow=hash")
+                .unwrap()
+                .unwrap()
+        );
+        assert_eq!(
+            render("{:last};max-length=8;overflow=hash")
+                .unwrap()
+                .unwrap()
+                .len(),
+            8
+        );
+        assert_eq!(render("{:last};max-length=10;overflow=skip").unwrap(), None);
+        assert!(matches!(
+            render("{:last};max-length=10;overflow=error"),
+            Err(Error::LabelValue(_))
+        ));
+        // rendering without a sink can't skip
+        assert!(
+            LabelTemplate::from_str("{:last};max-length=10;overflow=skip")
+                .unwrap()
+                .render(&ctx)
+                .is_err()
+        );
+
+        let t = LabelTemplate::from_str("{:last};overflow=hash;max-length=40").unwrap();
+        assert_eq!(t.to_string(), "{:last};max-length=40;overflow=hash");
+        assert_eq!(
+            LabelTemplate::from_str("{:last}").unwrap().to_string(),
+            "{:last}"
+        );
+        for invalid in [
+            "{:last};max-length=64",
+            "{:last};max-length=0",
+            "{:last};overflow=wrap",
+            "{:last};max-length=4;overflow=hash",
+            "{:last};color=red",
+        ] {
+            assert!(matches!(
+                LabelTemplate::from_str(invalid),
+                Err(Error::TemplateParser(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn test_template_render_bucket() {
+        let render = |template: &str, provider_id: &str| {
+            let id = ProviderID::new("my-node", provider_id).unwrap();
+            LabelTemplate::from_str(template)
+                .unwrap()
+                .render(&RenderContext::new(&id))
+                .unwrap()
+        };
+
+        // the hash is stable across releases, so nodes keep their bucket
+        let id = "aws://us-east-2/i-1234567890abcdef0";
+        assert_eq!(render("{:bucket(10)}", id), "4");
+        assert_eq!(render("shard-{:bucket(4)}", id), "shard-0");
+        assert_eq!(render("{:bucket(1)}", id), "0");
+
+        // nodes spread over the buckets
+        let buckets = (0..100)
+            .map(|i| render("{:bucket(4)}", &format!("fake://region/instance-{i}")))
+            .collect::<std::collections::BTreeSet<_>>();
+        assert_eq!(buckets.len(), 4);
+
+        assert!(LabelTemplate::from_str("{:bucket(0)}").is_err());
+        assert!(LabelTemplate::from_str("{:bucket()}").is_err());
+        assert!(ProviderIDTemplate::from_str("metal://{:bucket(4)}").is_err());
+    }
+
+    #[test]
+    fn test_template_render_lifecycle() {
+        let id = ProviderID::new("my-node-name", "aws://us-east-2/i-1234567890abcdef0").unwrap();
+        let metadata = ObjectMeta {
+            labels: Some(
+                [(
+                    "eks.amazonaws.com/capacityType".to_string(),
+                    "SPOT".to_string(),
+                )]
+                .into(),
+            ),
+            ..Default::default()
+        };
+        let template = LabelTemplate::from_str("{:lifecycle}-{:last}").unwrap();
+        let output = template
+            .render(&RenderContext::new(&id).with_metadata(&metadata))
+            .unwrap();
+        assert_eq!(output, "spot-i-1234567890abcdef0");
+
+        assert!(matches!(
+            template.render(&RenderContext::new(&id)),
+            Err(Error::MissingField(_))
+        ));
+        assert!(ProviderIDTemplate::from_str("metal://{:lifecycle}").is_err());
+    }
+
+    #[test]
+    fn test_template_render_filters() {
+        let id = ProviderID::new("my-node-name", "aws://us-east-2/i-1234567890abcdef0").unwrap();
+        let fields = Fields::from([("azure:sku".to_string(), "Standard_D2s_v3".to_string())]);
+        let ctx = RenderContext::new(&id).with_fields(&fields);
+        for (template, expected) in [
+            ("{azure:sku|kebab}", "standard-d2s-v3"),
+            ("{azure:sku|snake}", "standard_d2s_v3"),
+            ("{azure:sku|camel}", "standardD2sV3"),
+            ("{:last|camel}", "i1234567890abcdef0"),
+            // filters apply in order
+            ("{azure:sku|camel|snake}", "standard_d2s_v3"),
+            ("{azure:sku|split('_',1)}", "D2s"),
+            ("{azure:sku|split('_', 2)|camel}", "v3"),
+            ("{azure:sku|split('_D',0)}", "Standard"),
+        ] {
+            let output = LabelTemplate::from_str(template)
+                .unwrap()
+                .render(&ctx)
+                .unwrap();
+            assert_eq!(output, expected, "{template}");
+        }
+
+        // fallback values are filtered too
+        let metadata = ObjectMeta {
+            labels: Some(
+                [(
+                    "topology.kubernetes.io/zone".to_string(),
+                    "US_East_1a".to_string(),
+                )]
+                .into(),
+            ),
+            ..Default::default()
+        };
+        let ctx = RenderContext::new(&id)
+            .with_metadata(&metadata)
+            .with_topology_fallback();
+        let output = LabelTemplate::from_str("{:first|zone|kebab}")
+            .unwrap()
+            .render(&ctx)
+            .unwrap();
+        assert_eq!(output, "us-east-1a");
+
+        // a part that doesn't exist is missing
+        assert!(matches!(
+            LabelTemplate::from_str("{azure:sku|split('.',1)}")
+                .unwrap()
+                .render(&RenderContext::new(&id).with_fields(&fields)),
+            Err(Error::MissingField(_))
+        ));
+
+        assert!(LabelTemplate::from_str("{:last|upper}").is_err());
+        assert!(LabelTemplate::from_str("{:last|split('',0)}").is_err());
+        assert!(LabelTemplate::from_str("{:last|map()}").is_err());
+        assert!(LabelTemplate::from_str("{:url|kebab}").is_err());
+    }
+
+    #[test]
+    fn test_template_render_map() {
+        let id = ProviderID::new("my-node-name", "aws://us-east-2/i-1234567890abcdef0").unwrap();
+        let maps = Maps::from([(
+            "regions".to_string(),
+            BTreeMap::from([("us-east-2".to_string(), "Ohio_DC".to_string())]),
+        )]);
+        let ctx = RenderContext::new(&id).with_maps(&maps);
+        let output = LabelTemplate::from_str("{:first|map(regions)|kebab}")
+            .unwrap()
+            .render(&ctx)
+            .unwrap();
+        assert_eq!(output, "ohio-dc");
+
+        // a key that isn't in the table is missing
+        assert!(matches!(
+            LabelTemplate::from_str("{:last|map(regions)}")
+                .unwrap()
+                .render(&ctx),
+            Err(Error::MissingField(_))
+        ));
+        assert!(matches!(
+            LabelTemplate::from_str("{:first|map(zones)}")
+                .unwrap()
+                .render(&ctx),
+            Err(Error::TemplateParser(_))
+        ));
+    }
+
+    #[test]
+    fn test_template_render_fields() {
+        let id = ProviderID::new("my-node-name", "aws://us-east-2/i-1234567890abcdef0").unwrap();
+        let mut fields = Fields::new();
+        fields.insert("azure:sku".to_string(), "Standard_D2s_v3".to_string());
+        fields.insert("azure:tag:team".to_string(), "platform/core".to_string());
+
+        let output = LabelTemplate::from_str("{azure:sku}-{:last}")
+            .unwrap()
+            .render(&RenderContext::new(&id).with_fields(&fields))
+            .unwrap();
+        assert_eq!(output, "Standard_D2s_v3-i-1234567890abcdef0");
+
+        let output = LabelTemplate::from_str("{azure:tag:team}")
+            .unwrap()
+            .render(&RenderContext::new(&id).with_fields(&fields))
+            .unwrap();
+        assert_eq!(output, "platform_core");
+
+        let output = AnnotationTemplate::from_str("{azure:tag:team}")
+            .unwrap()
+            .render(&RenderContext::new(&id).with_fields(&fields))
+            .unwrap();
+        assert_eq!(output, "platform/core");
+
+        assert!(matches!(
+            LabelTemplate::from_str("{azure:tag:missing}")
+                .unwrap()
+                .render(&RenderContext::new(&id).with_fields(&fields)),
+            Err(Error::MissingField(_))
+        ));
+    }
+
+    #[test]
+    fn test_template_render_sources() {
+        use crate::source::{NodeLabels, StaticValues};
+
+        let id = ProviderID::new("my-node-name", "aws://us-east-2/i-1234567890abcdef0").unwrap();
+        let metadata = ObjectMeta {
+            labels: Some([("topology.kubernetes.io/zone".into(), "us-east-2a".into())].into()),
+            ..Default::default()
+        };
+        let mut fields = Fields::new();
+        fields.insert("cluster:name".to_string(), "enriched".to_string());
+        let sources: Vec<Arc<dyn ValueSource>> = vec![
+            Arc::new(NodeLabels),
+            Arc::new(StaticValues::new(
+                "cluster",
+                [
+                    ("name".into(), "static".into()),
+                    ("env".into(), "prod".into()),
+                ]
+                .into(),
+            )),
+        ];
+        let ctx = RenderContext::new(&id)
+            .with_metadata(&metadata)
+            .with_fields(&fields)
+            .with_sources(&sources);
+        let render = |template: &str| LabelTemplate::from_str(template).unwrap().render(&ctx);
+
+        assert_eq!(
+            render("{label:topology.kubernetes.io/zone}-{:last}").unwrap(),
+            "us-east-2a-i-1234567890abcdef0"
+        );
+        // enrichment fields take precedence over sources
+        assert_eq!(
+            render("{cluster:name}-{cluster:env}").unwrap(),
+            "enriched-prod"
+        );
+        assert!(matches!(
+            render("{label:missing}"),
+            Err(Error::MissingField(_))
+        ));
+    }
+
+    #[test]
+    fn test_template_render_transforms() {
+        #[derive(Debug)]
+        struct Upper;
+
+        impl Transform for Upper {
+            fn name(&self) -> &str {
+                "upper"
+            }
+
+            fn apply(&self, input: &str) -> Result<String, Error> {
+                Ok(input.to_uppercase())
+            }
+        }
+
+        let id = ProviderID::new("my-node-name", "aws://us-east-2/i-1234567890abcdef0").unwrap();
+        let transforms: Vec<Arc<dyn Transform>> = vec![Arc::new(Upper)];
+        let ctx = RenderContext::new(&id).with_transforms(&transforms);
+
+        let output = LabelTemplate::from_str("{plugin:upper(:last)}-{:first}")
+            .unwrap()
+            .render(&ctx)
+            .unwrap();
+        assert_eq!(output, "I-1234567890ABCDEF0-us-east-2");
+
+        // labels are sanitized after the transform
+        let output = LabelTemplate::from_str("{plugin:upper(:url)}")
+            .unwrap()
+            .render(&ctx)
+            .unwrap();
+        assert_eq!(output, "AWS_US-EAST-2_I-1234567890ABCDEF0");
+
+        let output = AnnotationTemplate::from_str("{plugin:upper(:url)}")
+            .unwrap()
+            .render(&ctx)
+            .unwrap();
+        assert_eq!(output, "AWS://US-EAST-2/I-1234567890ABCDEF0");
+
+        assert!(matches!(
+            LabelTemplate::from_str("{plugin:lower(:last)}")
+                .unwrap()
+                .render(&ctx),
+            Err(Error::Plugin(_))
+        ));
+        assert!(matches!(
+            LabelTemplate::from_str("{plugin:upper(azure:sku)}")
+                .unwrap()
+                .render(&ctx),
+            Err(Error::MissingField(_))
+        ));
+    }
+
+    #[test]
+    fn test_provider_id_template() {
+        let t = |template: &str| ProviderIDTemplate::from_str(template);
+        assert!(t("metal://{label:rack}/{:node}").is_ok());
+        assert!(t("metal://{:last}").is_err());
+        assert!(t("metal://{plugin:upper(:node)}").is_err());
+        assert!(t("{:node}").is_err());
+
+        let metadata = ObjectMeta {
+            labels: Some([("rack".to_string(), "r12".to_string())].into()),
+            ..Default::default()
+        };
+        let sources = crate::source::defaults();
+        let id = t("metal://{label:rack}/{:node}")
+            .unwrap()
+            .render("my-node", &metadata, &sources)
+            .unwrap();
+        assert_eq!(id.to_string(), "metal://r12/my-node");
+        assert_eq!(id.last(), "my-node");
+
+        assert!(matches!(
+            t("metal://{label:row}/{:node}")
+                .unwrap()
+                .render("my-node", &metadata, &sources),
+            Err(Error::MissingField(_))
+        ));
+        assert!(matches!(
+            t("metal://{label:empty}").unwrap().render(
+                "my-node",
+                &ObjectMeta {
+                    labels: Some([("empty".to_string(), String::new())].into()),
+                    ..Default::default()
+                },
+                &sources
+            ),
+            Err(Error::ProviderID(_))
+        ));
+    }
+
+    #[test]
+    fn test_topology_fallback() {
+        let t = |template: &str| LabelTemplate::from_str(template).unwrap();
+        assert!(LabelTemplate::from_str("{:first|rack}").is_err());
+        assert!(LabelTemplate::from_str("{:node|zone}").is_err());
+
+        // with a provider ID, the fallback is ignored
+        let id = ProviderID::new("my-node", "aws://us-east-2a/i-0abc").unwrap();
+        let ctx = RenderContext::new(&id);
+        assert_eq!(t("{:first|zone}").render(&ctx).unwrap(), "us-east-2a");
+        assert_eq!(
+            t("{0|zone}-{:last|region}").render(&ctx).unwrap(),
+            "us-east-2a-i-0abc"
+        );
+
+        let metadata = ObjectMeta {
+            labels: Some(
+                [
+                    (
+                        "topology.kubernetes.io/zone".to_string(),
+                        "dc1-a".to_string(),
+                    ),
+                    (
+                        "topology.kubernetes.io/region".to_string(),
+                        "dc1".to_string(),
+                    ),
+                ]
+                .into(),
+            ),
+            ..Default::default()
+        };
+        let placeholder = ProviderID::new("my-node", "none://none").unwrap();
+        let ctx = RenderContext::new(&placeholder)
+            .with_metadata(&metadata)
+            .with_topology_fallback();
+        assert_eq!(t("{:first|zone}").render(&ctx).unwrap(), "dc1-a");
+        assert_eq!(t("{1|region}-{:node}").render(&ctx).unwrap(), "dc1-my-node");
+        assert!(matches!(
+            t("{:first}").render(&ctx),
+            Err(Error::MissingObjectKey(PROVIDER_ID_KEY))
+        ));
+
+        let ctx = RenderContext::new(&placeholder).with_topology_fallback();
+        assert!(matches!(
+            t("{:first|zone}").render(&ctx),
+            Err(Error::MissingField(_))
+        ));
+    }
 }
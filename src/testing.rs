@@ -0,0 +1,270 @@
+//! Helpers for testing code built on this crate: node fixtures and the
+//! rendering pipeline the controller runs, without an API server.
+//!
+//! ```
+//! use node_provider_labeler::testing::{self, Pipeline};
+//!
+//! let node = testing::node("worker-1")
+//!     .provider_id("aws://us-west-2/i-0abcdef1234567890")
+//!     .label("topology.kubernetes.io/zone", "us-west-2a")
+//!     .build();
+//! let patch = Pipeline::new()
+//!     .label("zone", "{label:topology.kubernetes.io/zone}")
+//!     .annotation("instance", "{:last}")
+//!     .render(&node)
+//!     .unwrap();
+//!
+//! testing::assert_pairs(&patch.labels, &[("zone", "us-west-2a")]);
+//! testing::assert_pairs(&patch.annotations, &[("instance", "i-0abcdef1234567890")]);
+//! ```
+use crate::{
+    controller,
+    renderer::node_provider_id,
+    sink::{MetadataPairs, Sink, Target, TargetPatch},
+    source::{self, ValueSource},
+    template::{Fields, RenderContext},
+    transform::Transform,
+    Error,
+};
+use k8s_openapi::api::core::v1::{Node, NodeCondition, NodeSpec, NodeStatus, Taint};
+use kube::api::ObjectMeta;
+use std::sync::Arc;
+
+/// Starts a node fixture.
+pub fn node(name: &str) -> NodeBuilder {
+    NodeBuilder(Node {
+        metadata: ObjectMeta {
+            name: Some(name.into()),
+            ..Default::default()
+        },
+        spec: Some(NodeSpec::default()),
+        ..Default::default()
+    })
+}
+
+/// Builds a [`Node`] fixture.
+#[derive(Debug)]
+pub struct NodeBuilder(Node);
+
+impl NodeBuilder {
+    pub fn provider_id(mut self, provider_id: &str) -> Self {
+        self.spec().provider_id = Some(provider_id.into());
+        self
+    }
+
+    pub fn label(mut self, key: &str, value: &str) -> Self {
+        self.0
+            .metadata
+            .labels
+            .get_or_insert_with(Default::default)
+            .insert(key.into(), value.into());
+        self
+    }
+
+    pub fn annotation(mut self, key: &str, value: &str) -> Self {
+        self.0
+            .metadata
+            .annotations
+            .get_or_insert_with(Default::default)
+            .insert(key.into(), value.into());
+        self
+    }
+
+    pub fn taint(mut self, key: &str, value: &str, effect: &str) -> Self {
+        self.spec()
+            .taints
+            .get_or_insert_with(Default::default)
+            .push(taint(key, value, effect));
+        self
+    }
+
+    /// Sets the node's Ready condition.
+    pub fn ready(mut self, ready: bool) -> Self {
+        self.0
+            .status
+            .get_or_insert_with(NodeStatus::default)
+            .conditions
+            .get_or_insert_with(Default::default)
+            .push(NodeCondition {
+                type_: "Ready".into(),
+                status: if ready { "True" } else { "False" }.into(),
+                ..Default::default()
+            });
+        self
+    }
+
+    pub fn build(self) -> Node {
+        self.0
+    }
+
+    fn spec(&mut self) -> &mut NodeSpec {
+        self.0.spec.get_or_insert_with(Default::default)
+    }
+}
+
+/// Builds a [`Taint`].
+pub fn taint(key: &str, value: &str, effect: &str) -> Taint {
+    Taint {
+        key: key.into(),
+        value: Some(value.into()),
+        effect: effect.into(),
+        time_added: None,
+    }
+}
+
+/// Renders nodes the way the controller does, returning the patch it would
+/// apply. Configured like [`ControllerBuilder`](crate::ControllerBuilder);
+/// invalid keys and templates are reported by [`Pipeline::render`].
+#[derive(Debug)]
+pub struct Pipeline {
+    labels: Vec<String>,
+    annotations: Vec<String>,
+    taints: Vec<String>,
+    sinks: Vec<Arc<dyn Sink>>,
+    sources: Vec<Arc<dyn ValueSource>>,
+    transforms: Vec<Arc<dyn Transform>>,
+    fields: Fields,
+}
+
+impl Default for Pipeline {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Pipeline {
+    pub fn new() -> Self {
+        Self {
+            labels: vec![],
+            annotations: vec![],
+            taints: vec![],
+            sinks: vec![],
+            sources: source::defaults(),
+            transforms: vec![],
+            fields: Fields::new(),
+        }
+    }
+
+    pub fn label(mut self, key: &str, template: &str) -> Self {
+        self.labels.push(format!("{key}={template}"));
+        self
+    }
+
+    pub fn annotation(mut self, key: &str, template: &str) -> Self {
+        self.annotations.push(format!("{key}={template}"));
+        self
+    }
+
+    pub fn taint(mut self, key: &str, template: &str, effect: &str) -> Self {
+        self.taints.push(format!("{key}={template}:{effect}"));
+        self
+    }
+
+    pub fn sink(mut self, sink: impl Sink + 'static) -> Self {
+        self.sinks.push(Arc::new(sink));
+        self
+    }
+
+    pub fn source(mut self, source: impl ValueSource + 'static) -> Self {
+        self.sources.push(Arc::new(source));
+        self
+    }
+
+    pub fn transform(mut self, transform: impl Transform + 'static) -> Self {
+        self.transforms.push(Arc::new(transform));
+        self
+    }
+
+    /// Sets a field as an enricher would, e.g. `("azure:sku", "Standard_D2s_v3")`.
+    pub fn field(mut self, name: &str, value: &str) -> Self {
+        self.fields.insert(name.into(), value.into());
+        self
+    }
+
+    /// Renders the node. A patch with no changes means the controller would
+    /// leave the node alone.
+    pub fn render(&self, node: &Node) -> Result<TargetPatch, Error> {
+        let provider_id = node_provider_id(node)?;
+        let annotations = controller::parse_renderers(non_empty(&self.annotations))?;
+        let taints = controller::parse_taints(non_empty(&self.taints))?;
+        let labels = controller::default_labels(
+            controller::parse_renderers(non_empty(&self.labels))?,
+            &annotations,
+            &taints,
+            &self.sinks,
+        );
+        let sinks = controller::by_priority(
+            controller::builtin_sinks(labels, annotations, taints)
+                .chain(self.sinks.iter().cloned()),
+        )
+        .collect::<Vec<_>>();
+
+        let ctx = RenderContext::new(&provider_id)
+            .with_metadata(&node.metadata)
+            .with_fields(&self.fields)
+            .with_sources(&self.sources)
+            .with_transforms(&self.transforms);
+        controller::render_sinks(&sinks, Target::Node(node), &ctx)
+    }
+}
+
+fn non_empty(list: &[String]) -> Option<Vec<String>> {
+    (!list.is_empty()).then(|| list.to_vec())
+}
+
+/// Asserts that the pairs are exactly `expected`.
+#[track_caller]
+pub fn assert_pairs(pairs: &MetadataPairs, expected: &[(&str, &str)]) {
+    let expected = expected
+        .iter()
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .collect::<MetadataPairs>();
+    assert_eq!(pairs, &expected);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pipeline() {
+        let node = node("my-node")
+            .provider_id("fake://region/instance")
+            .label("zone", "region")
+            .taint("other", "x", "NoSchedule")
+            .build();
+
+        // the default label
+        let patch = Pipeline::new().render(&node).unwrap();
+        assert_pairs(&patch.labels, &[("provider-id", "instance")]);
+        assert_eq!(patch.taints, None);
+        assert_eq!(patch.changed, 1);
+
+        let patch = Pipeline::new()
+            .label("zone", "{:first}")
+            .annotation("sku", "{azure:sku}")
+            .taint("dedicated", "{:last}", "NoSchedule")
+            .field("azure:sku", "Standard_D2s_v3")
+            .render(&node)
+            .unwrap();
+        assert_pairs(&patch.labels, &[("zone", "region")]);
+        assert_pairs(&patch.annotations, &[("sku", "Standard_D2s_v3")]);
+        assert_eq!(
+            patch.taints,
+            Some(vec![
+                taint("other", "x", "NoSchedule"),
+                taint("dedicated", "instance", "NoSchedule"),
+            ])
+        );
+        assert_eq!(patch.changed, 2);
+
+        assert!(matches!(
+            Pipeline::new().render(&super::node("no-provider-id").build()),
+            Err(Error::MissingObjectKey(_))
+        ));
+        assert!(Pipeline::new()
+            .label("zone", "{:first")
+            .render(&node)
+            .is_err());
+    }
+}
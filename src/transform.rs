@@ -0,0 +1,15 @@
+use crate::Error;
+
+/// Transforms a rendered value, callable from templates as
+/// `{plugin:<name>(<token>)}`, e.g. `{plugin:my_transform(:last)}`.
+///
+/// The token is rendered first and passed to the transform unsanitized;
+/// label values are sanitized after the transform runs. Transforms are
+/// registered on the controller, and an unknown name fails the render.
+pub trait Transform: std::fmt::Debug + Send + Sync {
+    /// The name templates call the transform by.
+    fn name(&self) -> &str;
+
+    /// Transforms `input`, failing with [`Error::Plugin`].
+    fn apply(&self, input: &str) -> Result<String, Error>;
+}
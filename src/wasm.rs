@@ -0,0 +1,171 @@
+//! [`Transform`]s implemented by WebAssembly modules, run with wasmtime.
+//!
+//! A module imports nothing and exports:
+//!
+//! * `memory`, its linear memory
+//! * `alloc(len: i32) -> i32`, returning a pointer to `len` writable bytes for
+//!   the input
+//! * `transform(ptr: i32, len: i32) -> i64`, transforming the UTF-8 input at
+//!   `ptr` and returning the pointer to its UTF-8 output in the high 32 bits
+//!   and the output's length in the low 32 bits
+//!
+//! Each call runs in a fresh instance with a fuel limit, so a plugin can't
+//! keep state between nodes or stall reconciliation.
+use crate::{transform::Transform, Error};
+use std::path::Path;
+use wasmtime::{Config, Engine, Instance, Module, Store};
+
+/// Instructions, roughly, a plugin may execute per call.
+const FUEL: u64 = 10_000_000;
+/// The longest output a plugin may return.
+const MAX_OUTPUT: usize = 64 * 1024;
+
+pub struct WasmTransform {
+    name: String,
+    engine: Engine,
+    module: Module,
+}
+
+impl std::fmt::Debug for WasmTransform {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("WasmTransform")
+            .field("name", &self.name)
+            .finish_non_exhaustive()
+    }
+}
+
+impl WasmTransform {
+    /// Compiles the module (binary, or text with the `wat` syntax) and checks
+    /// that it implements the interface.
+    pub fn new(name: impl Into<String>, module: impl AsRef<[u8]>) -> Result<Self, Error> {
+        let name = name.into();
+        let mut config = Config::new();
+        config.consume_fuel(true);
+        let engine = Engine::new(&config).map_err(|e| plugin_error(&name, e))?;
+        let module = Module::new(&engine, module).map_err(|e| plugin_error(&name, e))?;
+
+        if module.imports().len() > 0 {
+            return Err(Error::Plugin(format!("{name}: modules must not import")));
+        }
+        for export in ["memory", "alloc", "transform"] {
+            if module.get_export(export).is_none() {
+                return Err(Error::Plugin(format!("{name}: missing export '{export}'")));
+            }
+        }
+
+        Ok(Self {
+            name,
+            engine,
+            module,
+        })
+    }
+
+    /// Loads the module from a `.wasm` (or `.wat`) file.
+    pub fn from_file(name: impl Into<String>, path: impl AsRef<Path>) -> Result<Self, Error> {
+        let name = name.into();
+        let module = std::fs::read(path.as_ref()).map_err(|e| {
+            Error::Plugin(format!(
+                "{name}: unable to read {}: {e}",
+                path.as_ref().display()
+            ))
+        })?;
+        Self::new(name, module)
+    }
+
+    fn call(&self, input: &str) -> wasmtime::Result<String> {
+        let mut store = Store::new(&self.engine, ());
+        store.set_fuel(FUEL)?;
+        let instance = Instance::new(&mut store, &self.module, &[])?;
+        let memory = instance
+            .get_memory(&mut store, "memory")
+            .ok_or_else(|| wasmtime::Error::msg("'memory' is not a memory"))?;
+        let alloc = instance.get_typed_func::<i32, i32>(&mut store, "alloc")?;
+        let transform = instance.get_typed_func::<(i32, i32), i64>(&mut store, "transform")?;
+
+        let len = i32::try_from(input.len())?;
+        let ptr = alloc.call(&mut store, len)?;
+        memory.write(&mut store, ptr as u32 as usize, input.as_bytes())?;
+
+        let packed = transform.call(&mut store, (ptr, len))? as u64;
+        let (ptr, len) = ((packed >> 32) as usize, (packed & 0xffff_ffff) as usize);
+        if len > MAX_OUTPUT {
+            return Err(wasmtime::Error::msg(format!(
+                "output of {len} bytes exceeds {MAX_OUTPUT}"
+            )));
+        }
+        let mut output = vec![0; len];
+        memory.read(&store, ptr, &mut output)?;
+        Ok(String::from_utf8(output)?)
+    }
+}
+
+impl Transform for WasmTransform {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn apply(&self, input: &str) -> Result<String, Error> {
+        self.call(input).map_err(|e| plugin_error(&self.name, e))
+    }
+}
+
+fn plugin_error(name: &str, e: wasmtime::Error) -> Error {
+    Error::Plugin(format!("{name}: {e:#}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const UPPER: &str = r#"
+        (module
+          (memory (export "memory") 1)
+          (func (export "alloc") (param $len i32) (result i32)
+            (i32.const 1024))
+          (func (export "transform") (param $ptr i32) (param $len i32) (result i64)
+            (local $i i32)
+            (local $c i32)
+            (block $done
+              (loop $next
+                (br_if $done (i32.ge_u (local.get $i) (local.get $len)))
+                (local.set $c (i32.load8_u (i32.add (local.get $ptr) (local.get $i))))
+                (if (i32.and (i32.ge_u (local.get $c) (i32.const 97))
+                             (i32.le_u (local.get $c) (i32.const 122)))
+                  (then (i32.store8 (i32.add (local.get $ptr) (local.get $i))
+                                    (i32.sub (local.get $c) (i32.const 32)))))
+                (local.set $i (i32.add (local.get $i) (i32.const 1)))
+                (br $next)))
+            (i64.or (i64.shl (i64.extend_i32_u (local.get $ptr)) (i64.const 32))
+                    (i64.extend_i32_u (local.get $len)))))
+    "#;
+
+    const SPIN: &str = r#"
+        (module
+          (memory (export "memory") 1)
+          (func (export "alloc") (param i32) (result i32) (i32.const 0))
+          (func (export "transform") (param i32 i32) (result i64)
+            (loop $spin (br $spin))
+            (i64.const 0)))
+    "#;
+
+    #[test]
+    fn test_wasm_transform() {
+        let upper = WasmTransform::new("upper", UPPER).unwrap();
+        assert_eq!(upper.name(), "upper");
+        assert_eq!(upper.apply("i-0abc").unwrap(), "I-0ABC");
+        assert_eq!(upper.apply("").unwrap(), "");
+
+        // fuel runs out rather than the call hanging
+        let spin = WasmTransform::new("spin", SPIN).unwrap();
+        assert!(matches!(spin.apply("x"), Err(Error::Plugin(_))));
+
+        assert!(matches!(
+            WasmTransform::new("empty", "(module)"),
+            Err(Error::Plugin(_))
+        ));
+        assert!(matches!(
+            WasmTransform::new("invalid", "not wasm"),
+            Err(Error::Plugin(_))
+        ));
+    }
+}
@@ -0,0 +1,292 @@
+//! Cron schedules in UTC, and the change windows they open, at which the
+//! controller may write to nodes.
+use crate::Error;
+use std::{str::FromStr, time::Duration};
+use time::OffsetDateTime;
+
+/// The values a cron field matches.
+#[derive(Clone, Debug, PartialEq)]
+struct Field {
+    values: Vec<u8>,
+    any: bool,
+}
+
+impl Field {
+    fn parse(s: &str, min: u8, max: u8) -> Result<Self, Error> {
+        let invalid = || Error::Config(format!("invalid cron field '{s}'"));
+        let number = |n: &str| {
+            n.parse::<u8>()
+                .ok()
+                .filter(|n| (min..=max).contains(n))
+                .ok_or_else(invalid)
+        };
+        let mut values = vec![];
+        for part in s.split(',') {
+            let (range, step) = match part.split_once('/') {
+                Some((range, step)) => (range, step.parse::<u8>().map_err(|_| invalid())?),
+                None => (part, 1),
+            };
+            if step == 0 {
+                return Err(invalid());
+            }
+            let (start, end) = match range {
+                "*" => (min, max),
+                _ => match range.split_once('-') {
+                    Some((start, end)) => (number(start)?, number(end)?),
+                    None if step > 1 => (number(range)?, max),
+                    None => (number(range)?, number(range)?),
+                },
+            };
+            if start > end {
+                return Err(invalid());
+            }
+            values.extend((start..=end).step_by(step.into()));
+        }
+        Ok(Self {
+            values,
+            any: s == "*",
+        })
+    }
+
+    fn matches(&self, value: u8) -> bool {
+        self.values.contains(&value)
+    }
+}
+
+/// A cron schedule of minute, hour, day of month, month, and day of week, in
+/// UTC, e.g. "0 2 * * 1-5" for 02:00 on weekdays.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Schedule {
+    schedule: String,
+    minute: Field,
+    hour: Field,
+    day: Field,
+    month: Field,
+    weekday: Field,
+}
+
+impl Schedule {
+    /// Parses the schedule, with `*`, lists, ranges, and steps.
+    pub fn new(schedule: &str) -> Result<Self, Error> {
+        let fields = schedule.split_whitespace().collect::<Vec<_>>();
+        let [minute, hour, day, month, weekday] = fields[..] else {
+            return Err(Error::Config(format!(
+                "invalid schedule '{schedule}', expected 5 cron fields"
+            )));
+        };
+        let mut weekday = Field::parse(weekday, 0, 7)?;
+        // Sunday is 0 or 7
+        if weekday.matches(7) {
+            weekday.values.push(0);
+        }
+        Ok(Self {
+            schedule: schedule.to_string(),
+            minute: Field::parse(minute, 0, 59)?,
+            hour: Field::parse(hour, 0, 23)?,
+            day: Field::parse(day, 1, 31)?,
+            month: Field::parse(month, 1, 12)?,
+            weekday,
+        })
+    }
+
+    /// Whether the schedule fires at the minute. As in cron, a restricted
+    /// day of month and day of week match if either does.
+    fn fires_at(&self, t: OffsetDateTime) -> bool {
+        let day = self.day.matches(t.day());
+        let weekday = self.weekday.matches(t.weekday().number_days_from_sunday());
+        let date = match (self.day.any, self.weekday.any) {
+            (false, false) => day || weekday,
+            _ => day && weekday,
+        };
+        date && self.minute.matches(t.minute())
+            && self.hour.matches(t.hour())
+            && self.month.matches(t.month().into())
+    }
+
+    /// How long until the schedule fires next, after the minute of `now`, if
+    /// it does within `limit`.
+    pub fn next_within(&self, now: OffsetDateTime, limit: Duration) -> Option<Duration> {
+        let next = truncate(now) + time::Duration::minutes(1);
+        (0..=limit.as_secs() / 60)
+            .map(|m| next + time::Duration::minutes(m as i64))
+            .find(|t| self.fires_at(*t))
+            .and_then(|t| (t - now).try_into().ok())
+    }
+}
+
+impl FromStr for Schedule {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::new(s)
+    }
+}
+
+impl std::fmt::Display for Schedule {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.schedule)
+    }
+}
+
+/// A window that opens at the times of a cron [`Schedule`] and stays open
+/// for a duration.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ChangeWindow {
+    schedule: Schedule,
+    duration: Duration,
+}
+
+impl ChangeWindow {
+    /// Parses the window's cron schedule.
+    pub fn new(schedule: &str, duration: Duration) -> Result<Self, Error> {
+        if duration < Duration::from_secs(60) {
+            return Err(Error::Config(
+                "change window duration must be at least 60s".into(),
+            ));
+        }
+        Ok(Self {
+            schedule: schedule.parse()?,
+            duration,
+        })
+    }
+
+    /// Whether the window is open at `now`.
+    pub fn is_open(&self, now: OffsetDateTime) -> bool {
+        let now = truncate(now);
+        let minutes = self.duration.as_secs().div_ceil(60);
+        (0..minutes).any(|m| {
+            self.schedule
+                .fires_at(now - time::Duration::minutes(m as i64))
+        })
+    }
+
+    /// How long until the window opens next, if it does within `limit`.
+    pub fn opens_within(&self, now: OffsetDateTime, limit: Duration) -> Option<Duration> {
+        self.schedule.next_within(now, limit)
+    }
+}
+
+impl FromStr for ChangeWindow {
+    type Err = Error;
+
+    /// Parses a cron schedule, with the window open for an hour.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::new(s, Duration::from_secs(3600))
+    }
+}
+
+impl std::fmt::Display for ChangeWindow {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} for {}s", self.schedule, self.duration.as_secs())
+    }
+}
+
+fn truncate(t: OffsetDateTime) -> OffsetDateTime {
+    t.replace_second(0)
+        .and_then(|t| t.replace_nanosecond(0))
+        .unwrap_or(t)
+}
+
+/// Whether writes are allowed at `now`: always without windows, else while
+/// any is open. Otherwise, how long until one opens, if within `limit`.
+pub(crate) fn allows_writes(
+    windows: &[ChangeWindow],
+    now: OffsetDateTime,
+    limit: Duration,
+) -> Result<(), Option<Duration>> {
+    if windows.is_empty() || windows.iter().any(|w| w.is_open(now)) {
+        return Ok(());
+    }
+    Err(windows
+        .iter()
+        .filter_map(|w| w.opens_within(now, limit))
+        .min())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use time::format_description::well_known::Rfc3339;
+
+    fn datetime(s: &str) -> OffsetDateTime {
+        OffsetDateTime::parse(s, &Rfc3339).unwrap()
+    }
+
+    #[test]
+    fn test_change_window() {
+        let window = ChangeWindow::new("30 2 * * 1-5", Duration::from_secs(7200)).unwrap();
+        // Monday
+        assert!(!window.is_open(datetime("2024-06-03T02:29:59Z")));
+        assert!(window.is_open(datetime("2024-06-03T02:30:00Z")));
+        assert!(window.is_open(datetime("2024-06-03T04:29:59Z")));
+        assert!(!window.is_open(datetime("2024-06-03T04:30:00Z")));
+        // Sunday
+        assert!(!window.is_open(datetime("2024-06-02T03:00:00Z")));
+
+        assert_eq!(
+            window.opens_within(datetime("2024-06-03T02:00:30Z"), Duration::from_secs(3600)),
+            Some(Duration::from_secs(29 * 60 + 30))
+        );
+        assert_eq!(
+            window.opens_within(datetime("2024-06-03T05:00:00Z"), Duration::from_secs(3600)),
+            None
+        );
+
+        let sunday = ChangeWindow::new("*/15 22-23 1 * 7", Duration::from_secs(60)).unwrap();
+        assert!(sunday.is_open(datetime("2024-06-02T22:45:00Z")));
+        assert!(!sunday.is_open(datetime("2024-06-02T22:46:00Z")));
+        // the 1st of the month, a Saturday
+        assert!(sunday.is_open(datetime("2024-06-01T23:00:00Z")));
+        assert_eq!(sunday.to_string(), "*/15 22-23 1 * 7 for 60s");
+
+        for invalid in [
+            "* * * *",
+            "60 * * * *",
+            "* * 0 * *",
+            "5-1 * * * *",
+            "*/0 * * * *",
+        ] {
+            assert!(invalid.parse::<ChangeWindow>().is_err(), "{invalid}");
+        }
+        assert!(ChangeWindow::new("* * * * *", Duration::from_secs(1)).is_err());
+    }
+
+    #[test]
+    fn test_schedule() {
+        let nightly = Schedule::new("0 3 * * *").unwrap();
+        let day = Duration::from_secs(24 * 3600);
+        assert_eq!(
+            nightly.next_within(datetime("2024-06-03T02:59:30Z"), day),
+            Some(Duration::from_secs(30))
+        );
+        // not again within the minute it fired
+        assert_eq!(
+            nightly.next_within(datetime("2024-06-03T03:00:00Z"), day),
+            Some(day)
+        );
+        assert_eq!(
+            nightly.next_within(datetime("2024-06-03T03:00:00Z"), day / 2),
+            None
+        );
+        assert_eq!(nightly.to_string(), "0 3 * * *");
+        assert!("0 3 * *".parse::<Schedule>().is_err());
+    }
+
+    #[test]
+    fn test_allows_writes() {
+        let now = datetime("2024-06-03T01:00:00Z");
+        let hour = Duration::from_secs(3600);
+        assert_eq!(allows_writes(&[], now, hour), Ok(()));
+
+        let windows = ["0 3 * * *".parse().unwrap(), "30 1 * * *".parse().unwrap()];
+        assert_eq!(
+            allows_writes(&windows, now, hour),
+            Err(Some(Duration::from_secs(30 * 60)))
+        );
+        assert_eq!(allows_writes(&windows[..1], now, hour), Err(None));
+        assert_eq!(
+            allows_writes(&windows, datetime("2024-06-03T03:59:00Z"), hour),
+            Ok(())
+        );
+    }
+}
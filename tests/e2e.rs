@@ -0,0 +1,217 @@
+//! End-to-end tests against a kind cluster, run with
+//! `cargo test --features e2e --test e2e`. They create a cluster named
+//! "npl-e2e" and delete it afterwards, unless `NPL_E2E_CLUSTER` names an
+//! existing kind cluster to use instead.
+#![cfg(feature = "e2e")]
+
+use k8s_openapi::api::core::v1::Node;
+use kube::{
+    api::{DeleteParams, Patch, PatchParams, PostParams},
+    config::{KubeConfigOptions, Kubeconfig},
+    Api, Client, ResourceExt,
+};
+use node_provider_labeler::{controller, shutdown::Shutdown, Controller};
+use std::{
+    future::Future,
+    process::Command,
+    time::{Duration, Instant},
+};
+
+const CLUSTER: &str = "npl-e2e";
+const SELECTOR: &str = "node-provider-labeler/e2e";
+const MANAGER: &str = "node-provider-labeler";
+const TIMEOUT: Duration = Duration::from_secs(60);
+
+/// A kind cluster, deleted on drop if the tests created it.
+struct Cluster {
+    name: String,
+    created: bool,
+}
+
+impl Cluster {
+    fn start() -> Self {
+        if let Ok(name) = std::env::var("NPL_E2E_CLUSTER") {
+            return Self {
+                name,
+                created: false,
+            };
+        }
+        kind(&["create", "cluster", "--name", CLUSTER, "--wait", "120s"]);
+        Self {
+            name: CLUSTER.into(),
+            created: true,
+        }
+    }
+
+    async fn client(&self) -> Client {
+        let kubeconfig =
+            Kubeconfig::from_yaml(&kind(&["get", "kubeconfig", "--name", &self.name])).unwrap();
+        let config =
+            kube::Config::from_custom_kubeconfig(kubeconfig, &KubeConfigOptions::default())
+                .await
+                .unwrap();
+        Client::try_from(config).unwrap()
+    }
+}
+
+impl Drop for Cluster {
+    fn drop(&mut self) {
+        if self.created {
+            kind(&["delete", "cluster", "--name", &self.name]);
+        }
+    }
+}
+
+fn kind(args: &[&str]) -> String {
+    let output = Command::new("kind")
+        .args(args)
+        .output()
+        .expect("kind is installed");
+    assert!(
+        output.status.success(),
+        "kind {}: {}",
+        args.join(" "),
+        String::from_utf8_lossy(&output.stderr)
+    );
+    String::from_utf8(output.stdout).unwrap()
+}
+
+/// A node without a kubelet, with a fake provider ID.
+fn fake_node(name: &str, provider_id: &str) -> Node {
+    serde_json::from_value(serde_json::json!({
+        "metadata": {
+            "name": name,
+            "labels": { SELECTOR: "true", "team": "core", "zone": "user-zone" },
+        },
+        "spec": {
+            "providerID": provider_id,
+            "taints": [{ "key": SELECTOR, "value": "fake", "effect": "NoSchedule" }],
+        },
+    }))
+    .unwrap()
+}
+
+/// Polls a node until the check passes, panicking after [`TIMEOUT`].
+async fn eventually<F, Fut>(api: &Api<Node>, name: &str, what: &str, check: F) -> Node
+where
+    F: Fn(Node) -> Fut,
+    Fut: Future<Output = Option<Node>>,
+{
+    let start = Instant::now();
+    loop {
+        if let Some(node) = check(api.get(name).await.unwrap()).await {
+            return node;
+        }
+        assert!(
+            start.elapsed() < TIMEOUT,
+            "{name}: timed out waiting for {what}"
+        );
+        tokio::time::sleep(Duration::from_millis(500)).await;
+    }
+}
+
+fn label<'a>(node: &'a Node, key: &str) -> Option<&'a str> {
+    node.labels().get(key).map(String::as_str)
+}
+
+fn owns_label(node: &Node, manager: &str, key: &str) -> bool {
+    node.managed_fields().iter().any(|f| {
+        f.manager.as_deref() == Some(manager)
+            && f.fields_v1.as_ref().is_some_and(|fields| {
+                fields.0["f:metadata"]["f:labels"]
+                    .get(format!("f:{key}"))
+                    .is_some()
+            })
+    })
+}
+
+#[tokio::test]
+async fn test_e2e() {
+    let cluster = Cluster::start();
+    let client = cluster.client().await;
+    let api: Api<Node> = Api::all(client.clone());
+
+    let nodes = [
+        ("npl-e2e-1", "aws://us-east-2a/i-0000000000000001"),
+        ("npl-e2e-2", "gce://my-project/us-central1-a/npl-e2e-2"),
+    ];
+    for (name, provider_id) in nodes {
+        let _ = api.delete(name, &DeleteParams::default()).await;
+        api.create(&PostParams::default(), &fake_node(name, provider_id))
+            .await
+            .unwrap();
+    }
+
+    let (stop, shutdown) = Shutdown::manual();
+    let controller = Controller::builder()
+        .client(client.clone())
+        .label("zone", "{:first}")
+        .label("instance", "{:last}")
+        .annotation("team", "{label:team}")
+        .node_selector(SELECTOR)
+        .shutdown(shutdown)
+        .build()
+        .unwrap();
+    let running = tokio::spawn(controller.run());
+
+    // the templates converge, taking over the pre-existing zone label
+    let expected = [
+        ("npl-e2e-1", "us-east-2a", "i-0000000000000001"),
+        ("npl-e2e-2", "my-project", "npl-e2e-2"),
+    ];
+    for (name, zone, instance) in expected {
+        let node = eventually(&api, name, "labels", |node| async move {
+            (label(&node, "zone") == Some(zone) && label(&node, "instance") == Some(instance))
+                .then_some(node)
+        })
+        .await;
+        assert_eq!(
+            node.annotations().get("team").map(String::as_str),
+            Some("core")
+        );
+        assert!(owns_label(&node, MANAGER, "zone"));
+        assert!(owns_label(&node, MANAGER, "instance"));
+        assert!(!owns_label(&node, MANAGER, "team"));
+    }
+
+    // a change by someone else is reverted on the next event
+    let payload = serde_json::json!({ "metadata": { "labels": { "zone": "edited" } } });
+    api.patch(
+        "npl-e2e-1",
+        &PatchParams::default(),
+        &Patch::Merge(&payload),
+    )
+    .await
+    .unwrap();
+    eventually(
+        &api,
+        "npl-e2e-1",
+        "the zone to be restored",
+        |node| async move { (label(&node, "zone") == Some("us-east-2a")).then_some(node) },
+    )
+    .await;
+
+    stop.send(true).unwrap();
+    running.await.unwrap().unwrap();
+
+    // cleanup removes only the controller's keys
+    let cleaned = controller::cleanup(client.clone(), &[], false)
+        .await
+        .unwrap();
+    for (name, _) in nodes {
+        assert!(
+            cleaned.iter().any(|n| n == name),
+            "{name} wasn't cleaned up"
+        );
+        let node = api.get(name).await.unwrap();
+        assert_eq!(label(&node, "zone"), None);
+        assert_eq!(label(&node, "instance"), None);
+        assert_eq!(label(&node, "team"), Some("core"));
+        assert!(!node.annotations().contains_key("team"));
+        assert!(node
+            .managed_fields()
+            .iter()
+            .all(|f| f.manager.as_deref() != Some(MANAGER)));
+        api.delete(name, &DeleteParams::default()).await.unwrap();
+    }
+}